@@ -0,0 +1,520 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io;
+
+pub const ET_EXEC : u16 = 2;
+pub const ET_DYN : u16 = 3;
+pub const EM_RISCV : u16 = 243;
+
+pub const PT_LOAD : u32 = 1;
+pub const PT_INTERP : u32 = 3;
+pub const PT_PHDR : u32 = 6;
+
+pub const PF_X : u32 = 1;
+pub const PF_W : u32 = 2;
+pub const PF_R : u32 = 4;
+
+pub const SHT_SYMTAB : u32 = 2;
+pub const SHT_DYNSYM : u32 = 11;
+
+pub const STT_NOTYPE : u8 = 0;
+pub const STT_OBJECT : u8 = 1;
+pub const STT_FUNC : u8 = 2;
+
+pub const STB_GLOBAL : u8 = 1;
+pub const STB_WEAK : u8 = 2;
+
+const SHN_UNDEF : u16 = 0;
+
+/// e_flags: the program targets RV32E/RV64E.
+pub const EF_RISCV_RVE : u32 = 0x0008;
+
+const ELFCLASS32 : u8 = 1;
+const ELFCLASS64 : u8 = 2;
+const ELFDATA2LSB : u8 = 1;
+
+/// Size of the ELF header, e_ehsize.
+const EHDR_SIZE_32 : u64 = 52;
+const EHDR_SIZE_64 : u64 = 64;
+
+#[derive(Debug)]
+pub enum ElfError {
+    Io(io::Error),
+    BadMagic,
+    BadClass(u8),
+    NotLittleEndian,
+    NotRiscV(u16),
+    NotExecutable(u16),
+    Truncated,
+    /// A PT_LOAD segment with less memory than file contents, or one
+    /// that runs past the end of the address space.
+    BadSegment(u64)
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Io(e) => write!(f, "{}", e),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::BadClass(c) => write!(f, "unsupported ELF class {}", c),
+            ElfError::NotLittleEndian => write!(f, "not a little-endian ELF file"),
+            ElfError::NotRiscV(m) => write!(f, "not a RISC-V ELF file (e_machine = {})", m),
+            ElfError::NotExecutable(t) => write!(f, "not an executable ELF file (e_type = {})", t),
+            ElfError::Truncated => write!(f, "truncated ELF file"),
+            ElfError::BadSegment(vaddr) => write!(f, "bad PT_LOAD segment at 0x{:x}", vaddr)
+        }
+    }
+}
+
+impl From<io::Error> for ElfError {
+    fn from(e : io::Error) -> Self {
+        ElfError::Io(e)
+    }
+}
+
+#[derive(Debug)]
+pub struct ProgramHeader {
+    pub p_type : u32,
+    pub flags : u32,
+    pub offset : u64,
+    pub vaddr : u64,
+    pub filesz : u64,
+    pub memsz : u64
+}
+
+#[derive(Debug)]
+pub struct SectionHeader {
+    pub sh_type : u32,
+    pub offset : u64,
+    pub size : u64,
+    pub link : u32,
+    pub entsize : u64
+}
+
+#[derive(Debug)]
+pub struct ElfSymbol {
+    pub name : String,
+    pub value : u64,
+    pub size : u64,
+    pub sym_type : u8,
+    pub bind : u8
+}
+
+#[derive(Debug)]
+pub struct ElfFile {
+    pub data : Vec<u8>,
+    /// ELFCLASS32, i.e. an RV32 program.
+    pub elf32 : bool,
+    pub e_type : u16,
+    pub flags : u32,
+    pub entry : u64,
+    pub phoff : u64,
+    pub phentsize : u16,
+    pub phdrs : Vec<ProgramHeader>,
+    pub shdrs : Vec<SectionHeader>
+}
+
+#[inline(always)]
+fn bytes(data : &[u8], off : u64, len : u64) -> Result<&[u8], ElfError> {
+    let start = off as usize;
+    let end = start.checked_add(len as usize).ok_or(ElfError::Truncated)?;
+    data.get(start..end).ok_or(ElfError::Truncated)
+}
+
+#[inline(always)]
+fn u16_at(data : &[u8], off : u64) -> Result<u16, ElfError> {
+    Ok(u16::from_le_bytes(bytes(data, off, 2)?.try_into().unwrap()))
+}
+
+#[inline(always)]
+fn u32_at(data : &[u8], off : u64) -> Result<u32, ElfError> {
+    Ok(u32::from_le_bytes(bytes(data, off, 4)?.try_into().unwrap()))
+}
+
+#[inline(always)]
+fn u64_at(data : &[u8], off : u64) -> Result<u64, ElfError> {
+    Ok(u64::from_le_bytes(bytes(data, off, 8)?.try_into().unwrap()))
+}
+
+/// An address or size field: 4 bytes in ELF32, 8 in ELF64.
+#[inline(always)]
+fn word_at(data : &[u8], off : u64, elf32 : bool) -> Result<u64, ElfError> {
+    if elf32 { Ok(u32_at(data, off)? as u64) } else { u64_at(data, off) }
+}
+
+impl ElfFile {
+    pub fn open(filename : &str) -> Result<Self, ElfError> {
+        Self::parse(fs::read(filename)?)
+    }
+
+    pub fn parse(data : Vec<u8>) -> Result<Self, ElfError> {
+        if bytes(&data, 0, 4)? != b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+
+        let elf32 = match bytes(&data, 4, 1)?[0] {
+            ELFCLASS32 => true,
+            ELFCLASS64 => false,
+            class => return Err(ElfError::BadClass(class))
+        };
+
+        // The whole header has to be there before any of it is read
+        bytes(&data, 0, if elf32 { EHDR_SIZE_32 } else { EHDR_SIZE_64 })?;
+
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+
+        let e_type = u16_at(&data, 16)?;
+        let e_machine = u16_at(&data, 18)?;
+
+        if e_machine != EM_RISCV {
+            return Err(ElfError::NotRiscV(e_machine));
+        }
+
+        if e_type != ET_EXEC && e_type != ET_DYN {
+            return Err(ElfError::NotExecutable(e_type));
+        }
+
+        // Past e_entry, ELF32 headers are the ELF64 ones with 4-byte words
+        let w = if elf32 { 4 } else { 8 };
+        let word = |off| word_at(&data, off, elf32);
+
+        let entry = word(24)?;
+        let phoff = word(24 + w)?;
+        let shoff = word(24 + 2 * w)?;
+        let flags = u32_at(&data, 24 + 3 * w)?;
+        let phentsize = u16_at(&data, 30 + 3 * w)?;
+        let phnum = u16_at(&data, 32 + 3 * w)?;
+        let shentsize = u16_at(&data, 34 + 3 * w)?;
+        let shnum = if shoff == 0 { 0 } else { u16_at(&data, 36 + 3 * w)? };
+
+        // So that phdr_table() can hand out the table as it is
+        bytes(&data, phoff, phnum as u64 * phentsize as u64)?;
+
+        let mut phdrs = Vec::with_capacity(phnum as usize);
+
+        for i in 0..phnum as u64 {
+            let ph = phoff + i * phentsize as u64;
+
+            // ELF64 moves p_flags up next to p_type
+            phdrs.push(if elf32 {
+                ProgramHeader {
+                    p_type : u32_at(&data, ph)?,
+                    flags : u32_at(&data, ph + 24)?,
+                    offset : word(ph + 4)?,
+                    vaddr : word(ph + 8)?,
+                    filesz : word(ph + 16)?,
+                    memsz : word(ph + 20)?
+                }
+            }
+            else {
+                ProgramHeader {
+                    p_type : u32_at(&data, ph)?,
+                    flags : u32_at(&data, ph + 4)?,
+                    offset : word(ph + 8)?,
+                    vaddr : word(ph + 16)?,
+                    filesz : word(ph + 32)?,
+                    memsz : word(ph + 40)?
+                }
+            });
+        }
+
+        for ph in phdrs.iter().filter(|ph| ph.p_type == PT_LOAD) {
+            bytes(&data, ph.offset, ph.filesz)?;
+
+            if ph.memsz < ph.filesz || ph.vaddr.checked_add(ph.memsz).is_none() {
+                return Err(ElfError::BadSegment(ph.vaddr));
+            }
+        }
+
+        let mut shdrs = Vec::with_capacity(shnum as usize);
+
+        for i in 0..shnum as u64 {
+            let sh = shoff + i * shentsize as u64;
+            shdrs.push(SectionHeader {
+                sh_type : u32_at(&data, sh + 4)?,
+                offset : word(sh + 8 + 2 * w)?,
+                size : word(sh + 8 + 3 * w)?,
+                link : u32_at(&data, sh + 8 + 4 * w)?,
+                entsize : word(sh + 16 + 5 * w)?
+            });
+        }
+
+        Ok(Self { data, elf32, e_type, flags, entry, phoff, phentsize, phdrs, shdrs })
+    }
+
+    pub fn load_segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.phdrs.iter().filter(|ph| ph.p_type == PT_LOAD)
+    }
+
+    /// Path of the program interpreter named by PT_INTERP, for dynamically
+    /// linked executables.
+    pub fn interp(&self) -> Option<String> {
+        let ph = self.phdrs.iter().find(|ph| ph.p_type == PT_INTERP)?;
+        let path = bytes(&self.data, ph.offset, ph.filesz).ok()?;
+        let path = path.split(|b| *b == 0).next().unwrap_or_default();
+
+        Some(String::from_utf8_lossy(path).into_owned())
+    }
+
+    /// The raw program header table.
+    pub fn phdr_table(&self) -> &[u8] {
+        let size = self.phdrs.len() * self.phentsize as usize;
+        &self.data[self.phoff as usize..self.phoff as usize + size]
+    }
+
+    /// Where the program header table ends up in memory: PT_PHDR if the
+    /// image has one, otherwise the load segment whose file range covers it.
+    pub fn phdr_addr(&self) -> Option<u64> {
+        if let Some(ph) = self.phdrs.iter().find(|ph| ph.p_type == PT_PHDR) {
+            return Some(ph.vaddr);
+        }
+
+        let size = self.phdr_table().len() as u64;
+
+        self.load_segments()
+            .find(|ph| self.phoff >= ph.offset && self.phoff + size <= ph.offset + ph.filesz)
+            .map(|ph| ph.vaddr + (self.phoff - ph.offset))
+    }
+
+    /// File contents of a segment; the remaining memsz - filesz bytes are
+    /// zero-filled by the loader.
+    pub fn segment_data(&self, ph : &ProgramHeader) -> &[u8] {
+        &self.data[ph.offset as usize..(ph.offset + ph.filesz) as usize]
+    }
+
+    /// All defined symbols from .symtab and .dynsym. Stripped binaries
+    /// simply yield an empty list.
+    pub fn symbols(&self) -> Result<Vec<ElfSymbol>, ElfError> {
+        let mut syms = Vec::new();
+
+        let symtabs = self.shdrs.iter()
+            .filter(|sh| sh.sh_type == SHT_SYMTAB || sh.sh_type == SHT_DYNSYM);
+
+        for symtab in symtabs {
+            let strtab = self.shdrs.get(symtab.link as usize)
+                .ok_or(ElfError::Truncated)?;
+            let strs = bytes(&self.data, strtab.offset, strtab.size)?;
+            let default_entsize = if self.elf32 { 16 } else { 24 };
+            let entsize = if symtab.entsize == 0 { default_entsize } else { symtab.entsize };
+
+            // ELF32 symbols put st_value and st_size before st_info
+            let (info_off, value_off, size_off) = if self.elf32 { (12, 4, 8) } else { (4, 8, 16) };
+
+            for i in 1..symtab.size / entsize {
+                let sym = symtab.offset + i * entsize;
+                let name_off = u32_at(&self.data, sym)? as usize;
+                let info = bytes(&self.data, sym + info_off, 1)?[0];
+                let shndx = u16_at(&self.data, sym + info_off + 2)?;
+
+                if shndx == SHN_UNDEF || name_off >= strs.len() {
+                    continue;
+                }
+
+                let name_len = strs[name_off..].iter()
+                    .position(|&c| c == 0)
+                    .unwrap_or(strs.len() - name_off);
+
+                syms.push(ElfSymbol {
+                    name : String::from_utf8_lossy(&strs[name_off..name_off + name_len]).into_owned(),
+                    value : word_at(&self.data, sym + value_off, self.elf32)?,
+                    size : word_at(&self.data, sym + size_off, self.elf32)?,
+                    sym_type : info & 0xF,
+                    bind : info >> 4
+                });
+            }
+        }
+
+        Ok(syms)
+    }
+}
+
+#[cfg(test)]
+pub fn build_test_elf(
+    entry : u64,
+    segments : &[(u64, &[u8], u64)],
+    symbols : &[(&str, u64, u64, u8)]) -> Vec<u8>
+{
+    let phoff = 64;
+    let data_start = phoff + 56 * segments.len();
+    let mut data = vec![0u8; data_start];
+
+    data[0..4].copy_from_slice(b"\x7fELF");
+    data[4] = ELFCLASS64;
+    data[5] = ELFDATA2LSB;
+    data[6] = 1;
+    data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    data[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+    data[24..32].copy_from_slice(&entry.to_le_bytes());
+    data[32..40].copy_from_slice(&(phoff as u64).to_le_bytes());
+    data[52..54].copy_from_slice(&64u16.to_le_bytes());
+    data[54..56].copy_from_slice(&56u16.to_le_bytes());
+    data[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+    for (i, (vaddr, contents, memsz)) in segments.iter().enumerate() {
+        let ph = phoff + i * 56;
+        let offset = data.len() as u64;
+        data[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        data[ph + 4..ph + 8].copy_from_slice(&(PF_R | PF_W | PF_X).to_le_bytes());
+        data[ph + 8..ph + 16].copy_from_slice(&offset.to_le_bytes());
+        data[ph + 16..ph + 24].copy_from_slice(&vaddr.to_le_bytes());
+        data[ph + 24..ph + 32].copy_from_slice(&vaddr.to_le_bytes());
+        data[ph + 32..ph + 40].copy_from_slice(&(contents.len() as u64).to_le_bytes());
+        data[ph + 40..ph + 48].copy_from_slice(&memsz.to_le_bytes());
+        data[ph + 48..ph + 56].copy_from_slice(&0x1000u64.to_le_bytes());
+        data.extend_from_slice(contents);
+    }
+
+    if symbols.is_empty() {
+        return data;
+    }
+
+    // Sections: null, .symtab, .strtab
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; 24];
+
+    for (name, value, size, sym_type) in symbols {
+        let mut sym = [0u8; 24];
+        sym[0..4].copy_from_slice(&(strtab.len() as u32).to_le_bytes());
+        sym[4] = (STB_GLOBAL << 4) | sym_type;
+        sym[6..8].copy_from_slice(&1u16.to_le_bytes());
+        sym[8..16].copy_from_slice(&value.to_le_bytes());
+        sym[16..24].copy_from_slice(&size.to_le_bytes());
+        symtab.extend_from_slice(&sym);
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+
+    let symtab_off = data.len() as u64;
+    data.extend_from_slice(&symtab);
+    let strtab_off = data.len() as u64;
+    data.extend_from_slice(&strtab);
+
+    let shoff = data.len() as u64;
+    data[40..48].copy_from_slice(&shoff.to_le_bytes());
+    data[58..60].copy_from_slice(&64u16.to_le_bytes());
+    data[60..62].copy_from_slice(&3u16.to_le_bytes());
+
+    let sections : [(u32, u64, u64, u32, u64); 3] = [
+        (0, 0, 0, 0, 0),
+        (SHT_SYMTAB, symtab_off, symtab.len() as u64, 2, 24),
+        (3, strtab_off, strtab.len() as u64, 0, 0)];
+
+    for (sh_type, offset, size, link, entsize) in sections.iter() {
+        let mut sh = [0u8; 64];
+        sh[4..8].copy_from_slice(&sh_type.to_le_bytes());
+        sh[24..32].copy_from_slice(&offset.to_le_bytes());
+        sh[32..40].copy_from_slice(&size.to_le_bytes());
+        sh[40..44].copy_from_slice(&link.to_le_bytes());
+        sh[56..64].copy_from_slice(&entsize.to_le_bytes());
+        data.extend_from_slice(&sh);
+    }
+
+    data
+}
+
+#[test]
+fn test_parse_elf() {
+    let elf = ElfFile::parse(build_test_elf(
+        0x10078, &[(0x10000, &[1, 2, 3, 4], 0x10), (0x11000, &[5], 0x2000)], &[])).unwrap();
+
+    assert_eq!(elf.entry, 0x10078);
+    assert_eq!(elf.load_segments().count(), 2);
+    assert_eq!(elf.phdrs[1].vaddr, 0x11000);
+    assert_eq!(elf.phdrs[1].memsz, 0x2000);
+    assert_eq!(elf.segment_data(&elf.phdrs[0]), &[1, 2, 3, 4]);
+}
+
+#[test]
+fn test_parse_elf_interp() {
+    let mut data = build_test_elf(0, &[(0x10000, b"/lib/ld.so\0", 0x10)], &[]);
+    assert_eq!(ElfFile::parse(data.clone()).unwrap().interp(), None);
+
+    data[64..68].copy_from_slice(&PT_INTERP.to_le_bytes());
+    assert_eq!(ElfFile::parse(data).unwrap().interp().as_deref(), Some("/lib/ld.so"));
+}
+
+#[test]
+fn test_parse_elf_rejects_non_riscv() {
+    let mut data = build_test_elf(0, &[], &[]);
+    data[18..20].copy_from_slice(&62u16.to_le_bytes());
+    assert!(matches!(ElfFile::parse(data), Err(ElfError::NotRiscV(62))));
+    assert!(matches!(ElfFile::parse(b"\x7fELX".to_vec()), Err(ElfError::BadMagic)));
+}
+
+#[test]
+fn test_parse_elf_malformed() {
+    let data = build_test_elf(0, &[], &[]);
+
+    // Every cut short of the header, for either class
+    for len in 0..64 {
+        assert!(matches!(ElfFile::parse(data[..len].to_vec()), Err(ElfError::Truncated)), "{}", len);
+    }
+
+    let mut data32 = data[..52].to_vec();
+    data32[4] = ELFCLASS32;
+    assert!(matches!(ElfFile::parse(data32[..51].to_vec()), Err(ElfError::Truncated)));
+
+    // Segments with more file than memory, or that wrap around
+    assert!(matches!(ElfFile::parse(build_test_elf(0, &[(0x10000, &[1; 8], 4)], &[])),
+        Err(ElfError::BadSegment(0x10000))));
+    assert!(matches!(ElfFile::parse(build_test_elf(0, &[(u64::MAX - 4, &[1], 0x10)], &[])),
+        Err(ElfError::BadSegment(_))));
+
+    // A program header table that runs off the end
+    let mut data = build_test_elf(0, &[(0x10000, &[1], 0x10)], &[]);
+    data[56..58].copy_from_slice(&0xFFFFu16.to_le_bytes());
+    assert!(matches!(ElfFile::parse(data), Err(ElfError::Truncated)));
+}
+
+#[test]
+fn test_parse_elf_symbols() {
+    let elf = ElfFile::parse(build_test_elf(
+        0x10000, &[(0x10000, &[0; 16], 0x10)],
+        &[("_start", 0x10000, 0, STT_NOTYPE), ("main", 0x10008, 8, STT_FUNC)])).unwrap();
+
+    let syms = elf.symbols().unwrap();
+    assert_eq!(syms.len(), 2);
+    assert_eq!(syms[0].name, "_start");
+    assert_eq!(syms[1].name, "main");
+    assert_eq!(syms[1].value, 0x10008);
+    assert_eq!(syms[1].size, 8);
+    assert_eq!(syms[1].sym_type, STT_FUNC);
+    assert_eq!(syms[1].bind, STB_GLOBAL);
+}
+
+#[test]
+fn test_parse_elf32() {
+    // Header, one program header, then the segment contents
+    let mut data = vec![0u8; 52 + 32];
+    data[0..4].copy_from_slice(b"\x7fELF");
+    data[4] = ELFCLASS32;
+    data[5] = ELFDATA2LSB;
+    data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    data[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+    data[24..28].copy_from_slice(&0x8000_0004u32.to_le_bytes());
+    data[28..32].copy_from_slice(&52u32.to_le_bytes());
+    data[36..40].copy_from_slice(&EF_RISCV_RVE.to_le_bytes());
+    data[42..44].copy_from_slice(&32u16.to_le_bytes());
+    data[44..46].copy_from_slice(&1u16.to_le_bytes());
+
+    let ph = 52;
+    data[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+    data[ph + 4..ph + 8].copy_from_slice(&84u32.to_le_bytes());
+    data[ph + 8..ph + 12].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+    data[ph + 16..ph + 20].copy_from_slice(&4u32.to_le_bytes());
+    data[ph + 20..ph + 24].copy_from_slice(&0x100u32.to_le_bytes());
+    data[ph + 24..ph + 28].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+    data.extend_from_slice(&[0x13, 0, 0, 0]);
+
+    let elf = ElfFile::parse(data).unwrap();
+    assert!(elf.elf32);
+    assert_eq!(elf.flags & EF_RISCV_RVE, EF_RISCV_RVE);
+    assert_eq!(elf.entry, 0x8000_0004);
+    assert_eq!(elf.phdrs[0].vaddr, 0x8000_0000);
+    assert_eq!(elf.phdrs[0].memsz, 0x100);
+    assert_eq!(elf.phdrs[0].flags, PF_R | PF_X);
+    assert_eq!(elf.segment_data(&elf.phdrs[0]), &[0x13, 0, 0, 0]);
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use crate::marshal::Errno;
+
+//
+// The guest's file descriptors. Each maps to a host descriptor that the
+// emulator opened for the guest, except 0-2, which are the emulator's own
+// stdin, stdout and stderr and are never closed on the host.
+//
+
+#[derive(Debug)]
+struct HostFd {
+    fd : i32,
+    /// Opened for the guest, and so closed with it.
+    owned : bool
+}
+
+#[derive(Debug)]
+pub struct FdTable {
+    fds : BTreeMap<u64, HostFd>
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        let fds = (0..3).map(|fd| (fd, HostFd { fd : fd as i32, owned : false })).collect();
+        FdTable { fds }
+    }
+}
+
+impl FdTable {
+    /// The host descriptor behind a guest one.
+    pub fn get(&self, fd : u64) -> Result<i32, Errno> {
+        self.fds.get(&fd).map(|h| h.fd).ok_or(libc::EBADF)
+    }
+
+    /// Like get, for the dirfd argument of the *at() calls.
+    pub fn dir(&self, fd : u64) -> Result<i32, Errno> {
+        if fd as i32 == libc::AT_FDCWD { Ok(libc::AT_FDCWD) } else { self.get(fd) }
+    }
+
+    /// Takes ownership of a host descriptor and gives it the lowest free
+    /// guest number that is at least min.
+    pub fn insert(&mut self, host : i32, min : u64) -> u64 {
+        let fd = (min..).find(|fd| !self.fds.contains_key(fd)).unwrap();
+        self.fds.insert(fd, HostFd { fd : host, owned : true });
+        fd
+    }
+
+    pub fn close(&mut self, fd : u64) -> Result<(), Errno> {
+        match self.fds.remove(&fd) {
+            Some(HostFd { fd : host, owned : true }) if unsafe { libc::close(host) } < 0 => {
+                Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO))
+            },
+            Some(_) => Ok(()),
+            None => Err(libc::EBADF)
+        }
+    }
+}
+
+impl Drop for FdTable {
+    fn drop(&mut self) {
+        for host in self.fds.values().filter(|h| h.owned) {
+            unsafe { libc::close(host.fd) };
+        }
+    }
+}
+
+#[test]
+fn test_fd_table() {
+    let mut files = FdTable::default();
+    assert_eq!(files.get(1), Ok(1));
+    assert_eq!(files.get(3), Err(libc::EBADF));
+    assert_eq!(files.dir(-100i64 as u64), Ok(libc::AT_FDCWD));
+    assert_eq!(files.dir(3), Err(libc::EBADF));
+
+    let host = unsafe { libc::dup(2) };
+    assert_eq!(files.insert(host, 0), 3);
+    assert_eq!(files.get(3), Ok(host));
+
+    // Closing stdout leaves the emulator's own alone, and frees the number
+    assert_eq!(files.close(1), Ok(()));
+    assert_eq!(files.close(1), Err(libc::EBADF));
+    assert!(unsafe { libc::fcntl(1, libc::F_GETFD) } >= 0);
+    assert_eq!(files.insert(unsafe { libc::dup(2) }, 0), 1);
+    assert_eq!(files.insert(unsafe { libc::dup(2) }, 10), 10);
+
+    assert_eq!(files.close(3), Ok(()));
+}
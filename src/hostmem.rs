@@ -0,0 +1,259 @@
+use memmap2::MmapMut;
+use crate::pagestore::{PAGE_SHIFT, PAGE_SIZE};
+
+//
+// Flat guest memory: one host mapping covering guest addresses
+// [0, size), so a guest address is an offset into it and a load or store
+// is a single host access. The mapping is reserved with MAP_NORESERVE, so
+// the host only commits the pages the guest writes. Every access checks
+// that it is inside the window, so addresses past it panic rather than
+// reach outside the mapping.
+//
+
+pub struct HostWindow {
+    base : *mut u8,
+    size : u64,
+    /// One bit per page: written at all, and written since clear_dirty().
+    resident : MmapMut,
+    dirty : MmapMut
+}
+
+impl HostWindow {
+    pub fn new(size : u64) -> Result<Self, ()> {
+        let base = unsafe {
+            libc::mmap(std::ptr::null_mut(), size as usize, libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE, -1, 0)
+        };
+
+        if base == libc::MAP_FAILED {
+            return Err(());
+        }
+
+        let bitmap_len = ((size >> PAGE_SHIFT) as usize).div_ceil(8);
+
+        Ok(HostWindow {
+            base : base as *mut u8,
+            size,
+            resident : MmapMut::map_anon(bitmap_len).map_err(|_| ())?,
+            dirty : MmapMut::map_anon(bitmap_len).map_err(|_| ())?
+        })
+    }
+
+    /// Whether [addr, addr + len) is inside the window.
+    #[inline(always)]
+    pub fn contains(&self, addr : u64, len : u64) -> bool {
+        addr < self.size && len <= self.size - addr
+    }
+
+    /// The host address of guest addr, for an access of len bytes.
+    #[inline(always)]
+    fn ptr(&self, addr : u64, len : u64) -> *mut u8 {
+        assert!(self.contains(addr, len), "Access outside the host window: 0x{:016x}+{}", addr, len);
+        // In the mapping, which is size bytes long
+        unsafe { self.base.add(addr as usize) }
+    }
+
+    /// Marks the pages of [addr, addr + len) written. The range is clipped
+    /// to the window, which keeps the bitmap indexes in bounds.
+    #[inline(always)]
+    fn mark(&mut self, addr : u64, len : u64) {
+        let last = addr.saturating_add(len.max(1) - 1).min(self.size - 1);
+        let (first, last) = (addr.min(last) >> PAGE_SHIFT, last >> PAGE_SHIFT);
+
+        for vpn in first..=last {
+            let (byte, bit) = ((vpn / 8) as usize, 1 << (vpn % 8));
+
+            if self.dirty[byte] & bit == 0 {
+                self.dirty[byte] |= bit;
+                self.resident[byte] |= bit;
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn read(&self, addr : u64) -> u8 {
+        unsafe { *self.ptr(addr, 1) }
+    }
+
+    #[inline(always)]
+    pub fn write(&mut self, addr : u64, value : u8) {
+        let ptr = self.ptr(addr, 1);
+        self.mark(addr, 1);
+        unsafe { *ptr = value }
+    }
+
+    #[inline(always)]
+    pub fn read_le(&self, addr : u64, size : u64) -> u64 {
+        let ptr = self.ptr(addr, size);
+
+        unsafe {
+            match size {
+                1 => *ptr as u64,
+                2 => u16::from_le((ptr as *const u16).read_unaligned()) as u64,
+                4 => u32::from_le((ptr as *const u32).read_unaligned()) as u64,
+                8 => u64::from_le((ptr as *const u64).read_unaligned()),
+                _ => panic!("Bad access size {}", size)
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn write_le(&mut self, addr : u64, val : u64, size : u64) {
+        let ptr = self.ptr(addr, size);
+        self.mark(addr, size);
+
+        unsafe {
+            match size {
+                1 => *ptr = val as u8,
+                2 => (ptr as *mut u16).write_unaligned((val as u16).to_le()),
+                4 => (ptr as *mut u32).write_unaligned((val as u32).to_le()),
+                8 => (ptr as *mut u64).write_unaligned(val.to_le()),
+                _ => panic!("Bad access size {}", size)
+            }
+        }
+    }
+
+    pub fn fill(&mut self, addr : u64, data : &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let ptr = self.ptr(addr, data.len() as u64);
+        self.mark(addr, data.len() as u64);
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) }
+    }
+
+    pub fn read_bytes(&self, addr : u64, buf : &mut [u8]) {
+        if buf.is_empty() {
+            return;
+        }
+
+        let ptr = self.ptr(addr, buf.len() as u64);
+        unsafe { std::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), buf.len()) }
+    }
+
+    /// Zeroes [addr, addr + len), giving whole pages back to the host.
+    pub fn zero(&mut self, addr : u64, len : u64) {
+        if len == 0 {
+            return;
+        }
+
+        // Checks the whole range, not just the pieces cleared by hand
+        self.ptr(addr, len);
+        let end = addr + len;
+        let (first, last) = ((addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1), end & !(PAGE_SIZE - 1));
+
+        if first < last {
+            self.clear(addr, first - addr);
+            self.discard(first, last);
+            self.clear(last, end - last);
+        }
+        else {
+            self.clear(addr, len);
+        }
+    }
+
+    fn clear(&mut self, addr : u64, len : u64) {
+        if len != 0 {
+            let ptr = self.ptr(addr, len);
+            self.mark(addr, len);
+            unsafe { std::ptr::write_bytes(ptr, 0, len as usize) }
+        }
+    }
+
+    /// Gives the pages of [start, end) back to the host; they read as zero
+    /// again.
+    pub fn discard(&mut self, start : u64, end : u64) {
+        let end = end.min(self.size);
+
+        if start >= end {
+            return;
+        }
+
+        unsafe {
+            libc::madvise(self.base.add(start as usize) as *mut libc::c_void,
+                (end - start) as usize, libc::MADV_DONTNEED);
+        }
+
+        for vpn in (start >> PAGE_SHIFT)..((end + PAGE_SIZE - 1) >> PAGE_SHIFT) {
+            let (byte, bit) = ((vpn / 8) as usize, 1u8 << (vpn % 8));
+            self.dirty[byte] &= !bit;
+            self.resident[byte] &= !bit;
+        }
+    }
+
+    pub fn resident_bytes(&self) -> u64 {
+        self.resident.iter().map(|b| b.count_ones() as u64).sum::<u64>() * PAGE_SIZE
+    }
+
+    pub fn dirty_pages(&self) -> Vec<u64> {
+        let mut pages = Vec::new();
+
+        for (i, byte) in self.dirty.iter().enumerate().filter(|(_, b)| **b != 0) {
+            for bit in (0..8).filter(|bit| byte & (1 << bit) != 0) {
+                pages.push(((i as u64 * 8) + bit) << PAGE_SHIFT);
+            }
+        }
+
+        pages
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty.fill(0);
+    }
+}
+
+impl Drop for HostWindow {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.size as usize);
+        }
+    }
+}
+
+#[test]
+fn test_host_window() {
+    let mut window = HostWindow::new(1 << 32).unwrap();
+
+    assert!(window.contains(0xFFFF_FFF8, 8));
+    assert!(!window.contains(0xFFFF_FFFC, 8));
+    assert!(!window.contains(u64::MAX, 1));
+
+    window.write_le(0x1FFE, 0x0102_0304_0506_0708, 8);
+    assert_eq!(window.read_le(0x1FFE, 8), 0x0102_0304_0506_0708);
+    assert_eq!(window.read_le(0x2000, 4), 0x0304_0506);
+    assert_eq!(window.read(0x1FFE), 0x08);
+    assert_eq!(window.read_le(0x8000_0000, 8), 0);
+
+    // The store straddled two pages
+    assert_eq!(window.dirty_pages(), vec![0x1000, 0x2000]);
+    assert_eq!(window.resident_bytes(), 2 * PAGE_SIZE);
+
+    window.clear_dirty();
+    window.fill(0xFFFF_F000, &[0xAA; 16]);
+    assert_eq!(window.dirty_pages(), vec![0xFFFF_F000]);
+    assert_eq!(window.resident_bytes(), 3 * PAGE_SIZE);
+
+    let mut buf = [0; 3];
+    window.read_bytes(0x1FFF, &mut buf);
+    assert_eq!(buf, [0x07, 0x06, 0x05]);
+    window.zero(0x2000, 1);
+    assert_eq!(window.read_le(0x1FFE, 4), 0x0500_0708);
+
+    window.discard(0x1000, 0x3000);
+    assert_eq!(window.read_le(0x1FFE, 8), 0);
+    assert_eq!(window.resident_bytes(), PAGE_SIZE);
+
+    // Zeroing whole pages gives them back
+    window.fill(0x3FFE, &[0xBB; 3]);
+    window.zero(0x3FFF, 0x1001);
+    assert_eq!(window.read_le(0x3FFE, 4), 0xBB);
+    assert_eq!(window.resident_bytes(), 2 * PAGE_SIZE);
+}
+
+#[test]
+#[should_panic(expected = "outside the host window")]
+fn test_host_window_bounds() {
+    let window = HostWindow::new(1 << 20).unwrap();
+    window.read_le(u64::MAX - 3, 8);
+}
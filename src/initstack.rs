@@ -0,0 +1,278 @@
+use std::fs::File;
+use std::io::Read;
+use crate::memif::*;
+use crate::elf::*;
+
+//
+// The initial process stack of the Linux ABI. From sp upwards:
+//
+//   argc
+//   argv[0] .. argv[argc - 1], NULL
+//   envp[0] .. envp[n - 1], NULL
+//   auxv (type, value) pairs, ending with AT_NULL
+//
+// The strings, the AT_RANDOM bytes and, if the image does not load them,
+// a copy of the program headers sit above it at the top of the stack.
+// Every slot is one pointer wide.
+//
+
+pub const AT_NULL : u64 = 0;
+pub const AT_PHDR : u64 = 3;
+pub const AT_PHENT : u64 = 4;
+pub const AT_PHNUM : u64 = 5;
+pub const AT_PAGESZ : u64 = 6;
+pub const AT_BASE : u64 = 7;
+pub const AT_ENTRY : u64 = 9;
+pub const AT_HWCAP : u64 = 16;
+pub const AT_RANDOM : u64 = 25;
+pub const AT_EXECFN : u64 = 31;
+
+const PAGE_SIZE : u64 = 4096;
+const ENOMEM : i64 = 12;
+const EFAULT : i64 = 14;
+
+/// Guest command line and environment, argv[0] included.
+#[derive(Debug, Default, Clone)]
+pub struct MainVars {
+    pub args : Vec<String>,
+    pub env : Vec<String>
+}
+
+/// What the auxiliary vector tells the guest about its image.
+#[derive(Debug, Clone)]
+pub struct AuxInfo {
+    pub entry : u64,
+    /// None if no load segment covers the program headers.
+    pub phdr : Option<u64>,
+    pub phdr_table : Vec<u8>,
+    pub phent : u64,
+    pub hwcap : u64,
+    /// Load bias of the dynamic linker, zero for static executables.
+    pub base : u64
+}
+
+impl AuxInfo {
+    /// Describes an executable loaded at bias.
+    pub fn from_elf(elf : &ElfFile, bias : u64, hwcap : u64) -> Self {
+        AuxInfo {
+            entry : elf.entry + bias,
+            phdr : elf.phdr_addr().map(|addr| addr + bias),
+            phdr_table : elf.phdr_table().to_vec(),
+            phent : elf.phentsize as u64,
+            hwcap,
+            base : 0
+        }
+    }
+
+    /// For images without program headers, such as HEX files.
+    pub fn without_phdrs(entry : u64, hwcap : u64) -> Self {
+        AuxInfo { entry, phdr : None, phdr_table : Vec::new(), phent : 0, hwcap, base : 0 }
+    }
+}
+
+#[inline(always)]
+fn write_word(mem : &mut dyn MemIf, addr : u64, val : u64, word : u64) -> Result<(), MemFault> {
+    match word {
+        4 => mem.store(addr, val as u32),
+        _ => mem.store(addr, val)
+    }
+}
+
+/// Writes the argv and envp arrays at addr, each NULL-terminated, and
+/// returns the address just past them.
+fn write_vectors(
+    mem : &mut dyn MemIf, addr : u64, word : u64, argv : &[u64], envp : &[u64]) -> Result<u64, MemFault> {
+
+    let mut addr = addr;
+
+    for ptrs in [argv, envp].iter() {
+        for ptr in ptrs.iter().chain([0].iter()) {
+            write_word(mem, addr, *ptr, word)?;
+            addr += word;
+        }
+    }
+
+    Ok(addr)
+}
+
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0; 16];
+
+    if let Ok(mut f) = File::open("/dev/urandom") {
+        let _ = f.read_exact(&mut bytes);
+    }
+
+    bytes
+}
+
+/// Builds the initial stack below top and returns the guest's sp, which
+/// is 16-byte aligned and points at argc. Fails if it runs off the
+/// bottom of the stack.
+pub fn build_stack(
+    mem : &mut dyn MemIf, top : u64, word : u64, vars : &MainVars, aux : &AuxInfo) -> Result<u64, MemFault> {
+
+    let mut sp = top;
+
+    let mut push = |mem : &mut dyn MemIf, bytes : &[u8], align : u64| {
+        sp = sp.wrapping_sub(bytes.len() as u64) & !(align - 1);
+        mem.write_bytes(sp, bytes).map(|()| sp)
+    };
+
+    let mut push_str = |mem : &mut dyn MemIf, s : &str| {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        push(mem, &bytes, 1)
+    };
+
+    let execfn = push_str(mem, vars.args.first().map(|s| s.as_str()).unwrap_or(""))?;
+    let argv = vars.args.iter().map(|s| push_str(mem, s)).collect::<Result<Vec<u64>, _>>()?;
+    let envp = vars.env.iter().map(|s| push_str(mem, s)).collect::<Result<Vec<u64>, _>>()?;
+
+    let random = push(mem, &random_bytes(), 16)?;
+    let phdr = match aux.phdr {
+        Some(addr) => addr,
+        None => push(mem, &aux.phdr_table, 8)?
+    };
+
+    let phnum = aux.phdr_table.len() as u64 / aux.phent.max(1);
+    let auxv = [
+        (AT_PHDR, phdr),
+        (AT_PHENT, aux.phent),
+        (AT_PHNUM, phnum),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, aux.base),
+        (AT_ENTRY, aux.entry),
+        (AT_HWCAP, aux.hwcap),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+        (AT_NULL, 0)
+    ];
+
+    let slots = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * auxv.len();
+    let sp = sp.wrapping_sub(slots as u64 * word) & !0xF;
+
+    write_word(mem, sp, argv.len() as u64, word)?;
+    let mut addr = write_vectors(mem, sp + word, word, &argv, &envp)?;
+
+    for (key, val) in auxv.iter() {
+        write_word(mem, addr, *key, word)?;
+        write_word(mem, addr + word, *val, word)?;
+        addr += 2 * word;
+    }
+
+    Ok(sp)
+}
+
+/// SyscallNum::Getmainvars, from the proxy-kernel ABI: copies argc, argv
+/// and envp (each NULL-terminated) followed by the strings into the
+/// limit-byte buffer at buf. Returns 0, -ENOMEM if it does not fit, or
+/// -EFAULT if the buffer is not writable.
+pub fn getmainvars(mem : &mut dyn MemIf, buf : u64, limit : u64, word : u64, vars : &MainVars) -> u64 {
+    let slots = 1 + (vars.args.len() + 1) + (vars.env.len() + 1);
+    let strings = vars.args.iter().chain(vars.env.iter());
+    let size = slots as u64 * word + strings.clone().map(|s| s.len() as u64 + 1).sum::<u64>();
+
+    if size > limit {
+        return (-ENOMEM) as u64;
+    }
+    else if mem.check(buf, size, PERM_W).is_err() {
+        return (-EFAULT) as u64;
+    }
+
+    let fill = |mem : &mut dyn MemIf| -> Result<(), MemFault> {
+        let mut next = buf + slots as u64 * word;
+        let mut ptrs = Vec::with_capacity(vars.args.len() + vars.env.len());
+
+        for s in strings {
+            ptrs.push(next);
+            mem.write_bytes(next, s.as_bytes())?;
+            mem.write(next + s.len() as u64, 0)?;
+            next += s.len() as u64 + 1;
+        }
+
+        write_word(mem, buf, vars.args.len() as u64, word)?;
+        let (argv, envp) = ptrs.split_at(vars.args.len());
+        write_vectors(mem, buf + word, word, argv, envp)?;
+        Ok(())
+    };
+
+    match fill(mem) {
+        Ok(()) => 0,
+        Err(_) => (-EFAULT) as u64
+    }
+}
+
+#[cfg(test)]
+fn read_cstr(mem : &dyn MemIf, addr : u64) -> String {
+    String::from_utf8(mem.read_cstr(addr, 4096).unwrap()).unwrap()
+}
+
+#[cfg(test)]
+fn test_vars() -> MainVars {
+    MainVars {
+        args : vec!["prog".to_string(), "-v".to_string()],
+        env : vec!["HOME=/".to_string()]
+    }
+}
+
+#[test]
+fn test_build_stack() {
+    let mut mem = TestMem::new(0x1000);
+    let aux = AuxInfo {
+        entry : 0x100, phdr : None, phdr_table : vec![0xAA; 112], phent : 56, hwcap : 0x112D, base : 0
+    };
+    let sp = build_stack(&mut mem, 0x1000, 8, &test_vars(), &aux).unwrap();
+
+    assert_eq!(sp & 0xF, 0);
+    assert_eq!(read64(&mem, sp).unwrap(), 2);
+    assert_eq!(read_cstr(&mem, read64(&mem, sp + 8).unwrap()), "prog");
+    assert_eq!(read_cstr(&mem, read64(&mem, sp + 16).unwrap()), "-v");
+    assert_eq!(read64(&mem, sp + 24).unwrap(), 0);
+    assert_eq!(read_cstr(&mem, read64(&mem, sp + 32).unwrap()), "HOME=/");
+    assert_eq!(read64(&mem, sp + 40).unwrap(), 0);
+
+    let auxv : Vec<(u64, u64)> = (0..10)
+        .map(|i| (read64(&mem, sp + 48 + 16 * i).unwrap(), read64(&mem, sp + 56 + 16 * i).unwrap()))
+        .collect();
+
+    assert_eq!(auxv[1], (AT_PHENT, 56));
+    assert_eq!(auxv[2], (AT_PHNUM, 2));
+    assert_eq!(auxv[4], (AT_BASE, 0));
+    assert_eq!(auxv[5], (AT_ENTRY, 0x100));
+    assert_eq!(auxv[6], (AT_HWCAP, 0x112D));
+    assert_eq!(auxv[9], (AT_NULL, 0));
+
+    // Program headers that are not in the image are copied to the stack
+    let (key, phdr) = auxv[0];
+    assert_eq!(key, AT_PHDR);
+    assert_eq!(read8(&mem, phdr + 111).unwrap(), 0xAA);
+    assert_eq!(auxv[7].0, AT_RANDOM);
+    assert_eq!(auxv[7].1 & 0xF, 0);
+}
+
+#[test]
+fn test_build_stack_rv32() {
+    let mut mem = TestMem::new(0x1000);
+    let aux = AuxInfo {
+        entry : 0x100, phdr : Some(0x34), phdr_table : vec![0; 32], phent : 32, hwcap : 0, base : 0x8000_0000
+    };
+    let sp = build_stack(&mut mem, 0x1000, 4, &test_vars(), &aux).unwrap();
+
+    assert_eq!(read32(&mem, sp).unwrap(), 2);
+    assert_eq!(read_cstr(&mem, read32(&mem, sp + 8).unwrap()), "-v");
+    assert_eq!(read32(&mem, sp + 12).unwrap(), 0);
+    assert_eq!((read32(&mem, sp + 24).unwrap(), read32(&mem, sp + 28).unwrap()), (AT_PHDR, 0x34));
+    assert_eq!((read32(&mem, sp + 56).unwrap(), read32(&mem, sp + 60).unwrap()), (AT_BASE, 0x8000_0000));
+}
+
+#[test]
+fn test_getmainvars() {
+    let mut mem = TestMem::new(0x100);
+
+    assert_eq!(getmainvars(&mut mem, 0x10, 0x20, 8, &test_vars()), (-ENOMEM) as u64);
+    assert_eq!(getmainvars(&mut mem, 0x10, 0x100, 8, &test_vars()), 0);
+    assert_eq!(read64(&mem, 0x10).unwrap(), 2);
+    assert_eq!(read_cstr(&mem, read64(&mem, 0x18).unwrap()), "prog");
+    assert_eq!(read64(&mem, 0x28).unwrap(), 0);
+    assert_eq!(read_cstr(&mem, read64(&mem, 0x30).unwrap()), "HOME=/");
+}
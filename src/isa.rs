@@ -0,0 +1,341 @@
+use std::fmt;
+use crate::rv64defs::*;
+
+//
+// ISA configuration, parsed from strings such as "rv64gc", "rv32imac" or
+// "rv64imac_zicsr_zba_zbb_zbs". The base sets XLEN and, for the E
+// variants, a 16-register file. Instructions from extensions that are not
+// enabled raise illegal-instruction exceptions.
+//
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Extension {
+    M        = 0,
+    A        = 1,
+    F        = 2,
+    D        = 3,
+    C        = 4,
+    Zicsr    = 5,
+    Zifencei = 6,
+    Zba      = 7,
+    Zbb      = 8,
+    Zbs      = 9
+}
+
+impl Extension {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Extension::M => "M",
+            Extension::A => "A",
+            Extension::F => "F",
+            Extension::D => "D",
+            Extension::C => "C",
+            Extension::Zicsr => "Zicsr",
+            Extension::Zifencei => "Zifencei",
+            Extension::Zba => "Zba",
+            Extension::Zbb => "Zbb",
+            Extension::Zbs => "Zbs"
+        }
+    }
+}
+
+/// Single-letter extensions in canonical order.
+const SINGLE_LETTER : [(char, Extension); 5] = [
+    ('m', Extension::M),
+    ('a', Extension::A),
+    ('f', Extension::F),
+    ('d', Extension::D),
+    ('c', Extension::C)
+];
+
+/// Multi-letter extensions in canonical order.
+const MULTI_LETTER : [(&str, Extension); 5] = [
+    ("zicsr", Extension::Zicsr),
+    ("zifencei", Extension::Zifencei),
+    ("zba", Extension::Zba),
+    ("zbb", Extension::Zbb),
+    ("zbs", Extension::Zbs)
+];
+
+#[derive(Debug, PartialEq)]
+pub enum IsaError {
+    BadBase(String),
+    UnknownExtension(String),
+    MissingDependency(&'static str, &'static str),
+    /// Extension with no definition at the selected XLEN.
+    WrongXlen(&'static str)
+}
+
+impl fmt::Display for IsaError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IsaError::BadBase(s) => write!(f, "unsupported base ISA in \"{}\"", s),
+            IsaError::UnknownExtension(s) => write!(f, "unknown extension \"{}\"", s),
+            IsaError::MissingDependency(ext, dep) =>
+                write!(f, "extension {} requires {}", ext, dep),
+            IsaError::WrongXlen(ext) => write!(f, "extension {} is not available on RV128", ext)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Isa {
+    exts : u32,
+    xlen : ArchWidth,
+    embedded : bool
+}
+
+impl Default for Isa {
+    fn default() -> Self {
+        Isa::parse("rv64gc").unwrap()
+    }
+}
+
+impl Isa {
+    pub fn parse(s : &str) -> Result<Self, IsaError> {
+        let lower = s.to_ascii_lowercase();
+
+        let (xlen, rest) = if let Some(rest) = lower.strip_prefix("rv32") {
+            (ArchWidth::RV32, rest)
+        }
+        else if let Some(rest) = lower.strip_prefix("rv64") {
+            (ArchWidth::RV64, rest)
+        }
+        else if let Some(rest) = lower.strip_prefix("rv128") {
+            (ArchWidth::RV128, rest)
+        }
+        else {
+            return Err(IsaError::BadBase(s.to_string()));
+        };
+
+        let mut parts = rest.split('_');
+        let letters = parts.next().unwrap_or("");
+        let mut isa = Isa { exts : 0, xlen, embedded : false };
+        let mut chars = letters.chars();
+
+        match chars.next() {
+            Some('i') => (),
+            Some('e') if xlen != ArchWidth::RV128 => isa.embedded = true,
+            Some('g') => isa.enable_g(),
+            _ => return Err(IsaError::BadBase(s.to_string()))
+        }
+
+        for c in chars {
+            match c {
+                'g' => isa.enable_g(),
+                _ => {
+                    let ext = SINGLE_LETTER.iter()
+                        .find(|(l, _)| *l == c)
+                        .ok_or_else(|| IsaError::UnknownExtension(c.to_string()))?;
+                    isa.enable(ext.1);
+                }
+            }
+        }
+
+        for name in parts.filter(|p| !p.is_empty()) {
+            let ext = MULTI_LETTER.iter()
+                .find(|(n, _)| *n == name)
+                .ok_or_else(|| IsaError::UnknownExtension(name.to_string()))?;
+            isa.enable(ext.1);
+        }
+
+        if isa.has(Extension::D) && !isa.has(Extension::F) {
+            return Err(IsaError::MissingDependency("D", "F"));
+        }
+
+        if isa.has(Extension::F) && !isa.has(Extension::Zicsr) {
+            return Err(IsaError::MissingDependency("F", "Zicsr"));
+        }
+
+        // RV128I only defines the base, M and C; the rest are frozen at RV64
+        if xlen == ArchWidth::RV128 {
+            let rv128_exts = [Extension::A, Extension::F, Extension::D,
+                              Extension::Zba, Extension::Zbb, Extension::Zbs];
+
+            if let Some(ext) = rv128_exts.iter().find(|e| isa.has(**e)) {
+                return Err(IsaError::WrongXlen(ext.name()));
+            }
+        }
+
+        Ok(isa)
+    }
+
+    fn enable_g(&mut self) {
+        for ext in [Extension::M, Extension::A, Extension::F, Extension::D,
+                    Extension::Zicsr, Extension::Zifencei].iter() {
+            self.enable(*ext);
+        }
+    }
+
+    pub fn enable(&mut self, ext : Extension) {
+        self.exts |= 1 << ext as u32;
+    }
+
+    #[inline(always)]
+    pub fn has(&self, ext : Extension) -> bool {
+        self.exts & (1 << ext as u32) != 0
+    }
+
+    #[inline(always)]
+    pub fn xlen(&self) -> ArchWidth {
+        self.xlen
+    }
+
+    /// RV32E/RV64E: only x0-x15 exist.
+    #[inline(always)]
+    pub fn embedded(&self) -> bool {
+        self.embedded
+    }
+
+    /// AT_HWCAP as Linux reports it: one bit per single-letter extension.
+    pub fn hwcap(&self) -> u64 {
+        SINGLE_LETTER.iter()
+            .filter(|(_, ext)| self.has(*ext))
+            .fold(1 << ('i' as u64 - 'a' as u64), |hwcap, (l, _)| hwcap | 1 << (*l as u64 - 'a' as u64))
+    }
+
+    /// Whether every extension the instruction belongs to is enabled.
+    pub fn supports(&self, inst : &DecodedInst) -> bool {
+        use DecodedInst::*;
+        use Extension::*;
+
+        match inst {
+            Mul {..} | Mulh {..} | Mulhsu {..} | Mulhu {..} |
+            Div {..} | Divu {..} | Rem {..} | Remu {..} |
+            Mulw {..} | Divw {..} | Divuw {..} | Remw {..} | Remuw {..} |
+            Muld {..} | Divd {..} | Divud {..} | Remd {..} | Remud {..} =>
+                self.has(M),
+
+            Lr {..} | Sc {..} | Amo {..} => self.has(A),
+
+            FLoad {fmt, ..} | FStore {fmt, ..} |
+            FMadd {fmt, ..} | FMsub {fmt, ..} | FNmsub {fmt, ..} | FNmadd {fmt, ..} |
+            FAdd {fmt, ..} | FSub {fmt, ..} | FMul {fmt, ..} | FDiv {fmt, ..} |
+            FSqrt {fmt, ..} | FSgnj {fmt, ..} | FSgnjn {fmt, ..} | FSgnjx {fmt, ..} |
+            FMin {fmt, ..} | FMax {fmt, ..} | FCvtToInt {fmt, ..} | FCvtFromInt {fmt, ..} |
+            FEq {fmt, ..} | FLt {fmt, ..} | FLe {fmt, ..} | FClass {fmt, ..} |
+            FMvXF {fmt, ..} | FMvFX {fmt, ..} => match fmt {
+                FpFmt::S => self.has(F),
+                FpFmt::D => self.has(D)
+            },
+
+            FCvtSD {..} | FCvtDS {..} => self.has(D),
+
+            Csr {..} => self.has(Zicsr),
+
+            FenceI => self.has(Zifencei),
+
+            Sh1add {..} | Sh2add {..} | Sh3add {..} | AddUw {..} |
+            Sh1addUw {..} | Sh2addUw {..} | Sh3addUw {..} | SlliUw {..} =>
+                self.has(Zba),
+
+            Andn {..} | Orn {..} | Xnor {..} |
+            Clz {..} | Ctz {..} | Cpop {..} | Clzw {..} | Ctzw {..} | Cpopw {..} |
+            Max {..} | Maxu {..} | Min {..} | Minu {..} |
+            SextB {..} | SextH {..} | ZextH {..} |
+            Rol {..} | Ror {..} | Rori {..} | Rolw {..} | Rorw {..} | Roriw {..} |
+            OrcB {..} | Rev8 {..} => self.has(Zbb),
+
+            Bclr {..} | Bclri {..} | Bext {..} | Bexti {..} |
+            Binv {..} | Binvi {..} | Bset {..} | Bseti {..} => self.has(Zbs),
+
+            CLoad {width : CLoadStoreWidth::Cfw, ..} |
+            CStore {width : CLoadStoreWidth::Cfw, ..} |
+            CLoadStack {width : CLoadStoreWidth::Cfw, ..} |
+            CStoreStack {width : CLoadStoreWidth::Cfw, ..} =>
+                self.has(C) && self.has(F),
+
+            CLoad {width : CLoadStoreWidth::Cfd, ..} |
+            CStore {width : CLoadStoreWidth::Cfd, ..} |
+            CLoadStack {width : CLoadStoreWidth::Cfd, ..} |
+            CStoreStack {width : CLoadStoreWidth::Cfd, ..} =>
+                self.has(C) && self.has(D),
+
+            CAddi4spn {..} | CLoad {..} | CLoadStack {..} | CStore {..} |
+            CStoreStack {..} | CAddi {..} | CAddiw {..} | CLi {..} |
+            CAddi16sp {..} | CLui {..} | CSrli {..} | CSrai {..} | CAndi {..} |
+            CSub {..} | CXor {..} | COr {..} | CAnd {..} | CSubw {..} |
+            CAddw {..} | CJ {..} | CJal {..} | CBeqz {..} | CBnez {..} |
+            CSlli {..} | CJr {..} | CMv {..} | CEBreak | CJalr {..} |
+            CAdd {..} => self.has(C),
+
+            _ => true
+        }
+    }
+}
+
+/// Instructions that touch FP state, which mstatus.FS can turn off.
+pub fn uses_fp(inst : &DecodedInst) -> bool {
+    use DecodedInst::*;
+
+    match inst {
+        FLoad {..} | FStore {..} | FMadd {..} | FMsub {..} | FNmsub {..} | FNmadd {..} |
+        FAdd {..} | FSub {..} | FMul {..} | FDiv {..} | FSqrt {..} |
+        FSgnj {..} | FSgnjn {..} | FSgnjx {..} | FMin {..} | FMax {..} |
+        FCvtToInt {..} | FCvtFromInt {..} | FCvtSD {..} | FCvtDS {..} |
+        FEq {..} | FLt {..} | FLe {..} | FClass {..} | FMvXF {..} | FMvFX {..} => true,
+
+        CLoad {width, ..} | CStore {width, ..} |
+        CLoadStack {width, ..} | CStoreStack {width, ..} =>
+            matches!(width, CLoadStoreWidth::Cfw | CLoadStoreWidth::Cfd),
+
+        _ => false
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let xlen = match self.xlen {
+            ArchWidth::RV32 => 32,
+            ArchWidth::RV64 => 64,
+            ArchWidth::RV128 => 128
+        };
+
+        write!(f, "rv{}{}", xlen, if self.embedded { 'e' } else { 'i' })?;
+
+        for (letter, ext) in SINGLE_LETTER.iter() {
+            if self.has(*ext) {
+                write!(f, "{}", letter)?;
+            }
+        }
+
+        for (name, ext) in MULTI_LETTER.iter() {
+            if self.has(*ext) {
+                write!(f, "_{}", name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_isa_parse() {
+    let isa = Isa::parse("rv64gc").unwrap();
+    assert!(isa.has(Extension::M) && isa.has(Extension::D) && isa.has(Extension::C));
+    assert!(!isa.has(Extension::Zba));
+    assert_eq!(isa.to_string(), "rv64imafdc_zicsr_zifencei");
+    assert_eq!(isa.hwcap(), 0x112D);
+
+    let isa = Isa::parse("RV64IMAC_zba_zbb_zbs").unwrap();
+    assert!(isa.has(Extension::Zbb) && !isa.has(Extension::F));
+    assert_eq!(isa.to_string(), "rv64imac_zba_zbb_zbs");
+
+    let isa = Isa::parse("rv32emc").unwrap();
+    assert!(isa.embedded() && isa.xlen() == ArchWidth::RV32);
+    assert_eq!(isa.to_string(), "rv32emc");
+    assert_eq!(Isa::parse("rv32gc").unwrap().to_string(), "rv32imafdc_zicsr_zifencei");
+
+    assert_eq!(Isa::parse("rv16i"), Err(IsaError::BadBase("rv16i".to_string())));
+    assert_eq!(Isa::parse("rv32x"), Err(IsaError::BadBase("rv32x".to_string())));
+    assert_eq!(Isa::parse("rv64ix"), Err(IsaError::UnknownExtension("x".to_string())));
+    assert_eq!(Isa::parse("rv64gc_zfoo"), Err(IsaError::UnknownExtension("zfoo".to_string())));
+    assert_eq!(Isa::parse("rv64id_zicsr"), Err(IsaError::MissingDependency("D", "F")));
+
+    let isa = Isa::parse("rv128imc_zicsr").unwrap();
+    assert!(isa.xlen() == ArchWidth::RV128 && isa.has(Extension::M));
+    assert_eq!(isa.to_string(), "rv128imc_zicsr");
+    assert_eq!(Isa::parse("rv128gc"), Err(IsaError::WrongXlen("A")));
+    assert_eq!(Isa::parse("rv128i_zbb"), Err(IsaError::WrongXlen("Zbb")));
+    assert_eq!(Isa::parse("rv128e"), Err(IsaError::BadBase("rv128e".to_string())));
+}
@@ -0,0 +1,249 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use crate::elf::*;
+
+//
+// Program images other than ELF: Intel HEX and Motorola S-record files,
+// and raw binaries placed at an address given on the command line.
+//
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    Elf(ElfError),
+    UnknownFormat,
+    BadRecord(usize),
+    BadChecksum(usize)
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => write!(f, "{}", e),
+            ImageError::Elf(e) => write!(f, "{}", e),
+            ImageError::UnknownFormat => write!(f, "not an ELF, Intel HEX or S-record file"),
+            ImageError::BadRecord(line) => write!(f, "malformed record on line {}", line),
+            ImageError::BadChecksum(line) => write!(f, "bad checksum on line {}", line)
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e : io::Error) -> Self {
+        ImageError::Io(e)
+    }
+}
+
+impl From<ElfError> for ImageError {
+    fn from(e : ElfError) -> Self {
+        ImageError::Elf(e)
+    }
+}
+
+/// Contiguous runs of bytes and where they go, plus the start address if
+/// the file gives one.
+#[derive(Debug, Default)]
+pub struct Image {
+    pub chunks : Vec<(u64, Vec<u8>)>,
+    pub entry : Option<u64>
+}
+
+impl Image {
+    /// Appends data at addr, extending the last chunk if it ends there.
+    fn push(&mut self, addr : u64, data : &[u8]) {
+        if let Some((start, last)) = self.chunks.last_mut() {
+            if *start + last.len() as u64 == addr {
+                last.extend_from_slice(data);
+                return;
+            }
+        }
+
+        self.chunks.push((addr, data.to_vec()));
+    }
+
+    /// Where execution starts: the file's start address, else the first
+    /// byte it loads.
+    pub fn start(&self) -> u64 {
+        self.entry.or_else(|| self.chunks.first().map(|(addr, _)| *addr)).unwrap_or(0)
+    }
+}
+
+pub enum Program {
+    Elf(ElfFile),
+    Image(Image)
+}
+
+impl Program {
+    /// Opens an ELF, Intel HEX or S-record file, going by its contents.
+    pub fn open(filename : &str) -> Result<Self, ImageError> {
+        let data = fs::read(filename)?;
+
+        match data.first() {
+            Some(0x7f) => Ok(Program::Elf(ElfFile::parse(data)?)),
+            Some(b':') => Ok(Program::Image(parse_ihex(&String::from_utf8_lossy(&data))?)),
+            Some(b'S') => Ok(Program::Image(parse_srec(&String::from_utf8_lossy(&data))?)),
+            _ => Err(ImageError::UnknownFormat)
+        }
+    }
+}
+
+/// Parses "FILE@ADDR", where ADDR is decimal or 0x-prefixed hex.
+pub fn parse_placement(arg : &str) -> Option<(&str, u64)> {
+    let (file, addr) = arg.rsplit_once('@')?;
+    Some((file, parse_addr(addr)?))
+}
+
+pub fn parse_addr(s : &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => s.parse().ok()
+    }
+}
+
+/// Decodes the hex digits of one record, after its start character.
+fn record_bytes(hex : &str, line : usize) -> Result<Vec<u8>, ImageError> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(ImageError::BadRecord(line));
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| ImageError::BadRecord(line)))
+        .collect()
+}
+
+#[inline(always)]
+fn be_value(bytes : &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64)
+}
+
+/// Intel HEX: ":LLAAAATT<data>CC", where the bytes including the checksum
+/// sum to zero. Types 02 and 04 set the upper address bits, 03 and 05
+/// give the start address.
+pub fn parse_ihex(text : &str) -> Result<Image, ImageError> {
+    let mut image = Image::default();
+    let mut base = 0u64;
+
+    for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let hex = line.strip_prefix(':').ok_or(ImageError::BadRecord(i))?;
+        let rec = record_bytes(hex, i)?;
+
+        if rec.len() < 5 || rec.len() != rec[0] as usize + 5 {
+            return Err(ImageError::BadRecord(i));
+        }
+
+        if rec.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+            return Err(ImageError::BadChecksum(i));
+        }
+
+        let offset = be_value(&rec[1..3]);
+        let data = &rec[4..rec.len() - 1];
+
+        match (rec[3], data.len()) {
+            (0x00, _) => image.push(base + offset, data),
+            (0x01, _) => break,
+            (0x02, 2) => base = be_value(data) << 4,
+            (0x03, 4) => image.entry = Some((be_value(&data[0..2]) << 4) + be_value(&data[2..4])),
+            (0x04, 2) => base = be_value(data) << 16,
+            (0x05, 4) => image.entry = Some(be_value(data)),
+            _ => return Err(ImageError::BadRecord(i))
+        }
+    }
+
+    Ok(image)
+}
+
+/// Motorola S-record: "St<count><address><data><checksum>", the checksum
+/// being the ones' complement of the sum of the other bytes. S1-S3 carry
+/// data with 16, 24 and 32-bit addresses and S9-S7 the start address.
+pub fn parse_srec(text : &str) -> Result<Image, ImageError> {
+    let mut image = Image::default();
+
+    for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let (kind, hex) = match line.as_bytes() {
+            [b'S', kind, ..] => (*kind, &line[2..]),
+            _ => return Err(ImageError::BadRecord(i))
+        };
+
+        let addr_len = match kind {
+            b'0' | b'1' | b'5' | b'9' => 2,
+            b'2' | b'6' | b'8' => 3,
+            b'3' | b'7' => 4,
+            _ => return Err(ImageError::BadRecord(i))
+        };
+
+        let rec = record_bytes(hex, i)?;
+
+        if rec.len() < 2 + addr_len || rec.len() != rec[0] as usize + 1 {
+            return Err(ImageError::BadRecord(i));
+        }
+
+        if !rec.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+            return Err(ImageError::BadChecksum(i));
+        }
+
+        let addr = be_value(&rec[1..1 + addr_len]);
+        let data = &rec[1 + addr_len..rec.len() - 1];
+
+        match kind {
+            b'1' | b'2' | b'3' => image.push(addr, data),
+            b'7' | b'8' | b'9' => image.entry = Some(addr),
+            // Header and record counts
+            _ => ()
+        }
+    }
+
+    Ok(image)
+}
+
+#[test]
+fn test_parse_ihex() {
+    let image = parse_ihex("\
+        :020000040800F2\n\
+        :0400000013000000E9\n\
+        :0400040001020304EE\n\
+        :0400000508000004EB\n\
+        :00000001FF\n").unwrap();
+
+    assert_eq!(image.chunks, vec![(0x0800_0000, vec![0x13, 0, 0, 0, 1, 2, 3, 4])]);
+    assert_eq!(image.entry, Some(0x0800_0004));
+
+    let image = parse_ihex(":020000021000EC\n:01001000559A\n:0400000300100020C9\n").unwrap();
+    assert_eq!(image.chunks, vec![(0x10010, vec![0x55])]);
+    assert_eq!(image.start(), 0x120);
+
+    assert!(matches!(parse_ihex(":0400000013000000FF\n"), Err(ImageError::BadChecksum(1))));
+    assert!(matches!(parse_ihex("\n:0500000013000000E9\n"), Err(ImageError::BadRecord(2))));
+}
+
+#[test]
+fn test_parse_srec() {
+    let image = parse_srec("\
+        S005000048446E\n\
+        S107000013000000E5\n\
+        S3090000100001020304DC\n\
+        S5030002FA\n\
+        S70500001000EA\n").unwrap();
+
+    assert_eq!(image.chunks, vec![(0, vec![0x13, 0, 0, 0]), (0x1000, vec![1, 2, 3, 4])]);
+    assert_eq!(image.entry, Some(0x1000));
+
+    assert!(matches!(parse_srec("S107000013000000FF\n"), Err(ImageError::BadChecksum(1))));
+    assert!(matches!(parse_srec("S4030000FC\n"), Err(ImageError::BadRecord(1))));
+}
+
+#[test]
+fn test_parse_placement() {
+    assert_eq!(parse_placement("fw.bin@0x8000_0000"), Some(("fw.bin", 0x8000_0000)));
+    assert_eq!(parse_placement("a@b.bin@4096"), Some(("a@b.bin", 4096)));
+    assert_eq!(parse_placement("fw.bin"), None);
+    assert_eq!(parse_placement("fw.bin@0xZZ"), None);
+}
@@ -3,12 +3,11 @@ extern crate num;
 #[macro_use]
 extern crate num_derive;
 
-#[macro_use]
 extern crate libc;
 
 extern crate memmap2;
-
-use std::collections::HashMap;
+extern crate rustc_demangle;
+extern crate cpp_demangle;
 
 mod syscalls;
 #[macro_use]
@@ -16,74 +15,331 @@ mod bitops;
 mod memif;
 mod rv64defs;
 mod rv64alu;
+mod rvalu;
+mod rv64fpu;
+mod rv64csr;
+mod rv64priv;
+mod rv64mmu;
+mod rv64pmp;
+mod rv64icache;
+mod isa;
 mod rv64inst;
 mod rv64emu;
-mod disasm;
+mod rv128emu;
+mod elf;
+mod symbols;
+mod pagestore;
+mod hostmem;
 mod progmem;
+mod loader;
+mod initstack;
+mod marshal;
+mod fdtable;
 
-use libc::ENOTNAM;
-use memif::*;
-use bitops::*;
 use rv64defs::*;
-use rv64inst::*;
 use rv64emu::*;
+use memif::MemIf;
+
+/// Unhandled exceptions terminate the guest with a report of where they
+/// happened. For access faults the memory map says what went wrong.
+fn report_exception(
+    arch : &ArchState, mem : &progmem::ProgramMemory, symbols : &symbols::SymbolTable, res : ExecResult) {
+
+    if let ExecResult::Exception(e) = res {
+        let access = match e {
+            Exception::InstructionAccessFault(addr) => Some((addr, memif::PERM_X)),
+            Exception::LoadAccessFault(addr) => Some((addr, memif::PERM_R)),
+            Exception::StoreAccessFault(addr) => Some((addr, memif::PERM_W)),
+            _ => None
+        };
+
+        match access.and_then(|(addr, perm)| mem.check(addr, 1, perm).err()) {
+            Some(fault) => {
+                eprintln!("Unhandled exception: {} at {} (pc 0x{:x})",
+                    fault, symbols.describe(arch.pc), arch.pc);
+                mem.dump_map();
+            },
+            None => eprintln!("Unhandled exception: {} at {} (pc 0x{:x})",
+                e, symbols.describe(arch.pc), arch.pc)
+        }
+
+        std::process::exit(1);
+    }
+}
 
+fn print_stats(arch : &ArchState, mem : &progmem::ProgramMemory) {
+    println!("# executed inst: {}", arch.num_inst);
+    println!("# resident memory: {} KiB", mem.resident_bytes() / 1024);
+    println!("# pages written: {}", mem.dirty_pages().len());
+
+    if arch.system {
+        let stats = arch.tlb.stats;
+        println!("# tlb hits: {}, misses: {}, walks: {}", stats.hits, stats.misses, stats.walks);
+    }
+}
 
 fn main() {
-    let filename = std::env::args().nth(1).expect("No filename provided!");
+    let mut args = std::env::args().skip(1);
+    let mut isa = None;
+    let mut system = false;
+    let mut warn_smc = false;
+    let mut flat_mem = false;
+    let mut filename = None;
+    let mut sysroot = String::new();
+    let mut loads = Vec::new();
+    let mut entry = None;
+    let mut reset_vector = None;
+    let mut env : Vec<String> = std::env::vars().map(|(k, v)| format!("{}={}", k, v)).collect();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--system" => system = true,
+            "--warn-smc" => warn_smc = true,
+            "--flat-mem" => flat_mem = true,
+            "--clear-env" => env.clear(),
+            "--sysroot" => sysroot = args.next().unwrap_or_default(),
+            "--env" => {
+                let var = args.next().unwrap_or_default();
+                let name = var.split('=').next().unwrap_or_default().to_string();
+                env.retain(|v| v.split('=').next() != Some(name.as_str()));
+                env.push(var);
+            },
+            "--load" => {
+                let arg = args.next().unwrap_or_default();
+                let (file, addr) = loader::parse_placement(&arg).unwrap_or_else(|| {
+                    eprintln!("Invalid --load {}, expected FILE@ADDR", arg);
+                    std::process::exit(1);
+                });
+                loads.push((file.to_string(), addr));
+            },
+            "--entry" | "--reset-vector" => {
+                let addr = args.next().as_deref().and_then(loader::parse_addr).unwrap_or_else(|| {
+                    eprintln!("Invalid {} address", arg);
+                    std::process::exit(1);
+                });
 
-    let disasm_map =
-        if let Some(disasm_file ) = std::env::args().nth(2) {
-            disasm::parse_disasm(&disasm_file)
+                if arg == "--entry" { entry = Some(addr) } else { reset_vector = Some(addr) }
+            },
+            "--isa" => {
+                let s = args.next().unwrap_or_default();
+                isa = Some(isa::Isa::parse(&s).unwrap_or_else(|e| {
+                    eprintln!("Invalid --isa: {}", e);
+                    std::process::exit(1);
+                }));
+            },
+            _ => {
+                filename = Some(arg);
+                break;
+            }
         }
-        else {
-            HashMap::<u64, String>::new()
+    }
+
+    let filename = filename.unwrap_or_else(|| {
+        eprintln!("Usage: rustv [--isa ISA] [--system] [--warn-smc] [--flat-mem] [--clear-env] \
+                   [--env NAME=VALUE]... [--sysroot DIR] \
+                   [--load FILE@ADDR]... [--entry ADDR] [--reset-vector ADDR] PROGRAM [ARGS]...");
+        std::process::exit(1);
+    });
+
+    let (elf, image) = match loader::Program::open(&filename) {
+        Ok(loader::Program::Elf(elf)) => (Some(elf), None),
+        Ok(loader::Program::Image(image)) => (None, Some(image)),
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    };
+
+    // Without --isa, the ELF class picks XLEN and e_flags the E base. HEX
+    // and S-record files say neither.
+    let isa = isa.unwrap_or_else(|| {
+        let (elf32, rve) = elf.as_ref()
+            .map(|elf| (elf.elf32, elf.flags & elf::EF_RISCV_RVE != 0))
+            .unwrap_or((false, false));
+        let base = match (elf32, rve) {
+            (true, false) => "rv32gc",
+            (true, true) => "rv32emac",
+            (false, false) => "rv64gc",
+            (false, true) => "rv64emac"
         };
+        isa::Isa::parse(base).unwrap()
+    });
+
+    // RV128 has no ELF class of its own and runs ELF64 images
+    let xlen_ok = match (isa.xlen(), &elf) {
+        (_, None) => true,
+        (ArchWidth::RV128, Some(elf)) => !elf.elf32,
+        (xlen, Some(elf)) => (xlen == ArchWidth::RV32) == elf.elf32
+    };
+
+    if !xlen_ok {
+        eprintln!("ISA {} does not match the ELF class of {}", isa, filename);
+        std::process::exit(1);
+    }
+
+    let symbols = elf.as_ref().map(|elf| symbols::SymbolTable::from_elf(elf).unwrap_or_else(|e| {
+        eprintln!("Failed to read symbols from {}: {}", filename, e);
+        symbols::SymbolTable::new()
+    })).unwrap_or_else(symbols::SymbolTable::new);
+
+    // Flat memory is faster but only covers the low part of the address
+    // space
+    let elf32 = isa.xlen() == ArchWidth::RV32;
+    let mut mem = if flat_mem {
+        progmem::ProgramMemory::new_flat(elf32).unwrap_or_else(|()| {
+            eprintln!("Failed to reserve host memory for --flat-mem");
+            std::process::exit(1);
+        })
+    }
+    else {
+        progmem::ProgramMemory::new(elf32)
+    };
+
+    if let Some(elf) = &elf {
+        if mem.load_elf(elf).is_err() {
+            if flat_mem {
+                eprintln!("{} does not fit in flat memory, try without --flat-mem", filename);
+            }
+            else {
+                eprintln!("{} does not fit in guest memory", filename);
+            }
+            std::process::exit(1);
+        }
+    }
+
+    let image_start = image.as_ref().map(|image| image.start());
+    let mut blobs : Vec<(String, u64, Vec<u8>)> = image.into_iter()
+        .flat_map(|image| image.chunks)
+        .map(|(addr, data)| (filename.clone(), addr, data))
+        .collect();
+
+    for (file, addr) in loads {
+        let data = std::fs::read(&file).unwrap_or_else(|e| {
+            eprintln!("Failed to load {}: {}", file, e);
+            std::process::exit(1);
+        });
+        blobs.push((file, addr, data));
+    }
+
+    for (file, addr, data) in blobs {
+        if mem.load_blob(addr, &data).is_err() {
+            eprintln!("Cannot place {} at 0x{:x}: it overlaps the stack or is outside flat memory",
+                file, addr);
+            std::process::exit(1);
+        }
+    }
 
-    let mut mem =
-        progmem::ProgramMemory::new(&filename);
+    let entry = entry.or(image_start).unwrap_or_else(|| mem.entry());
 
-    let mut arch = ArchState::new();
-    arch.set_stack_addr(0x7000_0000_0000);
+    // Bare-metal code gets PMP in place of region permissions
+    mem.set_protection(!system);
+
+    let mut arch = if system { ArchState::new_system() } else { ArchState::new() };
+    arch.isa = isa;
+    arch.icache.warn_stale = warn_smc;
+
+    // A hart comes out of reset at the reset vector, typically a boot
+    // loader that goes on to the entry point
+    arch.pc = if system { reset_vector.unwrap_or(entry) } else { entry };
+
+    // Everything after the program name is passed to the guest
+    let mut process = syscalls::ProcessState {
+        vars : initstack::MainVars { args : std::iter::once(filename.clone()).chain(args).collect(), env },
+        word : if isa.xlen() == ArchWidth::RV32 { 4 } else { 8 },
+        files : fdtable::FdTable::default(),
+        sysroot : if sysroot.is_empty() { None } else { Some(sysroot.clone().into()) }
+    };
+
+    if !system {
+        let mut aux = match &elf {
+            Some(elf) => initstack::AuxInfo::from_elf(elf, mem.load_bias(), isa.hwcap()),
+            None => initstack::AuxInfo::without_phdrs(entry, isa.hwcap())
+        };
+        aux.entry = entry;
+
+        // Dynamically linked programs start in their interpreter, which is
+        // looked up under the sysroot
+        if let Some((elf, interp)) = elf.as_ref().and_then(|elf| Some((elf, elf.interp()?))) {
+            let path = format!("{}/{}", sysroot.trim_end_matches('/'), interp.trim_start_matches('/'));
+            let ld = elf::ElfFile::open(&path).unwrap_or_else(|e| {
+                eprintln!("Failed to load interpreter {}: {}", path, e);
+                std::process::exit(1);
+            });
+
+            if ld.elf32 != elf.elf32 {
+                eprintln!("Interpreter {} does not match the ELF class of {}", path, filename);
+                std::process::exit(1);
+            }
+
+            aux.base = mem.load_interp(&ld).unwrap_or_else(|()| {
+                eprintln!("Interpreter {} does not fit in guest memory", path);
+                std::process::exit(1);
+            });
+            arch.pc = ld.entry + aux.base;
+        }
+
+        let top = mem.stack_top();
+        let sp = initstack::build_stack(&mut mem, top, process.word, &process.vars, &aux).unwrap_or_else(|_| {
+            eprintln!("The arguments and environment do not fit on the stack");
+            std::process::exit(1);
+        });
+        arch.set_stack_addr(sp);
+    }
+
+    // Bare-metal programs signal completion through the HTIF tohost word:
+    // (exit code << 1) | 1.
+    let tohost = symbols.find("tohost").map(|s| s.addr).filter(|_| system);
+
+    // Only count what the program itself writes, not the loaded image
+    mem.clear_dirty();
 
     let mut debug = false;
 
     loop {
-        let raw_inst = arch.fetch_inst(&mut mem);
-        let decoded = decode(&raw_inst);
+        if let Some(val) = tohost.and_then(|addr| memif::read64(&mem, addr).ok()) {
+            if val & 1 == 1 {
+                print_stats(&arch, &mem);
+                std::process::exit((val >> 1) as i32);
+            }
+        }
+
+        if system && arch.take_interrupt() {
+            continue;
+        }
+
+        let (raw_inst, decoded) = match arch.fetch_decode(&mut mem) {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                let res = arch.raise(e);
+                report_exception(&arch, &mem, &symbols, res);
+                continue;
+            }
+        };
 
         if debug {
             println!("    {:04x}: ({:08x}) {:?}", arch.pc, raw_inst.raw, decoded);
         }
 
 
-        let res = arch.exec_inst(&mut mem, &decoded);
+        let res = arch.exec_inst(&mut mem, &raw_inst, &decoded);
 
         if debug {
-            if let DecodedInst::Jalr {rs1 , rd, imm } = decoded {
-                if let Some(sym) = disasm_map.get(&arch.pc) {
-                    println!("Call {}", sym);
-                }
-                else if rs1 == 1 {
-                    println!("Return");
-                }
-            }
-
-            if let DecodedInst::CJalr {rs1} = decoded {
-                if let Some(sym) = disasm_map.get(&arch.pc) {
-                    println!("Call {}", sym);
-                }
-            }
-
-            if let DecodedInst::CJr {rs1} = decoded {
-                if rs1 == 1 {
-                    println!("Return");
-                }
+            match decoded {
+                DecodedInst::Jal {rd : 1, ..} |
+                DecodedInst::Jalr {rd : 1, ..} |
+                DecodedInst::CJalr {..} => {
+                    println!("Call {}", symbols.describe(arch.pc));
+                },
+                DecodedInst::Jalr {rd : 0, rs1 : 1, ..} |
+                DecodedInst::CJr {rs1 : 1} => {
+                    println!("Return to {}", symbols.describe(arch.pc));
+                },
+                _ => ()
             }
         }
 
 
-        if let DecodedInst::Addi {rs1, rd, imm} = decoded {
+        if let DecodedInst::Addi {rd, imm, ..} = decoded {
             if rd == 0 && imm == 1 {
                 debug = true;
             }
@@ -98,18 +354,31 @@ fn main() {
         //     }
         // }
 
-        if res == ExecResult::Trap {
+        if res == ExecResult::Syscall {
             // println!("{:?}", arch.regs);
             let syscall = arch.rv64_parse_syscall();
-            let res = syscalls::exec_syscall(&syscall, &mut mem, debug);
-            // println!("Syscall result = {}", res);
-            arch.regs[10] = res as u64;
+
+            match syscalls::exec_syscall(&syscall, &mut mem, &mut process, debug) {
+                syscalls::SyscallResult::Return(val) => arch.regw(10, val),
+                syscalls::SyscallResult::Exit(code) => {
+                    print_stats(&arch, &mem);
+                    std::process::exit(code);
+                }
+            }
+
+            // Code may have appeared, gone away or stopped being executable
+            // under cached decodes
+            if syscall.remaps() {
+                arch.icache.flush();
+            }
         }
         else if res == ExecResult::Halt {
             break;
         }
+        else {
+            report_exception(&arch, &mem, &symbols, res);
+        }
     }
 
-    println!("# executed inst: {}", arch.num_inst);
-
+    print_stats(&arch, &mem);
 }
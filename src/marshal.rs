@@ -0,0 +1,366 @@
+use crate::memif::*;
+
+//
+// Copying syscall arguments between guest memory and the host. Buffers
+// are copied in and out with bounds and permission checks, so a bad
+// pointer is EFAULT rather than a host crash. Guest structs are laid out
+// by hand for the RISC-V Linux ABI, where long and pointers are word
+// bytes wide, rather than taken from the host's libc types.
+//
+
+/// A syscall failure, as the errno value to give the guest.
+pub type Errno = i32;
+
+/// Copies len bytes from addr, which must all be mapped and readable.
+pub fn copy_in(mem : &dyn MemIf, addr : u64, len : u64) -> Result<Vec<u8>, Errno> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    else if mem.check(addr, len, PERM_R).is_err() {
+        return Err(libc::EFAULT);
+    }
+
+    let mut data = vec![0; len as usize];
+    mem.read_bytes(addr, &mut data).map_err(|_| libc::EFAULT)?;
+    Ok(data)
+}
+
+/// Copies data to addr, which must all be mapped and writable.
+pub fn copy_out(mem : &mut dyn MemIf, addr : u64, data : &[u8]) -> Result<(), Errno> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    else if mem.check(addr, data.len() as u64, PERM_W).is_err() {
+        return Err(libc::EFAULT);
+    }
+
+    mem.write_bytes(addr, data).map_err(|_| libc::EFAULT)
+}
+
+/// Stores a long or pointer, word bytes wide, at addr.
+pub fn put_long(mem : &mut dyn MemIf, addr : u64, word : u64, val : u64) -> Result<(), Errno> {
+    if mem.check(addr, word, PERM_W).is_err() {
+        return Err(libc::EFAULT);
+    }
+
+    match word {
+        4 => mem.store(addr, val as u32),
+        _ => mem.store(addr, val)
+    }.map_err(|_| libc::EFAULT)
+}
+
+/// Zeroes len bytes at addr, which must all be mapped and writable.
+pub fn zero_out(mem : &mut dyn MemIf, addr : u64, len : u64) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    else if mem.check(addr, len, PERM_W).is_err() {
+        return Err(libc::EFAULT);
+    }
+
+    mem.zero(addr, len).map_err(|_| libc::EFAULT)
+}
+
+/// A pathname or other string argument.
+pub fn copy_in_str(mem : &dyn MemIf, addr : u64) -> Result<Vec<u8>, Errno> {
+    const PATH_MAX : u64 = 4096;
+
+    if !mem.mapped(addr, 1) {
+        return Err(libc::EFAULT);
+    }
+
+    mem.read_cstr(addr, PATH_MAX - 1).ok_or_else(|| {
+        // Either too long or it ran into unmapped memory
+        if mem.check(addr, PATH_MAX, PERM_R).is_ok() { libc::ENAMETOOLONG } else { libc::EFAULT }
+    })
+}
+
+/// Builds a guest struct field by field, little-endian.
+pub struct Encoder {
+    word : u64,
+    bytes : Vec<u8>
+}
+
+impl Encoder {
+    pub fn new(word : u64) -> Self {
+        Encoder { word, bytes : Vec::new() }
+    }
+
+    pub fn u32(&mut self, val : u32) -> &mut Self {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, val : u64) -> &mut Self {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    /// A long, unsigned long or pointer.
+    pub fn long(&mut self, val : u64) -> &mut Self {
+        self.bytes.extend_from_slice(&val.to_le_bytes()[..self.word as usize]);
+        self
+    }
+
+    /// A char array of len bytes, NUL-padded. Longer strings are cut
+    /// short, leaving room for the NUL.
+    pub fn chars(&mut self, s : &[u8], len : usize) -> &mut Self {
+        let n = s.len().min(len - 1);
+        self.bytes.extend_from_slice(&s[..n]);
+        self.bytes.resize(self.bytes.len() + len - n, 0);
+        self
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+/// Reads a guest struct field by field.
+pub struct Decoder<'a> {
+    word : u64,
+    bytes : &'a [u8]
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes : &'a [u8], word : u64) -> Self {
+        Decoder { word, bytes }
+    }
+
+    fn take(&mut self, n : usize) -> u64 {
+        let mut le = [0; 8];
+        le[..n].copy_from_slice(&self.bytes[..n]);
+        self.bytes = &self.bytes[n..];
+        u64::from_le_bytes(le)
+    }
+
+    pub fn long(&mut self) -> u64 {
+        self.take(self.word as usize)
+    }
+}
+
+/// A struct the guest reads, as syscall output.
+pub trait ToGuest {
+    fn encode(&self, out : &mut Encoder);
+}
+
+/// A struct the guest passes in.
+pub trait FromGuest : Sized {
+    fn size(word : u64) -> u64;
+    fn decode(input : &mut Decoder) -> Self;
+}
+
+pub fn write_struct<T : ToGuest>(
+    mem : &mut dyn MemIf, addr : u64, word : u64, val : &T) -> Result<(), Errno> {
+
+    let mut out = Encoder::new(word);
+    val.encode(&mut out);
+    copy_out(mem, addr, &out.finish())
+}
+
+/// An array of count structs at addr.
+pub fn read_array<T : FromGuest>(
+    mem : &dyn MemIf, addr : u64, count : u64, word : u64) -> Result<Vec<T>, Errno> {
+
+    let len = count.checked_mul(T::size(word)).ok_or(libc::EINVAL)?;
+    let bytes = copy_in(mem, addr, len)?;
+    let mut input = Decoder::new(&bytes, word);
+
+    Ok((0..count).map(|_| T::decode(&mut input)).collect())
+}
+
+/// struct stat from asm-generic, which RISC-V uses for both XLENs (the
+/// proxy kernel's struct kernel_stat has the same layout).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GuestStat {
+    pub dev : u64,
+    pub ino : u64,
+    pub mode : u32,
+    pub nlink : u32,
+    pub uid : u32,
+    pub gid : u32,
+    pub rdev : u64,
+    pub size : i64,
+    pub blksize : i32,
+    pub blocks : i64,
+    /// Seconds and nanoseconds.
+    pub atime : (i64, i64),
+    pub mtime : (i64, i64),
+    pub ctime : (i64, i64)
+}
+
+// The host's field types vary from one platform to the next
+#[allow(clippy::unnecessary_cast)]
+impl From<&libc::stat> for GuestStat {
+    fn from(st : &libc::stat) -> Self {
+        GuestStat {
+            dev : st.st_dev as u64,
+            ino : st.st_ino as u64,
+            mode : st.st_mode as u32,
+            nlink : st.st_nlink as u32,
+            uid : st.st_uid as u32,
+            gid : st.st_gid as u32,
+            rdev : st.st_rdev as u64,
+            size : st.st_size as i64,
+            blksize : st.st_blksize as i32,
+            blocks : st.st_blocks as i64,
+            atime : (st.st_atime as i64, st.st_atime_nsec as i64),
+            mtime : (st.st_mtime as i64, st.st_mtime_nsec as i64),
+            ctime : (st.st_ctime as i64, st.st_ctime_nsec as i64)
+        }
+    }
+}
+
+impl ToGuest for GuestStat {
+    fn encode(&self, out : &mut Encoder) {
+        out.u64(self.dev).u64(self.ino)
+            .u32(self.mode).u32(self.nlink).u32(self.uid).u32(self.gid)
+            .u64(self.rdev).u64(0)
+            .u64(self.size as u64).u32(self.blksize as u32).u32(0).u64(self.blocks as u64);
+
+        for (sec, nsec) in [self.atime, self.mtime, self.ctime].iter() {
+            out.u64(*sec as u64).u64(*nsec as u64);
+        }
+
+        out.u32(0).u32(0);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GuestTimeval {
+    pub sec : i64,
+    pub usec : i64
+}
+
+impl ToGuest for GuestTimeval {
+    fn encode(&self, out : &mut Encoder) {
+        out.long(self.sec as u64).long(self.usec as u64);
+    }
+}
+
+/// Clock ticks used by the process and its children.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GuestTms {
+    pub utime : i64,
+    pub stime : i64,
+    pub cutime : i64,
+    pub cstime : i64
+}
+
+impl ToGuest for GuestTms {
+    fn encode(&self, out : &mut Encoder) {
+        out.long(self.utime as u64).long(self.stime as u64)
+            .long(self.cutime as u64).long(self.cstime as u64);
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GuestUtsname {
+    pub sysname : String,
+    pub nodename : String,
+    pub release : String,
+    pub version : String,
+    pub machine : String,
+    pub domainname : String
+}
+
+impl ToGuest for GuestUtsname {
+    fn encode(&self, out : &mut Encoder) {
+        const LEN : usize = 65;
+
+        for field in [&self.sysname, &self.nodename, &self.release, &self.version,
+                      &self.machine, &self.domainname].iter() {
+            out.chars(field.as_bytes(), LEN);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GuestIovec {
+    pub base : u64,
+    pub len : u64
+}
+
+impl FromGuest for GuestIovec {
+    fn size(word : u64) -> u64 {
+        2 * word
+    }
+
+    fn decode(input : &mut Decoder) -> Self {
+        GuestIovec { base : input.long(), len : input.long() }
+    }
+}
+
+#[test]
+fn test_struct_layouts() {
+    let stat = GuestStat {
+        dev : 1, ino : 2, mode : 0o100644, nlink : 3, uid : 4, gid : 5, rdev : 6, size : 7,
+        blksize : 8, blocks : 9, atime : (10, 11), mtime : (12, 13), ctime : (14, 15)
+    };
+
+    let mut out = Encoder::new(8);
+    stat.encode(&mut out);
+    let bytes = out.finish();
+    let field = |offset : usize, size : usize| Decoder::new(&bytes[offset..], 8).take(size);
+
+    assert_eq!(bytes.len(), 128);
+    assert_eq!((field(16, 4), field(20, 4), field(32, 8)), (0o100644, 3, 6));
+    assert_eq!((field(48, 8), field(56, 4), field(64, 8)), (7, 8, 9));
+    assert_eq!((field(72, 8), field(80, 8), field(112, 8)), (10, 11, 15));
+
+    // long is the word size
+    let mut out = Encoder::new(4);
+    GuestTimeval { sec : -1, usec : 2 }.encode(&mut out);
+    assert_eq!(out.finish(), vec![0xff, 0xff, 0xff, 0xff, 2, 0, 0, 0]);
+
+    let mut out = Encoder::new(8);
+    GuestTms { utime : 1, stime : 2, cutime : 3, cstime : 4 }.encode(&mut out);
+    assert_eq!(out.finish().len(), 32);
+
+    let uts = GuestUtsname { sysname : "Linux".to_string(), machine : "x".repeat(100), ..Default::default() };
+    let mut out = Encoder::new(8);
+    uts.encode(&mut out);
+    let bytes = out.finish();
+    assert_eq!(bytes.len(), 6 * 65);
+    assert_eq!(&bytes[..6], b"Linux\0");
+    assert_eq!((bytes[4 * 65 + 63], bytes[4 * 65 + 64]), (b'x', 0));
+}
+
+#[test]
+fn test_copy_in_out() {
+    let mut mem = TestMem::new(0x2000);
+
+    write_struct(&mut mem, 0x100, 8, &GuestTimeval { sec : 5, usec : 6 }).unwrap();
+    assert_eq!((read64(&mem, 0x100).unwrap(), read64(&mem, 0x108).unwrap()), (5, 6));
+
+    write64(&mut mem, 0x200, 0x1000).unwrap();
+    write64(&mut mem, 0x208, 3).unwrap();
+    mem.store::<u32>(0x210, 0x1100).unwrap();
+    mem.store::<u32>(0x214, 4).unwrap();
+    assert_eq!(read_array::<GuestIovec>(&mem, 0x200, 1, 8), Ok(vec![GuestIovec { base : 0x1000, len : 3 }]));
+    assert_eq!(read_array::<GuestIovec>(&mem, 0x210, 1, 4), Ok(vec![GuestIovec { base : 0x1100, len : 4 }]));
+
+    // Buffers must be mapped
+    assert_eq!(copy_in(&mem, 0x1FFC, 8), Err(libc::EFAULT));
+    assert_eq!(copy_in(&mem, u64::MAX, 0), Ok(vec![]));
+    assert_eq!(write_struct(&mut mem, 0x1FF8, 8, &GuestTimeval::default()), Err(libc::EFAULT));
+    assert_eq!(read_array::<GuestIovec>(&mem, 0, u64::MAX, 8), Err(libc::EINVAL));
+
+    mem.write_bytes(0x300, b"/etc/passwd\0").unwrap();
+    assert_eq!(copy_in_str(&mem, 0x300), Ok(b"/etc/passwd".to_vec()));
+    mem.write_bytes(0x1FFD, b"abc").unwrap();
+    assert_eq!(copy_in_str(&mem, 0x1FFD), Err(libc::EFAULT));
+    assert_eq!(copy_in_str(&mem, 0x2000), Err(libc::EFAULT));
+
+    mem.zero(0x1FFD, 3).unwrap();
+    mem.write_bytes(0x0, &[b'a'; 0x1800]).unwrap();
+    assert_eq!(copy_in_str(&mem, 0), Err(libc::ENAMETOOLONG));
+
+    assert_eq!(put_long(&mut mem, 0x400, 4, 0x1_2345_6789), Ok(()));
+    assert_eq!(read64(&mem, 0x400).unwrap(), 0x6161_6161_2345_6789);
+    assert_eq!(put_long(&mut mem, 0x400, 8, u64::MAX), Ok(()));
+    assert_eq!(put_long(&mut mem, 0x1FFC, 8, 0), Err(libc::EFAULT));
+    assert_eq!(zero_out(&mut mem, 0x404, 2), Ok(()));
+    assert_eq!(read64(&mem, 0x400).unwrap(), 0xFFFF_0000_FFFF_FFFF);
+    assert_eq!(zero_out(&mut mem, 0x1FFF, 2), Err(libc::EFAULT));
+}
@@ -1,73 +1,328 @@
 
-use crate::bitops;
 
+use std::fmt;
+use crate::pagestore::PAGE_SIZE;
+
+/// Region permissions, with the values of PROT_READ, PROT_WRITE and
+/// PROT_EXEC.
+pub const PERM_R : u8 = 1 << 0;
+pub const PERM_W : u8 = 1 << 1;
+pub const PERM_X : u8 = 1 << 2;
+pub const PERM_RWX : u8 = PERM_R | PERM_W | PERM_X;
+
+/// Why a guest access was refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemFault {
+    Unmapped(u64),
+    /// An unmapped address in the first page.
+    NullDeref(u64),
+    /// The region is mapped but does not allow the access.
+    Denied { addr : u64, region : String, perm : u8 }
+}
+
+impl fmt::Display for MemFault {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemFault::Unmapped(addr) => write!(f, "access to unmapped address 0x{:x}", addr),
+            MemFault::NullDeref(addr) => write!(f, "null pointer dereference (address 0x{:x})", addr),
+            MemFault::Denied { addr, region, perm } => {
+                let what = match *perm {
+                    PERM_X => "execute from non-executable",
+                    PERM_W => "write to read-only",
+                    _ => "read from unreadable"
+                };
+                write!(f, "{} {} at 0x{:x}", what, region, addr)
+            }
+        }
+    }
+}
+
+/// Guest memory. The raw accessors below only need the memory to be
+/// mapped, whatever its permissions, and return the fault otherwise.
 pub trait MemIf {
-    fn read(&self, addr : u64) -> u8;
-    fn write(&mut self, addr : u64, value : u8);
-    unsafe fn mut_ptr(&mut self, addr : u64) -> *mut u8;
+    fn read(&self, addr : u64) -> Result<u8, MemFault>;
+    fn write(&mut self, addr : u64, value : u8) -> Result<(), MemFault>;
+
+    /// Little-endian load of size (at most 8) bytes. Backends override
+    /// this, and write_le, to make it a single host access.
+    #[inline(always)]
+    fn read_le(&self, addr : u64, size : u64) -> Result<u64, MemFault> {
+        (0..size).try_fold(0, |val, i| Ok(val | (self.read(addr.wrapping_add(i))? as u64) << (8 * i)))
+    }
+
+    #[inline(always)]
+    fn write_le(&mut self, addr : u64, val : u64, size : u64) -> Result<(), MemFault> {
+        self.check(addr, size, 0)?;
+
+        for i in 0..size {
+            self.write(addr + i, (val >> (8 * i)) as u8)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies buf.len() bytes from addr into buf. Backends override this,
+    /// write_bytes and zero to copy a page or more at a time.
+    fn read_bytes(&self, addr : u64, buf : &mut [u8]) -> Result<(), MemFault> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.read(addr.wrapping_add(i as u64))?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes nothing if any of it is unmapped.
+    fn write_bytes(&mut self, addr : u64, data : &[u8]) -> Result<(), MemFault> {
+        if !data.is_empty() {
+            self.check(addr, data.len() as u64, 0)?;
+        }
+
+        for (i, b) in data.iter().enumerate() {
+            self.write(addr + i as u64, *b)?;
+        }
+
+        Ok(())
+    }
+
+    /// Zeroes [addr, addr + len), or nothing if any of it is unmapped.
+    fn zero(&mut self, addr : u64, len : u64) -> Result<(), MemFault> {
+        if len != 0 {
+            self.check(addr, len, 0)?;
+        }
+
+        for i in 0..len {
+            self.write(addr + i, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// The NUL-terminated string at addr, without the NUL. None if it runs
+    /// into unmapped memory or is more than max bytes long.
+    fn read_cstr(&self, addr : u64, max : u64) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut next = addr;
+
+        // A page at a time, down to a byte at a time where the string
+        // may end just before unmapped memory
+        while (bytes.len() as u64) <= max {
+            let page_left = PAGE_SIZE - (next & (PAGE_SIZE - 1));
+            let mut len = page_left.min(max + 1 - bytes.len() as u64);
+
+            if !self.mapped(next, len) {
+                len = 1;
+
+                if !self.mapped(next, 1) {
+                    return None;
+                }
+            }
+
+            let start = bytes.len();
+            bytes.resize(start + len as usize, 0);
+            self.read_bytes(next, &mut bytes[start..]).ok()?;
+
+            if let Some(nul) = bytes[start..].iter().position(|b| *b == 0) {
+                bytes.truncate(start + nul);
+                return Some(bytes);
+            }
+
+            next += len;
+        }
+
+        None
+    }
+
+    /// Checks that every byte of [addr, addr + size) is mapped and allows
+    /// perm (some of PERM_R, PERM_W and PERM_X).
+    fn check(&self, addr : u64, size : u64, perm : u8) -> Result<(), MemFault>;
+
+    /// Whether every byte of [addr, addr + size) is backed by memory.
+    fn mapped(&self, addr : u64, size : u64) -> bool {
+        self.check(addr, size, 0).is_ok()
+    }
 
     fn heap_start(&self) -> u64;
     fn brk(&mut self, new_heap_end : u64) -> Result<u64, ()>;
+
+    /// Maps len bytes, rounded up to whole pages, at addr (as with
+    /// MAP_FIXED, replacing what was there) or at an address the backend
+    /// picks. The mapping starts with data, if any, and reads as zero past
+    /// it. Returns the start of the mapping.
+    fn mmap(&mut self, addr : Option<u64>, len : u64, data : Option<&[u8]>, perms : u8)
+        -> Result<u64, ()>;
+
+    /// Removes every page of mmap()ed memory in [addr, addr + len).
+    fn munmap(&mut self, addr : u64, len : u64) -> Result<(), ()>;
+
+    /// Changes the permissions of the pages in [addr, addr + len), all of
+    /// which must be mapped.
+    fn mprotect(&mut self, addr : u64, len : u64, perms : u8) -> Result<(), ()>;
 }
 
-#[inline(always)]
-pub fn read8(mem : &dyn MemIf, addr : u64) -> u64 {
-    mem.read(addr + 0) as u64
+/// Integers that can be loaded from and stored to guest memory.
+pub trait Scalar : Copy {
+    const SIZE : u64;
+    fn from_le_u64(val : u64) -> Self;
+    fn to_le_u64(self) -> u64;
 }
 
-#[inline(always)]
-pub fn read16(mem : &dyn MemIf, addr : u64) -> u64 {
-    (mem.read(addr + 1) as u64) << 8 |
-    (mem.read(addr + 0) as u64)
+macro_rules! scalar {
+    ($($t:ty),*) => {
+        $(impl Scalar for $t {
+            const SIZE : u64 = std::mem::size_of::<$t>() as u64;
+
+            #[inline(always)]
+            fn from_le_u64(val : u64) -> Self { val as $t }
+
+            #[inline(always)]
+            fn to_le_u64(self) -> u64 { self as u64 }
+        })*
+    }
 }
 
-#[inline(always)]
-pub fn read32(mem : &dyn MemIf, addr : u64) -> u64 {
-    (mem.read(addr + 3) as u64) << 24 |
-    (mem.read(addr + 2) as u64) << 16 |
-    (mem.read(addr + 1) as u64) << 8 |
-    (mem.read(addr + 0) as u64)
+scalar!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// Typed little-endian loads and stores, for every MemIf including
+/// dyn MemIf: mem.load::<u32>(addr).
+pub trait MemIfExt : MemIf {
+    #[inline(always)]
+    fn load<T : Scalar>(&self, addr : u64) -> Result<T, MemFault> {
+        Ok(T::from_le_u64(self.read_le(addr, T::SIZE)?))
+    }
+
+    #[inline(always)]
+    fn store<T : Scalar>(&mut self, addr : u64, val : T) -> Result<(), MemFault> {
+        self.write_le(addr, val.to_le_u64(), T::SIZE)
+    }
 }
 
+impl<M : MemIf + ?Sized> MemIfExt for M {}
+
 #[inline(always)]
-pub fn read64(mem : &dyn MemIf, addr : u64) -> u64 {
-    (mem.read(addr + 7) as u64) << 56 |
-    (mem.read(addr + 6) as u64) << 48 |
-    (mem.read(addr + 5) as u64) << 40 |
-    (mem.read(addr + 4) as u64) << 32 |
-    (mem.read(addr + 3) as u64) << 24 |
-    (mem.read(addr + 2) as u64) << 16 |
-    (mem.read(addr + 1) as u64) << 8 |
-    (mem.read(addr + 0) as u64)
+pub fn read8(mem : &dyn MemIf, addr : u64) -> Result<u64, MemFault> {
+    mem.read_le(addr, 1)
 }
 
 #[inline(always)]
-pub fn write8(mem : &mut dyn MemIf, addr : u64, val : u64) {
-    mem.write(addr + 0, bit_range_get!(val, (0, 7)) as u8);
+pub fn read32(mem : &dyn MemIf, addr : u64) -> Result<u64, MemFault> {
+    mem.read_le(addr, 4)
 }
 
 #[inline(always)]
-pub fn write16(mem : &mut dyn MemIf, addr : u64, val : u64) {
-    mem.write(addr + 0, bit_range_get!(val, (0, 7)) as u8);
-    mem.write(addr + 1, bit_range_get!(val, (8, 15)) as u8);
+pub fn read64(mem : &dyn MemIf, addr : u64) -> Result<u64, MemFault> {
+    mem.read_le(addr, 8)
 }
 
 #[inline(always)]
-pub fn write32(mem : &mut dyn MemIf, addr : u64, val : u64) {
-    mem.write(addr + 0, bit_range_get!(val, (0, 7)) as u8);
-    mem.write(addr + 1, bit_range_get!(val, (8, 15)) as u8);
-    mem.write(addr + 2, bit_range_get!(val, (16, 23)) as u8);
-    mem.write(addr + 3, bit_range_get!(val, (24, 31)) as u8);
+pub fn write64(mem : &mut dyn MemIf, addr : u64, val : u64) -> Result<(), MemFault> {
+    mem.write_le(addr, val, 8)
 }
 
-#[inline(always)]
-pub fn write64(mem : &mut dyn MemIf, addr : u64, val : u64) {
-    mem.write(addr + 0, bit_range_get!(val, (0, 7)) as u8);
-    mem.write(addr + 1, bit_range_get!(val, (8, 15)) as u8);
-    mem.write(addr + 2, bit_range_get!(val, (16, 23)) as u8);
-    mem.write(addr + 3, bit_range_get!(val, (24, 31)) as u8);
-    mem.write(addr + 4, bit_range_get!(val, (32, 39)) as u8);
-    mem.write(addr + 5, bit_range_get!(val, (40, 47)) as u8);
-    mem.write(addr + 6, bit_range_get!(val, (48, 55)) as u8);
-    mem.write(addr + 7, bit_range_get!(val, (56, 63)) as u8);
+/// Flat little-endian memory for unit tests, mapped at address 0.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct TestMem {
+    pub data : Vec<u8>
+}
+
+#[cfg(test)]
+impl TestMem {
+    pub fn new(size : usize) -> Self {
+        Self { data : vec![0; size] }
+    }
+}
+
+#[cfg(test)]
+impl MemIf for TestMem {
+    fn read(&self, addr : u64) -> Result<u8, MemFault> {
+        self.data.get(addr as usize).copied().ok_or(MemFault::Unmapped(addr))
+    }
+
+    fn write(&mut self, addr : u64, value : u8) -> Result<(), MemFault> {
+        *self.data.get_mut(addr as usize).ok_or(MemFault::Unmapped(addr))? = value;
+        Ok(())
+    }
+
+    fn read_bytes(&self, addr : u64, buf : &mut [u8]) -> Result<(), MemFault> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        self.check(addr, buf.len() as u64, 0)?;
+        buf.copy_from_slice(&self.data[addr as usize..addr as usize + buf.len()]);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr : u64, data : &[u8]) -> Result<(), MemFault> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        self.check(addr, data.len() as u64, 0)?;
+        self.data[addr as usize..addr as usize + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn check(&self, addr : u64, size : u64, _perm : u8) -> Result<(), MemFault> {
+        match addr.checked_add(size) {
+            Some(end) if end <= self.data.len() as u64 => Ok(()),
+            _ => Err(MemFault::Unmapped(addr))
+        }
+    }
+
+    fn heap_start(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn brk(&mut self, _new_heap_end : u64) -> Result<u64, ()> {
+        Err(())
+    }
+
+    fn mmap(&mut self, _addr : Option<u64>, _len : u64, _data : Option<&[u8]>, _perms : u8)
+        -> Result<u64, ()> {
+        Err(())
+    }
+
+    fn munmap(&mut self, _addr : u64, _len : u64) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn mprotect(&mut self, _addr : u64, _len : u64, _perms : u8) -> Result<(), ()> {
+        Err(())
+    }
+}
+
+#[test]
+fn test_bulk_and_typed_access() {
+    let mut mem = TestMem::new(0x3000);
+
+    mem.store::<u32>(0x10, 0x1234_5678).unwrap();
+    mem.store(0x18, -2i64).unwrap();
+    assert_eq!(mem.load::<u16>(0x12).unwrap(), 0x1234);
+    assert_eq!(mem.load::<i64>(0x18).unwrap(), -2);
+    assert_eq!(mem.load::<u8>(0x18).unwrap(), 0xFE);
+
+    // Through a trait object too
+    let dynmem : &mut dyn MemIf = &mut mem;
+    dynmem.store::<u16>(0x20, 0xBEEF).unwrap();
+    assert_eq!(dynmem.load::<u64>(0x20).unwrap(), 0xBEEF);
+
+    mem.write_bytes(0xFFE, b"hi\0there").unwrap();
+    let mut buf = [0; 4];
+    mem.read_bytes(0xFFF, &mut buf).unwrap();
+    assert_eq!(&buf, b"i\0th");
+
+    mem.zero(0x10, 0x10).unwrap();
+    assert_eq!((mem.load::<u64>(0x10).unwrap(), mem.load::<u64>(0x18).unwrap()), (0, 0));
+
+    assert_eq!(mem.read_cstr(0xFFE, 16), Some(b"hi".to_vec()));
+    assert_eq!(mem.read_cstr(0x1001, 16), Some(b"there".to_vec()));
+    assert_eq!(mem.read_cstr(0x1001, 4), None);
+    assert_eq!(mem.read_cstr(0x1001, 5), Some(b"there".to_vec()));
+
+    // Strings may end right before unmapped memory, but not run into it
+    mem.write_bytes(0x2FFC, b"end\0").unwrap();
+    assert_eq!(mem.read_cstr(0x2FFC, 4096), Some(b"end".to_vec()));
+    mem.write(0x2FFF, b'!').unwrap();
+    assert_eq!(mem.read_cstr(0x2FFC, 4096), None);
 }
@@ -0,0 +1,269 @@
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap};
+
+//
+// Sparse guest memory. Pages are allocated on the first write to them;
+// until then they read as zero. Pages written since the last
+// clear_dirty() are tracked. One-entry caches skip the hash lookup for
+// runs of accesses to the same page.
+//
+
+pub const PAGE_SHIFT : u64 = 12;
+pub const PAGE_SIZE : u64 = 1 << PAGE_SHIFT;
+
+type Page = [u8; PAGE_SIZE as usize];
+
+static ZERO_PAGE : Page = [0; PAGE_SIZE as usize];
+
+pub struct PageStore {
+    pages : HashMap<u64, Box<Page>>,
+    dirty : BTreeSet<u64>,
+    /// Page number and contents of the last page read, possibly ZERO_PAGE.
+    read_cache : Cell<Option<(u64, *const u8)>>,
+    /// The last page written, which is resident and dirty.
+    write_cache : Option<(u64, *mut u8)>
+}
+
+impl Default for PageStore {
+    fn default() -> Self {
+        PageStore {
+            pages : HashMap::new(),
+            dirty : BTreeSet::new(),
+            read_cache : Cell::new(None),
+            write_cache : None
+        }
+    }
+}
+
+#[inline(always)]
+fn split(addr : u64) -> (u64, usize) {
+    (addr >> PAGE_SHIFT, (addr & (PAGE_SIZE - 1)) as usize)
+}
+
+impl PageStore {
+    #[inline(always)]
+    fn read_page(&self, vpn : u64) -> *const u8 {
+        match self.read_cache.get() {
+            Some((cached, page)) if cached == vpn => page,
+            _ => {
+                let page = self.pages.get(&vpn).map_or(ZERO_PAGE.as_ptr(), |p| p.as_ptr());
+                self.read_cache.set(Some((vpn, page)));
+                page
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn write_page(&mut self, vpn : u64) -> *mut u8 {
+        match self.write_cache {
+            Some((cached, page)) if cached == vpn => page,
+            _ => self.touch(vpn)
+        }
+    }
+
+    // The cached pages stay allocated until discard() clears the caches.
+
+    #[inline(always)]
+    pub fn read(&self, addr : u64) -> u8 {
+        let (vpn, offset) = split(addr);
+        unsafe { *self.read_page(vpn).add(offset) }
+    }
+
+    #[inline(always)]
+    pub fn write(&mut self, addr : u64, value : u8) {
+        let (vpn, offset) = split(addr);
+        unsafe { *self.write_page(vpn).add(offset) = value }
+    }
+
+    /// Little-endian load of up to 8 bytes within one page.
+    #[inline(always)]
+    pub fn read_le(&self, addr : u64, size : u64) -> u64 {
+        let (vpn, offset) = split(addr);
+        let mut bytes = [0; 8];
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.read_page(vpn).add(offset), bytes.as_mut_ptr(), size as usize);
+        }
+
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Little-endian store of up to 8 bytes within one page.
+    #[inline(always)]
+    pub fn write_le(&mut self, addr : u64, val : u64, size : u64) {
+        let (vpn, offset) = split(addr);
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                val.to_le_bytes().as_ptr(), self.write_page(vpn).add(offset), size as usize);
+        }
+    }
+
+    /// Allocates a page if need be and marks it dirty.
+    fn touch(&mut self, vpn : u64) -> *mut u8 {
+        let page = self.pages.entry(vpn).or_insert_with(|| Box::new([0; PAGE_SIZE as usize]));
+        let ptr = page.as_mut_ptr();
+
+        self.dirty.insert(vpn);
+        self.write_cache = Some((vpn, ptr));
+        self.read_cache.set(Some((vpn, ptr)));
+        ptr
+    }
+
+    /// Calls f with each page-bounded piece of [addr, addr + len), as
+    /// (address, offset into data, length).
+    fn pieces(addr : u64, len : usize, mut f : impl FnMut(u64, usize, usize)) {
+        let mut done = 0;
+
+        while done < len {
+            let next = addr + done as u64;
+            let n = (PAGE_SIZE as usize - split(next).1).min(len - done);
+            f(next, done, n);
+            done += n;
+        }
+    }
+
+    pub fn read_bytes(&self, addr : u64, buf : &mut [u8]) {
+        Self::pieces(addr, buf.len(), |next, done, n| {
+            let (vpn, offset) = split(next);
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    self.read_page(vpn).add(offset), buf[done..].as_mut_ptr(), n);
+            }
+        });
+    }
+
+    /// Zeroes [addr, addr + len). Whole pages are freed rather than
+    /// cleared, and pages that are not resident are left alone.
+    pub fn zero(&mut self, addr : u64, len : u64) {
+        let end = addr + len;
+        let (first, last) = ((addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1), end & !(PAGE_SIZE - 1));
+
+        if first < last {
+            self.clear(addr, first - addr);
+            self.discard(first, last);
+            self.clear(last, end - last);
+        }
+        else {
+            self.clear(addr, len);
+        }
+    }
+
+    fn clear(&mut self, addr : u64, len : u64) {
+        Self::pieces(addr, len as usize, |next, _, n| {
+            let (vpn, offset) = split(next);
+
+            if self.pages.contains_key(&vpn) {
+                unsafe { std::ptr::write_bytes(self.touch(vpn).add(offset), 0, n) }
+            }
+        });
+    }
+
+    /// Copies data to addr. Zero bytes bound for pages that are not
+    /// resident leave them that way.
+    pub fn fill(&mut self, addr : u64, data : &[u8]) {
+        Self::pieces(addr, data.len(), |next, done, n| {
+            let (vpn, offset) = split(next);
+            let chunk = &data[done..done + n];
+
+            if self.pages.contains_key(&vpn) || chunk.iter().any(|b| *b != 0) {
+                let page = self.touch(vpn);
+                unsafe { std::slice::from_raw_parts_mut(page.add(offset), n).copy_from_slice(chunk) }
+            }
+        });
+    }
+
+    /// Frees the pages of [start, end), which then read as zero again.
+    pub fn discard(&mut self, start : u64, end : u64) {
+        let (first, last) = (start >> PAGE_SHIFT, (end + PAGE_SIZE - 1) >> PAGE_SHIFT);
+
+        self.pages.retain(|vpn, _| *vpn < first || *vpn >= last);
+        self.dirty.retain(|vpn| *vpn < first || *vpn >= last);
+        self.read_cache.set(None);
+        self.write_cache = None;
+    }
+
+    /// Bytes of host memory the guest's pages take up.
+    pub fn resident_bytes(&self) -> u64 {
+        self.pages.len() as u64 * PAGE_SIZE
+    }
+
+    /// Addresses of the pages written since the last clear_dirty().
+    pub fn dirty_pages(&self) -> Vec<u64> {
+        self.dirty.iter().map(|vpn| vpn << PAGE_SHIFT).collect()
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+        self.write_cache = None;
+    }
+}
+
+#[test]
+fn test_sparse_pages() {
+    let mut store = PageStore::default();
+
+    // Reads allocate nothing
+    assert_eq!(store.read(0x1234), 0);
+    assert_eq!(store.read(u64::MAX), 0);
+    assert_eq!(store.resident_bytes(), 0);
+
+    store.write(u64::MAX, 0xAA);
+    store.write(0x1000, 1);
+    store.write(0x1FFF, 2);
+    assert_eq!(store.read(u64::MAX), 0xAA);
+    assert_eq!((store.read(0x1000), store.read(0x1FFF), store.read(0x2000)), (1, 2, 0));
+    assert_eq!(store.resident_bytes(), 2 * PAGE_SIZE);
+
+    // Zeros going to pages that are not resident are skipped
+    store.fill(0x10_0ffe, &[0; 0x2000]);
+    assert_eq!(store.resident_bytes(), 2 * PAGE_SIZE);
+    store.fill(0x10_0ffe, &[7; 4]);
+    assert_eq!((store.read(0x10_0ffe), store.read(0x10_1001), store.read(0x10_1002)), (7, 7, 0));
+    assert_eq!(store.resident_bytes(), 4 * PAGE_SIZE);
+
+    store.write_le(0x10_0ff8, 0x0102_0304_0506_0708, 8);
+    assert_eq!(store.read_le(0x10_0ff8, 8), 0x0102_0304_0506_0708);
+    assert_eq!(store.read_le(0x10_0ffc, 2), 0x0304);
+
+    let mut buf = [0; 6];
+    store.read_bytes(0x10_0ffc, &mut buf);
+    assert_eq!(buf, [4, 3, 2, 1, 7, 7]);
+
+    // Zeroing a page that is not resident does not allocate it
+    store.zero(0x10_1001, 0x2000);
+    assert_eq!((store.read(0x10_1000), store.read(0x10_1001)), (7, 0));
+    assert_eq!(store.resident_bytes(), 4 * PAGE_SIZE);
+
+    store.discard(0x10_0000, 0x10_2000);
+    assert_eq!(store.read(0x10_0ffe), 0);
+    assert_eq!(store.resident_bytes(), 2 * PAGE_SIZE);
+
+    // and whole pages that are zeroed are freed
+    store.write(0x3000, 1);
+    store.write(0x4000, 2);
+    store.zero(0x2FFF, 0x1001);
+    assert_eq!((store.read(0x3000), store.read(0x4000)), (0, 2));
+    assert_eq!(store.resident_bytes(), 3 * PAGE_SIZE);
+}
+
+#[test]
+fn test_dirty_pages() {
+    let mut store = PageStore::default();
+
+    store.write(0x3000, 1);
+    store.write(0x1000, 1);
+    assert_eq!(store.dirty_pages(), vec![0x1000, 0x3000]);
+
+    store.clear_dirty();
+    assert!(store.dirty_pages().is_empty());
+
+    // Reads do not dirty a page but writes through the cached page do
+    assert_eq!(store.read(0x1000), 1);
+    store.write(0x1001, 2);
+    store.write(0x1002, 3);
+    store.fill(0x5000, &[4]);
+    assert_eq!(store.dirty_pages(), vec![0x1000, 0x5000]);
+    assert_eq!(store.read(0x5000), 4);
+}
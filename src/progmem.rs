@@ -1,118 +1,1019 @@
-
-use std::fs::File;
-use std::io::Read;
-use memmap2::MmapMut;
+use std::cell::Cell;
 use crate::memif::*;
+use crate::elf::*;
+use crate::pagestore::*;
+use crate::hostmem::HostWindow;
 
 const MAX_HEAP : u64 = 4 * (1 << 30);
 const MAX_STACK : u64 = 256 * (1 << 20);
 
+/// Where things go in the guest address space. The stack is on top, above
+/// the heap. Position-independent executables are loaded at a fixed bias,
+/// and mmap() and the dynamic linker get the range between heap and
+/// stack. RV32 programs need it all below 4 GiB.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    stack_top : u64,
+    pie_base : u64,
+    mmap_base : u64
+}
+
+const LAYOUT_64 : Layout = Layout {
+    stack_top : 0x7000_0000_0000,
+    pie_base : 0x5555_5555_4000,
+    mmap_base : 0x6000_0000_0000
+};
+
+const LAYOUT_32 : Layout = Layout {
+    stack_top : 0xC000_0000,
+    pie_base : 0x0040_0000,
+    mmap_base : 0x8000_0000
+};
+
+/// Flat memory covers [0, FLAT_WINDOW_64) on RV64, so the layout is
+/// squeezed into it. RV32 gets all 4 GiB and its usual layout.
+const FLAT_WINDOW_64 : u64 = 1 << 36;
+const FLAT_WINDOW_32 : u64 = 1 << 32;
+
+const LAYOUT_FLAT_64 : Layout = Layout {
+    stack_top : FLAT_WINDOW_64,
+    pie_base : 0x1_0000_0000,
+    mmap_base : FLAT_WINDOW_64 / 2
+};
+
+// The old names, for the tests
+#[cfg(test)]
+const PIE_BASE_64 : u64 = LAYOUT_64.pie_base;
+#[cfg(test)]
+const MMAP_BASE_64 : u64 = LAYOUT_64.mmap_base;
+#[cfg(test)]
+const STACK_TOP_32 : u64 = LAYOUT_32.stack_top;
+
+/// Where the bytes live: sparse pages, or one flat host mapping.
+enum Storage {
+    Paged(PageStore),
+    Flat(HostWindow)
+}
+
+macro_rules! storage {
+    ($self:expr, $s:ident => $e:expr) => {
+        match $self {
+            Storage::Paged($s) => $e,
+            Storage::Flat($s) => $e
+        }
+    }
+}
+
+impl Storage {
+    /// Whether [addr, addr + len) can be stored at all.
+    #[inline(always)]
+    fn holds(&self, addr : u64, len : u64) -> bool {
+        match self {
+            Storage::Paged(_) => true,
+            Storage::Flat(window) => window.contains(addr, len)
+        }
+    }
+}
+
+/// The stack and heap are always the first two regions.
+const STACK : usize = 0;
+const HEAP : usize = 1;
+
+/// A named, page-aligned range of the address space, like a line of
+/// /proc/self/maps. The contents live in the page store.
+#[derive(Debug, Clone)]
+struct Region {
+    name : String,
+    start : u64,
+    len : u64,
+    perms : u8,
+    /// Created by mmap(), and so open to munmap() and MAP_FIXED.
+    mmapped : bool
+}
+
+impl Region {
+    fn new(name : &str, start : u64, len : u64, perms : u8, mmapped : bool) -> Self {
+        Region { name : name.to_string(), start, len, perms, mmapped }
+    }
+
+    #[inline(always)]
+    fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    #[inline(always)]
+    fn contains(&self, addr : u64) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+}
+
 pub struct ProgramMemory {
-    image : Vec<u8>,
-    heap : MmapMut,
-    heap_start : u64,
-    heap_end : u64,
-    stack : MmapMut,
-    stack_start : u64
+    entry : u64,
+    load_bias : u64,
+    regions : Vec<Region>,
+    /// The region of the last lookup, which is usually the next one's.
+    last : Cell<usize>,
+    store : Storage,
+    layout : Layout,
+    /// Whether region permissions apply. Bare-metal code has PMP instead.
+    protect : bool,
+    mmap_base : u64,
+    mmap_next : u64
 }
 
 
-fn read_bin(filename: &String) -> Vec<u8> {
-    let mut f = File::open(&filename).expect("no file found");
-    let metadata = std::fs::metadata(&filename).expect("unable to read metadata");
-    let mut buffer = vec![0; metadata.len() as usize];
-    f.read(&mut buffer).expect("buffer overflow");
+#[inline(always)]
+fn page_align_down(addr : u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
 
-    buffer
+#[inline(always)]
+fn page_align_up(addr : u64) -> u64 {
+    page_align_down(addr + PAGE_SIZE - 1)
 }
 
 impl ProgramMemory {
 
-    pub fn new(image_file : &String) -> Self {
-        let image = read_bin(&image_file);
-        let image_len = image.len();
+    /// Just a stack and an empty heap at 0, for raw images which are
+    /// placed with load_blob(), and ELF files placed with load_elf().
+    pub fn new(elf32 : bool) -> Self {
+        let layout = if elf32 { LAYOUT_32 } else { LAYOUT_64 };
+        Self::with_storage(Storage::Paged(PageStore::default()), layout)
+    }
+
+    /// As new(), but with guest memory in one flat host mapping, which
+    /// is faster and only holds addresses below 64 GiB (4 GiB on RV32).
+    pub fn new_flat(elf32 : bool) -> Result<Self, ()> {
+        let (window, layout) = if elf32 {
+            (FLAT_WINDOW_32, LAYOUT_32)
+        }
+        else {
+            (FLAT_WINDOW_64, LAYOUT_FLAT_64)
+        };
+
+        Ok(Self::with_storage(Storage::Flat(HostWindow::new(window)?), layout))
+    }
+
+    fn with_storage(store : Storage, layout : Layout) -> Self {
+        let rw = PERM_R | PERM_W;
+
         Self {
-            image,
-            heap : memmap2::MmapMut::map_anon(MAX_HEAP as usize).unwrap(),
-            heap_start : image_len as u64,
-            heap_end : image_len as u64,
-            stack : memmap2::MmapMut::map_anon(MAX_STACK as usize).unwrap(),
-            stack_start : 0x7000_0000_0000
+            entry : 0,
+            load_bias : 0,
+            regions : vec![
+                Region::new("[stack]", layout.stack_top - MAX_STACK, MAX_STACK, rw, false),
+                Region::new("[heap]", 0, 0, rw, false)
+            ],
+            last : Cell::new(0),
+            store,
+            layout,
+            protect : true,
+            mmap_base : layout.mmap_base,
+            mmap_next : layout.mmap_base
         }
     }
-}
 
+    /// Maps every PT_LOAD segment at its vaddr, plus the load bias for
+    /// ET_DYN executables, with the segment's permissions. The heap
+    /// starts right after the highest segment. This must come before
+    /// anything else, and fails if the storage cannot hold the executable.
+    pub fn load_elf(&mut self, elf : &ElfFile) -> Result<(), ()> {
+        let load_bias = if elf.e_type == ET_DYN { self.layout.pie_base } else { 0 };
+        let segments = segment_regions(elf, load_bias, "")?;
+        let image_end = segments.iter().map(|r| r.end()).max().unwrap_or(0);
 
-impl MemIf for ProgramMemory {
-    fn read(&self, addr : u64) -> u8 {
-        if addr < self.heap_start {
-            self.image[addr as usize]
+        if segments.iter().any(|r| !self.store.holds(r.start, r.len)) {
+            return Err(());
         }
-        else if addr < self.heap_end {
-            self.heap[(addr - self.heap_start) as usize]
+
+        self.entry = elf.entry + load_bias;
+        self.load_bias = load_bias;
+        self.regions[HEAP].start = image_end;
+        self.regions.extend(segments);
+        self.copy_segments(elf, load_bias);
+        Ok(())
+    }
+
+    /// Turns region permissions on or off; only whether an address is
+    /// mapped at all is checked without them.
+    pub fn set_protection(&mut self, protect : bool) {
+        self.protect = protect;
+    }
+
+    /// Copies data to addr before the guest runs, mapping whatever part of
+    /// it is not already mapped. The heap and the mmap area move up past
+    /// it. Fails if it would overlap the stack, or if the storage cannot
+    /// hold it.
+    pub fn load_blob(&mut self, addr : u64, data : &[u8]) -> Result<(), ()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let end = addr.checked_add(data.len() as u64).ok_or(())?;
+        let (start, end) = (page_align_down(addr), page_align_up(end));
+        let stack = &self.regions[STACK];
+
+        if (start < stack.end() && stack.start < end) || !self.store.holds(start, end - start) {
+            return Err(());
         }
-        else if addr > self.stack_start - MAX_STACK {
-            self.stack[(self.stack_start - addr) as usize]
+
+        // Map the holes a page at a time, merging neighbouring pages
+        let mut page = start;
+
+        while page < end {
+            if self.region(page).is_some() {
+                page += PAGE_SIZE;
+                continue;
+            }
+
+            let hole = page;
+            while page < end && self.region(page).is_none() {
+                page += PAGE_SIZE;
+            }
+
+            self.regions.push(Region::new("[load]", hole, page - hole, PERM_RWX, false));
+        }
+
+        storage!(&mut self.store, s => s.fill(addr, data));
+
+        if end <= self.mmap_base {
+            let heap = &mut self.regions[HEAP];
+            heap.start = heap.start.max(end);
         }
         else {
-            println!("    Image: [0x{:016x}-0x{:016x}]", 0, self.heap_start);
-            println!("    Heap:  [0x{:016x}-0x{:016x}]", self.heap_start, self.heap_end);
-            println!("    Stack Base: 0x{:016x}", self.stack_start);
+            self.mmap_next = self.mmap_next.max(end);
+        }
+
+        Ok(())
+    }
+
+    /// Loads the program interpreter (the dynamic linker) into the mmap
+    /// area and returns its load bias, which the guest gets as AT_BASE.
+    /// Fails if the storage cannot hold it.
+    pub fn load_interp(&mut self, elf : &ElfFile) -> Result<u64, ()> {
+        // Interpreters are linked at 0 but be exact about it
+        let first = elf.load_segments().map(|ph| page_align_down(ph.vaddr)).min().unwrap_or(0);
+        let bias = self.mmap_next.checked_sub(first).ok_or(())?;
+        let segments = segment_regions(elf, bias, "[interp] ")?;
+
+        if segments.iter().any(|r| !self.store.holds(r.start, r.len)) {
+            return Err(());
+        }
 
-            panic!("Unmapped memory address! 0x{:016x}", addr);
+        for mut region in segments {
+            region.mmapped = true;
+            self.unmap_range(region.start, region.end());
+            self.mmap_next = self.mmap_next.max(region.end());
+            self.regions.push(region);
         }
+
+        self.copy_segments(elf, bias);
+        Ok(bias)
     }
 
-    fn write(&mut self, addr : u64, value : u8) {
-        if addr < self.heap_start {
-            self.image[addr as usize] = value
+    /// Copies the file contents of the PT_LOAD segments; the rest of
+    /// their memory reads as zero.
+    fn copy_segments(&mut self, elf : &ElfFile, bias : u64) {
+        for ph in elf.load_segments() {
+            let start = ph.vaddr + bias;
+            storage!(&mut self.store, s => s.fill(start, elf.segment_data(ph)));
+
+            // .bss, whatever the memory held before
+            storage!(&mut self.store, s => s.zero(start + ph.filesz, ph.memsz - ph.filesz));
         }
-        else if addr < self.heap_end {
-            self.heap[(addr - self.heap_start) as usize] = value
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Offset added to the ELF's addresses: zero unless it is a PIE.
+    pub fn load_bias(&self) -> u64 {
+        self.load_bias
+    }
+
+    pub fn stack_top(&self) -> u64 {
+        self.regions[STACK].end()
+    }
+
+    /// Host memory taken up by the pages the guest has written.
+    pub fn resident_bytes(&self) -> u64 {
+        storage!(&self.store, s => s.resident_bytes())
+    }
+
+    /// Addresses of the pages written since the last clear_dirty().
+    pub fn dirty_pages(&self) -> Vec<u64> {
+        storage!(&self.store, s => s.dirty_pages())
+    }
+
+    pub fn clear_dirty(&mut self) {
+        storage!(&mut self.store, s => s.clear_dirty())
+    }
+
+    #[inline(always)]
+    fn region(&self, addr : u64) -> Option<usize> {
+        let last = self.last.get();
+
+        if self.regions.get(last).is_some_and(|r| r.contains(addr)) {
+            return Some(last);
         }
-        else if addr > self.stack_start - MAX_STACK {
-            self.stack[(self.stack_start - addr) as usize] = value
+
+        let i = self.regions.iter().position(|r| r.contains(addr))?;
+        self.last.set(i);
+        Some(i)
+    }
+
+    /// The region holding addr, or the fault for an access to it.
+    #[inline(always)]
+    fn mapped_region(&self, addr : u64) -> Result<&Region, MemFault> {
+        match self.region(addr) {
+            Some(i) => Ok(&self.regions[i]),
+            None => Err(unmapped(addr))
         }
-        else {
-            panic!("Unmapped memory address! 0x{:016x}", addr);
+    }
+
+    /// Whether a size-byte access at addr can be done in one go, rather
+    /// than a byte at a time.
+    #[inline(always)]
+    fn single_access(&self, addr : u64, size : u64) -> Result<bool, MemFault> {
+        let r = self.mapped_region(addr)?;
+
+        Ok(addr + size <= r.end() && match self.store {
+            Storage::Paged(_) => (addr & (PAGE_SIZE - 1)) + size <= PAGE_SIZE,
+            Storage::Flat(_) => true
+        })
+    }
+
+    /// Prints the regions in address order, like /proc/self/maps.
+    pub fn dump_map(&self) {
+        let mut regions : Vec<&Region> = self.regions.iter().collect();
+        regions.sort_by_key(|r| r.start);
+
+        for r in regions {
+            let perm = |bit, c| if r.perms & bit != 0 { c } else { '-' };
+            eprintln!("    [0x{:016x}-0x{:016x}] {}{}{} {}", r.start, r.end(),
+                perm(PERM_R, 'r'), perm(PERM_W, 'w'), perm(PERM_X, 'x'), r.name);
         }
     }
 
-    unsafe fn mut_ptr(&mut self, addr : u64) -> *mut u8 {
-        if addr < self.heap_start {
-            let image_ptr = self.image.as_mut_ptr();
-            image_ptr.add(addr as usize)
+    /// Whether [start, end) overlaps the image, heap or stack, which mmap
+    /// must leave alone. The heap counts up to its limit.
+    fn overlaps_fixed(&self, start : u64, end : u64) -> bool {
+        let heap = &self.regions[HEAP];
+        let heap_limit = (heap.start + MAX_HEAP).min(self.mmap_base);
+
+        (start < heap_limit && heap.start < end) ||
+        self.regions.iter().any(|r| !r.mmapped && start < r.end() && r.start < end)
+    }
+
+    /// Splits the region straddling addr in two. The stack and heap stay
+    /// whole.
+    fn split_at(&mut self, addr : u64) {
+        let i = match self.region(addr) {
+            Some(i) if i > HEAP && self.regions[i].start != addr => i,
+            _ => return
+        };
+
+        let mut tail = self.regions[i].clone();
+        tail.start = addr;
+        tail.len = self.regions[i].end() - addr;
+        self.regions[i].len = addr - self.regions[i].start;
+        self.regions.push(tail);
+    }
+
+    /// Drops the mmap()ed memory in [start, end), splitting regions that
+    /// straddle either end.
+    fn unmap_range(&mut self, start : u64, end : u64) {
+        self.split_at(start);
+        self.split_at(end);
+
+        for r in self.regions.iter().filter(|r| r.mmapped && r.start >= start && r.end() <= end) {
+            storage!(&mut self.store, s => s.discard(r.start, r.end()));
         }
-        else if addr < self.heap_end {
-            let heap_ptr = self.heap.as_mut_ptr();
-            heap_ptr.add((addr - self.heap_start) as usize)
+
+        self.regions.retain(|r| !(r.mmapped && r.start >= start && r.end() <= end));
+    }
+}
+
+/// The fault for an access to an address outside every region.
+#[inline(always)]
+fn unmapped(addr : u64) -> MemFault {
+    if addr < PAGE_SIZE { MemFault::NullDeref(addr) } else { MemFault::Unmapped(addr) }
+}
+
+/// Region permissions for ELF p_flags.
+fn segment_perms(flags : u32) -> u8 {
+    [(PF_R, PERM_R), (PF_W, PERM_W), (PF_X, PERM_X)].iter()
+        .filter(|(pf, _)| flags & pf != 0)
+        .fold(0, |perms, (_, perm)| perms | perm)
+}
+
+fn segment_name(perms : u8) -> &'static str {
+    if perms & PERM_X != 0 { ".text" } else if perms & PERM_W != 0 { ".data" } else { ".rodata" }
+}
+
+/// One region per PT_LOAD segment, shifted by bias. Segments sharing a
+/// page share a region, with the permissions of both. Fails if a segment
+/// ends up past the top of the address space.
+fn segment_regions(elf : &ElfFile, bias : u64, prefix : &str) -> Result<Vec<Region>, ()> {
+    let mut segments : Vec<&ProgramHeader> = elf.load_segments().collect();
+    segments.sort_by_key(|ph| ph.vaddr);
+
+    let mut regions : Vec<Region> = Vec::new();
+
+    for ph in segments {
+        let start = ph.vaddr.checked_add(bias).ok_or(())?;
+        let end = start.checked_add(ph.memsz).and_then(|end| end.checked_add(PAGE_SIZE - 1)).ok_or(())?;
+        let (start, end) = (page_align_down(start), page_align_down(end));
+        let perms = segment_perms(ph.flags);
+
+        match regions.last_mut() {
+            Some(r) if start < r.end() => {
+                r.perms |= perms;
+                r.len = r.len.max(end - r.start);
+            },
+            _ => regions.push(Region::new("", start, end - start, perms, false))
         }
-        else if addr > self.stack_start - MAX_STACK {
-            let stack_ptr = self.stack.as_mut_ptr();
-            stack_ptr.sub((self.stack_start - addr) as usize)
+    }
+
+    for r in regions.iter_mut() {
+        r.name = format!("{}{}", prefix, segment_name(r.perms));
+    }
+
+    Ok(regions)
+}
+
+
+impl MemIf for ProgramMemory {
+    #[inline(always)]
+    fn read(&self, addr : u64) -> Result<u8, MemFault> {
+        self.mapped_region(addr)?;
+        Ok(storage!(&self.store, s => s.read(addr)))
+    }
+
+    #[inline(always)]
+    fn write(&mut self, addr : u64, value : u8) -> Result<(), MemFault> {
+        self.mapped_region(addr)?;
+        storage!(&mut self.store, s => s.write(addr, value));
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn read_le(&self, addr : u64, size : u64) -> Result<u64, MemFault> {
+        if self.single_access(addr, size)? {
+            Ok(storage!(&self.store, s => s.read_le(addr, size)))
         }
         else {
-            panic!("Unmapped memory address! 0x{:016x}", addr);
+            (0..size).try_fold(0, |val, i| Ok(val | (self.read(addr.wrapping_add(i))? as u64) << (8 * i)))
+        }
+    }
+
+    /// A store that faults part way writes nothing.
+    #[inline(always)]
+    fn write_le(&mut self, addr : u64, val : u64, size : u64) -> Result<(), MemFault> {
+        if self.single_access(addr, size)? {
+            storage!(&mut self.store, s => s.write_le(addr, val, size));
+            return Ok(());
+        }
+
+        self.check(addr, size, 0)?;
+
+        for i in 0..size {
+            self.write(addr + i, (val >> (8 * i)) as u8)?;
+        }
+
+        Ok(())
+    }
+
+    // Regions only hold metadata, so a range that spans several of them
+    // is one copy in the store.
+
+    fn read_bytes(&self, addr : u64, buf : &mut [u8]) -> Result<(), MemFault> {
+        if !buf.is_empty() {
+            self.check(addr, buf.len() as u64, 0)?;
+            storage!(&self.store, s => s.read_bytes(addr, buf));
+        }
+
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr : u64, data : &[u8]) -> Result<(), MemFault> {
+        if !data.is_empty() {
+            self.check(addr, data.len() as u64, 0)?;
+            storage!(&mut self.store, s => s.fill(addr, data));
+        }
+
+        Ok(())
+    }
+
+    fn zero(&mut self, addr : u64, len : u64) -> Result<(), MemFault> {
+        if len != 0 {
+            self.check(addr, len, 0)?;
+            storage!(&mut self.store, s => s.zero(addr, len));
+        }
+
+        Ok(())
+    }
+
+    fn check(&self, addr : u64, size : u64, perm : u8) -> Result<(), MemFault> {
+        let last = addr.checked_add(size - 1).ok_or(MemFault::Unmapped(addr))?;
+        let mut next = addr;
+
+        // An access may span neighbouring regions
+        loop {
+            let r = match self.region(next) {
+                Some(i) => &self.regions[i],
+                None => return Err(unmapped(next))
+            };
+
+            if self.protect && r.perms & perm != perm {
+                return Err(MemFault::Denied { addr : next, region : r.name.clone(), perm });
+            }
+
+            if last < r.end() {
+                return Ok(());
+            }
+
+            next = r.end();
         }
     }
 
     fn heap_start(&self) -> u64 {
-        self.heap_start
+        self.regions[HEAP].start
     }
 
+    /// Shrinking the heap frees its pages, so that growing it again gives
+    /// zeroed memory. A break below the start of the heap is refused.
     fn brk(&mut self, new_heap_end : u64) -> Result<u64, ()> {
-        if new_heap_end == 0 {
-            Ok(self.heap_end)
+        if new_heap_end != 0 && new_heap_end < self.heap_start() {
+            return Err(());
         }
-        else if new_heap_end < self.heap_start {
-            panic!("Attempt to set heap < heap_start!")
+
+        let heap = &mut self.regions[HEAP];
+
+        if new_heap_end == 0 {
+            Ok(heap.end())
         }
-        else if new_heap_end - self.heap_start > MAX_HEAP {
+        else if new_heap_end - heap.start > MAX_HEAP || new_heap_end > self.mmap_base {
             Err(())
         }
         else {
-            self.heap_end = new_heap_end;
-            Ok(self.heap_end)
+            if new_heap_end < heap.end() {
+                storage!(&mut self.store, s => s.discard(page_align_up(new_heap_end), heap.end()));
+            }
+
+            heap.len = new_heap_end - heap.start;
+            Ok(heap.end())
+        }
+    }
+
+    /// Nothing is allocated for the mapping here; only data is copied
+    /// in, and the rest of it reads as zero from the store.
+    fn mmap(&mut self, addr : Option<u64>, len : u64, data : Option<&[u8]>, perms : u8)
+        -> Result<u64, ()> {
+
+        let len = len.checked_add(PAGE_SIZE - 1).map(page_align_down).ok_or(())?;
+        let data = data.unwrap_or(&[]);
+
+        let start = match addr {
+            Some(addr) if addr & (PAGE_SIZE - 1) != 0 => return Err(()),
+            Some(addr) => addr,
+            None => self.mmap_next
+        };
+
+        let end = start.checked_add(len).ok_or(())?;
+
+        if len == 0 || data.len() as u64 > len || self.overlaps_fixed(start, end) ||
+            !self.store.holds(start, len) {
+            return Err(());
+        }
+
+        self.unmap_range(start, end);
+        self.mmap_next = self.mmap_next.max(end);
+
+        self.regions.push(Region::new("[mmap]", start, len, perms, true));
+        storage!(&mut self.store, s => s.fill(start, data));
+        // Past data, even where this replaced another mapping
+        storage!(&mut self.store, s => s.zero(start + data.len() as u64, len - data.len() as u64));
+        Ok(start)
+    }
+
+    fn munmap(&mut self, addr : u64, len : u64) -> Result<(), ()> {
+        if addr & (PAGE_SIZE - 1) != 0 || len == 0 {
+            return Err(());
+        }
+
+        let end = addr.checked_add(page_align_up(len)).ok_or(())?;
+        self.unmap_range(addr, end);
+        Ok(())
+    }
+
+    /// The heap and stack keep their permissions.
+    fn mprotect(&mut self, addr : u64, len : u64, perms : u8) -> Result<(), ()> {
+        if addr & (PAGE_SIZE - 1) != 0 {
+            return Err(());
         }
+
+        let end = addr.checked_add(page_align_up(len)).ok_or(())?;
+
+        if (addr..end).step_by(PAGE_SIZE as usize).any(|page| self.region(page).is_none()) {
+            return Err(());
+        }
+
+        self.split_at(addr);
+        self.split_at(end);
+
+        for r in self.regions.iter_mut().skip(HEAP + 1).filter(|r| r.start >= addr && r.end() <= end) {
+            r.perms = perms;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl ProgramMemory {
+    /// Paged memory holding elf.
+    pub fn from_elf(elf : &ElfFile) -> Self {
+        let mut mem = Self::new(elf.elf32);
+        mem.load_elf(elf).unwrap();
+        mem
+    }
+}
+
+#[test]
+fn test_load_elf_segments() {
+    let elf = ElfFile::parse(build_test_elf(
+        0x10004, &[(0x10000, &[0x13, 0, 0, 0], 0x4), (0x11000, &[0xAA], 0x100)], &[])).unwrap();
+    let mem = ProgramMemory::from_elf(&elf);
+
+    assert_eq!(mem.entry(), 0x10004);
+    assert_eq!(read32(&mem, 0x10000).unwrap(), 0x13);
+    assert_eq!(read8(&mem, 0x11000).unwrap(), 0xAA);
+    assert_eq!(read8(&mem, 0x11001).unwrap(), 0);
+    assert_eq!(read8(&mem, 0x110FF).unwrap(), 0);
+    assert_eq!(mem.heap_start(), 0x12000);
+
+    assert!(mem.mapped(0x10000, 8));
+    assert!(!mem.mapped(0xFFFC, 8));
+    assert!(!mem.mapped(0x11FFC, 8));
+}
+
+#[test]
+fn test_mmap_munmap() {
+    let elf = ElfFile::parse(build_test_elf(0x10000, &[(0x10000, &[0x13, 0, 0, 0], 0x4)], &[])).unwrap();
+    let mut mem = ProgramMemory::from_elf(&elf);
+
+    // Anonymous mappings are zero filled and placed at the mmap base
+    let addr = mem.mmap(None, 3 * 4096, None, PERM_R | PERM_W).unwrap();
+    assert_eq!(addr, MMAP_BASE_64);
+    assert!(mem.mapped(addr, 3 * 4096));
+    assert_eq!(read64(&mem, addr + 0x1000).unwrap(), 0);
+    write64(&mut mem, addr + 0x1000, 0x1234).unwrap();
+
+    // Unmapping the middle page splits the mapping
+    mem.munmap(addr + 0x1000, 1).unwrap();
+    assert!(mem.mapped(addr, 4096));
+    assert!(!mem.mapped(addr + 0x1000, 1));
+    assert!(mem.mapped(addr + 0x2000, 4096));
+
+    // MAP_FIXED replaces what is there
+    mem.store::<u8>(addr, 0x55).unwrap();
+    assert_eq!(mem.mmap(Some(addr), 10, Some(&[0xAA; 10]), PERM_RWX), Ok(addr));
+    assert_eq!(read8(&mem, addr).unwrap(), 0xAA);
+    assert_eq!(read8(&mem, addr + 10).unwrap(), 0);
+
+    // but not the image, heap or stack, nor unaligned addresses
+    assert!(mem.mmap(Some(0x10000), 1, None, PERM_RWX).is_err());
+    assert!(mem.mmap(Some(mem.stack_top() - 4096), 1, None, PERM_RWX).is_err());
+    assert!(mem.mmap(Some(addr + 1), 1, None, PERM_RWX).is_err());
+    assert!(mem.munmap(addr + 1, 1).is_err());
+
+    // New mappings do not reuse addresses handed out before
+    assert_eq!(mem.mmap(None, 1, None, PERM_RWX), Ok(addr + 0x3000));
+
+    // Large mappings cost nothing until touched, and lengths that do not
+    // fit fail rather than wrap
+    let resident = mem.resident_bytes();
+    let big = mem.mmap(None, 1 << 40, None, PERM_R | PERM_W).unwrap();
+    assert_eq!(read64(&mem, big + (1 << 39)).unwrap(), 0);
+    assert_eq!(mem.resident_bytes(), resident);
+    assert!(mem.mmap(None, 1 << 46, None, PERM_RWX).is_err());
+    assert!(mem.mmap(None, u64::MAX, None, PERM_RWX).is_err());
+    assert!(mem.mmap(None, 4096, Some(&[1; 4097]), PERM_RWX).is_err());
+}
+
+#[test]
+fn test_load_pie_and_interp() {
+    let mut data = build_test_elf(0x1004, &[(0x1000, &[0x13, 0, 0, 0], 0x4)], &[]);
+    data[16..18].copy_from_slice(&ET_DYN.to_le_bytes());
+    let mut mem = ProgramMemory::from_elf(&ElfFile::parse(data).unwrap());
+
+    assert_eq!(mem.load_bias(), PIE_BASE_64);
+    assert_eq!(mem.entry(), PIE_BASE_64 + 0x1004);
+    assert_eq!(read32(&mem, PIE_BASE_64 + 0x1000).unwrap(), 0x13);
+
+    let ld = ElfFile::parse(build_test_elf(0x10, &[(0, &[0xEF; 0x20], 0x2000)], &[])).unwrap();
+    let base = mem.load_interp(&ld).unwrap();
+
+    assert_eq!(base, MMAP_BASE_64);
+    assert_eq!(read8(&mem, base + 0x1F).unwrap(), 0xEF);
+    assert_eq!(read8(&mem, base + 0x20).unwrap(), 0);
+    assert!(mem.mapped(base, 0x2000));
+
+    // An interpreter that runs past flat memory is an error, and leaves
+    // nothing behind
+    let mut flat = ProgramMemory::new_flat(false).unwrap();
+    let big = ElfFile::parse(build_test_elf(0, &[(0, &[0xEF; 0x20], FLAT_WINDOW_64)], &[])).unwrap();
+    assert_eq!(flat.load_interp(&big), Err(()));
+    assert!(!flat.mapped(LAYOUT_FLAT_64.mmap_base, 1));
+
+    // As is a PIE whose segments wrap around once moved up by the bias
+    let mut data = build_test_elf(0, &[(u64::MAX - 0x1FFF, &[0x13], 0x10)], &[]);
+    data[16..18].copy_from_slice(&ET_DYN.to_le_bytes());
+    assert_eq!(ProgramMemory::new(false).load_elf(&ElfFile::parse(data).unwrap()), Err(()));
+}
+
+#[test]
+fn test_load_raw_images() {
+    let mut mem = ProgramMemory::new(true);
+
+    mem.load_blob(0x8000_0000, &[0x13, 0, 0, 0]).unwrap();
+    mem.load_blob(0x1000_0ffe, &[1, 2, 3, 4]).unwrap();
+    mem.load_blob(0x1000_0000, &[0xAA]).unwrap();
+
+    assert_eq!(read32(&mem, 0x8000_0000).unwrap(), 0x13);
+    assert_eq!(read32(&mem, 0x1000_0ffe).unwrap(), 0x0403_0201);
+    assert_eq!(read8(&mem, 0x1000_0000).unwrap(), 0xAA);
+    assert!(mem.mapped(0x1000_0000, 0x2000));
+    assert!(!mem.mapped(0x1000_2000, 1));
+
+    // Anything below the mmap area pushes the heap past it, and the
+    // mmap area does not hand out what is above
+    assert_eq!(mem.heap_start(), 0x1000_2000);
+    assert_eq!(mem.mmap(None, 1, None, PERM_RWX), Ok(0x8000_1000));
+    assert!(mem.load_blob(STACK_TOP_32 - 4, &[0; 4]).is_err());
+
+    // Loads may also patch an ELF image
+    let elf = ElfFile::parse(build_test_elf(0x10000, &[(0x10000, &[0x13, 0, 0, 0], 0x4)], &[])).unwrap();
+    let mut mem = ProgramMemory::from_elf(&elf);
+    mem.load_blob(0x10002, &[0xFF]).unwrap();
+    assert_eq!(read32(&mem, 0x10000).unwrap(), 0xFF0013);
+    assert_eq!(mem.heap_start(), 0x11000);
+}
+
+#[cfg(test)]
+fn test_elf_with_flags(flags : &[u32]) -> ElfFile {
+    let mut data = build_test_elf(
+        0x10000, &[(0x10000, &[0x13, 0, 0, 0], 0x4), (0x11000, &[0xAA], 0x100)], &[]);
+
+    for (i, f) in flags.iter().enumerate() {
+        let ph = 64 + 56 * i;
+        data[ph + 4..ph + 8].copy_from_slice(&f.to_le_bytes());
+    }
+
+    ElfFile::parse(data).unwrap()
+}
+
+#[test]
+fn test_region_permissions() {
+    let mut mem = ProgramMemory::from_elf(&test_elf_with_flags(&[PF_R | PF_X, PF_R | PF_W]));
+    let stack = mem.stack_top() - 8;
+
+    assert_eq!(mem.check(0x10000, 4, PERM_X), Ok(()));
+    assert_eq!(mem.check(0x11000, 8, PERM_R | PERM_W), Ok(()));
+    assert_eq!(mem.check(stack, 8, PERM_W), Ok(()));
+
+    // Writes to .text, jumps to data and null pointers
+    assert_eq!(mem.check(0x10004, 4, PERM_W),
+        Err(MemFault::Denied { addr : 0x10004, region : ".text".to_string(), perm : PERM_W }));
+    assert!(matches!(mem.check(0x11000, 2, PERM_X), Err(MemFault::Denied { .. })));
+    assert!(matches!(mem.check(stack, 2, PERM_X), Err(MemFault::Denied { .. })));
+    assert_eq!(mem.check(0x8, 8, PERM_R), Err(MemFault::NullDeref(0x8)));
+    assert_eq!(mem.check(0x20000, 8, PERM_R), Err(MemFault::Unmapped(0x20000)));
+
+    // An access may cover neighbouring regions, if both allow it
+    assert_eq!(mem.check(0x10FFC, 8, PERM_R), Ok(()));
+    assert!(mem.check(0x10FFC, 8, PERM_W).is_err());
+
+    // Without protection only the map matters
+    mem.set_protection(false);
+    assert_eq!(mem.check(0x10004, 4, PERM_W), Ok(()));
+    assert_eq!(mem.check(0x8, 8, PERM_R), Err(MemFault::NullDeref(0x8)));
+}
+
+#[test]
+fn test_segments_sharing_a_page() {
+    let mut data = build_test_elf(
+        0x10000, &[(0x10000, &[0x13, 0, 0, 0], 0x4), (0x10800, &[0xAA], 0x1000)], &[]);
+    data[64 + 4..64 + 8].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+    data[120 + 4..120 + 8].copy_from_slice(&(PF_R | PF_W).to_le_bytes());
+    let mem = ProgramMemory::from_elf(&ElfFile::parse(data).unwrap());
+
+    assert_eq!(read8(&mem, 0x10800).unwrap(), 0xAA);
+    assert_eq!(mem.check(0x10000, 4, PERM_RWX), Ok(()));
+    assert_eq!(mem.check(0x11000, 4, PERM_RWX), Ok(()));
+    assert_eq!(mem.heap_start(), 0x12000);
+}
+
+#[test]
+fn test_mprotect() {
+    let mut mem = ProgramMemory::from_elf(&test_elf_with_flags(&[PF_R | PF_X, PF_R | PF_W]));
+    let addr = mem.mmap(None, 2 * 4096, None, PERM_R | PERM_W).unwrap();
+
+    // A guard page at the start of the mapping
+    mem.mprotect(addr, 4096, 0).unwrap();
+    assert!(mem.check(addr, 1, PERM_R).is_err());
+    assert_eq!(mem.check(addr + 0x1000, 8, PERM_W), Ok(()));
+
+    // RELRO: data becomes read-only after relocation
+    mem.mprotect(0x11000, 4096, PERM_R).unwrap();
+    assert!(mem.check(0x11000, 1, PERM_W).is_err());
+
+    assert!(mem.mprotect(addr + 1, 4096, PERM_R).is_err());
+    assert!(mem.mprotect(0x20000, 4096, PERM_R).is_err());
+}
+
+#[test]
+fn test_access_faults() {
+    use crate::rv64emu::{ArchState, Exception};
+    use crate::rv64mmu::Access;
+
+    let mut mem = ProgramMemory::from_elf(&test_elf_with_flags(&[PF_R | PF_X, PF_R | PF_W]));
+    let mut arch = ArchState::new();
+
+    assert_eq!(arch.access(&mut mem, 0x10000, 4, Access::Fetch), Ok(0x10000));
+    assert_eq!(arch.access(&mut mem, 0x10000, 4, Access::Store),
+        Err(Exception::StoreAccessFault(0x10000)));
+    assert_eq!(arch.access(&mut mem, 0x11000, 4, Access::Fetch),
+        Err(Exception::InstructionAccessFault(0x11000)));
+    assert_eq!(arch.access(&mut mem, 0, 8, Access::Load), Err(Exception::LoadAccessFault(0)));
+
+    // The raw accessors report the fault rather than panicking
+    assert_eq!(mem.read(0), Err(MemFault::NullDeref(0)));
+    assert_eq!(mem.read_le(0x12000, 8), Err(MemFault::Unmapped(0x12000)));
+    assert_eq!(mem.write_le(0x11FFC, 0, 8), Err(MemFault::Unmapped(0x12000)));
+    assert_eq!(mem.load::<u32>(0x11FFC), Ok(0));
+}
+
+#[test]
+fn test_bulk_access() {
+    let elf = test_elf_with_flags(&[PF_R | PF_X, PF_R | PF_W]);
+    let mut flat = ProgramMemory::new_flat(false).unwrap();
+    flat.load_elf(&elf).unwrap();
+
+    for mem in [&mut ProgramMemory::from_elf(&elf), &mut flat] {
+        // Across the .text/.data boundary
+        mem.write_bytes(0x10FFE, b"abcd\0").unwrap();
+        assert_eq!(mem.load::<u32>(0x10FFE).unwrap(), u32::from_le_bytes(*b"abcd"));
+        assert_eq!(mem.read_cstr(0x10FFE, 64), Some(b"abcd".to_vec()));
+
+        let mut buf = [0; 6];
+        mem.read_bytes(0x10000, &mut buf).unwrap();
+        assert_eq!(buf, [0x13, 0, 0, 0, 0, 0]);
+
+        mem.zero(0x10FFF, 2).unwrap();
+        assert_eq!(mem.read_cstr(0x10FFE, 64), Some(b"a".to_vec()));
+        assert_eq!(mem.load::<u8>(0x11001).unwrap(), b'd');
+
+        // .data ends at 0x12000, with nothing past it
+        mem.write_bytes(0x11FFE, b"xy").unwrap();
+        assert_eq!(mem.read_cstr(0x11FFE, 64), None);
+        assert_eq!(mem.read_cstr(0x20000, 64), None);
     }
 }
+
+#[test]
+fn test_sparse_footprint() {
+    // A 1 GiB .bss costs nothing until it is written
+    let elf = ElfFile::parse(build_test_elf(
+        0x10000, &[(0x10000, &[0x13, 0, 0, 0], 0x4000_0000)], &[])).unwrap();
+    let mut mem = ProgramMemory::from_elf(&elf);
+    assert_eq!(mem.resident_bytes(), 4096);
+
+    let top = mem.stack_top();
+    mem.clear_dirty();
+    write64(&mut mem, 0x2000_0000, 1).unwrap();
+    write64(&mut mem, top - 8, 1).unwrap();
+    assert_eq!(mem.resident_bytes(), 3 * 4096);
+    assert_eq!(mem.dirty_pages(), vec![0x2000_0000, top - 4096]);
+
+    // Heap pages go when the heap shrinks, and come back zeroed
+    let heap = mem.heap_start();
+    mem.brk(heap + 0x3000).unwrap();
+    write64(&mut mem, heap + 0x2000, 0x55).unwrap();
+    assert_eq!(mem.resident_bytes(), 4 * 4096);
+    mem.brk(heap + 0x1000).unwrap();
+    assert_eq!(mem.resident_bytes(), 3 * 4096);
+    mem.brk(heap + 0x3000).unwrap();
+    assert_eq!(read64(&mem, heap + 0x2000).unwrap(), 0);
+
+    // As do munmap()ed pages
+    let addr = mem.mmap(None, 8192, Some(&[1; 8192]), PERM_R).unwrap();
+    assert_eq!(mem.resident_bytes(), 5 * 4096);
+    mem.munmap(addr, 8192).unwrap();
+    assert_eq!(mem.resident_bytes(), 3 * 4096);
+}
+
+#[test]
+fn test_flat_memory() {
+    let elf = test_elf_with_flags(&[PF_R | PF_X, PF_R | PF_W]);
+    let mut mem = ProgramMemory::new_flat(false).unwrap();
+    mem.load_elf(&elf).unwrap();
+
+    assert_eq!(read32(&mem, 0x10000).unwrap(), 0x13);
+    assert!(mem.check(0x10000, 4, PERM_W).is_err());
+
+    // Word accesses, including ones that straddle pages and regions
+    write64(&mut mem, 0x10FFC, 0x0102_0304_0506_0708).unwrap();
+    assert_eq!(read64(&mem, 0x10FFC).unwrap(), 0x0102_0304_0506_0708);
+    assert_eq!(read32(&mem, 0x10FFE).unwrap(), 0x0304_0506);
+
+    let top = mem.stack_top();
+    assert_eq!(top, FLAT_WINDOW_64);
+    write64(&mut mem, top - 8, u64::MAX).unwrap();
+    assert_eq!(read64(&mem, top - 8).unwrap(), u64::MAX);
+
+    // Everything must fit in the window
+    assert!(mem.mmap(Some(FLAT_WINDOW_64 + 0x1000), 1, None, PERM_R).is_err());
+    assert!(mem.load_blob(FLAT_WINDOW_64 + 0x1000, &[1]).is_err());
+
+    let addr = mem.mmap(None, 4096, Some(&[1; 4096]), PERM_R).unwrap();
+    assert_eq!(addr, LAYOUT_FLAT_64.mmap_base);
+    assert_eq!(mem.resident_bytes(), 4 * 4096);
+    mem.munmap(addr, 4096).unwrap();
+    assert_eq!(mem.resident_bytes(), 3 * 4096);
+
+    let mut far = build_test_elf(0x10000, &[(0x20_0000_0000, &[0x13, 0, 0, 0], 0x4)], &[]);
+    far[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    let mut mem = ProgramMemory::new_flat(false).unwrap();
+    assert!(mem.load_elf(&ElfFile::parse(far).unwrap()).is_err());
+}
+
+/// Guest memory without the word accessors, so that every load and store
+/// is done a byte at a time, for comparison.
+#[cfg(test)]
+struct ByteWise(ProgramMemory);
+
+#[cfg(test)]
+impl MemIf for ByteWise {
+    fn read(&self, addr : u64) -> Result<u8, MemFault> { self.0.read(addr) }
+    fn write(&mut self, addr : u64, value : u8) -> Result<(), MemFault> { self.0.write(addr, value) }
+    fn check(&self, addr : u64, size : u64, perm : u8) -> Result<(), MemFault> {
+        self.0.check(addr, size, perm)
+    }
+    fn heap_start(&self) -> u64 { self.0.heap_start() }
+    fn brk(&mut self, end : u64) -> Result<u64, ()> { self.0.brk(end) }
+    fn mmap(&mut self, addr : Option<u64>, len : u64, data : Option<&[u8]>, perms : u8)
+        -> Result<u64, ()> {
+        self.0.mmap(addr, len, data, perms)
+    }
+    fn munmap(&mut self, addr : u64, len : u64) -> Result<(), ()> { self.0.munmap(addr, len) }
+    fn mprotect(&mut self, addr : u64, len : u64, perms : u8) -> Result<(), ()> {
+        self.0.mprotect(addr, len, perms)
+    }
+}
+
+/// Runs a loop of loads and stores and returns millions of instructions
+/// per second.
+#[cfg(test)]
+fn bench_mips(mem : &mut dyn MemIf, steps : u64) -> f64 {
+    use crate::rv64emu::{ArchState, ExecResult};
+
+    let mut arch = ArchState::new();
+    arch.pc = 0x10000;
+
+    let start = std::time::Instant::now();
+
+    for _ in 0..steps {
+        let (raw, inst) = arch.fetch_decode(mem).unwrap();
+        assert_eq!(arch.exec_inst(mem, &raw, &inst), ExecResult::Continue);
+    }
+
+    steps as f64 / start.elapsed().as_secs_f64() / 1e6
+}
+
+/// cargo test --release bench_memory -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_memory() {
+    // lui a0, 0x20; 1: ld t0, 0(a0); addi t0, t0, 1; sd t0, 8(a0);
+    // lw t1, 16(a0); sw t1, 24(a0); j 1b
+    let code : Vec<u8> = [0x00020537u32, 0x00053283, 0x00128293, 0x00553423,
+                          0x01052303, 0x00652c23, 0xfedff06f]
+        .iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let elf = ElfFile::parse(build_test_elf(
+        0x10000, &[(0x10000, &code, code.len() as u64), (0x20000, &[], 0x1000)], &[])).unwrap();
+
+    let steps = 20_000_000;
+    let mut flat = ProgramMemory::new_flat(false).unwrap();
+    flat.load_elf(&elf).unwrap();
+
+    let bytewise = bench_mips(&mut ByteWise(ProgramMemory::from_elf(&elf)), steps);
+    let paged = bench_mips(&mut ProgramMemory::from_elf(&elf), steps);
+    let flat = bench_mips(&mut flat, steps);
+
+    println!("byte-wise: {:.1} MIPS", bytewise);
+    println!("paged:     {:.1} MIPS ({:.2}x)", paged, paged / bytewise);
+    println!("flat:      {:.1} MIPS ({:.2}x)", flat, flat / bytewise);
+}
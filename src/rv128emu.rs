@@ -0,0 +1,368 @@
+use crate::memif::*;
+use crate::rv64defs::*;
+use crate::rv64emu::*;
+use crate::rv64mmu::*;
+use crate::rvalu;
+
+//
+// RV128I. Registers are 128 bits wide but the address space stays 64-bit:
+// addresses and pc use the low half of a register. Instructions whose
+// result depends on the upper bits run here on rvalu::<u128>; everything
+// else, including the *W forms and CSR accesses, works on the low 64 bits
+// in the common path and is sign-extended on writeback.
+//
+
+#[inline(always)]
+fn sext128(val : u64) -> u128 {
+    val as i64 as i128 as u128
+}
+
+impl ArchState {
+    #[inline(always)]
+    pub fn regr128(&self, rnum : usize) -> u128 {
+        match rnum {
+            0 => 0,
+            1..=31 => self.regs[rnum],
+            _ => panic!("Invalid register!")
+        }
+    }
+
+    #[inline(always)]
+    pub fn regw128(&mut self, rnum : usize, val : u128) {
+        if self.debug {
+            println!("        x{} <= {:032x}", rnum, val);
+        }
+
+        match rnum {
+            0 => (),
+            1..=31 => self.regs[rnum] = val,
+            _ => panic!("Invalid register!")
+        }
+    }
+
+    /// Quadword load as two doublewords, low half first.
+    pub fn load128(&mut self, mem : &mut dyn MemIf, addr : u64) -> Result<u128, Exception> {
+        let low = self.load(mem, addr, 8)?;
+        let high = self.load(mem, addr.wrapping_add(8), 8)?;
+
+        Ok((high as u128) << 64 | low as u128)
+    }
+
+    /// Quadword store. Both halves are checked first so that a faulting
+    /// store writes nothing.
+    pub fn store128(&mut self, mem : &mut dyn MemIf, addr : u64, val : u128) -> Result<(), Exception> {
+        if crosses_page(addr, 16) {
+            for i in 0..16 {
+                self.access(mem, addr.wrapping_add(i), 1, Access::Store)?;
+            }
+        }
+        else {
+            self.access(mem, addr, 16, Access::Store)?;
+        }
+
+        self.store(mem, addr, val as u64, 8)?;
+        self.store(mem, addr.wrapping_add(8), (val >> 64) as u64, 8)
+    }
+
+    /// Executes the instructions that behave differently at RV128. Returns
+    /// None for the ones the common path handles.
+    pub fn exec_rv128(&mut self, mem : &mut dyn MemIf, inst : &DecodedInst) -> Option<ExecResult> {
+        use DecodedInst::*;
+        use ExecResult::Continue;
+
+        macro_rules! try_exec {
+            ($e:expr) => {
+                match $e {
+                    Ok(val) => val,
+                    Err(e) => return Some(ExecResult::Exception(e))
+                }
+            }
+        }
+
+        macro_rules! op_inst {
+            ($rs1:expr, $op2:expr, $rd:expr, $func:ident, $len:expr) => {
+                {
+                    let res = rvalu::$func::<u128>(self.regr128($rs1), $op2);
+                    self.regw128($rd, res);
+                    self.pc = self.pc.wrapping_add($len);
+                    Continue
+                }
+            }
+        }
+
+        // The doubleword forms work on the low 64 bits and sign-extend
+        macro_rules! opd_inst {
+            ($rs1:expr, $op2:expr, $rd:expr, $func:ident) => {
+                {
+                    let res = rvalu::$func::<u64>(self.regr($rs1), $op2);
+                    self.regw128($rd, sext128(res));
+                    self.pc = self.pc.wrapping_add(4);
+                    Continue
+                }
+            }
+        }
+
+        let res = match inst {
+            Add {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, add, 4),
+            Sub {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, sub, 4),
+            Sll {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, sll, 4),
+            Slt {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, slt, 4),
+            Sltu {rs1, rs2, rd} =>   op_inst!(*rs1, self.regr128(*rs2), *rd, sltu, 4),
+            Xor {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, xor, 4),
+            Srl {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, srl, 4),
+            Sra {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, sra, 4),
+            Or {rs1, rs2, rd} =>     op_inst!(*rs1, self.regr128(*rs2), *rd, or, 4),
+            And {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, and, 4),
+            Mul {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, mul, 4),
+            Mulh {rs1, rs2, rd} =>   op_inst!(*rs1, self.regr128(*rs2), *rd, mulh, 4),
+            Mulhsu {rs1, rs2, rd} => op_inst!(*rs1, self.regr128(*rs2), *rd, mulhsu, 4),
+            Mulhu {rs1, rs2, rd} =>  op_inst!(*rs1, self.regr128(*rs2), *rd, mulhu, 4),
+            Div {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, div, 4),
+            Divu {rs1, rs2, rd} =>   op_inst!(*rs1, self.regr128(*rs2), *rd, divu, 4),
+            Rem {rs1, rs2, rd} =>    op_inst!(*rs1, self.regr128(*rs2), *rd, rem, 4),
+            Remu {rs1, rs2, rd} =>   op_inst!(*rs1, self.regr128(*rs2), *rd, remu, 4),
+
+            Addi {rs1, imm, rd} =>   op_inst!(*rs1, sext128(*imm), *rd, add, 4),
+            Slti {rs1, imm, rd} =>   op_inst!(*rs1, sext128(*imm), *rd, slt, 4),
+            Sltiu {rs1, imm, rd} =>  op_inst!(*rs1, sext128(*imm), *rd, sltu, 4),
+            Xori {rs1, imm, rd} =>   op_inst!(*rs1, sext128(*imm), *rd, xor, 4),
+            Ori {rs1, imm, rd} =>    op_inst!(*rs1, sext128(*imm), *rd, or, 4),
+            Andi {rs1, imm, rd} =>   op_inst!(*rs1, sext128(*imm), *rd, and, 4),
+            Slli {rs1, shamt, rd} => op_inst!(*rs1, *shamt as u128, *rd, sll, 4),
+            Srli {rs1, shamt, rd} => op_inst!(*rs1, *shamt as u128, *rd, srl, 4),
+            Srai {rs1, shamt, rd} => op_inst!(*rs1, *shamt as u128, *rd, sra, 4),
+
+            //
+            // Op64 and OpImm64
+            //
+
+            Addd {rs1, rs2, rd} =>  opd_inst!(*rs1, self.regr(*rs2), *rd, add),
+            Subd {rs1, rs2, rd} =>  opd_inst!(*rs1, self.regr(*rs2), *rd, sub),
+            Slld {rs1, rs2, rd} =>  opd_inst!(*rs1, self.regr(*rs2), *rd, sll),
+            Srld {rs1, rs2, rd} =>  opd_inst!(*rs1, self.regr(*rs2), *rd, srl),
+            Srad {rs1, rs2, rd} =>  opd_inst!(*rs1, self.regr(*rs2), *rd, sra),
+            Muld {rs1, rs2, rd} =>  opd_inst!(*rs1, self.regr(*rs2), *rd, mul),
+            Divd {rs1, rs2, rd} =>  opd_inst!(*rs1, self.regr(*rs2), *rd, div),
+            Divud {rs1, rs2, rd} => opd_inst!(*rs1, self.regr(*rs2), *rd, divu),
+            Remd {rs1, rs2, rd} =>  opd_inst!(*rs1, self.regr(*rs2), *rd, rem),
+            Remud {rs1, rs2, rd} => opd_inst!(*rs1, self.regr(*rs2), *rd, remu),
+            Addid {rs1, imm, rd} =>   opd_inst!(*rs1, *imm, *rd, add),
+            Sllid {rs1, shamt, rd} => opd_inst!(*rs1, *shamt, *rd, sll),
+            Srlid {rs1, shamt, rd} => opd_inst!(*rs1, *shamt, *rd, srl),
+            Sraid {rs1, shamt, rd} => opd_inst!(*rs1, *shamt, *rd, sra),
+
+            Branch {func, rs1, rs2, imm} => {
+                use BranchType::*;
+                let (op1, op2) = (self.regr128(*rs1), self.regr128(*rs2));
+                let pred = match func {
+                    Eq => op1 == op2,
+                    Neq => op1 != op2,
+                    Lt => rvalu::slt(op1, op2) == 1,
+                    Ge => rvalu::slt(op1, op2) == 0,
+                    Ltu => op1 < op2,
+                    Geu => op1 >= op2
+                };
+
+                if pred {
+                    try_exec!(self.jump(self.pc.wrapping_add(*imm)));
+                }
+                else {
+                    self.pc = self.pc.wrapping_add(4);
+                }
+
+                Continue
+            },
+
+            Load {width : LoadStoreWidth::DoubleU, rs1, rd, imm} => {
+                let addr = self.regr(*rs1).wrapping_add(*imm);
+                let val = try_exec!(self.load(mem, addr, 8));
+                self.regw128(*rd, val as u128);
+                self.pc = self.pc.wrapping_add(4);
+                Continue
+            },
+
+            Load {width : LoadStoreWidth::Quad, rs1, rd, imm} => {
+                let addr = self.regr(*rs1).wrapping_add(*imm);
+                let val = try_exec!(self.load128(mem, addr));
+                self.regw128(*rd, val);
+                self.pc = self.pc.wrapping_add(4);
+                Continue
+            },
+
+            Store {width : LoadStoreWidth::Quad, rs1, rs2, imm} => {
+                let addr = self.regr(*rs1).wrapping_add(*imm);
+                try_exec!(self.store128(mem, addr, self.regr128(*rs2)));
+                self.pc = self.pc.wrapping_add(4);
+                Continue
+            },
+
+            //
+            // Compressed Instructions
+            //
+
+            CAddi4spn {rd, imm} => op_inst!(2, *imm as u128, *rd, add, 2),
+            CAddi {rsrd, imm} =>   op_inst!(*rsrd, sext128(*imm), *rsrd, add, 2),
+            CAndi {rsrd, imm} =>   op_inst!(*rsrd, sext128(*imm), *rsrd, and, 2),
+            CAddi16sp {imm} =>     op_inst!(2, sext128(*imm), 2, add, 2),
+            CAdd {rsrd, rs2} =>    op_inst!(*rsrd, self.regr128(*rs2), *rsrd, add, 2),
+            CSub {rsrd, rs2} =>    op_inst!(*rsrd, self.regr128(*rs2), *rsrd, sub, 2),
+            COr {rsrd, rs2} =>     op_inst!(*rsrd, self.regr128(*rs2), *rsrd, or, 2),
+            CAnd {rsrd, rs2} =>    op_inst!(*rsrd, self.regr128(*rs2), *rsrd, and, 2),
+            CXor {rsrd, rs2} =>    op_inst!(*rsrd, self.regr128(*rs2), *rsrd, xor, 2),
+            CMv {rsrd, rs2} =>     op_inst!(0, self.regr128(*rs2), *rsrd, add, 2),
+            CSlli {rsrd, shamt} => op_inst!(*rsrd, *shamt as u128, *rsrd, sll, 2),
+            CSrli {rsrd, shamt} => op_inst!(*rsrd, *shamt as u128, *rsrd, srl, 2),
+            CSrai {rsrd, shamt} => op_inst!(*rsrd, *shamt as u128, *rsrd, sra, 2),
+
+            CBeqz {rs1, imm} | CBnez {rs1, imm} => {
+                let zero = self.regr128(*rs1) == 0;
+
+                if zero == matches!(inst, CBeqz {..}) {
+                    self.pc = self.pc.wrapping_add(*imm);
+                }
+                else {
+                    self.pc = self.pc.wrapping_add(2);
+                }
+
+                Continue
+            },
+
+            CLoad {width : CLoadStoreWidth::Cq, rs1, rd, imm} => {
+                let addr = self.regr(*rs1).wrapping_add(*imm);
+                let val = try_exec!(self.load128(mem, addr));
+                self.regw128(*rd, val);
+                self.pc = self.pc.wrapping_add(2);
+                Continue
+            },
+
+            CStore {width : CLoadStoreWidth::Cq, rs1, rs2, imm} => {
+                let addr = self.regr(*rs1).wrapping_add(*imm);
+                try_exec!(self.store128(mem, addr, self.regr128(*rs2)));
+                self.pc = self.pc.wrapping_add(2);
+                Continue
+            },
+
+            CLoadStack {width : CLoadStoreWidth::Cq, rd, imm} => {
+                let addr = self.regr(2).wrapping_add(*imm);
+                let val = try_exec!(self.load128(mem, addr));
+                self.regw128(*rd, val);
+                self.pc = self.pc.wrapping_add(2);
+                Continue
+            },
+
+            CStoreStack {width : CLoadStoreWidth::Cq, rs2, imm} => {
+                let addr = self.regr(2).wrapping_add(*imm);
+                try_exec!(self.store128(mem, addr, self.regr128(*rs2)));
+                self.pc = self.pc.wrapping_add(2);
+                Continue
+            },
+
+            _ => return None
+        };
+
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+fn rv128_arch() -> ArchState {
+    let mut arch = ArchState::new();
+    arch.isa = crate::isa::Isa::parse("rv128imc_zicsr_zifencei").unwrap();
+    arch
+}
+
+#[cfg(test)]
+fn exec128(arch : &mut ArchState, mem : &mut dyn MemIf, raw : u32) -> ExecResult {
+    let rinst = RawInst { raw };
+    let inst = crate::rv64inst::decode_for(&rinst, &arch.isa).unwrap();
+    arch.exec_inst(mem, &rinst, &inst)
+}
+
+#[test]
+fn test_rv128_alu() {
+    let mut arch = rv128_arch();
+    let mut mem = TestMem::new(0x100);
+
+    // addi x5, x0, -1; srli x5, x5, 64
+    exec128(&mut arch, &mut mem, 0xfff00293);
+    assert_eq!(arch.regr128(5), u128::MAX);
+    exec128(&mut arch, &mut mem, 0x0402d293);
+    assert_eq!(arch.regr128(5), u64::MAX as u128);
+
+    // addi x5, x5, 1 carries into the upper half
+    exec128(&mut arch, &mut mem, 0x00128293);
+    assert_eq!(arch.regr128(5), 1 << 64);
+
+    // slli x6, x5, 63: a 7-bit shift amount
+    exec128(&mut arch, &mut mem, 0x03f29313);
+    assert_eq!(arch.regr128(6), 1 << 127);
+
+    // bltu x5, x6 compares all 128 bits
+    arch.pc = 0x40;
+    exec128(&mut arch, &mut mem, 0x0062e463);
+    assert_eq!(arch.pc, 0x48);
+
+    // addd x7, x5, x5 ignores the upper half and sign-extends
+    arch.regw128(5, 1 << 64 | 0x8000_0000_0000_0000);
+    exec128(&mut arch, &mut mem, 0x005283fb);
+    assert_eq!(arch.regr128(7), 0);
+
+    // addid x7, x5, 0 and slld x7, x5, x0
+    exec128(&mut arch, &mut mem, 0x000283db);
+    assert_eq!(arch.regr128(7), !0u128 << 63);
+    exec128(&mut arch, &mut mem, 0x000293fb);
+    assert_eq!(arch.regr128(7), !0u128 << 63);
+
+    // sraid x7, x5, 33 and srlid x7, x5, 63 take 6-bit shift amounts
+    exec128(&mut arch, &mut mem, 0x4212d3db);
+    assert_eq!(arch.regr128(7), !0u128 << 30);
+    exec128(&mut arch, &mut mem, 0x03f2d3db);
+    assert_eq!(arch.regr128(7), 1);
+
+    // sllid x7, x5, 40 drops what leaves the low 64 bits
+    arch.regw128(5, 1 << 100 | 0x1_0000_0001);
+    exec128(&mut arch, &mut mem, 0x028293db);
+    assert_eq!(arch.regr128(7), 1 << 40);
+
+    // addw still works on the low 32 bits
+    arch.regw128(5, 0x7FFF_FFFF);
+    exec128(&mut arch, &mut mem, 0x005283bb);
+    assert_eq!(arch.regr128(7), (-2i128) as u128);
+}
+
+#[test]
+fn test_rv128_quad() {
+    let mut arch = rv128_arch();
+    let mut mem = TestMem::new(0x100);
+    let val = 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210;
+
+    // sq x5, 16(x0); lq x6, 16(x0)
+    arch.regw128(5, val);
+    exec128(&mut arch, &mut mem, 0x00504823);
+    assert_eq!(read64(&mem, 0x18).unwrap(), (val >> 64) as u64);
+    exec128(&mut arch, &mut mem, 0x0100230f);
+    assert_eq!(arch.regr128(6), val);
+
+    // c.lqsp x7, 16(sp); c.sqsp x7, 32(sp)
+    arch.regw128(2, 0);
+    exec128(&mut arch, &mut mem, 0x23c2);
+    assert_eq!(arch.regr128(7), val);
+    exec128(&mut arch, &mut mem, 0xb01e);
+    assert_eq!(read64(&mem, 0x20).unwrap(), val as u64);
+
+    // c.lq x8, 272(x9) uses offset bit 8
+    arch.regw128(9, (-0xF0i128) as u128);
+    exec128(&mut arch, &mut mem, 0x2c80);
+    assert_eq!(arch.regr128(8), val);
+
+    // ldu x8, 24(x9) zero-extends
+    arch.regw128(9, 0);
+    exec128(&mut arch, &mut mem, 0x0184f403);
+    assert_eq!(arch.regr128(8), (val >> 64) as u64 as u128);
+
+    // sq x5, 248(x0) runs off the end and writes nothing
+    arch.regw128(5, u128::MAX);
+    let res = exec128(&mut arch, &mut mem, 0x0e504c23);
+    assert_eq!(res, ExecResult::Exception(Exception::StoreAccessFault(0xf8)));
+    assert_eq!(read64(&mem, 0xf8).unwrap(), 0);
+}
@@ -1,6 +1,4 @@
 
-use std::mem;
-use crate::bitops::*;
 
 #[inline(always)]
 pub fn add(op1 : u64, op2 : u64) -> u64 {
@@ -26,7 +24,7 @@ fn test_sub() {
 
 #[inline(always)]
 pub fn addw(op1 : u64, op2 : u64) -> u64 {
-    sign_ext64!(32, ((op1 as u32).overflowing_add(op2 as u32).0 & 0xFFFFFFFF) as u64)
+    sign_ext64!(32, (op1 as u32).overflowing_add(op2 as u32).0 as u64)
 }
 
 #[test]
@@ -37,13 +35,13 @@ fn test_addw() {
 
 #[inline(always)]
 pub fn subw(op1 : u64, op2 : u64) -> u64 {
-    sign_ext64!(32, ((op1 as u32).overflowing_sub(op2 as u32).0 & 0xFFFFFFFF) as u64)
+    sign_ext64!(32, (op1 as u32).overflowing_sub(op2 as u32).0 as u64)
 }
 
 #[test]
 fn test_subw() {
     assert_eq!(subw(1, 0x00000000FFFFFFFF), 2);
-    assert_eq!(subw(0x00000000FFFFFFFF, 1), 0x00000000FFFFFFFE);
+    assert_eq!(subw(0x00000000FFFFFFFF, 1), 0xFFFFFFFFFFFFFFFE);
 }
 
 #[inline(always)]
@@ -95,23 +93,20 @@ pub fn xor(op1 : u64, op2 : u64) -> u64 {
     op1 ^ op2
 }
 
-#[inline(always)]
-pub fn not(op1 : u64) -> u64 {
-    !op1
-}
-
 #[inline(always)]
 pub fn sll(v : u64, shamt : u64) -> u64 {
-    v << shamt
+    v << (shamt & 0x3F)
 }
 
 #[inline(always)]
 pub fn srl(v : u64, shamt : u64) -> u64 {
-    v >> shamt
+    v >> (shamt & 0x3F)
 }
 
 #[inline(always)]
 pub fn sra(v : u64, shamt : u64) -> u64 {
+    let shamt = shamt & 0x3F;
+
     if shamt == 0 {
         v >> shamt
     }
@@ -128,71 +123,545 @@ pub fn sllw(v : u64, shamt : u64) -> u64 {
 
 #[inline(always)]
 pub fn srlw(v : u64, shamt : u64) -> u64 {
-    sign_ext64!(32, srl(v, shamt  & 0x1F) & 0xFFFFFFFF)
+    sign_ext64!(32, srl(v & 0xFFFFFFFF, shamt & 0x1F))
 }
 
 #[inline(always)]
 pub fn sraw(v : u64, shamt : u64) -> u64 {
-    sign_ext64!(32, sra(v, shamt) & 0xFFFFFFFF)
+    sign_ext64!(32, sra(sign_ext64!(32, v & 0xFFFFFFFF), shamt & 0x1F) & 0xFFFFFFFF)
+}
+
+#[test]
+fn test_shifts() {
+    assert_eq!(sll(1, 65), 2);
+    assert_eq!(srl(0x8000_0000_0000_0000, 127), 1);
+    assert_eq!(sra(0x8000_0000_0000_0000, 67), 0xF000_0000_0000_0000);
+    assert_eq!(sllw(1, 33), 2);
+    assert_eq!(srlw(0xFFFF_FFFF_8000_0000, 31), 1);
+    assert_eq!(sraw(0x8000_0000, 33), 0xFFFF_FFFF_C000_0000);
+}
+
+//
+// M Extension
+//
+// Division never traps: dividing by zero gives all ones (quotient) or the
+// dividend (remainder), and the signed overflow case MIN / -1 gives MIN
+// with a remainder of zero. Word variants operate on the low 32 bits and
+// sign-extend their result.
+//
+
+#[inline(always)]
+pub fn mul(op1 : u64, op2 : u64) -> u64 {
+    op1.wrapping_mul(op2)
+}
+
+#[inline(always)]
+pub fn mulh(op1 : u64, op2 : u64) -> u64 {
+    (((op1 as i64 as i128) * (op2 as i64 as i128)) >> 64) as u64
+}
+
+#[inline(always)]
+pub fn mulhsu(op1 : u64, op2 : u64) -> u64 {
+    (((op1 as i64 as i128) * (op2 as i128)) >> 64) as u64
+}
+
+#[inline(always)]
+pub fn mulhu(op1 : u64, op2 : u64) -> u64 {
+    (((op1 as u128) * (op2 as u128)) >> 64) as u64
+}
+
+#[inline(always)]
+pub fn mulw(op1 : u64, op2 : u64) -> u64 {
+    sign_ext64!(32, (op1 as u32).wrapping_mul(op2 as u32) as u64)
 }
 
 #[inline(always)]
 pub fn div(n : u64, d : u64) -> u64 {
-    unsafe {
-        let sn : i64 = mem::transmute(n);
-        let sd : i64 = mem::transmute(d);
-        let res : i64 = sn / sd;
-        mem::transmute(res)
+    if d == 0 {
+        u64::MAX
+    }
+    else {
+        (n as i64).wrapping_div(d as i64) as u64
     }
 }
 
 #[inline(always)]
 pub fn divu(n : u64, d : u64) -> u64 {
-    n / d
+    n.checked_div(d).unwrap_or(u64::MAX)
 }
 
 #[inline(always)]
 pub fn rem(n : u64, d : u64) -> u64 {
-    unsafe {
-        let sn : i64 = mem::transmute(n);
-        let sd : i64 = mem::transmute(d);
-        let res : i64 = sn % sd;
-        mem::transmute(res)
+    if d == 0 {
+        n
+    }
+    else {
+        (n as i64).wrapping_rem(d as i64) as u64
     }
 }
 
 #[inline(always)]
 pub fn remu(n : u64, d : u64) -> u64 {
-    n % d
+    n.checked_rem(d).unwrap_or(n)
+}
+
+#[inline(always)]
+pub fn divw(n : u64, d : u64) -> u64 {
+    if d as u32 == 0 {
+        u64::MAX
+    }
+    else {
+        sign_ext64!(32, (n as i32).wrapping_div(d as i32) as u32 as u64)
+    }
+}
+
+#[inline(always)]
+pub fn divuw(n : u64, d : u64) -> u64 {
+    let q = (n as u32).checked_div(d as u32).unwrap_or(u32::MAX);
+    sign_ext64!(32, q as u64)
 }
 
 #[inline(always)]
 pub fn remw(n : u64, d : u64) -> u64 {
-    unsafe {
-        let sn : i32 = mem::transmute((n & 0xFFFFFFFF) as u32);
-        let sd : i32 = mem::transmute((d & 0xFFFFFFFF) as u32);
-        let res_i32 : i32 = sn % sd;
-        let res_u32 : u32 = mem::transmute(res_i32);
-        res_u32 as u64
+    let r = if d as u32 == 0 {
+        n as u32
     }
+    else {
+        (n as i32).wrapping_rem(d as i32) as u32
+    };
+
+    sign_ext64!(32, r as u64)
 }
 
 #[inline(always)]
 pub fn remuw(n : u64, d : u64) -> u64 {
-    (n & 0xFFFFFFFF) % (d & 0xFFFFFFFF)
+    let r = (n as u32).checked_rem(d as u32).unwrap_or(n as u32);
+    sign_ext64!(32, r as u64)
 }
 
 #[test]
 fn test_rem() {
-    let x1 = (1 as u64) % (3 as u64);
-    let x2 = (3 as u64) % (3 as u64);
-    let x3 = (4 as u64) % (3 as u64);
-    let x4 = rem(u64::MAX, 3 as u64);
+    assert_eq!(remu(1, 3), 1);
+    assert_eq!(remu(3, 3), 0);
+    assert_eq!(remu(4, 3), 1);
+    assert_eq!(rem(u64::MAX, 3), u64::MAX);
+}
+
+//
+// Zba
+//
+
+#[inline(always)]
+pub fn sh1add(op1 : u64, op2 : u64) -> u64 {
+    add(op1 << 1, op2)
+}
+
+#[inline(always)]
+pub fn sh2add(op1 : u64, op2 : u64) -> u64 {
+    add(op1 << 2, op2)
+}
+
+#[inline(always)]
+pub fn sh3add(op1 : u64, op2 : u64) -> u64 {
+    add(op1 << 3, op2)
+}
+
+#[inline(always)]
+pub fn add_uw(op1 : u64, op2 : u64) -> u64 {
+    add(op1 & 0xFFFFFFFF, op2)
+}
+
+#[inline(always)]
+pub fn sh1add_uw(op1 : u64, op2 : u64) -> u64 {
+    add((op1 & 0xFFFFFFFF) << 1, op2)
+}
+
+#[inline(always)]
+pub fn sh2add_uw(op1 : u64, op2 : u64) -> u64 {
+    add((op1 & 0xFFFFFFFF) << 2, op2)
+}
+
+#[inline(always)]
+pub fn sh3add_uw(op1 : u64, op2 : u64) -> u64 {
+    add((op1 & 0xFFFFFFFF) << 3, op2)
+}
+
+#[inline(always)]
+pub fn slli_uw(v : u64, shamt : u64) -> u64 {
+    (v & 0xFFFFFFFF) << shamt
+}
+
+#[test]
+fn test_zba() {
+    assert_eq!(sh1add(3, 1), 7);
+    assert_eq!(sh3add(0x2000_0000_0000_0001, 0), 8);
+    assert_eq!(add_uw(0xFFFF_FFFF_FFFF_FFFF, 1), 0x1_0000_0000);
+    assert_eq!(sh2add_uw(0xFFFF_FFFF_8000_0000, 4), 0x2_0000_0004);
+    assert_eq!(slli_uw(0xFFFF_FFFF_FFFF_FFFF, 4), 0xF_FFFF_FFF0);
+}
+
+//
+// Zbb
+//
+
+#[inline(always)]
+pub fn andn(op1 : u64, op2 : u64) -> u64 {
+    op1 & !op2
+}
+
+#[inline(always)]
+pub fn orn(op1 : u64, op2 : u64) -> u64 {
+    op1 | !op2
+}
+
+#[inline(always)]
+pub fn xnor(op1 : u64, op2 : u64) -> u64 {
+    !(op1 ^ op2)
+}
+
+#[inline(always)]
+pub fn clz(v : u64) -> u64 {
+    v.leading_zeros() as u64
+}
+
+#[inline(always)]
+pub fn ctz(v : u64) -> u64 {
+    v.trailing_zeros() as u64
+}
+
+#[inline(always)]
+pub fn cpop(v : u64) -> u64 {
+    v.count_ones() as u64
+}
+
+#[inline(always)]
+pub fn clzw(v : u64) -> u64 {
+    (v as u32).leading_zeros() as u64
+}
+
+#[inline(always)]
+pub fn ctzw(v : u64) -> u64 {
+    (v as u32).trailing_zeros() as u64
+}
 
+#[inline(always)]
+pub fn cpopw(v : u64) -> u64 {
+    (v as u32).count_ones() as u64
+}
 
-    println!("{}", x1);
-    println!("{}", x2);
-    println!("{}", x3);
-    println!("{:016x}", x4);
+#[inline(always)]
+pub fn max(op1 : u64, op2 : u64) -> u64 {
+    (op1 as i64).max(op2 as i64) as u64
+}
 
+#[inline(always)]
+pub fn maxu(op1 : u64, op2 : u64) -> u64 {
+    op1.max(op2)
+}
+
+#[inline(always)]
+pub fn min(op1 : u64, op2 : u64) -> u64 {
+    (op1 as i64).min(op2 as i64) as u64
+}
+
+#[inline(always)]
+pub fn minu(op1 : u64, op2 : u64) -> u64 {
+    op1.min(op2)
+}
+
+#[inline(always)]
+pub fn sext_b(v : u64) -> u64 {
+    sign_ext64!(8, v & 0xFF)
+}
+
+#[inline(always)]
+pub fn sext_h(v : u64) -> u64 {
+    sign_ext64!(16, v & 0xFFFF)
+}
+
+#[inline(always)]
+pub fn zext_h(v : u64) -> u64 {
+    v & 0xFFFF
+}
+
+#[inline(always)]
+pub fn rol(v : u64, shamt : u64) -> u64 {
+    v.rotate_left((shamt & 0x3F) as u32)
+}
+
+#[inline(always)]
+pub fn ror(v : u64, shamt : u64) -> u64 {
+    v.rotate_right((shamt & 0x3F) as u32)
+}
+
+#[inline(always)]
+pub fn rolw(v : u64, shamt : u64) -> u64 {
+    sign_ext64!(32, (v as u32).rotate_left((shamt & 0x1F) as u32) as u64)
+}
+
+#[inline(always)]
+pub fn rorw(v : u64, shamt : u64) -> u64 {
+    sign_ext64!(32, (v as u32).rotate_right((shamt & 0x1F) as u32) as u64)
+}
+
+#[inline(always)]
+pub fn orc_b(v : u64) -> u64 {
+    (0..8).fold(0, |acc, i| {
+        if (v >> (i * 8)) & 0xFF != 0 { acc | (0xFF << (i * 8)) } else { acc }
+    })
+}
+
+#[inline(always)]
+pub fn rev8(v : u64) -> u64 {
+    v.swap_bytes()
+}
+
+#[test]
+fn test_zbb() {
+    assert_eq!(andn(0b1100, 0b1010), 0b0100);
+    assert_eq!(orn(0, u64::MAX - 1), 1);
+    assert_eq!(xnor(u64::MAX, 0), 0);
+
+    assert_eq!(clz(0), 64);
+    assert_eq!(clz(1), 63);
+    assert_eq!(ctz(0), 64);
+    assert_eq!(ctz(0x8000_0000_0000_0000), 63);
+    assert_eq!(cpop(u64::MAX), 64);
+    assert_eq!(clzw(0xFFFF_FFFF_0000_0000), 32);
+    assert_eq!(clzw(0x0000_8000), 16);
+    assert_eq!(ctzw(0x1_0000_0000), 32);
+    assert_eq!(cpopw(u64::MAX), 32);
+
+    assert_eq!(max(u64::MAX, 1), 1);
+    assert_eq!(maxu(u64::MAX, 1), u64::MAX);
+    assert_eq!(min(u64::MAX, 1), u64::MAX);
+    assert_eq!(minu(u64::MAX, 1), 1);
+
+    assert_eq!(sext_b(0x1_80), 0xFFFF_FFFF_FFFF_FF80);
+    assert_eq!(sext_h(0x1_7FFF), 0x7FFF);
+    assert_eq!(zext_h(0xFFFF_FFFF_FFFF_8000), 0x8000);
+
+    assert_eq!(rol(0x8000_0000_0000_0001, 1), 3);
+    assert_eq!(ror(1, 65), 0x8000_0000_0000_0000);
+    assert_eq!(rolw(0x8000_0000, 1), 1);
+    assert_eq!(rorw(1, 1), 0xFFFF_FFFF_8000_0000);
+
+    assert_eq!(orc_b(0x0001_0000_8000_0100), 0x00FF_0000_FF00_FF00);
+    assert_eq!(rev8(0x0102_0304_0506_0708), 0x0807_0605_0403_0201);
+}
+
+//
+// Zbs
+//
+
+#[inline(always)]
+pub fn bclr(v : u64, idx : u64) -> u64 {
+    v & !(1 << (idx & 0x3F))
+}
+
+#[inline(always)]
+pub fn bext(v : u64, idx : u64) -> u64 {
+    (v >> (idx & 0x3F)) & 1
+}
+
+#[inline(always)]
+pub fn binv(v : u64, idx : u64) -> u64 {
+    v ^ (1 << (idx & 0x3F))
+}
+
+#[inline(always)]
+pub fn bset(v : u64, idx : u64) -> u64 {
+    v | (1 << (idx & 0x3F))
+}
+
+#[test]
+fn test_zbs() {
+    assert_eq!(bclr(u64::MAX, 63), 0x7FFF_FFFF_FFFF_FFFF);
+    assert_eq!(bclr(u64::MAX, 64), u64::MAX - 1);
+    assert_eq!(bext(0x8000_0000_0000_0000, 63), 1);
+    assert_eq!(bext(0x8000_0000_0000_0000, 62), 0);
+    assert_eq!(binv(0, 65), 2);
+    assert_eq!(bset(0, 32), 0x1_0000_0000);
+}
+
+#[cfg(test)]
+const M_EDGE_VALUES : [u64; 12] = [
+    0, 1, 2, 3, 0x7FFF_FFFF, 0x8000_0000, 0xFFFF_FFFF,
+    0x1_0000_0000, 0x7FFF_FFFF_FFFF_FFFF, 0x8000_0000_0000_0000,
+    0xFFFF_FFFF_FFFF_FFFE, 0xFFFF_FFFF_FFFF_FFFF
+];
+
+#[test]
+fn test_mul() {
+    assert_eq!(mul(3, 5), 15);
+    assert_eq!(mul(u64::MAX, u64::MAX), 1);
+    assert_eq!(mul(0x8000_0000_0000_0000, 2), 0);
+
+    assert_eq!(mulh(u64::MAX, u64::MAX), 0);
+    assert_eq!(mulh(u64::MAX, 1), u64::MAX);
+    assert_eq!(mulh(0x8000_0000_0000_0000, 0x8000_0000_0000_0000), 0x4000_0000_0000_0000);
+    assert_eq!(mulh(0x8000_0000_0000_0000, u64::MAX), 0);
+
+    assert_eq!(mulhu(u64::MAX, u64::MAX), 0xFFFF_FFFF_FFFF_FFFE);
+    assert_eq!(mulhu(u64::MAX, 2), 1);
+    assert_eq!(mulhu(0x8000_0000_0000_0000, 0x8000_0000_0000_0000), 0x4000_0000_0000_0000);
+
+    assert_eq!(mulhsu(u64::MAX, u64::MAX), u64::MAX);
+    assert_eq!(mulhsu(1, u64::MAX), 0);
+    assert_eq!(mulhsu(0x8000_0000_0000_0000, u64::MAX), 0x8000_0000_0000_0000);
+    assert_eq!(mulhsu(0x8000_0000_0000_0000, 0x8000_0000_0000_0000), 0xC000_0000_0000_0000);
+
+    assert_eq!(mulw(0x7FFF_FFFF, 2), 0xFFFF_FFFF_FFFF_FFFE);
+    assert_eq!(mulw(0x1_0000_0003, 0x2_0000_0005), 15);
+    assert_eq!(mulw(0x8000_0000, u64::MAX), 0xFFFF_FFFF_8000_0000);
+}
+
+#[test]
+fn test_div() {
+    let min = 0x8000_0000_0000_0000;
+
+    assert_eq!(div(7, 2), 3);
+    assert_eq!(div(-7_i64 as u64, 2), -3_i64 as u64);
+    assert_eq!(div(7, -2_i64 as u64), -3_i64 as u64);
+    assert_eq!(div(5, 0), u64::MAX);
+    assert_eq!(div(min, u64::MAX), min);
+
+    assert_eq!(divu(7, 2), 3);
+    assert_eq!(divu(u64::MAX, 2), 0x7FFF_FFFF_FFFF_FFFF);
+    assert_eq!(divu(5, 0), u64::MAX);
+
+    assert_eq!(rem(-7_i64 as u64, 2), u64::MAX);
+    assert_eq!(rem(7, -2_i64 as u64), 1);
+    assert_eq!(rem(5, 0), 5);
+    assert_eq!(rem(min, u64::MAX), 0);
+
+    assert_eq!(remu(u64::MAX, 10), 5);
+    assert_eq!(remu(5, 0), 5);
+}
+
+#[test]
+fn test_divw() {
+    let min = 0x8000_0000;
+
+    // Upper bits of the operands are ignored
+    assert_eq!(divw(0xFFFF_FFFF_0000_0007, 0x1234_0000_0000_0002), 3);
+    assert_eq!(divw(-7_i64 as u64, 2), -3_i64 as u64);
+    assert_eq!(divw(5, 0x1_0000_0000), u64::MAX);
+    assert_eq!(divw(min, u64::MAX), 0xFFFF_FFFF_8000_0000);
+
+    assert_eq!(divuw(0xFFFF_FFFF, 1), u64::MAX);
+    assert_eq!(divuw(0xFFFF_FFFF, 2), 0x7FFF_FFFF);
+    assert_eq!(divuw(5, 0), u64::MAX);
+
+    assert_eq!(remw(-7_i64 as u64, 2), u64::MAX);
+    assert_eq!(remw(0x1_8000_0000, 0), 0xFFFF_FFFF_8000_0000);
+    assert_eq!(remw(0x1_0000_0005, 0), 5);
+    assert_eq!(remw(min, u64::MAX), 0);
+
+    assert_eq!(remuw(0xFFFF_FFFF, 0x10), 0xF);
+    assert_eq!(remuw(0x8000_0001, 0x1_0000_0000), 0xFFFF_FFFF_8000_0001);
+    assert_eq!(remuw(0xFFFF_FFFF, 0xFFFF_FFFF_0000_0000), u64::MAX);
+}
+
+/// Cross-checks every pair of edge values against i128/u128 reference
+/// arithmetic, including the identity n = q * d + r wherever d != 0.
+#[test]
+fn test_m_edge_cases() {
+    for &a in M_EDGE_VALUES.iter() {
+        for &b in M_EDGE_VALUES.iter() {
+            let (sa, sb) = (a as i64 as i128, b as i64 as i128);
+            let (ua, ub) = (a as u128, b as u128);
+
+            assert_eq!(mul(a, b), (ua * ub) as u64);
+            assert_eq!(mulh(a, b), ((sa * sb) >> 64) as u64);
+            assert_eq!(mulhsu(a, b), ((sa * ub as i128) >> 64) as u64);
+            assert_eq!(mulhu(a, b), ((ua * ub) >> 64) as u64);
+            assert_eq!(mulw(a, b), ((a as i32 as i64).wrapping_mul(b as i32 as i64) as i32) as i64 as u64);
+
+            if b != 0 {
+                assert_eq!(add(mul(div(a, b), b), rem(a, b)), a);
+                assert_eq!(add(mul(divu(a, b), b), remu(a, b)), a);
+                assert!(remu(a, b) < b);
+            }
+
+            if b as u32 != 0 {
+                assert_eq!(addw(mulw(divw(a, b), b), remw(a, b)), sign_ext64!(32, a & 0xFFFF_FFFF));
+                assert_eq!(addw(mulw(divuw(a, b), b), remuw(a, b)), sign_ext64!(32, a & 0xFFFF_FFFF));
+            }
+
+            // Results of word ops are always sign-extended
+            for r in [mulw(a, b), divw(a, b), divuw(a, b), remw(a, b), remuw(a, b)].iter() {
+                assert_eq!(*r, sign_ext64!(32, r & 0xFFFF_FFFF));
+            }
+        }
+    }
+}
+
+//
+// RV32
+//
+// Registers hold RV32 values sign-extended to 64 bits, so most operations
+// are shared with RV64 or its *W forms. These are the ones that are not.
+//
+
+#[inline(always)]
+pub fn mulh32(op1 : u64, op2 : u64) -> u64 {
+    sign_ext64!(32, ((op1 as i32 as i64 * op2 as i32 as i64) >> 32) as u64 & 0xFFFFFFFF)
+}
+
+#[inline(always)]
+pub fn mulhsu32(op1 : u64, op2 : u64) -> u64 {
+    sign_ext64!(32, ((op1 as i32 as i64 * op2 as u32 as i64) >> 32) as u64 & 0xFFFFFFFF)
+}
+
+#[inline(always)]
+pub fn mulhu32(op1 : u64, op2 : u64) -> u64 {
+    sign_ext64!(32, (op1 as u32 as u64 * op2 as u32 as u64) >> 32)
+}
+
+#[inline(always)]
+pub fn rev8_32(v : u64) -> u64 {
+    sign_ext64!(32, (v as u32).swap_bytes() as u64)
+}
+
+#[inline(always)]
+pub fn bclr32(v : u64, idx : u64) -> u64 {
+    sign_ext64!(32, bclr(v, idx & 0x1F) & 0xFFFFFFFF)
+}
+
+#[inline(always)]
+pub fn bext32(v : u64, idx : u64) -> u64 {
+    bext(v, idx & 0x1F)
+}
+
+#[inline(always)]
+pub fn binv32(v : u64, idx : u64) -> u64 {
+    sign_ext64!(32, binv(v, idx & 0x1F) & 0xFFFFFFFF)
+}
+
+#[inline(always)]
+pub fn bset32(v : u64, idx : u64) -> u64 {
+    sign_ext64!(32, bset(v, idx & 0x1F) & 0xFFFFFFFF)
+}
+
+#[test]
+fn test_rv32() {
+    let neg1 = 0xFFFF_FFFF_FFFF_FFFF;
+    let min = 0xFFFF_FFFF_8000_0000;
+
+    assert_eq!(mulh32(neg1, neg1), 0);
+    assert_eq!(mulh32(min, min), 0x4000_0000);
+    assert_eq!(mulhsu32(neg1, neg1), neg1);
+    assert_eq!(mulhu32(neg1, neg1), 0xFFFF_FFFF_FFFF_FFFE);
+
+    // The *W forms are the RV32 operations
+    assert_eq!(srlw(min, 4), 0x0800_0000);
+    assert_eq!(divuw(min, 2), 0x4000_0000);
+    assert_eq!(div(min, neg1), 0x8000_0000);
+
+    assert_eq!(rev8_32(0x0102_0380), 0xFFFF_FFFF_8003_0201);
+    assert_eq!(bclr32(min, 63), 0);
+    assert_eq!(bset32(0, 31), min);
+    assert_eq!(binv32(1, 32), 0);
+    assert_eq!(bext32(min, 63), 1);
 }
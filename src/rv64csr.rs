@@ -0,0 +1,309 @@
+use crate::rv64emu::*;
+use crate::rv64defs::*;
+use crate::isa::*;
+use crate::rv64priv::*;
+use crate::rv64mmu::*;
+
+//
+// CSR Addresses
+//
+
+pub const CSR_FFLAGS : usize  = 0x001;
+pub const CSR_FRM : usize     = 0x002;
+pub const CSR_FCSR : usize    = 0x003;
+pub const CSR_CYCLE : usize   = 0xC00;
+pub const CSR_TIME : usize    = 0xC01;
+pub const CSR_INSTRET : usize = 0xC02;
+
+// Upper halves of the counters, RV32 only
+pub const CSR_CYCLEH : usize   = 0xC80;
+pub const CSR_TIMEH : usize    = 0xC81;
+pub const CSR_INSTRETH : usize = 0xC82;
+
+pub const CSR_SSTATUS : usize    = 0x100;
+pub const CSR_SIE : usize        = 0x104;
+pub const CSR_STVEC : usize      = 0x105;
+pub const CSR_SCOUNTEREN : usize = 0x106;
+pub const CSR_SSCRATCH : usize   = 0x140;
+pub const CSR_SEPC : usize       = 0x141;
+pub const CSR_SCAUSE : usize     = 0x142;
+pub const CSR_STVAL : usize      = 0x143;
+pub const CSR_SIP : usize        = 0x144;
+pub const CSR_SATP : usize       = 0x180;
+
+pub const CSR_MSTATUS : usize    = 0x300;
+pub const CSR_MSTATUSH : usize   = 0x310;
+pub const CSR_MISA : usize       = 0x301;
+pub const CSR_MEDELEG : usize    = 0x302;
+pub const CSR_MIDELEG : usize    = 0x303;
+pub const CSR_MIE : usize        = 0x304;
+pub const CSR_MTVEC : usize      = 0x305;
+pub const CSR_MCOUNTEREN : usize = 0x306;
+pub const CSR_MSCRATCH : usize   = 0x340;
+pub const CSR_MEPC : usize       = 0x341;
+pub const CSR_MCAUSE : usize     = 0x342;
+pub const CSR_MTVAL : usize      = 0x343;
+pub const CSR_MIP : usize        = 0x344;
+pub const CSR_PMPCFG0 : usize    = 0x3A0;
+pub const CSR_PMPADDR0 : usize   = 0x3B0;
+pub const CSR_MVENDORID : usize  = 0xF11;
+pub const CSR_MARCHID : usize    = 0xF12;
+pub const CSR_MIMPID : usize     = 0xF13;
+pub const CSR_MHARTID : usize    = 0xF14;
+
+/// CSRs with both top address bits set are read-only.
+#[inline(always)]
+pub fn csr_read_only(csr : usize) -> bool {
+    bit_range_get!(csr, (10, 11)) == 0b11
+}
+
+/// Lowest privilege mode that may access a CSR.
+#[inline(always)]
+pub fn csr_privilege(csr : usize) -> usize {
+    bit_range_get!(csr, (8, 9))
+}
+
+impl ArchState {
+    fn csr_exists(&self, csr : usize) -> bool {
+        match csr {
+            CSR_FFLAGS | CSR_FRM | CSR_FCSR => self.isa.has(Extension::F) && self.fp_enabled(),
+            CSR_CYCLE | CSR_TIME | CSR_INSTRET => self.counter_enabled(csr),
+            CSR_CYCLEH | CSR_TIMEH | CSR_INSTRETH => self.rv32() && self.counter_enabled(csr),
+            CSR_MSTATUSH => self.rv32(),
+
+            CSR_SSTATUS | CSR_SIE | CSR_STVEC | CSR_SCOUNTEREN | CSR_SSCRATCH |
+            CSR_SEPC | CSR_SCAUSE | CSR_STVAL | CSR_SIP | CSR_SATP |
+            CSR_MSTATUS | CSR_MISA | CSR_MEDELEG | CSR_MIDELEG | CSR_MIE |
+            CSR_MTVEC | CSR_MCOUNTEREN | CSR_MSCRATCH | CSR_MEPC | CSR_MCAUSE |
+            CSR_MTVAL | CSR_MIP | CSR_MVENDORID | CSR_MARCHID | CSR_MIMPID |
+            CSR_MHARTID => true,
+
+            // RV64 packs eight entries into each even-numbered pmpcfg,
+            // RV32 four into each
+            CSR_PMPCFG0..=0x3AF => csr & 1 == 0 || self.rv32(),
+            CSR_PMPADDR0..=0x3EF => true,
+
+            _ => false
+        }
+    }
+
+    /// Whether a CSR can be accessed at all from the current mode.
+    fn csr_accessible(&self, csr : usize) -> bool {
+        // mstatus.TVM traps S-mode satp accesses
+        if csr == CSR_SATP && self.mode == PrivMode::Supervisor &&
+           self.csrs.mstatus & MSTATUS_TVM != 0 {
+            return false;
+        }
+
+        csr_privilege(csr) <= self.mode as usize && self.csr_exists(csr)
+    }
+
+    /// Lower modes only see the counters enabled in mcounteren (and, for
+    /// U-mode, scounteren).
+    fn counter_enabled(&self, csr : usize) -> bool {
+        let bit = 1 << (csr & 0x1F);
+
+        match self.mode {
+            PrivMode::Machine => true,
+            PrivMode::Supervisor => self.csrs.mcounteren & bit != 0,
+            PrivMode::User => self.csrs.mcounteren & self.csrs.scounteren & bit != 0
+        }
+    }
+
+    /// misa reports MXL and the enabled single-letter extensions, plus I
+    /// (or E), S and U. Writes are ignored.
+    fn misa(&self) -> u64 {
+        let letters = [
+            ('a', Extension::A), ('c', Extension::C), ('d', Extension::D),
+            ('f', Extension::F), ('m', Extension::M)
+        ];
+
+        // CSRs hold 64 bits; on RV128 MXL=3 reaches bits 127:126 when the
+        // value is sign-extended into a register
+        let mxl = match self.isa.xlen() {
+            ArchWidth::RV32 => 1 << 30,
+            ArchWidth::RV64 => 2 << 62,
+            ArchWidth::RV128 => 3 << 62
+        };
+        let base = if self.isa.embedded() { 'e' } else { 'i' };
+
+        letters.iter()
+            .filter(|(_, ext)| self.isa.has(*ext))
+            .map(|(l, _)| *l)
+            .chain([base, 's', 'u'].iter().copied())
+            .fold(mxl, |misa, l| misa | 1 << (l as u64 - 'a' as u64))
+    }
+
+    fn pmpcfg_entries(&self) -> usize {
+        if self.rv32() { 4 } else { 8 }
+    }
+
+    /// Reads a CSR, failing if it does not exist.
+    pub fn csr_read(&self, csr : usize) -> Result<u64, IllegalReason> {
+        if !self.csr_accessible(csr) {
+            return Err(IllegalReason::InvalidCsr(csr));
+        }
+
+        let val = match csr {
+            CSR_FFLAGS => self.fflags() as u64,
+            CSR_FRM => self.frm() as u64,
+            CSR_FCSR => (self.fcsr & 0xFF) as u64,
+
+            // All counters tick once per retired instruction, so exclude
+            // the instruction that reads them.
+            CSR_CYCLE | CSR_TIME | CSR_INSTRET => self.num_inst,
+            CSR_CYCLEH | CSR_TIMEH | CSR_INSTRETH => self.num_inst >> 32,
+
+            CSR_SSTATUS => self.mstatus() & SSTATUS_MASK,
+            CSR_SIE => self.csrs.mie & self.csrs.mideleg,
+            CSR_STVEC => self.csrs.stvec,
+            CSR_SCOUNTEREN => self.csrs.scounteren,
+            CSR_SSCRATCH => self.csrs.sscratch,
+            CSR_SEPC => self.epc(self.csrs.sepc),
+            CSR_SCAUSE => self.csrs.scause,
+            CSR_STVAL => self.csrs.stval,
+            CSR_SIP => self.csrs.mip & self.csrs.mideleg,
+            CSR_SATP => self.csrs.satp,
+
+            CSR_MSTATUS => self.mstatus(),
+            CSR_MSTATUSH => 0,
+            CSR_MISA => self.misa(),
+            CSR_MEDELEG => self.csrs.medeleg,
+            CSR_MIDELEG => self.csrs.mideleg,
+            CSR_MIE => self.csrs.mie,
+            CSR_MTVEC => self.csrs.mtvec,
+            CSR_MCOUNTEREN => self.csrs.mcounteren,
+            CSR_MSCRATCH => self.csrs.mscratch,
+            CSR_MEPC => self.epc(self.csrs.mepc),
+            CSR_MCAUSE => self.csrs.mcause,
+            CSR_MTVAL => self.csrs.mtval,
+            CSR_MIP => self.csrs.mip,
+            CSR_MVENDORID | CSR_MARCHID | CSR_MIMPID | CSR_MHARTID => 0,
+
+            CSR_PMPCFG0..=0x3AF => {
+                let first = (csr - CSR_PMPCFG0) * 4;
+                (0..self.pmpcfg_entries())
+                    .fold(0, |val, i| val | (self.pmp.cfg[first + i] as u64) << (8 * i))
+            },
+            CSR_PMPADDR0..=0x3EF => self.pmp.addr[csr - CSR_PMPADDR0],
+
+            _ => return Err(IllegalReason::InvalidCsr(csr))
+        };
+
+        if self.debug {
+            println!("        csr 0x{:03x} => {:016x}", csr, val);
+        }
+
+        Ok(val)
+    }
+
+    /// Writes a CSR, failing if it does not exist or is read-only.
+    pub fn csr_write(&mut self, csr : usize, val : u64) -> Result<(), IllegalReason> {
+        if self.debug {
+            println!("        csr 0x{:03x} <= {:016x}", csr, val);
+        }
+
+        if !self.csr_accessible(csr) || csr_read_only(csr) {
+            return Err(IllegalReason::InvalidCsr(csr));
+        }
+
+        // RV32 CSRs are 32 bits wide
+        let val = self.xlen_addr(val);
+
+        match csr {
+            CSR_FFLAGS => self.set_fcsr((self.fcsr & !0x1F) | (val as u32 & 0x1F)),
+            CSR_FRM => self.set_fcsr((self.fcsr & !0xE0) | ((val as u32 & 0x7) << 5)),
+            CSR_FCSR => self.set_fcsr(val as u32 & 0xFF),
+
+            CSR_SSTATUS => {
+                let val = (self.csrs.mstatus & !SSTATUS_MASK) | (val & SSTATUS_MASK);
+                self.set_mstatus(val);
+            },
+            CSR_SIE => {
+                let mask = self.csrs.mideleg;
+                self.csrs.mie = (self.csrs.mie & !mask) | (val & mask);
+            },
+            CSR_STVEC => self.csrs.stvec = legalize_tvec(val),
+            CSR_SCOUNTEREN => self.csrs.scounteren = val & 0b111,
+            CSR_SSCRATCH => self.csrs.sscratch = val,
+            CSR_SEPC => self.csrs.sepc = val & !1,
+            CSR_SCAUSE => self.csrs.scause = val,
+            CSR_STVAL => self.csrs.stval = val,
+            // Sv32 is not implemented, so RV32 stays in Bare mode
+            CSR_SATP if self.rv32() => (),
+            CSR_SATP => self.csrs.satp = legalize_satp(self.csrs.satp, val),
+            CSR_SIP => {
+                // Only the supervisor software interrupt can be raised here
+                let mask = self.csrs.mideleg & 1 << IRQ_S_SOFT;
+                self.csrs.mip = (self.csrs.mip & !mask) | (val & mask);
+            },
+
+            CSR_MSTATUS => self.set_mstatus(val),
+            CSR_MSTATUSH => (),
+            CSR_MISA => (),
+            CSR_MEDELEG => self.csrs.medeleg = val & MEDELEG_MASK,
+            CSR_MIDELEG => self.csrs.mideleg = val & S_IRQ_MASK,
+            CSR_MIE => self.csrs.mie = val & MIE_MASK,
+            CSR_MTVEC => self.csrs.mtvec = legalize_tvec(val),
+            CSR_MCOUNTEREN => self.csrs.mcounteren = val & 0b111,
+            CSR_MSCRATCH => self.csrs.mscratch = val,
+            CSR_MEPC => self.csrs.mepc = val & !1,
+            CSR_MCAUSE => self.csrs.mcause = val,
+            CSR_MTVAL => self.csrs.mtval = val,
+            CSR_MIP => {
+                // The M-level bits are driven by devices, not software
+                self.csrs.mip = (self.csrs.mip & !S_IRQ_MASK) | (val & S_IRQ_MASK);
+            },
+
+            CSR_PMPCFG0..=0x3AF => {
+                let first = (csr - CSR_PMPCFG0) * 4;
+
+                for i in 0..self.pmpcfg_entries() {
+                    self.pmp.write_cfg(first + i, (val >> (8 * i)) as u8);
+                }
+            },
+            CSR_PMPADDR0..=0x3EF => self.pmp.write_addr(csr - CSR_PMPADDR0, val),
+
+            _ => return Err(IllegalReason::InvalidCsr(csr))
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_csr_access() {
+    let mut arch = ArchState::new();
+
+    arch.csr_write(CSR_FCSR, 0xFFFF_FFFF).unwrap();
+    assert_eq!(arch.csr_read(CSR_FCSR), Ok(0xFF));
+    assert_eq!(arch.csr_read(CSR_FRM), Ok(0b111));
+    assert_eq!(arch.csr_read(CSR_FFLAGS), Ok(0x1F));
+
+    arch.csr_write(CSR_FFLAGS, 0).unwrap();
+    assert_eq!(arch.csr_read(CSR_FCSR), Ok(0xE0));
+
+    arch.num_inst = 10;
+    assert_eq!(arch.csr_read(CSR_INSTRET), Ok(10));
+    assert_eq!(arch.csr_write(CSR_CYCLE, 0), Err(IllegalReason::InvalidCsr(CSR_CYCLE)));
+
+    // mstatus is not accessible from user mode
+    assert_eq!(arch.csr_read(0x300), Err(IllegalReason::InvalidCsr(0x300)));
+    assert_eq!(arch.csr_write(0x300, 0), Err(IllegalReason::InvalidCsr(0x300)));
+
+    // sstatus is a window onto mstatus, and MPP = 2 is not a mode
+    let mut arch = ArchState::new_system();
+    arch.csr_write(CSR_MSTATUS, MSTATUS_SIE | MSTATUS_MIE | 2 << 11).unwrap();
+    assert_eq!(arch.csr_read(CSR_SSTATUS), Ok(MSTATUS_SIE | 2 << 32));
+    assert_eq!(arch.csr_read(CSR_MSTATUS), Ok(MSTATUS_SIE | MSTATUS_MIE | 2 << 32 | 2 << 34));
+    assert_eq!(arch.csr_read(CSR_MISA), Ok(2 << 62 | 0x14112D));
+
+    // Below M-mode, M-level CSRs and disabled counters are inaccessible
+    arch.mode = PrivMode::Supervisor;
+    assert_eq!(arch.csr_read(CSR_MSTATUS), Err(IllegalReason::InvalidCsr(CSR_MSTATUS)));
+    assert_eq!(arch.csr_read(CSR_CYCLE), Err(IllegalReason::InvalidCsr(CSR_CYCLE)));
+    arch.csrs.mcounteren = 0b001;
+    assert!(arch.csr_read(CSR_CYCLE).is_ok());
+    arch.mode = PrivMode::User;
+    assert!(arch.csr_read(CSR_CYCLE).is_err());
+}
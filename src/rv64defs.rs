@@ -1,11 +1,62 @@
 
-#[derive(Debug)]
+use std::fmt;
+
+#[derive(Debug, Clone, Copy)]
 pub struct RawInst {
-    pub pc: u64,
     pub raw : u32
 }
 
-#[derive(Debug)]
+/// Why an instruction is illegal, either at decode or at execution time.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IllegalReason {
+    UnknownOpcode,
+    InvalidField(&'static str),
+    Reserved(&'static str),
+    ExtensionDisabled,
+    InvalidCsr(usize),
+    InvalidRoundingMode(usize),
+    Privileged,
+    /// Encoding that only exists at another XLEN.
+    WrongXlen,
+    /// Register outside the RV32E register file.
+    InvalidRegister(usize)
+}
+
+/// An illegal instruction and its raw bits, which become the trap value.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IllegalInst {
+    pub raw : u32,
+    pub reason : IllegalReason
+}
+
+impl fmt::Display for IllegalReason {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IllegalReason::UnknownOpcode => write!(f, "unknown opcode"),
+            IllegalReason::InvalidField(name) => write!(f, "invalid {} field", name),
+            IllegalReason::Reserved(inst) => write!(f, "reserved {} encoding", inst),
+            IllegalReason::ExtensionDisabled => write!(f, "extension not enabled"),
+            IllegalReason::InvalidCsr(csr) => write!(f, "inaccessible CSR 0x{:03x}", csr),
+            IllegalReason::InvalidRoundingMode(rm) => write!(f, "invalid rounding mode {}", rm),
+            IllegalReason::Privileged => write!(f, "not permitted at the current privilege"),
+            IllegalReason::WrongXlen => write!(f, "not available at this XLEN"),
+            IllegalReason::InvalidRegister(reg) => write!(f, "register x{} not present", reg)
+        }
+    }
+}
+
+impl fmt::Display for IllegalInst {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        if self.raw & 0b11 == 0b11 {
+            write!(f, "illegal instruction {:08x} ({})", self.raw, self.reason)
+        }
+        else {
+            write!(f, "illegal instruction {:04x} ({})", self.raw, self.reason)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ArchWidth {
     RV32,
     RV64,
@@ -18,19 +69,7 @@ pub enum ArchWidth {
 //     CR, CI, CSS, CIW, CL, CS, CB, CJ
 // }
 
-#[derive(Debug, PartialEq, FromPrimitive)]
-pub enum OpFunct {
-    AddSub = 0b000,
-    Sll    = 0b001,
-    Slt    = 0b010,
-    Sltu   = 0b011,
-    Xor    = 0b100,
-    SrlSra = 0b101,
-    Or     = 0b110,
-    And    = 0b111
-}
-
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Debug, PartialEq, Clone, Copy, FromPrimitive)]
 pub enum LoadStoreWidth {
     Byte   = 0b000,
     Half   = 0b001,
@@ -38,10 +77,14 @@ pub enum LoadStoreWidth {
     Double = 0b011,
     ByteU  = 0b100,
     HalfU  = 0b101,
-    WordU  = 0b110
+    WordU  = 0b110,
+    /// LDU, RV128 only
+    DoubleU = 0b111,
+    /// LQ/SQ, which have their own opcodes rather than a funct3
+    Quad   = 0b1000
 }
 
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Debug, PartialEq, Clone, Copy, FromPrimitive)]
 pub enum BranchType {
     Eq  = 0b000,
     Neq = 0b001,
@@ -51,13 +94,69 @@ pub enum BranchType {
     Geu = 0b111
 }
 
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Debug, PartialEq, Clone, Copy, FromPrimitive)]
+pub enum CsrFunct {
+    Rw  = 0b001,
+    Rs  = 0b010,
+    Rc  = 0b011,
+    Rwi = 0b101,
+    Rsi = 0b110,
+    Rci = 0b111
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, FromPrimitive)]
+pub enum AmoWidth {
+    W = 0b010,
+    D = 0b011
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, FromPrimitive)]
+pub enum AmoOp {
+    Add  = 0b00000,
+    Swap = 0b00001,
+    Xor  = 0b00100,
+    Or   = 0b01000,
+    And  = 0b01100,
+    Min  = 0b10000,
+    Max  = 0b10100,
+    Minu = 0b11000,
+    Maxu = 0b11100
+}
+
+/// Memory ordering bits of an atomic instruction. Accesses are performed
+/// in program order on a single hart, so these are only recorded.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct AmoOrdering {
+    pub aq : bool,
+    pub rl : bool
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, FromPrimitive)]
+pub enum FpFmt {
+    S = 0b00,
+    D = 0b01
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, FromPrimitive)]
+pub enum FpIntType {
+    W  = 0b00,
+    Wu = 0b01,
+    L  = 0b10,
+    Lu = 0b11
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, FromPrimitive)]
 pub enum CLoadStoreWidth {
+    /// C.FLW/C.FSW and their stack forms, RV32 only
+    Cfw,
     Cfd,
     Cw,
-    Cd
+    Cd,
+    /// C.LQ/C.SQ and their stack forms, RV128 only
+    Cq
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, FromPrimitive)]
 pub enum InstOpcode {
     C0      = 0b00,
@@ -93,7 +192,7 @@ pub enum InstOpcode {
 #[derive(Debug)]
 pub struct InstSpec(pub InstOpcode, pub usize);
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum DecodedInst {
     //
     // Base Integer Instructions
@@ -110,6 +209,10 @@ pub enum DecodedInst {
     Sra   { rs1 : usize, rs2 : usize, rd : usize },
     Or    { rs1 : usize, rs2 : usize, rd : usize },
     And   { rs1 : usize, rs2 : usize, rd : usize },
+    Mul   { rs1 : usize, rs2 : usize, rd : usize },
+    Mulh  { rs1 : usize, rs2 : usize, rd : usize },
+    Mulhsu { rs1 : usize, rs2 : usize, rd : usize },
+    Mulhu { rs1 : usize, rs2 : usize, rd : usize },
     Div   { rs1 : usize, rs2 : usize, rd : usize },
     Divu  { rs1 : usize, rs2 : usize, rd : usize },
     Rem   { rs1 : usize, rs2 : usize, rd : usize },
@@ -117,7 +220,6 @@ pub enum DecodedInst {
 
     // OpImm
     Addi  { rs1 : usize, rd : usize, imm : u64 },
-    Subi  { rs1 : usize, rd : usize, imm : u64 },
     Slti  { rs1 : usize, rd : usize, imm : u64 },
     Sltiu { rs1 : usize, rd : usize, imm : u64 },
     Xori  { rs1 : usize, rd : usize, imm : u64 },
@@ -133,16 +235,80 @@ pub enum DecodedInst {
     Sllw  { rs1 : usize, rs2 : usize, rd : usize },
     Srlw  { rs1 : usize, rs2 : usize, rd : usize },
     Sraw  { rs1 : usize, rs2 : usize, rd : usize },
+    Mulw  { rs1 : usize, rs2 : usize, rd : usize },
+    Divw  { rs1 : usize, rs2 : usize, rd : usize },
+    Divuw { rs1 : usize, rs2 : usize, rd : usize },
     Remw  { rs1 : usize, rs2 : usize, rd : usize },
     Remuw { rs1 : usize, rs2 : usize, rd : usize },
 
+    // Zba
+    Sh1add   { rs1 : usize, rs2 : usize, rd : usize },
+    Sh2add   { rs1 : usize, rs2 : usize, rd : usize },
+    Sh3add   { rs1 : usize, rs2 : usize, rd : usize },
+    AddUw    { rs1 : usize, rs2 : usize, rd : usize },
+    Sh1addUw { rs1 : usize, rs2 : usize, rd : usize },
+    Sh2addUw { rs1 : usize, rs2 : usize, rd : usize },
+    Sh3addUw { rs1 : usize, rs2 : usize, rd : usize },
+    SlliUw   { rs1 : usize, rd : usize, shamt : u64 },
+
+    // Zbb
+    Andn  { rs1 : usize, rs2 : usize, rd : usize },
+    Orn   { rs1 : usize, rs2 : usize, rd : usize },
+    Xnor  { rs1 : usize, rs2 : usize, rd : usize },
+    Clz   { rs1 : usize, rd : usize },
+    Ctz   { rs1 : usize, rd : usize },
+    Cpop  { rs1 : usize, rd : usize },
+    Clzw  { rs1 : usize, rd : usize },
+    Ctzw  { rs1 : usize, rd : usize },
+    Cpopw { rs1 : usize, rd : usize },
+    Max   { rs1 : usize, rs2 : usize, rd : usize },
+    Maxu  { rs1 : usize, rs2 : usize, rd : usize },
+    Min   { rs1 : usize, rs2 : usize, rd : usize },
+    Minu  { rs1 : usize, rs2 : usize, rd : usize },
+    SextB { rs1 : usize, rd : usize },
+    SextH { rs1 : usize, rd : usize },
+    ZextH { rs1 : usize, rd : usize },
+    Rol   { rs1 : usize, rs2 : usize, rd : usize },
+    Ror   { rs1 : usize, rs2 : usize, rd : usize },
+    Rori  { rs1 : usize, rd : usize, shamt : u64 },
+    Rolw  { rs1 : usize, rs2 : usize, rd : usize },
+    Rorw  { rs1 : usize, rs2 : usize, rd : usize },
+    Roriw { rs1 : usize, rd : usize, shamt : u64 },
+    OrcB  { rs1 : usize, rd : usize },
+    Rev8  { rs1 : usize, rd : usize },
+
+    // Zbs
+    Bclr  { rs1 : usize, rs2 : usize, rd : usize },
+    Bclri { rs1 : usize, rd : usize, shamt : u64 },
+    Bext  { rs1 : usize, rs2 : usize, rd : usize },
+    Bexti { rs1 : usize, rd : usize, shamt : u64 },
+    Binv  { rs1 : usize, rs2 : usize, rd : usize },
+    Binvi { rs1 : usize, rd : usize, shamt : u64 },
+    Bset  { rs1 : usize, rs2 : usize, rd : usize },
+    Bseti { rs1 : usize, rd : usize, shamt : u64 },
+
     // OpImm32
     Addiw { rs1 : usize, rd : usize, imm : u64 },
-    Subiw { rs1 : usize, rd : usize, imm : u64 },
     Slliw { rs1 : usize, rd : usize, shamt : u64 },
     Srliw { rs1 : usize, rd : usize, shamt : u64 },
     Sraiw { rs1 : usize, rd : usize, shamt : u64 },
 
+    // Op64 and OpImm64, RV128 only
+    Addd  { rs1 : usize, rs2 : usize, rd : usize },
+    Subd  { rs1 : usize, rs2 : usize, rd : usize },
+    Slld  { rs1 : usize, rs2 : usize, rd : usize },
+    Srld  { rs1 : usize, rs2 : usize, rd : usize },
+    Srad  { rs1 : usize, rs2 : usize, rd : usize },
+    Muld  { rs1 : usize, rs2 : usize, rd : usize },
+    Divd  { rs1 : usize, rs2 : usize, rd : usize },
+    Divud { rs1 : usize, rs2 : usize, rd : usize },
+    Remd  { rs1 : usize, rs2 : usize, rd : usize },
+    Remud { rs1 : usize, rs2 : usize, rd : usize },
+    Addid { rs1 : usize, rd : usize, imm : u64 },
+    Sllid { rs1 : usize, rd : usize, shamt : u64 },
+    Srlid { rs1 : usize, rd : usize, shamt : u64 },
+    Sraid { rs1 : usize, rd : usize, shamt : u64 },
+
     Lui   { rd : usize, imm : u64 },
     Auipc { rd : usize, imm : u64 },
     Jal   { rd : usize, imm : u64 },
@@ -152,6 +318,16 @@ pub enum DecodedInst {
     Load   { width : LoadStoreWidth, rs1 : usize, rd : usize, imm : u64 },
     Store  { width : LoadStoreWidth, rs1 : usize, rs2 : usize, imm : u64 },
 
+    //
+    // Memory ordering. A single hart performs its accesses in program
+    // order, so only FENCE.I has an effect.
+    //
+
+    Fence { pred : u8, succ : u8 },
+    FenceTso,
+    Pause,
+    FenceI,
+
     //
     // System Instructions
     //
@@ -159,6 +335,58 @@ pub enum DecodedInst {
 
     ECall,
     EBreak,
+    Mret,
+    Sret,
+    Wfi,
+    SfenceVma { rs1 : usize, rs2 : usize },
+
+    // For the immediate forms rs1 holds the 5-bit zero-extended uimm
+    Csr { func : CsrFunct, csr : usize, rs1 : usize, rd : usize },
+
+    //
+    // Atomic Instructions (A)
+    //
+
+    Lr  { width : AmoWidth, ord : AmoOrdering, rs1 : usize, rd : usize },
+    Sc  { width : AmoWidth, ord : AmoOrdering, rs1 : usize, rs2 : usize, rd : usize },
+    Amo { op : AmoOp, width : AmoWidth, ord : AmoOrdering, rs1 : usize, rs2 : usize, rd : usize },
+
+    //
+    // Floating-Point Instructions (F and D)
+    //
+
+    FLoad   { fmt : FpFmt, rs1 : usize, rd : usize, imm : u64 },
+    FStore  { fmt : FpFmt, rs1 : usize, rs2 : usize, imm : u64 },
+
+    FMadd   { fmt : FpFmt, rs1 : usize, rs2 : usize, rs3 : usize, rd : usize, rm : usize },
+    FMsub   { fmt : FpFmt, rs1 : usize, rs2 : usize, rs3 : usize, rd : usize, rm : usize },
+    FNmsub  { fmt : FpFmt, rs1 : usize, rs2 : usize, rs3 : usize, rd : usize, rm : usize },
+    FNmadd  { fmt : FpFmt, rs1 : usize, rs2 : usize, rs3 : usize, rd : usize, rm : usize },
+
+    FAdd    { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize, rm : usize },
+    FSub    { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize, rm : usize },
+    FMul    { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize, rm : usize },
+    FDiv    { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize, rm : usize },
+    FSqrt   { fmt : FpFmt, rs1 : usize, rd : usize, rm : usize },
+
+    FSgnj   { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize },
+    FSgnjn  { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize },
+    FSgnjx  { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize },
+    FMin    { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize },
+    FMax    { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize },
+
+    FCvtSD  { rs1 : usize, rd : usize, rm : usize },
+    FCvtDS  { rs1 : usize, rd : usize, rm : usize },
+    FCvtToInt   { fmt : FpFmt, ity : FpIntType, rs1 : usize, rd : usize, rm : usize },
+    FCvtFromInt { fmt : FpFmt, ity : FpIntType, rs1 : usize, rd : usize, rm : usize },
+
+    FEq     { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize },
+    FLt     { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize },
+    FLe     { fmt : FpFmt, rs1 : usize, rs2 : usize, rd : usize },
+    FClass  { fmt : FpFmt, rs1 : usize, rd : usize },
+
+    FMvXF   { fmt : FpFmt, rs1 : usize, rd : usize },
+    FMvFX   { fmt : FpFmt, rs1 : usize, rd : usize },
 
     //
     // Compressed Quandrant 0 Instructions
@@ -175,7 +403,6 @@ pub enum DecodedInst {
     //
 
     CAddi     { rsrd : usize, imm : u64 },
-    // CJal      { imm : u64 },
     CAddiw    { rsrd : usize, imm : u64 },
     CLi       { rd : usize, imm : u64 },
     CAddi16sp { imm : u64 },
@@ -207,9 +434,6 @@ pub enum DecodedInst {
     CMv     { rsrd : usize, rs2 : usize },
     CEBreak,
     CJalr   { rs1 : usize },
-    CAdd    { rsrd : usize, rs2 : usize },
-    CFsdsp  { rs2 : usize, imm : u64 },
-    CSwsp   { rs2 : usize, imm : u64 },
-    CSdsp   { rs2 : usize, imm : u64 }
+    CAdd    { rsrd : usize, rs2 : usize }
 
 }
@@ -1,26 +1,134 @@
 use std::fmt;
-
 use crate::syscalls::*;
 use crate::memif::*;
-use crate::bitops::*;
 use crate::rv64defs::*;
-use crate::rv64inst::*;
 use crate::rv64alu;
-
-
-#[derive(Debug)]
+use crate::rv64fpu;
+use crate::rv64fpu::RoundingMode;
+use crate::isa::*;
+use crate::rv64priv::*;
+use crate::rv64mmu::*;
+use crate::rv64pmp::*;
+use crate::rv64icache::*;
+#[cfg(test)]
+use crate::rv64csr::*;
+
+
+#[derive(Debug, Clone)]
 pub struct ArchState {
     pub debug : bool,
     pub num_inst : u64,
+    pub isa : Isa,
     pub pc : u64,
-    pub regs : [u64; 32]
+    /// Wide enough for RV128; narrower XLENs keep values sign-extended.
+    pub regs : [u128; 32],
+    pub fregs : [u64; 32],
+    pub fcsr : u32,
+    pub reservation : Option<Reservation>,
+
+    /// Full-system mode: ECALLs and exceptions trap into the guest. When
+    /// clear, ECALLs go to the host syscall layer and exceptions stop
+    /// execution.
+    pub system : bool,
+    pub mode : PrivMode,
+    pub csrs : PrivCsrs,
+    pub tlb : Tlb,
+    pub pmp : Pmp,
+    pub icache : DecodeCache
+}
+
+/// Address range claimed by the last LR, invalidated by any store that
+/// overlaps it or by the next SC.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Reservation {
+    pub addr : u64,
+    pub size : u64
+}
+
+/// Synchronous exceptions raised by an instruction. The faulting
+/// instruction does not retire and pc is left pointing at it.
+/// Address-carrying variants hold the faulting address.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(IllegalInst),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvironmentCall(PrivMode),
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64)
+}
+
+impl Exception {
+    /// Exception code as reported in mcause/scause.
+    pub fn cause(&self) -> u64 {
+        use Exception::*;
+
+        match self {
+            InstructionAddressMisaligned(_) => 0,
+            InstructionAccessFault(_) => 1,
+            IllegalInstruction(_) => 2,
+            Breakpoint(_) => 3,
+            LoadAddressMisaligned(_) => 4,
+            LoadAccessFault(_) => 5,
+            StoreAddressMisaligned(_) => 6,
+            StoreAccessFault(_) => 7,
+            EnvironmentCall(mode) => 8 + *mode as u64,
+            InstructionPageFault(_) => 12,
+            LoadPageFault(_) => 13,
+            StorePageFault(_) => 15
+        }
+    }
+
+    /// Trap value as reported in mtval/stval.
+    pub fn tval(&self) -> u64 {
+        use Exception::*;
+
+        match self {
+            InstructionAddressMisaligned(addr) | InstructionAccessFault(addr) |
+            Breakpoint(addr) | LoadAddressMisaligned(addr) | LoadAccessFault(addr) |
+            StoreAddressMisaligned(addr) | StoreAccessFault(addr) |
+            InstructionPageFault(addr) | LoadPageFault(addr) | StorePageFault(addr) => *addr,
+            IllegalInstruction(inst) => inst.raw as u64,
+            EnvironmentCall(_) => 0
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        use Exception::*;
+
+        match self {
+            InstructionAddressMisaligned(addr) =>
+                write!(f, "misaligned instruction address 0x{:x}", addr),
+            InstructionAccessFault(addr) => write!(f, "instruction access fault at 0x{:x}", addr),
+            IllegalInstruction(inst) => write!(f, "{}", inst),
+            Breakpoint(addr) => write!(f, "breakpoint at 0x{:x}", addr),
+            LoadAddressMisaligned(addr) => write!(f, "misaligned load from 0x{:x}", addr),
+            LoadAccessFault(addr) => write!(f, "load access fault at 0x{:x}", addr),
+            StoreAddressMisaligned(addr) => write!(f, "misaligned store to 0x{:x}", addr),
+            StoreAccessFault(addr) => write!(f, "store access fault at 0x{:x}", addr),
+            EnvironmentCall(mode) => write!(f, "environment call from {:?} mode", mode),
+            InstructionPageFault(addr) => write!(f, "instruction page fault at 0x{:x}", addr),
+            LoadPageFault(addr) => write!(f, "load page fault at 0x{:x}", addr),
+            StorePageFault(addr) => write!(f, "store page fault at 0x{:x}", addr)
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum ExecResult {
     Continue,
-    Trap,
-    Halt
+    /// ECALL to be serviced by the host syscall layer.
+    Syscall,
+    Halt,
+    Exception(Exception)
 }
 
 impl ArchState {
@@ -28,24 +136,54 @@ impl ArchState {
         ArchState {
             debug: false,
             num_inst: 0,
+            isa: Isa::default(),
             pc: 0,
-            regs: [0; 32]
+            regs: [0; 32],
+            fregs: [0; 32],
+            fcsr: 0,
+            reservation: None,
+            system: false,
+            mode: PrivMode::User,
+            csrs: PrivCsrs {
+                mcounteren: 0b111,
+                scounteren: 0b111,
+                ..PrivCsrs::default()
+            },
+            tlb: Tlb::default(),
+            pmp: Pmp::default(),
+            icache: DecodeCache::default()
+        }
+    }
+
+    /// A hart coming out of reset in M-mode, for bare-metal code. FP is
+    /// off (mstatus.FS = Off) until software turns it on.
+    pub fn new_system() -> Self {
+        ArchState {
+            system: true,
+            mode: PrivMode::Machine,
+            csrs: PrivCsrs::default(),
+            ..ArchState::new()
         }
     }
 
-    pub fn set_stack_addr(&mut self, addr : u64) -> () {
+    pub fn set_stack_addr(&mut self, addr : u64) {
         self.regw(2, addr);
     }
 
-    pub fn fetch_inst(&self, mem : &mut dyn MemIf) -> RawInst {
-        let low = read16(mem, self.pc);
+    pub fn fetch_inst(&mut self, mem : &mut dyn MemIf) -> Result<RawInst, Exception> {
+        let pc = self.pc;
+        let fault = |_| Access::Fetch.access_fault(pc);
+        let paddr = self.access(mem, self.pc, 2, Access::Fetch)?;
+        let low = mem.load::<u16>(paddr).map_err(fault)?;
 
+        // The upper half may be on the next page
         if low & 0b11 == 0b11 {
-            let high = read16(mem, self.pc + 2);
-            RawInst { pc : self.pc, raw : ((high << 16) as u32) | (low as u32) }
+            let paddr = self.access(mem, self.pc.wrapping_add(2), 2, Access::Fetch)?;
+            let high = mem.load::<u16>(paddr).map_err(fault)?;
+            Ok(RawInst { raw : ((high as u32) << 16) | (low as u32) })
         }
         else {
-            RawInst { pc : self.pc, raw : low as u32 }
+            Ok(RawInst { raw : low as u32 })
         }
     }
 
@@ -53,7 +191,7 @@ impl ArchState {
     pub fn regr(&self, rnum : usize) -> u64 {
         let res = match rnum {
             0 => 0,
-            1..=31 => self.regs[rnum],
+            1..=31 => self.regs[rnum] as u64,
             _ => panic!("Invalid register!")
         };
 
@@ -72,19 +210,307 @@ impl ArchState {
 
         match rnum {
             0 => (),
-            1..=31 => self.regs[rnum] = val,
+            1..=31 => self.regs[rnum] = self.xlen_value(val) as i64 as i128 as u128,
             _ => panic!("Invalid register!")
         }
     }
 
+    #[inline(always)]
+    pub fn rv32(&self) -> bool {
+        self.isa.xlen() == ArchWidth::RV32
+    }
+
+    /// RV32 registers hold their value sign-extended from bit 31, so that
+    /// comparisons, multiplies and signed shifts can share the RV64 code.
+    #[inline(always)]
+    pub fn xlen_value(&self, val : u64) -> u64 {
+        if self.rv32() { sign_ext64!(32, val & 0xFFFF_FFFF) } else { val }
+    }
+
+    /// Addresses and pc wrap at 2^XLEN.
+    #[inline(always)]
+    pub fn xlen_addr(&self, addr : u64) -> u64 {
+        if self.rv32() { addr & 0xFFFF_FFFF } else { addr }
+    }
+
+    /// Reads an FP register as the given format. Single-precision values
+    /// that are not properly NaN-boxed read as the canonical NaN.
+    #[inline(always)]
+    pub fn fregr(&self, fmt : &FpFmt, rnum : usize) -> u64 {
+        let raw = self.fregs[rnum];
+
+        let res = match fmt {
+            FpFmt::S if raw >> 32 != 0xFFFF_FFFF =>
+                rv64fpu::F32.canonical_nan(),
+            FpFmt::S => raw & 0xFFFF_FFFF,
+            FpFmt::D => raw
+        };
+
+        if self.debug {
+            println!("        f{} => {:016x}", rnum, res);
+        }
+
+        res
+    }
+
+    /// Writes an FP register, NaN-boxing single-precision values.
+    #[inline(always)]
+    pub fn fregw(&mut self, fmt : &FpFmt, rnum : usize, val : u64) {
+        let boxed = match fmt {
+            FpFmt::S => 0xFFFF_FFFF_0000_0000 | (val & 0xFFFF_FFFF),
+            FpFmt::D => val
+        };
+
+        if self.debug {
+            println!("        f{} <= {:016x}", rnum, boxed);
+        }
+
+        self.fregs[rnum] = boxed;
+        self.set_fp_dirty();
+    }
+
+    #[inline(always)]
+    pub fn frm(&self) -> usize {
+        ((self.fcsr >> 5) & 0b111) as usize
+    }
+
+    #[inline(always)]
+    pub fn fflags(&self) -> u32 {
+        self.fcsr & 0b11111
+    }
+
+    #[inline(always)]
+    pub fn set_fcsr(&mut self, val : u32) {
+        self.fcsr = val;
+        self.set_fp_dirty();
+    }
+
+    /// FP exception flags are sticky until software clears them.
+    #[inline(always)]
+    fn accrue_fflags(&mut self, flags : u32) {
+        if flags != 0 {
+            self.set_fcsr(self.fcsr | flags);
+        }
+    }
+
+    /// Resolves an instruction's rm field, with 0b111 (DYN) selecting frm.
+    /// Reserved modes are illegal.
+    #[inline(always)]
+    fn rounding_mode(&self, rm : usize) -> Result<RoundingMode, IllegalReason> {
+        let rm = if rm == 0b111 { self.frm() } else { rm };
+
+        num::FromPrimitive::from_usize(rm).ok_or(IllegalReason::InvalidRoundingMode(rm))
+    }
+
+    /// Translates an access that stays within one page and checks that
+    /// the physical range is backed by memory that allows the access, and
+    /// that PMP does too. Accesses of every width funnel through here.
+    #[inline(always)]
+    pub fn access(
+        &mut self, mem : &mut dyn MemIf, addr : u64, size : u64, access : Access) -> Result<u64, Exception> {
+
+        let paddr = self.translate(mem, addr, access)?;
+        let mode = self.effective_mode(access);
+
+        if !self.pmp_permits(paddr, size, access, mode) ||
+           mem.check(paddr, size, access.perm()).is_err() {
+            return Err(access.access_fault(addr));
+        }
+
+        Ok(paddr)
+    }
+
+    #[inline(always)]
+    fn read_phys(mem : &dyn MemIf, paddr : u64, size : u64) -> Result<u64, MemFault> {
+        match size {
+            1 | 2 | 4 | 8 => mem.read_le(paddr, size),
+            _ => panic!("Invalid load size!")
+        }
+    }
+
+    /// Physical stores break any LR reservation they overlap.
+    #[inline(always)]
+    fn write_phys(&mut self, mem : &mut dyn MemIf, paddr : u64, val : u64, size : u64)
+        -> Result<(), MemFault> {
+        if let Some(res) = self.reservation {
+            if paddr < res.addr + res.size && res.addr < paddr + size {
+                self.reservation = None;
+            }
+        }
+
+        self.icache.check_store(paddr, size);
+
+        match size {
+            1 | 2 | 4 | 8 => mem.write_le(paddr, val, size),
+            _ => panic!("Invalid store size!")
+        }
+    }
+
+    /// Every guest load goes through here. The value is zero-extended.
+    #[inline(always)]
+    pub fn load(&mut self, mem : &mut dyn MemIf, addr : u64, size : u64) -> Result<u64, Exception> {
+        let addr = self.xlen_addr(addr);
+
+        let fault = |_| Access::Load.access_fault(addr);
+
+        if !crosses_page(addr, size) {
+            let paddr = self.access(mem, addr, size, Access::Load)?;
+            return Self::read_phys(mem, paddr, size).map_err(fault);
+        }
+
+        // Misaligned across a page boundary: each byte is translated
+        let mut val = 0;
+
+        for i in 0..size {
+            let paddr = self.access(mem, addr.wrapping_add(i), 1, Access::Load)?;
+            val |= read8(mem, paddr).map_err(fault)? << (8 * i);
+        }
+
+        Ok(val)
+    }
+
+    /// Every guest store goes through here. A faulting store writes
+    /// nothing, even when it spans two pages.
+    #[inline(always)]
+    pub fn store(
+        &mut self, mem : &mut dyn MemIf, addr : u64, val : u64, size : u64) -> Result<(), Exception> {
+
+        let addr = self.xlen_addr(addr);
+
+        let fault = |_| Access::Store.access_fault(addr);
+
+        if !crosses_page(addr, size) {
+            let paddr = self.access(mem, addr, size, Access::Store)?;
+            return self.write_phys(mem, paddr, val, size).map_err(fault);
+        }
+
+        let mut paddrs = [0; 8];
+
+        for (i, paddr) in paddrs.iter_mut().enumerate().take(size as usize) {
+            *paddr = self.access(mem, addr.wrapping_add(i as u64), 1, Access::Store)?;
+        }
+
+        for (i, paddr) in paddrs.iter().enumerate().take(size as usize) {
+            self.write_phys(mem, *paddr, val >> (8 * i), 1).map_err(fault)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets pc for a jump or taken branch. Without C, targets that are
+    /// not 4-byte aligned trap on the jump itself.
+    #[inline(always)]
+    pub fn jump(&mut self, target : u64) -> Result<(), Exception> {
+        let target = self.xlen_addr(target);
+
+        if target & 0b10 != 0 && !self.isa.has(Extension::C) {
+            return Err(Exception::InstructionAddressMisaligned(target));
+        }
+
+        self.pc = target;
+        Ok(())
+    }
+
+    /// Atomics must be naturally aligned. LR faults as a load, SC and AMOs
+    /// as stores.
+    #[inline(always)]
+    fn amo_addr(&self, rs1 : usize, width : &AmoWidth, is_lr : bool) -> Result<(u64, u64), Exception> {
+        let addr = self.xlen_addr(self.regr(rs1));
+        let size = match width {
+            AmoWidth::W => 4,
+            AmoWidth::D => 8
+        };
+
+        if addr & (size - 1) != 0 {
+            return Err(if is_lr {
+                Exception::LoadAddressMisaligned(addr)
+            }
+            else {
+                Exception::StoreAddressMisaligned(addr)
+            });
+        }
+
+        Ok((addr, size))
+    }
+
+    #[inline(always)]
+    fn amo_load(mem : &dyn MemIf, paddr : u64, width : &AmoWidth) -> Result<u64, MemFault> {
+        match width {
+            AmoWidth::W => Ok(sign_ext64!(32, read32(mem, paddr)?)),
+            AmoWidth::D => read64(mem, paddr)
+        }
+    }
+
+    /// Raises a synchronous exception for the current instruction. In
+    /// system mode it is delivered to the guest's trap handler; otherwise
+    /// it is handed back to the caller.
+    pub fn raise(&mut self, e : Exception) -> ExecResult {
+        if self.debug {
+            println!("        exception: {}", e);
+        }
+
+        if self.system {
+            self.take_trap(e.cause(), e.tval(), false);
+            ExecResult::Continue
+        }
+        else {
+            ExecResult::Exception(e)
+        }
+    }
+
     pub fn exec_inst(
-        &mut self, mem : &mut dyn MemIf, inst : &DecodedInst) -> ExecResult {
+        &mut self, mem : &mut dyn MemIf, rinst : &RawInst, inst : &DecodedInst) -> ExecResult {
+
+        // Only instructions that complete without a trap retire. An ecall
+        // the host handles retires too, as far as the guest can tell.
+        let res = match self.exec(mem, rinst, inst) {
+            res @ (ExecResult::Continue | ExecResult::Syscall) => {
+                self.num_inst += 1;
+                res
+            },
+            ExecResult::Exception(e) => self.raise(e),
+            res => res
+        };
+
+        self.pc = self.xlen_addr(self.pc);
+        res
+    }
+
+    fn exec(
+        &mut self, mem : &mut dyn MemIf, rinst : &RawInst, inst : &DecodedInst) -> ExecResult {
+
+        macro_rules! illegal {
+            ($reason:expr) => {
+                return ExecResult::Exception(Exception::IllegalInstruction(
+                    IllegalInst { raw : rinst.raw, reason : $reason }))
+            }
+        }
+
+        if !self.isa.supports(inst) || (uses_fp(inst) && !self.fp_enabled()) {
+            illegal!(IllegalReason::ExtensionDisabled);
+        }
+
+        if self.isa.xlen() == ArchWidth::RV128 {
+            if let Some(res) = self.exec_rv128(mem, inst) {
+                return res;
+            }
+        }
 
-        self.num_inst += 1;
+        // Memory accesses and jumps fail with an exception that leaves
+        // the instruction without side effects.
+        macro_rules! try_exec {
+            ($e:expr) => {
+                match $e {
+                    Ok(val) => val,
+                    Err(e) => return ExecResult::Exception(e)
+                }
+            }
+        }
 
         use DecodedInst::*;
-        use ExecResult::*;
+        use ExecResult::{Continue, Syscall, Halt};
 
+        // Operations that differ at RV32 name a second function for it.
         macro_rules! op_inst {
             ($rs1:expr, $rs2:expr, $rd:expr, $func:ident) => {
                 {
@@ -92,6 +518,19 @@ impl ArchState {
                     self.pc = rv64alu::add(self.pc, 4);
                     Continue
                 }
+            };
+            ($rs1:expr, $rs2:expr, $rd:expr, $func:ident, $func32:ident) => {
+                {
+                    let res = if self.rv32() {
+                        rv64alu::$func32(self.regr($rs1), self.regr($rs2))
+                    }
+                    else {
+                        rv64alu::$func(self.regr($rs1), self.regr($rs2))
+                    };
+                    self.regw($rd, res);
+                    self.pc = rv64alu::add(self.pc, 4);
+                    Continue
+                }
             }
         }
 
@@ -112,6 +551,42 @@ impl ArchState {
                     self.pc = rv64alu::add(self.pc, 4);
                     Continue
                 }
+            };
+            ($rs1:expr, $imm:expr, $rd:expr, $func:ident, $func32:ident) => {
+                {
+                    let res = if self.rv32() {
+                        rv64alu::$func32(self.regr($rs1), $imm)
+                    }
+                    else {
+                        rv64alu::$func(self.regr($rs1), $imm)
+                    };
+                    self.regw($rd, res);
+                    self.pc = rv64alu::add(self.pc, 4);
+                    Continue
+                }
+            }
+        }
+
+        macro_rules! unop_inst {
+            ($rs1:expr, $rd:expr, $func:ident) => {
+                {
+                    self.regw($rd, rv64alu::$func(self.regr($rs1)));
+                    self.pc = rv64alu::add(self.pc, 4);
+                    Continue
+                }
+            };
+            ($rs1:expr, $rd:expr, $func:ident, $func32:ident) => {
+                {
+                    let res = if self.rv32() {
+                        rv64alu::$func32(self.regr($rs1))
+                    }
+                    else {
+                        rv64alu::$func(self.regr($rs1))
+                    };
+                    self.regw($rd, res);
+                    self.pc = rv64alu::add(self.pc, 4);
+                    Continue
+                }
             }
         }
 
@@ -122,6 +597,102 @@ impl ArchState {
                     self.pc = rv64alu::add(self.pc, 2);
                     Continue
                 }
+            };
+            ($rs1:expr, $imm:expr, $rd:expr, $func:ident, $func32:ident) => {
+                {
+                    let res = if self.rv32() {
+                        rv64alu::$func32(self.regr($rs1), $imm)
+                    }
+                    else {
+                        rv64alu::$func(self.regr($rs1), $imm)
+                    };
+                    self.regw($rd, res);
+                    self.pc = rv64alu::add(self.pc, 2);
+                    Continue
+                }
+            }
+        }
+
+        // Arithmetic FP ops accrue exception flags into fcsr.fflags.
+        macro_rules! fp_inst {
+            ($fmt:expr, $rd:expr, $rm:expr, |$fp:ident, $rmv:ident, $flags:ident| $body:expr) => {
+                {
+                    let $fp = rv64fpu::format($fmt);
+                    let $rmv = match self.rounding_mode($rm) {
+                        Ok(rm) => rm,
+                        Err(reason) => illegal!(reason)
+                    };
+                    let mut $flags = 0;
+                    let res = $body;
+                    self.accrue_fflags($flags);
+                    self.fregw($fmt, $rd, res);
+                    self.pc = rv64alu::add(self.pc, 4);
+                    Continue
+                }
+            }
+        }
+
+        macro_rules! fp_op_inst {
+            ($fmt:expr, $rs1:expr, $rs2:expr, $rd:expr, $rm:expr, $func:ident) => {
+                {
+                    let (a, b) = (self.fregr($fmt, $rs1), self.fregr($fmt, $rs2));
+                    fp_inst!($fmt, $rd, $rm, |fp, rm, flags|
+                        rv64fpu::$func(fp, a, b, rm, &mut flags))
+                }
+            }
+        }
+
+        macro_rules! fp_fma_inst {
+            ($fmt:expr, $rs1:expr, $rs2:expr, $rs3:expr, $rd:expr, $rm:expr,
+             $neg_prod:expr, $neg_addend:expr) => {
+                {
+                    let sign = rv64fpu::format($fmt).sign_bit();
+                    let a = self.fregr($fmt, $rs1) ^ if $neg_prod { sign } else { 0 };
+                    let b = self.fregr($fmt, $rs2);
+                    let c = self.fregr($fmt, $rs3) ^ if $neg_addend { sign } else { 0 };
+                    fp_inst!($fmt, $rd, $rm, |fp, rm, flags|
+                        rv64fpu::muladd(fp, a, b, c, rm, &mut flags))
+                }
+            }
+        }
+
+        // Sign injection never raises exceptions and ignores rm.
+        macro_rules! fp_sgnj_inst {
+            ($fmt:expr, $rs1:expr, $rs2:expr, $rd:expr, $func:ident) => {
+                {
+                    let (a, b) = (self.fregr($fmt, $rs1), self.fregr($fmt, $rs2));
+                    self.fregw($fmt, $rd, rv64fpu::$func(rv64fpu::format($fmt), a, b));
+                    self.pc = rv64alu::add(self.pc, 4);
+                    Continue
+                }
+            }
+        }
+
+        macro_rules! fp_minmax_inst {
+            ($fmt:expr, $rs1:expr, $rs2:expr, $rd:expr, $func:ident) => {
+                {
+                    let (a, b) = (self.fregr($fmt, $rs1), self.fregr($fmt, $rs2));
+                    let mut flags = 0;
+                    let res = rv64fpu::$func(rv64fpu::format($fmt), a, b, &mut flags);
+                    self.accrue_fflags(flags);
+                    self.fregw($fmt, $rd, res);
+                    self.pc = rv64alu::add(self.pc, 4);
+                    Continue
+                }
+            }
+        }
+
+        macro_rules! fp_cmp_inst {
+            ($fmt:expr, $rs1:expr, $rs2:expr, $rd:expr, $func:ident) => {
+                {
+                    let (a, b) = (self.fregr($fmt, $rs1), self.fregr($fmt, $rs2));
+                    let mut flags = 0;
+                    let res = rv64fpu::$func(rv64fpu::format($fmt), a, b, &mut flags);
+                    self.accrue_fflags(flags);
+                    self.regw($rd, res as u64);
+                    self.pc = rv64alu::add(self.pc, 4);
+                    Continue
+                }
             }
         }
 
@@ -133,31 +704,34 @@ impl ArchState {
 
             Add {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, add),
             Sub {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, sub),
-            Sll {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, sll),
+            Sll {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, sll, sllw),
             Slt {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, slt),
             Sltu {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, sltu),
             Xor {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, xor),
-            Srl {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, srl),
-            Sra {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, sra),
+            Srl {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, srl, srlw),
+            Sra {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, sra, sraw),
             Or {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, or),
             And {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, and),
+            Mul {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, mul),
+            Mulh {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, mulh, mulh32),
+            Mulhsu {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, mulhsu, mulhsu32),
+            Mulhu {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, mulhu, mulhu32),
             Div {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, div),
-            Divu {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, divu),
+            Divu {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, divu, divuw),
             Rem {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, rem),
-            Remu {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, remu),
+            Remu {rs1, rs2, rd} =>  op_inst!(*rs1, *rs2, *rd, remu, remuw),
 
             //
             // OpImm
             //
 
             Addi {rs1, imm, rd} =>   opimm_inst!(*rs1, *imm, *rd, add),
-            Subi {rs1, imm, rd} =>   opimm_inst!(*rs1, *imm, *rd, sub),
-            Slli {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, sll),
+            Slli {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, sll, sllw),
             Slti {rs1, imm, rd} =>   opimm_inst!(*rs1, *imm, *rd, slt),
             Sltiu {rs1, imm, rd} =>  opimm_inst!(*rs1, *imm, *rd, sltu),
             Xori {rs1, imm, rd} =>   opimm_inst!(*rs1, *imm, *rd, xor),
-            Srli {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, srl),
-            Srai {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, sra),
+            Srli {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, srl, srlw),
+            Srai {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, sra, sraw),
             Ori {rs1, imm, rd} =>    opimm_inst!(*rs1, *imm, *rd, or),
             Andi {rs1, imm, rd} =>   opimm_inst!(*rs1, *imm, *rd, and),
 
@@ -170,6 +744,9 @@ impl ArchState {
             Sllw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, sllw),
             Srlw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, srlw),
             Sraw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, sraw),
+            Mulw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, mulw),
+            Divw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, divw),
+            Divuw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, divuw),
             Remw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, remw),
             Remuw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, remuw),
 
@@ -178,11 +755,75 @@ impl ArchState {
             //
 
             Addiw {rs1, imm, rd} =>   opimm_inst!(*rs1, *imm, *rd, addw),
-            Subiw {rs1, imm, rd} =>   opimm_inst!(*rs1, *imm, *rd, subw),
             Slliw {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, sllw),
             Srliw {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, srlw),
             Sraiw {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, sraw),
 
+            // Handled by exec_rv128
+            Addd {..} | Subd {..} | Slld {..} | Srld {..} | Srad {..} |
+            Muld {..} | Divd {..} | Divud {..} | Remd {..} | Remud {..} |
+            Addid {..} | Sllid {..} | Srlid {..} | Sraid {..} |
+            CLoad {width : CLoadStoreWidth::Cq, ..} |
+            CStore {width : CLoadStoreWidth::Cq, ..} |
+            CLoadStack {width : CLoadStoreWidth::Cq, ..} |
+            CStoreStack {width : CLoadStoreWidth::Cq, ..} =>
+                illegal!(IllegalReason::WrongXlen),
+
+            //
+            // Zba
+            //
+
+            Sh1add {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, sh1add),
+            Sh2add {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, sh2add),
+            Sh3add {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, sh3add),
+            AddUw {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, add_uw),
+            Sh1addUw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, sh1add_uw),
+            Sh2addUw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, sh2add_uw),
+            Sh3addUw {rs1, rs2, rd} => op_inst!(*rs1, *rs2, *rd, sh3add_uw),
+            SlliUw {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, slli_uw),
+
+            //
+            // Zbb
+            //
+
+            Andn {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, andn),
+            Orn {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, orn),
+            Xnor {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, xnor),
+            Clz {rs1, rd} =>         unop_inst!(*rs1, *rd, clz, clzw),
+            Ctz {rs1, rd} =>         unop_inst!(*rs1, *rd, ctz, ctzw),
+            Cpop {rs1, rd} =>        unop_inst!(*rs1, *rd, cpop, cpopw),
+            Clzw {rs1, rd} =>        unop_inst!(*rs1, *rd, clzw),
+            Ctzw {rs1, rd} =>        unop_inst!(*rs1, *rd, ctzw),
+            Cpopw {rs1, rd} =>       unop_inst!(*rs1, *rd, cpopw),
+            Max {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, max),
+            Maxu {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, maxu),
+            Min {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, min),
+            Minu {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, minu),
+            SextB {rs1, rd} =>       unop_inst!(*rs1, *rd, sext_b),
+            SextH {rs1, rd} =>       unop_inst!(*rs1, *rd, sext_h),
+            ZextH {rs1, rd} =>       unop_inst!(*rs1, *rd, zext_h),
+            Rol {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, rol, rolw),
+            Ror {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, ror, rorw),
+            Rori {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, ror, rorw),
+            Rolw {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, rolw),
+            Rorw {rs1, rs2, rd} =>   op_inst!(*rs1, *rs2, *rd, rorw),
+            Roriw {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, rorw),
+            OrcB {rs1, rd} =>        unop_inst!(*rs1, *rd, orc_b),
+            Rev8 {rs1, rd} =>        unop_inst!(*rs1, *rd, rev8, rev8_32),
+
+            //
+            // Zbs
+            //
+
+            Bclr {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, bclr, bclr32),
+            Bclri {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, bclr, bclr32),
+            Bext {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, bext, bext32),
+            Bexti {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, bext, bext32),
+            Binv {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, binv, binv32),
+            Binvi {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, binv, binv32),
+            Bset {rs1, rs2, rd} =>    op_inst!(*rs1, *rs2, *rd, bset, bset32),
+            Bseti {rs1, shamt, rd} => opimm_inst!(*rs1, *shamt, *rd, bset, bset32),
+
             Lui {rd, imm} => {
                 self.regw(*rd, *imm);
                 self.pc = rv64alu::add(self.pc, 4);
@@ -196,16 +837,17 @@ impl ArchState {
             }
 
             Jal {rd, imm} => {
-                self.regw(*rd, rv64alu::add(self.pc, 4));
-                self.pc = rv64alu::add(self.pc, *imm);
+                let ra = rv64alu::add(self.pc, 4);
+                try_exec!(self.jump(rv64alu::add(self.pc, *imm)));
+                self.regw(*rd, ra);
                 Continue
             },
 
             Jalr {rs1, rd, imm} => {
-                let target = rv64alu::add(self.regr(*rs1), *imm);
+                let target = rv64alu::add(self.regr(*rs1), *imm) & !1;
                 let ra = rv64alu::add(self.pc, 4);
+                try_exec!(self.jump(target));
                 self.regw(*rd, ra);
-                self.pc = target;
                 // println!("---");
                 Continue
             },
@@ -222,7 +864,7 @@ impl ArchState {
                 };
 
                 if pred {
-                    self.pc = rv64alu::add(self.pc, *imm);
+                    try_exec!(self.jump(rv64alu::add(self.pc, *imm)));
                 }
                 else {
                     self.pc = rv64alu::add(self.pc, 4);
@@ -235,14 +877,15 @@ impl ArchState {
                 let addr = rv64alu::add(self.regr(*rs1), *imm);
 
                 let val = match width {
-                    LoadStoreWidth::Byte => sign_ext64!(8, read8(mem, addr)),
-                    LoadStoreWidth::Half => sign_ext64!(16, read16(mem, addr)),
-                    LoadStoreWidth::Word => sign_ext64!(32, read32(mem, addr)),
-                    LoadStoreWidth::Double => read64(mem, addr),
-                    LoadStoreWidth::ByteU => read8(mem, addr),
-                    LoadStoreWidth::HalfU => read16(mem, addr),
-                    LoadStoreWidth::WordU => read32(mem, addr),
-                    _ => panic!("Unimplemented")
+                    LoadStoreWidth::Byte => sign_ext64!(8, try_exec!(self.load(mem, addr, 1))),
+                    LoadStoreWidth::Half => sign_ext64!(16, try_exec!(self.load(mem, addr, 2))),
+                    LoadStoreWidth::Word => sign_ext64!(32, try_exec!(self.load(mem, addr, 4))),
+                    LoadStoreWidth::Double => try_exec!(self.load(mem, addr, 8)),
+                    LoadStoreWidth::ByteU => try_exec!(self.load(mem, addr, 1)),
+                    LoadStoreWidth::HalfU => try_exec!(self.load(mem, addr, 2)),
+                    LoadStoreWidth::WordU => try_exec!(self.load(mem, addr, 4)),
+                    LoadStoreWidth::DoubleU | LoadStoreWidth::Quad =>
+                        illegal!(IllegalReason::WrongXlen)
                 };
 
                 // println!("        Load ({:?}) [{:x}] => {}", width, addr, val);
@@ -258,14 +901,31 @@ impl ArchState {
 
                 // println!("        Store ({:?}) [{:x}] <= {}", width, addr, val);
 
-                match width {
-                    LoadStoreWidth::Byte => write8(mem, addr, val.into()),
-                    LoadStoreWidth::Half => write16(mem, addr, val.into()),
-                    LoadStoreWidth::Word => write32(mem, addr, val.into()),
-                    LoadStoreWidth::Double => write64(mem, addr, val),
+                let size = match width {
+                    LoadStoreWidth::Byte => 1,
+                    LoadStoreWidth::Half => 2,
+                    LoadStoreWidth::Word => 4,
+                    LoadStoreWidth::Double => 8,
                     _ => panic!("Unimplemented")
                 };
 
+                try_exec!(self.store(mem, addr, val, size));
+
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            //
+            // Memory ordering
+            //
+
+            Fence {..} | FenceTso | Pause => {
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            FenceI => {
+                self.icache.flush();
                 self.pc = rv64alu::add(self.pc, 4);
                 Continue
             },
@@ -275,14 +935,314 @@ impl ArchState {
             //
 
             ECall => {
+                if self.system {
+                    return ExecResult::Exception(Exception::EnvironmentCall(self.mode));
+                }
+
                 self.pc = rv64alu::add(self.pc, 4);
-                Trap
+                Syscall
             },
 
-            EBreak => {
+            EBreak | CEBreak => {
+                if self.system {
+                    return ExecResult::Exception(Exception::Breakpoint(self.pc));
+                }
+
                 Halt
             },
 
+            Mret => {
+                if self.mode != PrivMode::Machine {
+                    illegal!(IllegalReason::Privileged);
+                }
+
+                self.mret();
+                Continue
+            },
+
+            Sret => {
+                if self.mode == PrivMode::User ||
+                   (self.mode == PrivMode::Supervisor && self.csrs.mstatus & MSTATUS_TSR != 0) {
+                    illegal!(IllegalReason::Privileged);
+                }
+
+                self.sret();
+                Continue
+            },
+
+            SfenceVma {rs1, rs2} => {
+                if self.mode == PrivMode::User ||
+                   (self.mode == PrivMode::Supervisor && self.csrs.mstatus & MSTATUS_TVM != 0) {
+                    illegal!(IllegalReason::Privileged);
+                }
+
+                let vaddr = if *rs1 != 0 { Some(self.regr(*rs1)) } else { None };
+                let asid = if *rs2 != 0 { Some(self.regr(*rs2) & 0xFFFF) } else { None };
+                self.tlb.flush(vaddr, asid);
+
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            // Nothing raises interrupts while a hart waits, so WFI is a nop
+            Wfi => {
+                if self.mode == PrivMode::User ||
+                   (self.mode == PrivMode::Supervisor && self.csrs.mstatus & MSTATUS_TW != 0) {
+                    illegal!(IllegalReason::Privileged);
+                }
+
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            Csr {func, csr, rs1, rd} => {
+                use CsrFunct::*;
+
+                let src = match func {
+                    Rw | Rs | Rc => self.regr(*rs1),
+                    Rwi | Rsi | Rci => *rs1 as u64
+                };
+
+                // CSRRW skips the read for rd = x0 and CSRRS/C skip the
+                // write for a zero source register or uimm.
+                let (read, write) = match func {
+                    Rw | Rwi => (*rd != 0, true),
+                    _ => (true, *rs1 != 0)
+                };
+
+                let old = if read {
+                    match self.csr_read(*csr) {
+                        Ok(val) => val,
+                        Err(reason) => illegal!(reason)
+                    }
+                }
+                else {
+                    0
+                };
+
+                if write {
+                    let new = match func {
+                        Rw | Rwi => src,
+                        Rs | Rsi => old | src,
+                        Rc | Rci => old & !src
+                    };
+
+                    if let Err(reason) = self.csr_write(*csr, new) {
+                        illegal!(reason);
+                    }
+                }
+
+                self.regw(*rd, old);
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            //
+            // Atomic Instructions
+            //
+
+            Lr {width, rs1, rd, ..} => {
+                let (addr, size) = try_exec!(self.amo_addr(*rs1, width, true));
+                let paddr = try_exec!(self.access(mem, addr, size, Access::Load));
+                let fault = |_| Access::Load.access_fault(addr);
+                let val = try_exec!(Self::amo_load(mem, paddr, width).map_err(fault));
+
+                self.reservation = Some(Reservation { addr : paddr, size });
+                self.regw(*rd, val);
+
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            Sc {width, rs1, rs2, rd, ..} => {
+                let (addr, size) = try_exec!(self.amo_addr(*rs1, width, false));
+                let paddr = try_exec!(self.access(mem, addr, size, Access::Store));
+                let success = self.reservation == Some(Reservation { addr : paddr, size });
+
+                if success {
+                    let res = self.write_phys(mem, paddr, self.regr(*rs2), size);
+                    try_exec!(res.map_err(|_| Access::Store.access_fault(addr)));
+                }
+
+                self.reservation = None;
+                self.regw(*rd, if success { 0 } else { 1 });
+
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            Amo {op, width, rs1, rs2, rd, ..} => {
+                let (addr, size) = try_exec!(self.amo_addr(*rs1, width, false));
+
+                // AMOs need write permission and fault as stores
+                let paddr = try_exec!(self.access(mem, addr, size, Access::Store));
+                let fault = |_| Access::Store.access_fault(addr);
+                let old = try_exec!(Self::amo_load(mem, paddr, width).map_err(fault));
+                let src = self.regr(*rs2);
+
+                // Word ops compare the low 32 bits, sign- or zero-extended
+                let (a, b) = match (op, width) {
+                    (AmoOp::Minu, AmoWidth::W) | (AmoOp::Maxu, AmoWidth::W) =>
+                        (old & 0xFFFF_FFFF, src & 0xFFFF_FFFF),
+                    (_, AmoWidth::W) => (old, sign_ext64!(32, src & 0xFFFF_FFFF)),
+                    (_, AmoWidth::D) => (old, src)
+                };
+
+                let new = match op {
+                    AmoOp::Swap => b,
+                    AmoOp::Add => rv64alu::add(a, b),
+                    AmoOp::Xor => rv64alu::xor(a, b),
+                    AmoOp::And => rv64alu::and(a, b),
+                    AmoOp::Or => rv64alu::or(a, b),
+                    AmoOp::Min => if (a as i64) < (b as i64) { a } else { b },
+                    AmoOp::Max => if (a as i64) > (b as i64) { a } else { b },
+                    AmoOp::Minu => a.min(b),
+                    AmoOp::Maxu => a.max(b)
+                };
+
+                try_exec!(self.write_phys(mem, paddr, new, size).map_err(fault));
+                self.regw(*rd, old);
+
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            //
+            // Floating-Point Instructions
+            //
+
+            FLoad {fmt, rs1, rd, imm} => {
+                let addr = rv64alu::add(self.regr(*rs1), *imm);
+
+                let val = match fmt {
+                    FpFmt::S => try_exec!(self.load(mem, addr, 4)),
+                    FpFmt::D => try_exec!(self.load(mem, addr, 8))
+                };
+
+                self.fregw(fmt, *rd, val);
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            FStore {fmt, rs1, rs2, imm} => {
+                let addr = rv64alu::add(self.regr(*rs1), *imm);
+
+                // Stores move the raw bits, NaN-boxed or not
+                let val = self.fregs[*rs2];
+
+                match fmt {
+                    FpFmt::S => try_exec!(self.store(mem, addr, val, 4)),
+                    FpFmt::D => try_exec!(self.store(mem, addr, val, 8))
+                };
+
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            FMadd {fmt, rs1, rs2, rs3, rd, rm} =>
+                fp_fma_inst!(fmt, *rs1, *rs2, *rs3, *rd, *rm, false, false),
+            FMsub {fmt, rs1, rs2, rs3, rd, rm} =>
+                fp_fma_inst!(fmt, *rs1, *rs2, *rs3, *rd, *rm, false, true),
+            FNmsub {fmt, rs1, rs2, rs3, rd, rm} =>
+                fp_fma_inst!(fmt, *rs1, *rs2, *rs3, *rd, *rm, true, false),
+            FNmadd {fmt, rs1, rs2, rs3, rd, rm} =>
+                fp_fma_inst!(fmt, *rs1, *rs2, *rs3, *rd, *rm, true, true),
+
+            FAdd {fmt, rs1, rs2, rd, rm} => fp_op_inst!(fmt, *rs1, *rs2, *rd, *rm, add),
+            FSub {fmt, rs1, rs2, rd, rm} => fp_op_inst!(fmt, *rs1, *rs2, *rd, *rm, sub),
+            FMul {fmt, rs1, rs2, rd, rm} => fp_op_inst!(fmt, *rs1, *rs2, *rd, *rm, mul),
+            FDiv {fmt, rs1, rs2, rd, rm} => fp_op_inst!(fmt, *rs1, *rs2, *rd, *rm, div),
+
+            FSqrt {fmt, rs1, rd, rm} => {
+                let a = self.fregr(fmt, *rs1);
+                fp_inst!(fmt, *rd, *rm, |fp, rm, flags|
+                    rv64fpu::sqrt(fp, a, rm, &mut flags))
+            },
+
+            FSgnj {fmt, rs1, rs2, rd} =>  fp_sgnj_inst!(fmt, *rs1, *rs2, *rd, sgnj),
+            FSgnjn {fmt, rs1, rs2, rd} => fp_sgnj_inst!(fmt, *rs1, *rs2, *rd, sgnjn),
+            FSgnjx {fmt, rs1, rs2, rd} => fp_sgnj_inst!(fmt, *rs1, *rs2, *rd, sgnjx),
+            FMin {fmt, rs1, rs2, rd} =>   fp_minmax_inst!(fmt, *rs1, *rs2, *rd, min),
+            FMax {fmt, rs1, rs2, rd} =>   fp_minmax_inst!(fmt, *rs1, *rs2, *rd, max),
+
+            FCvtSD {rs1, rd, rm} => {
+                let a = self.fregr(&FpFmt::D, *rs1);
+                fp_inst!(&FpFmt::S, *rd, *rm, |fp, rm, flags|
+                    rv64fpu::convert(rv64fpu::F64, fp, a, rm, &mut flags))
+            },
+
+            FCvtDS {rs1, rd, rm} => {
+                let a = self.fregr(&FpFmt::S, *rs1);
+                fp_inst!(&FpFmt::D, *rd, *rm, |fp, rm, flags|
+                    rv64fpu::convert(rv64fpu::F32, fp, a, rm, &mut flags))
+            },
+
+            FCvtToInt {fmt, ity, rs1, rd, rm} => {
+                let (signed, bits) = match ity {
+                    FpIntType::W => (true, 32),
+                    FpIntType::Wu => (false, 32),
+                    FpIntType::L => (true, 64),
+                    FpIntType::Lu => (false, 64)
+                };
+
+                let a = self.fregr(fmt, *rs1);
+                let rm = match self.rounding_mode(*rm) {
+                    Ok(rm) => rm,
+                    Err(reason) => illegal!(reason)
+                };
+                let mut flags = 0;
+                let res = rv64fpu::to_int(
+                    rv64fpu::format(fmt), a, signed, bits, rm, &mut flags);
+
+                self.accrue_fflags(flags);
+                self.regw(*rd, res);
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            FCvtFromInt {fmt, ity, rs1, rd, rm} => {
+                let (signed, bits) = match ity {
+                    FpIntType::W => (true, 32),
+                    FpIntType::Wu => (false, 32),
+                    FpIntType::L => (true, 64),
+                    FpIntType::Lu => (false, 64)
+                };
+
+                let v = self.regr(*rs1);
+                fp_inst!(fmt, *rd, *rm, |fp, rm, flags|
+                    rv64fpu::from_int(fp, v, signed, bits, rm, &mut flags))
+            },
+
+            FEq {fmt, rs1, rs2, rd} => fp_cmp_inst!(fmt, *rs1, *rs2, *rd, eq),
+            FLt {fmt, rs1, rs2, rd} => fp_cmp_inst!(fmt, *rs1, *rs2, *rd, lt),
+            FLe {fmt, rs1, rs2, rd} => fp_cmp_inst!(fmt, *rs1, *rs2, *rd, le),
+
+            FClass {fmt, rs1, rd} => {
+                let a = self.fregr(fmt, *rs1);
+                self.regw(*rd, rv64fpu::classify(rv64fpu::format(fmt), a));
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            FMvXF {fmt, rs1, rd} => {
+                // Moves the raw bits without unboxing
+                let val = match fmt {
+                    FpFmt::S => sign_ext64!(32, self.fregs[*rs1] & 0xFFFF_FFFF),
+                    FpFmt::D => self.fregs[*rs1]
+                };
+
+                self.regw(*rd, val);
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
+            FMvFX {fmt, rs1, rd} => {
+                let val = self.regr(*rs1);
+                self.fregw(fmt, *rd, val);
+                self.pc = rv64alu::add(self.pc, 4);
+                Continue
+            },
+
             //
             // Compressed Quandrant 0 Instructions
             //
@@ -296,13 +1256,19 @@ impl ArchState {
             CLoad {width, rs1, rd, imm} => {
                 let addr = rv64alu::add(self.regr(*rs1), *imm);
 
-                let val = match width {
-                    CLoadStoreWidth::Cfd => panic!("Unimplemented"),
-                    CLoadStoreWidth::Cw => read32(mem, addr),
-                    CLoadStoreWidth::Cd => read64(mem, addr)
+                let size = match width {
+                    CLoadStoreWidth::Cw | CLoadStoreWidth::Cfw => 4,
+                    _ => 8
                 };
+                let val = try_exec!(self.load(mem, addr, size));
 
-                self.regw(*rd, val);
+                match width {
+                    CLoadStoreWidth::Cfw => self.fregw(&FpFmt::S, *rd, val),
+                    CLoadStoreWidth::Cfd => self.fregw(&FpFmt::D, *rd, val),
+                    CLoadStoreWidth::Cw => self.regw(*rd, sign_ext64!(32, val)),
+                    CLoadStoreWidth::Cd => self.regw(*rd, val),
+                    CLoadStoreWidth::Cq => unreachable!()
+                };
 
                 self.pc = rv64alu::add(self.pc, 2);
                 Continue
@@ -310,12 +1276,17 @@ impl ArchState {
 
             CStore {width, rs1, rs2, imm} => {
                 let addr = rv64alu::add(self.regr(*rs1), *imm);
-                let val = self.regr(*rs2);
+                let val = match width {
+                    CLoadStoreWidth::Cfw | CLoadStoreWidth::Cfd => self.fregs[*rs2],
+                    _ => self.regr(*rs2)
+                };
 
                 match width {
-                    CLoadStoreWidth::Cfd => panic!("Unimplemented"),
-                    CLoadStoreWidth::Cw => write32(mem, addr, val.into()),
-                    CLoadStoreWidth::Cd => write64(mem, addr, val.into())
+                    CLoadStoreWidth::Cfw => try_exec!(self.store(mem, addr, val, 4)),
+                    CLoadStoreWidth::Cfd => try_exec!(self.store(mem, addr, val, 8)),
+                    CLoadStoreWidth::Cw => try_exec!(self.store(mem, addr, val, 4)),
+                    CLoadStoreWidth::Cd => try_exec!(self.store(mem, addr, val, 8)),
+                    CLoadStoreWidth::Cq => unreachable!()
                 };
 
                 self.pc = rv64alu::add(self.pc, 2);
@@ -411,22 +1382,31 @@ impl ArchState {
             //
 
             CSlli {rsrd, shamt} =>
-                c_opimm_inst!(*rsrd, *shamt, *rsrd, sll),
+                c_opimm_inst!(*rsrd, *shamt, *rsrd, sll, sllw),
 
             CSrli {rsrd, shamt} =>
-                c_opimm_inst!(*rsrd, *shamt, *rsrd, srl),
+                c_opimm_inst!(*rsrd, *shamt, *rsrd, srl, srlw),
+
+            CSrai {rsrd, shamt} =>
+                c_opimm_inst!(*rsrd, *shamt, *rsrd, sra, sraw),
 
 
             CLoadStack {width, rd, imm} => {
-                let addr = self.regr(2) + *imm;
+                let addr = rv64alu::add(self.regr(2), *imm);
 
-                let val = match width {
-                    CLoadStoreWidth::Cfd => panic!("Unimplemented"),
-                    CLoadStoreWidth::Cw => read32(mem, addr),
-                    CLoadStoreWidth::Cd => read64(mem, addr)
+                let size = match width {
+                    CLoadStoreWidth::Cw | CLoadStoreWidth::Cfw => 4,
+                    _ => 8
                 };
+                let val = try_exec!(self.load(mem, addr, size));
 
-                self.regw(*rd, val);
+                match width {
+                    CLoadStoreWidth::Cfw => self.fregw(&FpFmt::S, *rd, val),
+                    CLoadStoreWidth::Cfd => self.fregw(&FpFmt::D, *rd, val),
+                    CLoadStoreWidth::Cw => self.regw(*rd, sign_ext64!(32, val)),
+                    CLoadStoreWidth::Cd => self.regw(*rd, val),
+                    CLoadStoreWidth::Cq => unreachable!()
+                };
 
                 self.pc = rv64alu::add(self.pc, 2);
                 Continue
@@ -434,12 +1414,17 @@ impl ArchState {
 
             CStoreStack {width, rs2, imm} => {
                 let addr = rv64alu::add(self.regr(2), *imm);
-                let val = self.regr(*rs2);
+                let val = match width {
+                    CLoadStoreWidth::Cfw | CLoadStoreWidth::Cfd => self.fregs[*rs2],
+                    _ => self.regr(*rs2)
+                };
 
                 match width {
-                    CLoadStoreWidth::Cfd => panic!("Unimplemented"),
-                    CLoadStoreWidth::Cw => write32(mem, addr, val.into()),
-                    CLoadStoreWidth::Cd => write64(mem, addr, val.into())
+                    CLoadStoreWidth::Cfw => try_exec!(self.store(mem, addr, val, 4)),
+                    CLoadStoreWidth::Cfd => try_exec!(self.store(mem, addr, val, 8)),
+                    CLoadStoreWidth::Cw => try_exec!(self.store(mem, addr, val, 4)),
+                    CLoadStoreWidth::Cd => try_exec!(self.store(mem, addr, val, 8)),
+                    CLoadStoreWidth::Cq => unreachable!()
                 };
 
                 self.pc = rv64alu::add(self.pc, 2);
@@ -447,14 +1432,14 @@ impl ArchState {
             },
 
             CJr {rs1} => {
-                self.pc = self.regr(*rs1);
+                self.pc = self.regr(*rs1) & !1;
                 Continue
             },
 
 
             CJalr {rs1} => {
                 let next_pc = rv64alu::add(self.pc, 2);
-                self.pc = self.regr(*rs1);
+                self.pc = self.regr(*rs1) & !1;
                 self.regw(1, next_pc);
                 Continue
             },
@@ -465,38 +1450,570 @@ impl ArchState {
                 Continue
             },
 
-            CEBreak => {
-                Halt
-            }
-
-            CSdsp {rs2, imm} => {
-                let addr = self.regr(2) + *imm;
-                write64(mem, addr, self.regr(*rs2));
-                self.pc = rv64alu::add(self.pc, 2);
-                Continue
-            },
-
-            x => panic!("Unimplemented instruction: {:?}", x)
         }
 
     }
 
+    /// An ECALL under the Linux convention: the number in a7 and the
+    /// arguments in a0-a5, zero-extended on RV32.
     pub fn rv64_parse_syscall(&self) -> Syscall {
-        let raw_num = self.regr(17);
+        let reg = |rnum| self.xlen_addr(self.regr(rnum));
 
         Syscall {
-            num : num::FromPrimitive::from_u64(raw_num)
-                .expect(&format!("Unknown syscall: {}", raw_num)),
-            args : [
-                self.regr(10),
-                self.regr(11),
-                self.regr(12),
-                self.regr(13),
-                self.regr(14),
-                self.regr(15),
-                self.regr(16),
-            ]
+            num : reg(17),
+            args : [reg(10), reg(11), reg(12), reg(13), reg(14), reg(15)]
         }
     }
 
+
+}
+
+#[cfg(test)]
+pub fn exec_raw(arch : &mut ArchState, mem : &mut dyn MemIf, raw : u32) -> ExecResult {
+    let rinst = RawInst { raw };
+    match crate::rv64inst::decode_for(&rinst, &arch.isa) {
+        Ok(inst) => arch.exec_inst(mem, &rinst, &inst),
+        Err(e) => arch.raise(Exception::IllegalInstruction(e))
+    }
+}
+
+#[test]
+fn test_exec_fp() {
+    let mut arch = ArchState::new();
+    let mut mem = TestMem::new(0x100);
+
+    arch.fregw(&FpFmt::D, 1, 1.5_f64.to_bits());
+    arch.fregw(&FpFmt::D, 2, 2.25_f64.to_bits());
+
+    // fadd.d f3, f1, f2
+    exec_raw(&mut arch, &mut mem, 0x022081d3);
+    assert_eq!(f64::from_bits(arch.fregs[3]), 3.75);
+
+    // fcvt.w.d x5, f3, rtz
+    exec_raw(&mut arch, &mut mem, 0xc20192d3);
+    assert_eq!(arch.regr(5), 3);
+    assert_eq!(arch.fflags(), rv64fpu::FFLAG_NX);
+
+    // fdiv.d f8, f1, f0 raises DZ
+    exec_raw(&mut arch, &mut mem, 0x1a00f453);
+    assert_eq!(f64::from_bits(arch.fregs[8]), f64::INFINITY);
+    assert_ne!(arch.fflags() & rv64fpu::FFLAG_DZ, 0);
+
+    // Singles are NaN-boxed on write
+    arch.fregw(&FpFmt::S, 1, 2.0_f32.to_bits() as u64);
+    arch.fregw(&FpFmt::S, 2, 3.0_f32.to_bits() as u64);
+    arch.fregw(&FpFmt::S, 3, 1.0_f32.to_bits() as u64);
+
+    // fmadd.s f4, f1, f2, f3, dyn
+    exec_raw(&mut arch, &mut mem, 0x1820f243);
+    assert_eq!(arch.fregs[4], 0xFFFF_FFFF_0000_0000 | 7.0_f32.to_bits() as u64);
+
+    // fmv.x.w x6, f4 sign-extends the raw bits
+    exec_raw(&mut arch, &mut mem, 0xe0020353);
+    assert_eq!(arch.regr(6), 7.0_f32.to_bits() as u64);
+
+    // Improperly boxed singles read as the canonical NaN: fadd.s f5, f9, f1
+    arch.fregs[9] = 1.0_f32.to_bits() as u64;
+    exec_raw(&mut arch, &mut mem, 0x001482d3);
+    assert_eq!(arch.fregs[5], 0xFFFF_FFFF_7FC0_0000);
+
+    // fsd f3, 0x10(x0) stores the raw boxed bits; flw f7, 0x10(x0) reboxes
+    exec_raw(&mut arch, &mut mem, 0x00303827);
+    assert_eq!(read64(&mem, 0x10).unwrap(), 0xFFFF_FFFF_0000_0000 | 1.0_f32.to_bits() as u64);
+    mem.store::<u32>(0x14, 0).unwrap();
+    exec_raw(&mut arch, &mut mem, 0x01002387);
+    assert_eq!(arch.fregs[7], 0xFFFF_FFFF_0000_0000 | 1.0_f32.to_bits() as u64);
+}
+
+#[test]
+fn test_exec_atomics() {
+    let mut arch = ArchState::new();
+    let mut mem = TestMem::new(0x100);
+
+    arch.regw(10, 0x40);
+    write64(&mut mem, 0x40, 5).unwrap();
+
+    // lr.d.aq x5, (x10); sc.d.rl x6, x7, (x10)
+    arch.regw(7, 9);
+    exec_raw(&mut arch, &mut mem, 0x140532af);
+    assert_eq!(arch.regr(5), 5);
+    exec_raw(&mut arch, &mut mem, 0x1a75332f);
+    assert_eq!(arch.regr(6), 0);
+    assert_eq!(read64(&mem, 0x40).unwrap(), 9);
+
+    // A second SC without a fresh LR fails
+    exec_raw(&mut arch, &mut mem, 0x1a75332f);
+    assert_eq!(arch.regr(6), 1);
+
+    // An intervening store to the reserved range breaks the reservation:
+    // lr.d x5, (x10); sw x0, 4(x10); sc.d x6, x7, (x10)
+    exec_raw(&mut arch, &mut mem, 0x100532af);
+    exec_raw(&mut arch, &mut mem, 0x00052223);
+    exec_raw(&mut arch, &mut mem, 0x1875332f);
+    assert_eq!(arch.regr(6), 1);
+
+    // A store elsewhere does not
+    exec_raw(&mut arch, &mut mem, 0x100532af);
+    exec_raw(&mut arch, &mut mem, 0x00052423);
+    exec_raw(&mut arch, &mut mem, 0x1875332f);
+    assert_eq!(arch.regr(6), 0);
+
+    // amoadd.w x5, x7, (x10) sign-extends the old value
+    mem.store::<u32>(0x40, 0xFFFF_FFFF).unwrap();
+    arch.regw(7, 2);
+    exec_raw(&mut arch, &mut mem, 0x007522af);
+    assert_eq!(arch.regr(5), u64::MAX);
+    assert_eq!(read32(&mem, 0x40).unwrap(), 1);
+
+    // amomin.w / amominu.w compare as 32-bit values
+    mem.store::<u32>(0x40, 0x8000_0000).unwrap();
+    arch.regw(7, 1);
+    exec_raw(&mut arch, &mut mem, 0x807522af);
+    assert_eq!(read32(&mem, 0x40).unwrap(), 0x8000_0000);
+    exec_raw(&mut arch, &mut mem, 0xc07522af);
+    assert_eq!(read32(&mem, 0x40).unwrap(), 1);
+
+    // amoswap.d x5, x7, (x10)
+    write64(&mut mem, 0x40, 0x1234_5678_9abc_def0).unwrap();
+    arch.regw(7, 3);
+    exec_raw(&mut arch, &mut mem, 0x087532af);
+    assert_eq!(arch.regr(5), 0x1234_5678_9abc_def0);
+    assert_eq!(read64(&mem, 0x40).unwrap(), 3);
+}
+
+#[test]
+fn test_decode_atomics() {
+    use crate::rv64inst::decode;
+
+    assert_eq!(decode(&RawInst { raw : 0x140532af }), Ok(DecodedInst::Lr {
+        width : AmoWidth::D,
+        ord : AmoOrdering { aq : true, rl : false },
+        rs1 : 10,
+        rd : 5
+    }));
+
+    assert_eq!(decode(&RawInst { raw : 0xe665a2af }), Ok(DecodedInst::Amo {
+        op : AmoOp::Maxu,
+        width : AmoWidth::W,
+        ord : AmoOrdering { aq : true, rl : true },
+        rs1 : 11,
+        rs2 : 6,
+        rd : 5
+    }));
+}
+
+#[test]
+fn test_exec_csr() {
+    let mut arch = ArchState::new();
+    let mut mem = TestMem::new(0x100);
+
+    // csrr x5, instret counts the instructions retired before it
+    exec_raw(&mut arch, &mut mem, 0x00000013);
+    exec_raw(&mut arch, &mut mem, 0xc02022f3);
+    assert_eq!(arch.regr(5), 1);
+
+    // csrr x6, cycle: CSRRS with x0 does not write, so read-only is fine
+    exec_raw(&mut arch, &mut mem, 0xc0002373);
+    assert_eq!(arch.regr(6), 2);
+
+    // csrwi cycle, 1 is illegal and leaves pc on the faulting instruction
+    let pc = arch.pc;
+    assert_eq!(exec_raw(&mut arch, &mut mem, 0xc000d073),
+               ExecResult::Exception(Exception::IllegalInstruction(IllegalInst {
+                   raw : 0xc000d073,
+                   reason : IllegalReason::InvalidCsr(CSR_CYCLE)
+               })));
+    assert_eq!(arch.pc, pc);
+
+    // It does not retire, but an ecall handled by the host does
+    assert_eq!(arch.num_inst, 3);
+    assert_eq!(exec_raw(&mut arch, &mut mem, 0x00000073), ExecResult::Syscall);
+    assert_eq!(arch.num_inst, 4);
+
+    // csrrw x7, fcsr, x6; csrsi fflags, 0b10101; csrr x8, frm
+    exec_raw(&mut arch, &mut mem, 0x003313f3);
+    assert_eq!(arch.regr(7), 0);
+    assert_eq!(arch.fcsr, 2);
+    exec_raw(&mut arch, &mut mem, 0x001ae073);
+    assert_eq!(arch.csr_read(CSR_FFLAGS), Ok(0b10111));
+    exec_raw(&mut arch, &mut mem, 0x00202473);
+    assert_eq!(arch.regr(8), 0);
+
+    // csrr x5, mstatus does not exist in user mode
+    assert!(matches!(exec_raw(&mut arch, &mut mem, 0x300022f3),
+                     ExecResult::Exception(Exception::IllegalInstruction(_))));
+}
+
+#[test]
+fn test_decode_m() {
+    use crate::rv64inst::decode;
+
+    let d = |raw| decode(&RawInst { raw });
+
+    assert_eq!(d(0x02b542b3), Ok(DecodedInst::Div { rs1 : 10, rs2 : 11, rd : 5 }));
+    assert_eq!(d(0x00b542b3), Ok(DecodedInst::Xor { rs1 : 10, rs2 : 11, rd : 5 }));
+    assert_eq!(d(0x02b552b3), Ok(DecodedInst::Divu { rs1 : 10, rs2 : 11, rd : 5 }));
+    assert_eq!(d(0x02b512b3), Ok(DecodedInst::Mulh { rs1 : 10, rs2 : 11, rd : 5 }));
+    assert_eq!(d(0x02b502bb), Ok(DecodedInst::Mulw { rs1 : 10, rs2 : 11, rd : 5 }));
+    assert_eq!(d(0x02b542bb), Ok(DecodedInst::Divw { rs1 : 10, rs2 : 11, rd : 5 }));
+}
+
+#[test]
+fn test_decode_fence() {
+    use crate::rv64inst::decode;
+
+    let d = |raw| decode(&RawInst { raw });
+
+    assert_eq!(d(0x0330000f), Ok(DecodedInst::Fence { pred : 0b0011, succ : 0b0011 }));
+    assert_eq!(d(0x8330000f), Ok(DecodedInst::FenceTso));
+    assert_eq!(d(0x0100000f), Ok(DecodedInst::Pause));
+    assert_eq!(d(0x0000100f), Ok(DecodedInst::FenceI));
+
+    // Reserved fm values and a non-zero rd fall back to a plain fence
+    assert_eq!(d(0x8ff0000f), Ok(DecodedInst::Fence { pred : 0b1111, succ : 0b1111 }));
+    assert_eq!(d(0x0100028f), Ok(DecodedInst::Fence { pred : 0b0001, succ : 0 }));
+    assert!(d(0x0000200f).is_err());
+
+    // FENCE.I needs Zifencei
+    let mut arch = ArchState::new();
+    let mut mem = TestMem::new(0x100);
+    arch.isa = Isa::parse("rv64imac").unwrap();
+    assert!(matches!(exec_raw(&mut arch, &mut mem, 0x0000100f), ExecResult::Exception(_)));
+}
+
+#[test]
+fn test_decode_illegal() {
+    use crate::rv64inst::decode;
+
+    let reason = |raw| decode(&RawInst { raw }).unwrap_err().reason;
+
+    assert_eq!(reason(0x0000), IllegalReason::Reserved("C.ADDI4SPN"));
+    assert_eq!(reason(0xffffffff), IllegalReason::UnknownOpcode);
+    assert!(matches!(reason(0xfeb502b3), IllegalReason::InvalidField(_)));
+
+    let err = decode(&RawInst { raw : 0xffffffff }).unwrap_err();
+    assert_eq!(err.raw, 0xffffffff);
+    assert_eq!(Exception::IllegalInstruction(err).tval(), 0xffffffff);
+}
+
+#[test]
+fn test_exec_bitmanip() {
+    let mut arch = ArchState::new();
+    let mut mem = TestMem::new(0x100);
+
+    // rv64gc traps on bit-manipulation instructions
+    arch.regw(10, 0xFFFF_FFFF_0000_0F00);
+    arch.regw(11, 0x10);
+    assert_eq!(exec_raw(&mut arch, &mut mem, 0x20b522b3),
+               ExecResult::Exception(Exception::IllegalInstruction(IllegalInst {
+                   raw : 0x20b522b3,
+                   reason : IllegalReason::ExtensionDisabled
+               })));
+
+    arch.isa = Isa::parse("rv64gc_zba_zbb_zbs").unwrap();
+
+    let cases : [(u32, u64); 10] = [
+        (0x20b522b3, 0xFFFF_FFFE_0000_1E10),  // sh1add x5, x10, x11
+        (0x40b572b3, 0xFFFF_FFFF_0000_0F00),  // andn x5, x10, x11
+        (0x60051293, 0),                      // clz x5, x10
+        (0x6b855293, 0x000F_0000_FFFF_FFFF),  // rev8 x5, x10
+        (0x2a851293, 0xFFFF_FFFF_0000_0F00),  // bseti x5, x10, 40
+        (0x0835129b, 0x7800),                 // slli.uw x5, x10, 3
+        (0x080542bb, 0x0F00),                 // zext.h x5, x10
+        (0x6045529b, 0x0000_0000_0000_00F0),  // roriw x5, x10, 4
+        (0x28755293, 0xFFFF_FFFF_0000_FF00),  // orc.b x5, x10
+        (0x6025129b, 4)                       // cpopw x5, x10
+    ];
+
+    for (raw, expected) in cases.iter() {
+        assert_eq!(exec_raw(&mut arch, &mut mem, *raw), ExecResult::Continue);
+        assert_eq!(arch.regr(5), *expected, "inst {:08x}", raw);
+    }
+
+    // Extensions can also be removed: rv64imac has no FP CSRs
+    arch.isa = Isa::parse("rv64imac").unwrap();
+    assert_eq!(arch.csr_read(CSR_FCSR), Err(IllegalReason::InvalidCsr(CSR_FCSR)));
+}
+
+/// Reference expansion of a 16-bit instruction into its 32-bit equivalent,
+/// written straight from the RVC tables. None for reserved encodings.
+#[cfg(test)]
+fn expand_rvc(c : u32) -> Option<u32> {
+    let bits = |hi : u32, lo : u32| (c >> lo) & ((1 << (hi - lo + 1)) - 1);
+    let sext = |v : u32, width : u32| ((v << (32 - width)) as i32 >> (32 - width)) as u32;
+
+    let r = |f7 : u32, rs2 : u32, rs1 : u32, f3 : u32, rd : u32, op : u32|
+        (f7 << 25) | (rs2 << 20) | (rs1 << 15) | (f3 << 12) | (rd << 7) | op;
+    let i = |imm : u32, rs1 : u32, f3 : u32, rd : u32, op : u32|
+        ((imm & 0xFFF) << 20) | (rs1 << 15) | (f3 << 12) | (rd << 7) | op;
+    let s = |imm : u32, rs2 : u32, rs1 : u32, f3 : u32, op : u32|
+        ((imm >> 5 & 0x7F) << 25) | (rs2 << 20) | (rs1 << 15) | (f3 << 12) |
+        ((imm & 0x1F) << 7) | op;
+    let b = |imm : u32, rs1 : u32, f3 : u32|
+        ((imm >> 12 & 1) << 31) | ((imm >> 5 & 0x3F) << 25) | (rs1 << 15) |
+        (f3 << 12) | ((imm >> 1 & 0xF) << 8) | ((imm >> 11 & 1) << 7) | 0x63;
+    let j = |imm : u32, rd : u32|
+        ((imm >> 20 & 1) << 31) | ((imm >> 1 & 0x3FF) << 21) | ((imm >> 11 & 1) << 20) |
+        ((imm >> 12 & 0xFF) << 12) | (rd << 7) | 0x6F;
+
+    let rd = bits(11, 7);
+    let rs2 = bits(6, 2);
+    let rdp = bits(4, 2) + 8;
+    let rs1p = bits(9, 7) + 8;
+    let imm6 = sext((bits(12, 12) << 5) | bits(6, 2), 6);
+    let shamt = (bits(12, 12) << 5) | bits(6, 2);
+    let uimm_w = (bits(5, 5) << 6) | (bits(12, 10) << 3) | (bits(6, 6) << 2);
+    let uimm_d = (bits(6, 5) << 6) | (bits(12, 10) << 3);
+    let jimm = sext((bits(12, 12) << 11) | (bits(11, 11) << 4) | (bits(10, 9) << 8) |
+                    (bits(8, 8) << 10) | (bits(7, 7) << 6) | (bits(6, 6) << 7) |
+                    (bits(5, 3) << 1) | (bits(2, 2) << 5), 12);
+    let bimm = sext((bits(12, 12) << 8) | (bits(11, 10) << 3) | (bits(6, 5) << 6) |
+                    (bits(4, 3) << 1) | (bits(2, 2) << 5), 9);
+
+    let inst = match (c & 0b11, bits(15, 13)) {
+        (0, 0) => {
+            let nzuimm = (bits(10, 7) << 6) | (bits(12, 11) << 4) |
+                         (bits(5, 5) << 3) | (bits(6, 6) << 2);
+            if nzuimm == 0 {
+                return None;
+            }
+            i(nzuimm, 2, 0, rdp, 0x13)
+        },
+        (0, 1) => i(uimm_d, rs1p, 3, rdp, 0x07),
+        (0, 2) => i(uimm_w, rs1p, 2, rdp, 0x03),
+        (0, 3) => i(uimm_d, rs1p, 3, rdp, 0x03),
+        (0, 4) => return None,
+        (0, 5) => s(uimm_d, rdp, rs1p, 3, 0x27),
+        (0, 6) => s(uimm_w, rdp, rs1p, 2, 0x23),
+        (0, 7) => s(uimm_d, rdp, rs1p, 3, 0x23),
+
+        (1, 0) => i(imm6, rd, 0, rd, 0x13),
+        (1, 1) if rd == 0 => return None,
+        (1, 1) => i(imm6, rd, 0, rd, 0x1B),
+        (1, 2) => i(imm6, 0, 0, rd, 0x13),
+        (1, 3) if rd == 2 => {
+            let nzimm = sext((bits(12, 12) << 9) | (bits(4, 3) << 7) | (bits(5, 5) << 6) |
+                             (bits(2, 2) << 5) | (bits(6, 6) << 4), 10);
+            if nzimm == 0 {
+                return None;
+            }
+            i(nzimm, 2, 0, 2, 0x13)
+        },
+        (1, 3) => {
+            if imm6 == 0 {
+                return None;
+            }
+            ((imm6 & 0xFFFFF) << 12) | (rd << 7) | 0x37
+        },
+        (1, 4) => match (bits(11, 10), bits(12, 12), bits(6, 5)) {
+            (0, _, _) => i(shamt, rs1p, 5, rs1p, 0x13),
+            (1, _, _) => i(0x400 | shamt, rs1p, 5, rs1p, 0x13),
+            (2, _, _) => i(imm6, rs1p, 7, rs1p, 0x13),
+            (3, 0, 0) => r(0x20, rdp, rs1p, 0, rs1p, 0x33),
+            (3, 0, 1) => r(0, rdp, rs1p, 4, rs1p, 0x33),
+            (3, 0, 2) => r(0, rdp, rs1p, 6, rs1p, 0x33),
+            (3, 0, 3) => r(0, rdp, rs1p, 7, rs1p, 0x33),
+            (3, 1, 0) => r(0x20, rdp, rs1p, 0, rs1p, 0x3B),
+            (3, 1, 1) => r(0, rdp, rs1p, 0, rs1p, 0x3B),
+            _ => return None
+        },
+        (1, 5) => j(jimm, 0),
+        (1, 6) => b(bimm, rs1p, 0),
+        (1, 7) => b(bimm, rs1p, 1),
+
+        (2, 0) => i(shamt, rd, 1, rd, 0x13),
+        (2, 1) => i((bits(4, 2) << 6) | (bits(12, 12) << 5) | (bits(6, 5) << 3), 2, 3, rd, 0x07),
+        (2, 2) if rd == 0 => return None,
+        (2, 2) => i((bits(3, 2) << 6) | (bits(12, 12) << 5) | (bits(6, 4) << 2), 2, 2, rd, 0x03),
+        (2, 3) if rd == 0 => return None,
+        (2, 3) => i((bits(4, 2) << 6) | (bits(12, 12) << 5) | (bits(6, 5) << 3), 2, 3, rd, 0x03),
+        (2, 4) => match (bits(12, 12), rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => i(0, rd, 0, 0, 0x67),
+            (0, _, _) => r(0, rs2, 0, 0, rd, 0x33),
+            (_, 0, 0) => 0x00100073,
+            (_, _, 0) => i(0, rd, 0, 1, 0x67),
+            (_, _, _) => r(0, rs2, rd, 0, rd, 0x33)
+        },
+        (2, 5) => s((bits(9, 7) << 6) | (bits(12, 10) << 3), rs2, 2, 3, 0x27),
+        (2, 6) => s((bits(8, 7) << 6) | (bits(12, 9) << 2), rs2, 2, 2, 0x23),
+        (2, 7) => s((bits(9, 7) << 6) | (bits(12, 10) << 3), rs2, 2, 3, 0x23),
+
+        _ => unreachable!()
+    };
+
+    Some(inst)
+}
+
+/// Decodes and executes every 16-bit encoding next to its 32-bit expansion
+/// and checks that both leave the machine in the same state. Reserved
+/// encodings must be rejected by the decoder.
+#[test]
+fn test_rvc_all_encodings() {
+    use crate::rv64inst::decode;
+
+    let mut base = ArchState::new();
+    let mut base_mem = TestMem::new(0x4000);
+    let mut seed = 0x2545_F491_4F6C_DD1D_u64;
+
+    // Registers hold aligned in-range addresses so that every load and store
+    // hits memory, which is filled with random data. x9 is zero so branches
+    // go both ways.
+    for rn in 1..32 {
+        base.regw(rn, 0x1000 + ((rn as u64) << 6));
+    }
+    base.regw(9, 0);
+
+    for rn in 0..32 {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        base.fregs[rn] = seed;
+    }
+
+    for (n, byte) in base_mem.data.iter_mut().enumerate() {
+        *byte = (n as u8).wrapping_mul(0x9D) ^ 0x5A;
+    }
+
+    base.pc = 0x800;
+
+    for c in 0..=0xFFFF_u32 {
+        if c & 0b11 == 0b11 {
+            continue;
+        }
+
+        let rinst = RawInst { raw : c };
+        let decoded = decode(&rinst);
+
+        let expanded = match expand_rvc(c) {
+            Some(expanded) => expanded,
+            None => {
+                assert!(decoded.is_err(), "{:04x} is reserved but decoded as {:?}", c, decoded);
+                continue;
+            }
+        };
+
+        let decoded = decoded.unwrap_or_else(|e| panic!("{:04x} failed to decode: {}", c, e));
+        let rinst_e = RawInst { raw : expanded };
+        let expanded_inst = decode(&rinst_e).unwrap();
+
+        let (mut arch_c, mut mem_c) = (base.clone(), base_mem.clone());
+        let (mut arch_e, mut mem_e) = (base.clone(), base_mem.clone());
+
+        let res_c = arch_c.exec_inst(&mut mem_c, &rinst, &decoded);
+        let res_e = arch_e.exec_inst(&mut mem_e, &rinst_e, &expanded_inst);
+
+        // The 32-bit form falls through or links 4 bytes past pc instead of 2
+        let control_flow = matches!(expanded_inst,
+            DecodedInst::Jal {..} | DecodedInst::Jalr {..} | DecodedInst::Branch {..});
+
+        if arch_e.pc == base.pc + 4 && (!control_flow || arch_c.pc == base.pc + 2) {
+            arch_e.pc -= 2;
+        }
+
+        if let DecodedInst::Jalr {rd : 1, ..} | DecodedInst::Jal {rd : 1, ..} = expanded_inst {
+            arch_e.regs[1] -= 2;
+        }
+
+        assert_eq!(res_c, res_e, "{:04x}: {:?} vs {:?}", c, decoded, expanded_inst);
+        assert_eq!(arch_c.pc, arch_e.pc, "{:04x}: {:?} vs {:?}", c, decoded, expanded_inst);
+        assert_eq!(arch_c.regs, arch_e.regs, "{:04x}: {:?} vs {:?}", c, decoded, expanded_inst);
+        assert_eq!(arch_c.fregs, arch_e.fregs, "{:04x}: {:?} vs {:?}", c, decoded, expanded_inst);
+        assert!(mem_c.data == mem_e.data, "{:04x}: {:?} vs {:?}", c, decoded, expanded_inst);
+    }
+}
+
+#[test]
+fn test_rv32() {
+    use crate::rv64inst::decode_for;
+
+    let mut arch = ArchState::new();
+    let mut mem = TestMem::new(0x100);
+    arch.isa = Isa::parse("rv32imac_zbb").unwrap();
+
+    // lui x5, 0x80000; addi x5, x5, -1; addi x5, x5, 1 wraps at 32 bits and
+    // keeps registers sign-extended
+    exec_raw(&mut arch, &mut mem, 0x800002b7);
+    exec_raw(&mut arch, &mut mem, 0xfff28293);
+    assert_eq!(arch.regr(5), 0x7FFF_FFFF);
+    exec_raw(&mut arch, &mut mem, 0x00128293);
+    assert_eq!(arch.regr(5), 0xFFFF_FFFF_8000_0000);
+
+    // srli/srai x6, x5, 4 see a 32-bit value
+    exec_raw(&mut arch, &mut mem, 0x0042d313);
+    assert_eq!(arch.regr(6), 0x0800_0000);
+    exec_raw(&mut arch, &mut mem, 0x4042d313);
+    assert_eq!(arch.regr(6), 0xFFFF_FFFF_F800_0000);
+
+    // sll x6, x5, x7 uses the low five bits of the shift amount
+    arch.regw(5, 1);
+    arch.regw(7, 33);
+    exec_raw(&mut arch, &mut mem, 0x00729333);
+    assert_eq!(arch.regr(6), 2);
+
+    // mulhu/divu x6, x5, x7
+    arch.regw(5, 0xFFFF_FFFF);
+    arch.regw(7, 0xFFFF_FFFF);
+    exec_raw(&mut arch, &mut mem, 0x0272b333);
+    assert_eq!(arch.regr(6), 0xFFFF_FFFF_FFFF_FFFE);
+    arch.regw(5, 0x8000_0000);
+    arch.regw(7, 2);
+    exec_raw(&mut arch, &mut mem, 0x0272d333);
+    assert_eq!(arch.regr(6), 0x4000_0000);
+
+    // rev8 has its own RV32 encoding
+    arch.regw(5, 0x0102_0380);
+    exec_raw(&mut arch, &mut mem, 0x6982d313);
+    assert_eq!(arch.regr(6), 0xFFFF_FFFF_8003_0201);
+
+    // RV64-only encodings: slli by 32, addw, ld
+    let reason = |raw| decode_for(&RawInst { raw }, &arch.isa).unwrap_err().reason;
+    assert_eq!(reason(0x02029293), IllegalReason::WrongXlen);
+    assert_eq!(reason(0x00b502bb), IllegalReason::WrongXlen);
+    assert_eq!(reason(0x00053283), IllegalReason::WrongXlen);
+    assert_eq!(reason(0x6b82d313), IllegalReason::WrongXlen);
+
+    // C.JAL and C.FLW replace C.ADDIW and C.LD
+    let rv64 = Isa::default();
+    assert_eq!(decode_for(&RawInst { raw : 0x2001 }, &arch.isa),
+               Ok(DecodedInst::CJal { imm : 0 }));
+    assert!(decode_for(&RawInst { raw : 0x2001 }, &rv64).is_err());
+    assert_eq!(decode_for(&RawInst { raw : 0x6000 }, &arch.isa),
+               Ok(DecodedInst::CLoad { width : CLoadStoreWidth::Cfw, rs1 : 8, rd : 8, imm : 0 }));
+
+    // lw x5, 8(x10) with x10 = -4 wraps to address 4
+    mem.store::<u32>(4, 0x8000_0000).unwrap();
+    arch.regw(10, (-4_i64) as u64);
+    exec_raw(&mut arch, &mut mem, 0x00852283);
+    assert_eq!(arch.regr(5), 0xFFFF_FFFF_8000_0000);
+
+    // pc wraps too: jal x1, 8 from 0xFFFFFFF8
+    arch.pc = 0xFFFF_FFF8;
+    exec_raw(&mut arch, &mut mem, 0x008000ef);
+    assert_eq!(arch.pc, 0);
+    assert_eq!(arch.regr(1), 0xFFFF_FFFF_FFFF_FFFC);
+}
+
+#[test]
+fn test_rv32e() {
+    use crate::rv64inst::decode_for;
+
+    let isa = Isa::parse("rv32ec").unwrap();
+    let reason = |raw| decode_for(&RawInst { raw }, &isa).map_err(|e| e.reason);
+
+    // add x15, x0, x0 is fine, add x16, x0, x0 is not
+    assert!(reason(0x000007b3).is_ok());
+    assert_eq!(reason(0x00000833), Err(IllegalReason::InvalidRegister(16)));
+    assert_eq!(reason(0x01000033), Err(IllegalReason::InvalidRegister(16)));
+
+    // c.mv x1, x16 and c.li x17, 0
+    assert_eq!(reason(0x80c2), Err(IllegalReason::InvalidRegister(16)));
+    assert_eq!(reason(0x4881), Err(IllegalReason::InvalidRegister(17)));
+
+    // csrrwi x1, mscratch, 31 only names x1
+    assert!(reason(0x340fd0f3).is_ok());
+
+    let mut arch = ArchState::new_system();
+    arch.isa = isa;
+    assert_eq!(arch.csr_read(CSR_MISA), Ok(1 << 30 | 0x140014));
 }
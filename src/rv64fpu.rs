@@ -0,0 +1,986 @@
+use crate::rv64defs::*;
+
+//
+// Software IEEE 754 binary32/binary64 arithmetic with RISC-V semantics
+// (canonical NaNs, saturating conversions, tininess after rounding). Values
+// are passed as raw bit patterns so results never depend on the host FPU.
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive)]
+pub enum RoundingMode {
+    Rne = 0b000,
+    Rtz = 0b001,
+    Rdn = 0b010,
+    Rup = 0b011,
+    Rmm = 0b100
+}
+
+pub const FFLAG_NX : u32 = 1 << 0;
+pub const FFLAG_UF : u32 = 1 << 1;
+pub const FFLAG_OF : u32 = 1 << 2;
+pub const FFLAG_DZ : u32 = 1 << 3;
+pub const FFLAG_NV : u32 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FpFormat {
+    exp_bits : u32,
+    frac_bits : u32
+}
+
+pub const F32 : FpFormat = FpFormat { exp_bits : 8, frac_bits : 23 };
+pub const F64 : FpFormat = FpFormat { exp_bits : 11, frac_bits : 52 };
+
+#[inline(always)]
+pub fn format(fmt : &FpFmt) -> FpFormat {
+    match fmt {
+        FpFmt::S => F32,
+        FpFmt::D => F64
+    }
+}
+
+impl FpFormat {
+    #[inline(always)]
+    fn bias(&self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    #[inline(always)]
+    fn max_exp(&self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    /// Exponent of the least significant bit of a subnormal.
+    #[inline(always)]
+    fn min_exp(&self) -> i32 {
+        1 - self.bias() - self.frac_bits as i32
+    }
+
+    #[inline(always)]
+    pub fn sign_bit(&self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+
+    #[inline(always)]
+    fn frac_mask(&self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+
+    #[inline(always)]
+    pub fn canonical_nan(&self) -> u64 {
+        (self.max_exp() << self.frac_bits) | (1 << (self.frac_bits - 1))
+    }
+
+    #[inline(always)]
+    fn zero(&self, sign : bool) -> u64 {
+        if sign { self.sign_bit() } else { 0 }
+    }
+
+    #[inline(always)]
+    fn inf(&self, sign : bool) -> u64 {
+        self.zero(sign) | (self.max_exp() << self.frac_bits)
+    }
+
+    #[inline(always)]
+    fn max_finite(&self, sign : bool) -> u64 {
+        self.zero(sign) | (((self.max_exp() - 1) << self.frac_bits) | self.frac_mask())
+    }
+
+    #[inline(always)]
+    fn sign(&self, v : u64) -> bool {
+        v & self.sign_bit() != 0
+    }
+
+    #[inline(always)]
+    fn exp(&self, v : u64) -> u64 {
+        (v >> self.frac_bits) & self.max_exp()
+    }
+
+    #[inline(always)]
+    fn frac(&self, v : u64) -> u64 {
+        v & self.frac_mask()
+    }
+
+    #[inline(always)]
+    fn is_nan(&self, v : u64) -> bool {
+        self.exp(v) == self.max_exp() && self.frac(v) != 0
+    }
+
+    #[inline(always)]
+    fn is_snan(&self, v : u64) -> bool {
+        self.is_nan(v) && v & (1 << (self.frac_bits - 1)) == 0
+    }
+
+    #[inline(always)]
+    fn is_inf(&self, v : u64) -> bool {
+        self.exp(v) == self.max_exp() && self.frac(v) == 0
+    }
+
+    #[inline(always)]
+    fn is_zero(&self, v : u64) -> bool {
+        v & !self.sign_bit() == 0
+    }
+
+    /// Finite nonzero value as (sign, exp, sig) with value = sig * 2^exp.
+    #[inline(always)]
+    fn unpack(&self, v : u64) -> (bool, i32, u128) {
+        let exp = self.exp(v);
+        let frac = self.frac(v);
+
+        if exp == 0 {
+            (self.sign(v), self.min_exp(), frac as u128)
+        }
+        else {
+            (
+                self.sign(v),
+                exp as i32 - self.bias() - self.frac_bits as i32,
+                (frac | (1 << self.frac_bits)) as u128
+            )
+        }
+    }
+}
+
+#[inline(always)]
+fn msb(v : u128) -> i32 {
+    127 - v.leading_zeros() as i32
+}
+
+/// Shifts sig so its most significant bit lands on bit `to`.
+#[inline(always)]
+fn normalize(exp : i32, sig : u128, to : i32) -> (i32, u128) {
+    let shift = to - msb(sig);
+    (exp - shift, sig << shift)
+}
+
+/// Right shift that ORs every shifted-out bit into the lsb.
+#[inline(always)]
+fn shift_right_jam(sig : u128, shift : i32) -> u128 {
+    if shift <= 0 {
+        sig
+    }
+    else if shift >= 128 {
+        (sig != 0) as u128
+    }
+    else {
+        (sig >> shift) | ((sig & ((1 << shift) - 1)) != 0) as u128
+    }
+}
+
+/// Divides sig by 2^shift, rounding per rm. Returns the rounded value and
+/// whether any nonzero bits were discarded.
+#[inline(always)]
+fn shift_round(sig : u128, shift : i32, sign : bool, rm : RoundingMode) -> (u128, bool) {
+    if shift <= 0 {
+        return (sig << -shift, false);
+    }
+
+    let (kept, rem, half) = if shift > 128 {
+        // Entire value is below half an ulp
+        (0, (sig != 0) as u128, 2)
+    }
+    else if shift == 128 {
+        (0, sig, 1 << 127)
+    }
+    else {
+        (sig >> shift, sig & ((1 << shift) - 1), 1 << (shift - 1))
+    };
+
+    let inexact = rem != 0;
+
+    let inc = match rm {
+        RoundingMode::Rne => rem > half || (rem == half && kept & 1 == 1),
+        RoundingMode::Rtz => false,
+        RoundingMode::Rdn => sign && inexact,
+        RoundingMode::Rup => !sign && inexact,
+        RoundingMode::Rmm => rem >= half
+    };
+
+    (kept + inc as u128, inexact)
+}
+
+/// Rounds sig * 2^exp to the format, raising NX/UF/OF as needed.
+fn round_pack(
+    fmt : FpFormat, sign : bool, exp : i32, sig : u128,
+    rm : RoundingMode, flags : &mut u32) -> u64
+{
+    if sig == 0 {
+        return fmt.zero(sign);
+    }
+
+    let frac_bits = fmt.frac_bits as i32;
+    let emin = 1 - fmt.bias();
+    let e_val = exp + msb(sig);
+    let q = std::cmp::max(e_val - frac_bits, emin - frac_bits);
+
+    let (m, inexact) = shift_round(sig, q - exp, sign, rm);
+
+    // Tininess is detected after rounding: a value just below 2^emin that
+    // rounds up to it (with unbounded exponent) is not tiny.
+    let tiny = if e_val < emin - 1 {
+        true
+    }
+    else if e_val == emin - 1 {
+        let (m_unbounded, _) = shift_round(sig, e_val - frac_bits - exp, sign, rm);
+        m_unbounded < (1 << (frac_bits + 1))
+    }
+    else {
+        false
+    };
+
+    let (m, q) = if m == 1 << (frac_bits + 1) { (m >> 1, q + 1) } else { (m, q) };
+
+    let bits = if m >= 1 << frac_bits {
+        let biased = (q + frac_bits + fmt.bias()) as u64;
+
+        if biased >= fmt.max_exp() {
+            *flags |= FFLAG_OF | FFLAG_NX;
+
+            return match rm {
+                RoundingMode::Rne | RoundingMode::Rmm => fmt.inf(sign),
+                RoundingMode::Rtz => fmt.max_finite(sign),
+                RoundingMode::Rdn => if sign { fmt.inf(sign) } else { fmt.max_finite(sign) },
+                RoundingMode::Rup => if sign { fmt.max_finite(sign) } else { fmt.inf(sign) }
+            };
+        }
+
+        fmt.zero(sign) | (biased << fmt.frac_bits) | (m as u64 & fmt.frac_mask())
+    }
+    else {
+        fmt.zero(sign) | m as u64
+    };
+
+    if inexact {
+        *flags |= FFLAG_NX;
+
+        if tiny {
+            *flags |= FFLAG_UF;
+        }
+    }
+
+    bits
+}
+
+#[inline(always)]
+fn propagate_nan(fmt : FpFormat, a : u64, b : u64, flags : &mut u32) -> u64 {
+    if fmt.is_snan(a) || fmt.is_snan(b) {
+        *flags |= FFLAG_NV;
+    }
+
+    fmt.canonical_nan()
+}
+
+#[inline(always)]
+fn invalid(fmt : FpFormat, flags : &mut u32) -> u64 {
+    *flags |= FFLAG_NV;
+    fmt.canonical_nan()
+}
+
+/// Exact sum of two finite values given as (sign, exp, sig) with the most
+/// significant bits at `width`, followed by rounding.
+#[allow(clippy::too_many_arguments)]
+fn add_unpacked(
+    fmt : FpFormat,
+    (sa, ea, ma) : (bool, i32, u128),
+    (sb, eb, mb) : (bool, i32, u128),
+    width : i32, rm : RoundingMode, flags : &mut u32) -> u64
+{
+    let (ea, ma) = normalize(ea, ma, width);
+    let (eb, mb) = normalize(eb, mb, width);
+
+    let ((sa, ea, ma), (sb, _, mb)) = if ea >= eb {
+        ((sa, ea, ma), (sb, eb, shift_right_jam(mb, ea - eb)))
+    }
+    else {
+        ((sb, eb, mb), (sa, ea, shift_right_jam(ma, eb - ea)))
+    };
+
+    let (sign, m) = if sa == sb {
+        (sa, ma + mb)
+    }
+    else if ma >= mb {
+        (sa, ma - mb)
+    }
+    else {
+        (sb, mb - ma)
+    };
+
+    if m == 0 {
+        fmt.zero(rm == RoundingMode::Rdn)
+    }
+    else {
+        round_pack(fmt, sign, ea, m, rm, flags)
+    }
+}
+
+pub fn add(fmt : FpFormat, a : u64, b : u64, rm : RoundingMode, flags : &mut u32) -> u64 {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        return propagate_nan(fmt, a, b, flags);
+    }
+
+    if fmt.is_inf(a) {
+        if fmt.is_inf(b) && fmt.sign(a) != fmt.sign(b) {
+            return invalid(fmt, flags);
+        }
+        return a;
+    }
+
+    if fmt.is_inf(b) {
+        return b;
+    }
+
+    if fmt.is_zero(a) && fmt.is_zero(b) {
+        return if fmt.sign(a) == fmt.sign(b) { a } else { fmt.zero(rm == RoundingMode::Rdn) };
+    }
+
+    if fmt.is_zero(a) {
+        return b;
+    }
+
+    if fmt.is_zero(b) {
+        return a;
+    }
+
+    add_unpacked(fmt, fmt.unpack(a), fmt.unpack(b), 100, rm, flags)
+}
+
+pub fn sub(fmt : FpFormat, a : u64, b : u64, rm : RoundingMode, flags : &mut u32) -> u64 {
+    add(fmt, a, b ^ fmt.sign_bit(), rm, flags)
+}
+
+pub fn mul(fmt : FpFormat, a : u64, b : u64, rm : RoundingMode, flags : &mut u32) -> u64 {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        return propagate_nan(fmt, a, b, flags);
+    }
+
+    let sign = fmt.sign(a) ^ fmt.sign(b);
+
+    if fmt.is_inf(a) || fmt.is_inf(b) {
+        if fmt.is_zero(a) || fmt.is_zero(b) {
+            return invalid(fmt, flags);
+        }
+        return fmt.inf(sign);
+    }
+
+    if fmt.is_zero(a) || fmt.is_zero(b) {
+        return fmt.zero(sign);
+    }
+
+    let (_, ea, ma) = fmt.unpack(a);
+    let (_, eb, mb) = fmt.unpack(b);
+
+    round_pack(fmt, sign, ea + eb, ma * mb, rm, flags)
+}
+
+/// a * b + c with a single rounding.
+pub fn muladd(
+    fmt : FpFormat, a : u64, b : u64, c : u64,
+    rm : RoundingMode, flags : &mut u32) -> u64
+{
+    let inf_times_zero =
+        (fmt.is_inf(a) && fmt.is_zero(b)) || (fmt.is_zero(a) && fmt.is_inf(b));
+
+    if fmt.is_nan(a) || fmt.is_nan(b) || fmt.is_nan(c) {
+        // The invalid product is flagged even if the addend is a quiet NaN
+        if inf_times_zero || fmt.is_snan(c) {
+            *flags |= FFLAG_NV;
+        }
+        return propagate_nan(fmt, a, b, flags);
+    }
+
+    let sp = fmt.sign(a) ^ fmt.sign(b);
+
+    if inf_times_zero {
+        return invalid(fmt, flags);
+    }
+
+    if fmt.is_inf(a) || fmt.is_inf(b) {
+        if fmt.is_inf(c) && fmt.sign(c) != sp {
+            return invalid(fmt, flags);
+        }
+        return fmt.inf(sp);
+    }
+
+    if fmt.is_inf(c) {
+        return c;
+    }
+
+    if fmt.is_zero(a) || fmt.is_zero(b) {
+        if fmt.is_zero(c) {
+            return if fmt.sign(c) == sp { c } else { fmt.zero(rm == RoundingMode::Rdn) };
+        }
+        return c;
+    }
+
+    let (_, ea, ma) = fmt.unpack(a);
+    let (_, eb, mb) = fmt.unpack(b);
+
+    if fmt.is_zero(c) {
+        return round_pack(fmt, sp, ea + eb, ma * mb, rm, flags);
+    }
+
+    add_unpacked(fmt, (sp, ea + eb, ma * mb), fmt.unpack(c), 120, rm, flags)
+}
+
+pub fn div(fmt : FpFormat, a : u64, b : u64, rm : RoundingMode, flags : &mut u32) -> u64 {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        return propagate_nan(fmt, a, b, flags);
+    }
+
+    let sign = fmt.sign(a) ^ fmt.sign(b);
+
+    if fmt.is_inf(a) {
+        if fmt.is_inf(b) {
+            return invalid(fmt, flags);
+        }
+        return fmt.inf(sign);
+    }
+
+    if fmt.is_inf(b) {
+        return fmt.zero(sign);
+    }
+
+    if fmt.is_zero(b) {
+        if fmt.is_zero(a) {
+            return invalid(fmt, flags);
+        }
+        *flags |= FFLAG_DZ;
+        return fmt.inf(sign);
+    }
+
+    if fmt.is_zero(a) {
+        return fmt.zero(sign);
+    }
+
+    let (_, ea, ma) = fmt.unpack(a);
+    let (_, eb, mb) = fmt.unpack(b);
+    let (ea, ma) = normalize(ea, ma, 116);
+    let (eb, mb) = normalize(eb, mb, 52);
+
+    let q = (ma / mb) | ((ma % mb != 0) as u128);
+
+    round_pack(fmt, sign, ea - eb, q, rm, flags)
+}
+
+#[inline(always)]
+fn isqrt(v : u128) -> u128 {
+    let mut rem = v;
+    let mut res : u128 = 0;
+    let mut bit : u128 = 1 << 126;
+
+    while bit > v {
+        bit >>= 2;
+    }
+
+    while bit != 0 {
+        if rem >= res + bit {
+            rem -= res + bit;
+            res = (res >> 1) + bit;
+        }
+        else {
+            res >>= 1;
+        }
+        bit >>= 2;
+    }
+
+    res
+}
+
+pub fn sqrt(fmt : FpFormat, a : u64, rm : RoundingMode, flags : &mut u32) -> u64 {
+    if fmt.is_nan(a) {
+        return propagate_nan(fmt, a, a, flags);
+    }
+
+    if fmt.is_zero(a) {
+        return a;
+    }
+
+    if fmt.sign(a) {
+        return invalid(fmt, flags);
+    }
+
+    if fmt.is_inf(a) {
+        return a;
+    }
+
+    let (_, e, m) = fmt.unpack(a);
+    let (e, m) = normalize(e, m, 124);
+    let (e, m) = if e & 1 != 0 { (e - 1, m << 1) } else { (e, m) };
+
+    let r = isqrt(m);
+    let r = r | ((r * r != m) as u128);
+
+    round_pack(fmt, false, e / 2, r, rm, flags)
+}
+
+/// Converts between formats (FCVT.S.D / FCVT.D.S).
+pub fn convert(
+    from : FpFormat, to : FpFormat, a : u64,
+    rm : RoundingMode, flags : &mut u32) -> u64
+{
+    if from.is_nan(a) {
+        if from.is_snan(a) {
+            *flags |= FFLAG_NV;
+        }
+        return to.canonical_nan();
+    }
+
+    if from.is_inf(a) {
+        return to.inf(from.sign(a));
+    }
+
+    if from.is_zero(a) {
+        return to.zero(from.sign(a));
+    }
+
+    let (sign, e, m) = from.unpack(a);
+    round_pack(to, sign, e, m, rm, flags)
+}
+
+/// FCVT.{W,WU,L,LU}: rounds to an integer of the given width, saturating
+/// out-of-range inputs. 32-bit results are sign-extended to 64 bits.
+pub fn to_int(
+    fmt : FpFormat, a : u64, signed : bool, bits : u32,
+    rm : RoundingMode, flags : &mut u32) -> u64
+{
+    let max : i128 = if signed { (1 << (bits - 1)) - 1 } else { (1 << bits) - 1 };
+    let min : i128 = if signed { -(1 << (bits - 1)) } else { 0 };
+
+    let val : i128 = if fmt.is_nan(a) {
+        *flags |= FFLAG_NV;
+        max
+    }
+    else if fmt.is_inf(a) {
+        *flags |= FFLAG_NV;
+        if fmt.sign(a) { min } else { max }
+    }
+    else if fmt.is_zero(a) {
+        0
+    }
+    else {
+        let (sign, e, m) = fmt.unpack(a);
+
+        let (mag, inexact) = if msb(m) + e >= 66 {
+            // Far out of range for any supported width
+            (1 << 66, false)
+        }
+        else {
+            shift_round(m, -e, sign, rm)
+        };
+
+        let val = if sign { -(mag as i128) } else { mag as i128 };
+
+        if val > max {
+            *flags |= FFLAG_NV;
+            max
+        }
+        else if val < min {
+            *flags |= FFLAG_NV;
+            min
+        }
+        else {
+            if inexact {
+                *flags |= FFLAG_NX;
+            }
+            val
+        }
+    };
+
+    if bits == 32 {
+        val as i32 as i64 as u64
+    }
+    else {
+        val as u64
+    }
+}
+
+/// FCVT.{S,D}.{W,WU,L,LU}: only the low `bits` of v are used.
+pub fn from_int(
+    fmt : FpFormat, v : u64, signed : bool, bits : u32,
+    rm : RoundingMode, flags : &mut u32) -> u64
+{
+    let val : i128 = match (signed, bits) {
+        (true, 32) => v as i32 as i128,
+        (false, 32) => v as u32 as i128,
+        (true, _) => v as i64 as i128,
+        (false, _) => v as i128
+    };
+
+    round_pack(fmt, val < 0, 0, val.unsigned_abs(), rm, flags)
+}
+
+#[inline(always)]
+fn lt_quiet(fmt : FpFormat, a : u64, b : u64) -> bool {
+    if fmt.is_zero(a) && fmt.is_zero(b) {
+        false
+    }
+    else if fmt.sign(a) != fmt.sign(b) {
+        fmt.sign(a)
+    }
+    else if fmt.sign(a) {
+        a > b
+    }
+    else {
+        a < b
+    }
+}
+
+/// FEQ: quiet comparison, only signaling NaNs raise NV.
+pub fn eq(fmt : FpFormat, a : u64, b : u64, flags : &mut u32) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        if fmt.is_snan(a) || fmt.is_snan(b) {
+            *flags |= FFLAG_NV;
+        }
+        false
+    }
+    else {
+        a == b || (fmt.is_zero(a) && fmt.is_zero(b))
+    }
+}
+
+/// FLT: signaling comparison, any NaN raises NV.
+pub fn lt(fmt : FpFormat, a : u64, b : u64, flags : &mut u32) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        *flags |= FFLAG_NV;
+        false
+    }
+    else {
+        lt_quiet(fmt, a, b)
+    }
+}
+
+/// FLE: signaling comparison, any NaN raises NV.
+pub fn le(fmt : FpFormat, a : u64, b : u64, flags : &mut u32) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        *flags |= FFLAG_NV;
+        false
+    }
+    else {
+        !lt_quiet(fmt, b, a)
+    }
+}
+
+/// FMIN: IEEE 754-2019 minimumNumber, with -0 < +0.
+pub fn min(fmt : FpFormat, a : u64, b : u64, flags : &mut u32) -> u64 {
+    if fmt.is_snan(a) || fmt.is_snan(b) {
+        *flags |= FFLAG_NV;
+    }
+
+    match (fmt.is_nan(a), fmt.is_nan(b)) {
+        (true, true) => fmt.canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ => if lt_quiet(fmt, a, b) || (fmt.is_zero(b) && fmt.sign(a)) { a } else { b }
+    }
+}
+
+/// FMAX: IEEE 754-2019 maximumNumber, with -0 < +0.
+pub fn max(fmt : FpFormat, a : u64, b : u64, flags : &mut u32) -> u64 {
+    if fmt.is_snan(a) || fmt.is_snan(b) {
+        *flags |= FFLAG_NV;
+    }
+
+    match (fmt.is_nan(a), fmt.is_nan(b)) {
+        (true, true) => fmt.canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ => if lt_quiet(fmt, b, a) || (fmt.is_zero(b) && !fmt.sign(a)) { a } else { b }
+    }
+}
+
+#[inline(always)]
+pub fn sgnj(fmt : FpFormat, a : u64, b : u64) -> u64 {
+    (a & !fmt.sign_bit()) | (b & fmt.sign_bit())
+}
+
+#[inline(always)]
+pub fn sgnjn(fmt : FpFormat, a : u64, b : u64) -> u64 {
+    (a & !fmt.sign_bit()) | (!b & fmt.sign_bit())
+}
+
+#[inline(always)]
+pub fn sgnjx(fmt : FpFormat, a : u64, b : u64) -> u64 {
+    a ^ (b & fmt.sign_bit())
+}
+
+/// FCLASS result mask.
+pub fn classify(fmt : FpFormat, a : u64) -> u64 {
+    let sign = fmt.sign(a);
+
+    let bit = if fmt.is_inf(a) {
+        if sign { 0 } else { 7 }
+    }
+    else if fmt.is_nan(a) {
+        if fmt.is_snan(a) { 8 } else { 9 }
+    }
+    else if fmt.is_zero(a) {
+        if sign { 3 } else { 4 }
+    }
+    else if fmt.exp(a) == 0 {
+        if sign { 2 } else { 5 }
+    }
+    else if sign { 1 } else { 6 };
+
+    1 << bit
+}
+
+#[cfg(test)]
+struct TestRng(u64);
+
+#[cfg(test)]
+impl TestRng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Random bits biased towards interesting exponents and special values.
+    fn float(&mut self, fmt : FpFormat) -> u64 {
+        let r = self.next();
+        let raw = r & (fmt.sign_bit() | (fmt.sign_bit() - 1));
+        let exp = match r >> 60 {
+            0 => 0,
+            1 => fmt.max_exp(),
+            2 => 1,
+            3 => fmt.max_exp() - 1,
+            4..=7 => fmt.bias() as u64 + (r >> 40) % 8,
+            _ => return raw
+        };
+        (raw & !(fmt.max_exp() << fmt.frac_bits)) | (exp << fmt.frac_bits)
+    }
+}
+
+#[cfg(test)]
+fn same_f64(res : u64, host : f64) -> bool {
+    if host.is_nan() { res == F64.canonical_nan() } else { res == host.to_bits() }
+}
+
+#[cfg(test)]
+fn same_f32(res : u64, host : f32) -> bool {
+    if host.is_nan() { res == F32.canonical_nan() } else { res == host.to_bits() as u64 }
+}
+
+#[test]
+fn test_arith_matches_host_f64() {
+    let mut rng = TestRng(0x1234_5678_9abc_def1);
+    let rne = RoundingMode::Rne;
+    let mut flags = 0;
+
+    for _ in 0..200_000 {
+        let (a, b, c) = (rng.float(F64), rng.float(F64), rng.float(F64));
+        let (fa, fb, fc) = (f64::from_bits(a), f64::from_bits(b), f64::from_bits(c));
+
+        assert!(same_f64(add(F64, a, b, rne, &mut flags), fa + fb), "{:x} + {:x}", a, b);
+        assert!(same_f64(sub(F64, a, b, rne, &mut flags), fa - fb), "{:x} - {:x}", a, b);
+        assert!(same_f64(mul(F64, a, b, rne, &mut flags), fa * fb), "{:x} * {:x}", a, b);
+        assert!(same_f64(div(F64, a, b, rne, &mut flags), fa / fb), "{:x} / {:x}", a, b);
+        assert!(same_f64(sqrt(F64, a, rne, &mut flags), fa.sqrt()), "sqrt {:x}", a);
+        assert!(same_f64(muladd(F64, a, b, c, rne, &mut flags), fa.mul_add(fb, fc)),
+            "fma {:x} {:x} {:x}", a, b, c);
+        assert!(same_f32(convert(F64, F32, a, rne, &mut flags), fa as f32), "cvt {:x}", a);
+    }
+}
+
+#[test]
+fn test_arith_matches_host_f32() {
+    let mut rng = TestRng(0x0fed_cba9_8765_4321);
+    let rne = RoundingMode::Rne;
+    let mut flags = 0;
+
+    for _ in 0..200_000 {
+        let (a, b, c) = (rng.float(F32), rng.float(F32), rng.float(F32));
+        let (fa, fb, fc) = (
+            f32::from_bits(a as u32), f32::from_bits(b as u32), f32::from_bits(c as u32));
+
+        assert!(same_f32(add(F32, a, b, rne, &mut flags), fa + fb), "{:x} + {:x}", a, b);
+        assert!(same_f32(mul(F32, a, b, rne, &mut flags), fa * fb), "{:x} * {:x}", a, b);
+        assert!(same_f32(div(F32, a, b, rne, &mut flags), fa / fb), "{:x} / {:x}", a, b);
+        assert!(same_f32(sqrt(F32, a, rne, &mut flags), fa.sqrt()), "sqrt {:x}", a);
+        assert!(same_f32(muladd(F32, a, b, c, rne, &mut flags), fa.mul_add(fb, fc)),
+            "fma {:x} {:x} {:x}", a, b, c);
+        assert!(same_f64(convert(F32, F64, a, rne, &mut flags), fa as f64), "cvt {:x}", a);
+    }
+}
+
+#[test]
+fn test_rounding_modes() {
+    let one = 1.0f64.to_bits();
+    let eps_half = (f64::EPSILON / 2.0).to_bits();
+    let neg = |v : u64| v ^ F64.sign_bit();
+    let mut flags = 0;
+
+    // 1 + eps/2 is a tie between 1 and 1 + eps
+    assert_eq!(add(F64, one, eps_half, RoundingMode::Rne, &mut flags), one);
+    assert_eq!(add(F64, one, eps_half, RoundingMode::Rmm, &mut flags), one + 1);
+    assert_eq!(add(F64, one, eps_half, RoundingMode::Rup, &mut flags), one + 1);
+    assert_eq!(add(F64, one, eps_half, RoundingMode::Rdn, &mut flags), one);
+    assert_eq!(add(F64, one, eps_half, RoundingMode::Rtz, &mut flags), one);
+    assert_eq!(add(F64, neg(one), neg(eps_half), RoundingMode::Rdn, &mut flags), neg(one + 1));
+    assert_eq!(add(F64, neg(one), neg(eps_half), RoundingMode::Rup, &mut flags), neg(one));
+    assert_eq!(flags, FFLAG_NX);
+
+    // x - x is -0 only when rounding down
+    assert_eq!(sub(F64, one, one, RoundingMode::Rne, &mut flags), 0);
+    assert_eq!(sub(F64, one, one, RoundingMode::Rdn, &mut flags), F64.sign_bit());
+}
+
+#[test]
+fn test_exception_flags() {
+    let rne = RoundingMode::Rne;
+    let max = f64::MAX.to_bits();
+    let inf = f64::INFINITY.to_bits();
+    let snan = 0x7ff0_0000_0000_0001;
+
+    let mut flags = 0;
+    assert_eq!(mul(F64, max, 2.0f64.to_bits(), rne, &mut flags), inf);
+    assert_eq!(flags, FFLAG_OF | FFLAG_NX);
+
+    let mut flags = 0;
+    assert_eq!(mul(F64, max, 2.0f64.to_bits(), RoundingMode::Rtz, &mut flags), max);
+    assert_eq!(flags, FFLAG_OF | FFLAG_NX);
+
+    let mut flags = 0;
+    assert_eq!(div(F64, 1.0f64.to_bits(), 0, rne, &mut flags), inf);
+    assert_eq!(flags, FFLAG_DZ);
+
+    let mut flags = 0;
+    assert_eq!(sub(F64, inf, inf, rne, &mut flags), F64.canonical_nan());
+    assert_eq!(flags, FFLAG_NV);
+
+    let mut flags = 0;
+    assert_eq!(add(F64, snan, 0, rne, &mut flags), F64.canonical_nan());
+    assert_eq!(flags, FFLAG_NV);
+
+    // Smallest normal / 3 is tiny and inexact
+    let mut flags = 0;
+    div(F64, f64::MIN_POSITIVE.to_bits(), 3.0f64.to_bits(), rne, &mut flags);
+    assert_eq!(flags, FFLAG_UF | FFLAG_NX);
+
+    // Exact subnormal results do not underflow
+    let mut flags = 0;
+    div(F64, f64::MIN_POSITIVE.to_bits(), 2.0f64.to_bits(), rne, &mut flags);
+    assert_eq!(flags, 0);
+
+    // inf * 0 + qNaN still signals invalid
+    let mut flags = 0;
+    muladd(F64, inf, 0, F64.canonical_nan(), rne, &mut flags);
+    assert_eq!(flags, FFLAG_NV);
+
+    // Tininess is detected after rounding: MIN_POSITIVE * (1 - 2^-53)
+    // rounds to MIN_POSITIVE but would not with unbounded exponent range...
+    let mut flags = 0;
+    let res = mul(F64, f64::MIN_POSITIVE.to_bits(), (1.0 - f64::EPSILON / 2.0).to_bits(),
+        rne, &mut flags);
+    assert_eq!(res, f64::MIN_POSITIVE.to_bits());
+    assert_eq!(flags, FFLAG_UF | FFLAG_NX);
+
+    // ...while MIN_POSITIVE * (1 - 2^-54) rounds up either way
+    let mut flags = 0;
+    let x = 1.0 - 2f64.powi(-27);
+    let y = f64::MIN_POSITIVE * (1.0 + 2f64.powi(-27));
+    assert_eq!(mul(F64, x.to_bits(), y.to_bits(), rne, &mut flags), f64::MIN_POSITIVE.to_bits());
+    assert_eq!(flags, FFLAG_NX);
+}
+
+#[test]
+fn test_to_int() {
+    let rne = RoundingMode::Rne;
+    let mut flags = 0;
+    let f = |v : f64| v.to_bits();
+
+    assert_eq!(to_int(F64, f(2.5), true, 32, rne, &mut flags), 2);
+    assert_eq!(to_int(F64, f(3.5), true, 32, rne, &mut flags), 4);
+    assert_eq!(to_int(F64, f(-2.5), true, 64, RoundingMode::Rmm, &mut flags), (-3i64) as u64);
+    assert_eq!(to_int(F64, f(-2.5), true, 64, RoundingMode::Rdn, &mut flags), (-3i64) as u64);
+    assert_eq!(to_int(F64, f(-2.5), true, 64, RoundingMode::Rtz, &mut flags), (-2i64) as u64);
+    assert_eq!(flags, FFLAG_NX);
+
+    let mut flags = 0;
+    assert_eq!(to_int(F64, f(1e10), true, 32, rne, &mut flags), i32::MAX as u64);
+    assert_eq!(to_int(F64, f(-1e10), true, 32, rne, &mut flags), i32::MIN as i64 as u64);
+    assert_eq!(to_int(F64, F64.canonical_nan(), true, 64, rne, &mut flags), i64::MAX as u64);
+    assert_eq!(to_int(F64, f(-1.0), false, 64, rne, &mut flags), 0);
+    assert_eq!(to_int(F64, f(f64::INFINITY), false, 64, rne, &mut flags), u64::MAX);
+    assert_eq!(flags, FFLAG_NV);
+
+    // FCVT.WU sign-extends its 32-bit result
+    let mut flags = 0;
+    assert_eq!(to_int(F64, f(4294967295.0), false, 32, rne, &mut flags), u64::MAX);
+    assert_eq!(to_int(F64, f(-0.25), false, 32, rne, &mut flags), 0);
+    assert_eq!(flags, FFLAG_NX);
+
+    let mut flags = 0;
+    assert_eq!(to_int(F32, (-2147483648.0f32).to_bits() as u64, true, 32, rne, &mut flags),
+        i32::MIN as i64 as u64);
+    assert_eq!(flags, 0);
+}
+
+#[test]
+fn test_from_int() {
+    let rne = RoundingMode::Rne;
+    let mut flags = 0;
+
+    assert_eq!(from_int(F64, (-7i64) as u64, true, 64, rne, &mut flags), (-7.0f64).to_bits());
+    assert_eq!(from_int(F64, 0xFFFF_FFFF, false, 32, rne, &mut flags), 4294967295.0f64.to_bits());
+    assert_eq!(from_int(F64, 0xFFFF_FFFF, true, 32, rne, &mut flags), (-1.0f64).to_bits());
+    assert_eq!(from_int(F64, 0, true, 64, rne, &mut flags), 0);
+    assert_eq!(flags, 0);
+
+    assert_eq!(from_int(F64, u64::MAX, false, 64, rne, &mut flags), 18446744073709551616.0f64.to_bits());
+    assert_eq!(from_int(F32, u64::MAX, false, 64, RoundingMode::Rtz, &mut flags),
+        18446742974197923840.0f32.to_bits() as u64);
+    assert_eq!(flags, FFLAG_NX);
+}
+
+#[test]
+fn test_compare_min_max() {
+    let f = |v : f32| v.to_bits() as u64;
+    let qnan = F32.canonical_nan();
+    let snan = 0x7f80_0001;
+    let mut flags = 0;
+
+    assert!(eq(F32, f(0.0), f(-0.0), &mut flags));
+    assert!(!lt(F32, f(-0.0), f(0.0), &mut flags));
+    assert!(le(F32, f(-0.0), f(0.0), &mut flags));
+    assert!(lt(F32, f(-2.0), f(-1.0), &mut flags));
+    assert!(!eq(F32, qnan, qnan, &mut flags));
+    assert_eq!(flags, 0);
+
+    assert!(!lt(F32, qnan, f(1.0), &mut flags));
+    assert_eq!(flags, FFLAG_NV);
+
+    let mut flags = 0;
+    assert!(!eq(F32, snan, f(1.0), &mut flags));
+    assert_eq!(flags, FFLAG_NV);
+
+    let mut flags = 0;
+    assert_eq!(min(F32, f(-0.0), f(0.0), &mut flags), f(-0.0));
+    assert_eq!(min(F32, f(0.0), f(-0.0), &mut flags), f(-0.0));
+    assert_eq!(max(F32, f(-0.0), f(0.0), &mut flags), f(0.0));
+    assert_eq!(max(F32, qnan, f(3.0), &mut flags), f(3.0));
+    assert_eq!(min(F32, qnan, qnan, &mut flags), qnan);
+    assert_eq!(flags, 0);
+
+    assert_eq!(min(F32, snan, f(3.0), &mut flags), f(3.0));
+    assert_eq!(flags, FFLAG_NV);
+}
+
+#[test]
+fn test_classify_and_sign_injection() {
+    let f = |v : f64| v.to_bits();
+
+    assert_eq!(classify(F64, f(f64::NEG_INFINITY)), 1 << 0);
+    assert_eq!(classify(F64, f(-1.0)), 1 << 1);
+    assert_eq!(classify(F64, f(-5e-324)), 1 << 2);
+    assert_eq!(classify(F64, f(-0.0)), 1 << 3);
+    assert_eq!(classify(F64, f(0.0)), 1 << 4);
+    assert_eq!(classify(F64, f(5e-324)), 1 << 5);
+    assert_eq!(classify(F64, f(1.0)), 1 << 6);
+    assert_eq!(classify(F64, f(f64::INFINITY)), 1 << 7);
+    assert_eq!(classify(F64, 0x7ff0_0000_0000_0001), 1 << 8);
+    assert_eq!(classify(F64, F64.canonical_nan()), 1 << 9);
+
+    assert_eq!(sgnj(F64, f(1.0), f(-2.0)), f(-1.0));
+    assert_eq!(sgnjn(F64, f(1.0), f(-2.0)), f(1.0));
+    assert_eq!(sgnjx(F64, f(-1.0), f(-2.0)), f(1.0));
+}
@@ -0,0 +1,173 @@
+use std::collections::{HashMap, HashSet};
+use crate::memif::*;
+use crate::rv64defs::*;
+use crate::rv64emu::*;
+use crate::rv64mmu::*;
+
+//
+// Decoded-instruction cache, keyed by physical address. Like a hardware
+// instruction cache it is not coherent with stores: modified code is only
+// guaranteed to be seen after a FENCE.I.
+//
+
+const PAGE_SHIFT : u64 = 12;
+
+#[derive(Debug, Clone, Copy)]
+struct CachedInst {
+    raw : u32,
+    inst : DecodedInst,
+    warned : bool
+}
+
+impl CachedInst {
+    fn len(&self) -> u64 {
+        if self.raw & 0b11 == 0b11 { 4 } else { 2 }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DecodeCacheStats {
+    pub hits : u64,
+    pub misses : u64,
+    pub flushes : u64,
+    /// Stores that overwrote cached code, counted in warning mode only.
+    pub stale_stores : u64
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DecodeCache {
+    insts : HashMap<u64, CachedInst>,
+    /// Pages holding cached code, so stores elsewhere cost one lookup.
+    pages : HashSet<u64>,
+    /// Report stores to cached code that are not followed by a FENCE.I
+    /// before the code runs again.
+    pub warn_stale : bool,
+    pub stats : DecodeCacheStats
+}
+
+impl DecodeCache {
+    pub fn lookup(&mut self, paddr : u64) -> Option<(u32, DecodedInst)> {
+        match self.insts.get(&paddr) {
+            Some(entry) => {
+                self.stats.hits += 1;
+                Some((entry.raw, entry.inst))
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, paddr : u64, raw : u32, inst : DecodedInst) {
+        self.pages.insert(paddr >> PAGE_SHIFT);
+        self.insts.insert(paddr, CachedInst { raw, inst, warned : false });
+    }
+
+    pub fn flush(&mut self) {
+        self.stats.flushes += 1;
+        self.insts.clear();
+        self.pages.clear();
+    }
+
+    /// Called on every physical store. Warns once per cached instruction
+    /// the store overwrites.
+    #[inline(always)]
+    pub fn check_store(&mut self, paddr : u64, size : u64) {
+        if !self.warn_stale || self.pages.is_empty() {
+            return;
+        }
+
+        // A 4-byte instruction starting 2 bytes below the store overlaps it
+        let start = paddr.saturating_sub(2) & !1;
+        let end = paddr + size;
+
+        if !self.pages.contains(&(start >> PAGE_SHIFT)) &&
+           !self.pages.contains(&((end - 1) >> PAGE_SHIFT)) {
+            return;
+        }
+
+        for addr in (start..end).step_by(2) {
+            if let Some(entry) = self.insts.get_mut(&addr) {
+                if addr + entry.len() <= paddr || entry.warned {
+                    continue;
+                }
+
+                entry.warned = true;
+                self.stats.stale_stores += 1;
+                eprintln!("warning: store to 0x{:x} modifies cached instruction at 0x{:x} \
+                           without FENCE.I", paddr, addr);
+            }
+        }
+    }
+}
+
+impl ArchState {
+    /// Fetches and decodes the instruction at pc, reusing the decoded form
+    /// of code that has already run until the next FENCE.I.
+    pub fn fetch_decode(&mut self, mem : &mut dyn MemIf) -> Result<(RawInst, DecodedInst), Exception> {
+        let paddr = self.access(mem, self.pc, 2, Access::Fetch)?;
+
+        if let Some((raw, inst)) = self.icache.lookup(paddr) {
+            return Ok((RawInst { raw }, inst));
+        }
+
+        let rinst = self.fetch_inst(mem)?;
+        let inst = crate::rv64inst::decode_for(&rinst, &self.isa)
+            .map_err(Exception::IllegalInstruction)?;
+
+        // Instructions straddling a page need both halves translated
+        let len = if rinst.raw & 0b11 == 0b11 { 4 } else { 2 };
+        if !crosses_page(self.pc, len) {
+            self.icache.insert(paddr, rinst.raw, inst);
+        }
+
+        Ok((rinst, inst))
+    }
+}
+
+#[cfg(test)]
+fn step(arch : &mut ArchState, mem : &mut dyn MemIf) -> ExecResult {
+    let (rinst, inst) = arch.fetch_decode(mem).unwrap();
+    arch.exec_inst(mem, &rinst, &inst)
+}
+
+#[test]
+fn test_fence_i() {
+    let mut arch = ArchState::new();
+    let mut mem = TestMem::new(0x100);
+    arch.icache.warn_stale = true;
+
+    // 0x0: addi x5, x0, 1
+    // 0x4: sw x6, 0(x0)
+    // 0x8: fence.i
+    mem.store::<u32>(0x0, 0x00100293).unwrap();
+    mem.store::<u32>(0x4, 0x00602023).unwrap();
+    mem.store::<u32>(0x8, 0x0000100f).unwrap();
+
+    // Overwrite the first instruction with addi x5, x0, 2
+    arch.regw(6, 0x00200293);
+    step(&mut arch, &mut mem);
+    step(&mut arch, &mut mem);
+    assert_eq!(arch.icache.stats.stale_stores, 1);
+
+    // Without a FENCE.I the old instruction still runs
+    arch.pc = 0;
+    step(&mut arch, &mut mem);
+    assert_eq!(arch.regr(5), 1);
+
+    // Storing again warns only once per instruction
+    step(&mut arch, &mut mem);
+    assert_eq!(arch.icache.stats.stale_stores, 1);
+
+    step(&mut arch, &mut mem);
+    arch.pc = 0;
+    step(&mut arch, &mut mem);
+    assert_eq!(arch.regr(5), 2);
+    assert_eq!(arch.icache.stats.flushes, 1);
+
+    // Stores away from cached code are not reported
+    arch.regw(6, 0x80);
+    exec_raw(&mut arch, &mut mem, 0x00632023);
+    assert_eq!(arch.icache.stats.stale_stores, 1);
+}
@@ -1,15 +1,5 @@
-use std::fmt;
-
-use crate::bitops::*;
 use crate::rv64defs::*;
-
-#[inline(always)]
-pub fn opt_creg_to_reg(creg : Option<usize>) -> Option<usize> {
-    match creg {
-        Some(rn) => Some(8 + rn),
-        None => None
-    }
-}
+use crate::isa::Isa;
 
 macro_rules! immgen {
     (I, $v:expr) => {
@@ -95,10 +85,11 @@ macro_rules! immgen {
         bit_range_map!($v as u64, (5, 6),   (6, 7)) |
         bit_range_map!($v as u64, (10, 12), (3, 5))
     };
-    // (C0_LSQ, $v:expr) => {
-    //     bit_range_map!($v as u64, (5, 6),  (6, 7)) |
-    //     bit_range_map!($v as u64, (10, 12), (3, 5))
-    // };
+    (C0_LSQ, $v:expr) => {
+        bit_range_map!($v as u64, (5, 6),   (6, 7)) |
+        bit_range_map!($v as u64, (11, 12), (4, 5)) |
+        bit_range_map!($v as u64, (10, 10), (8, 8))
+    };
 
     (C0_ADDI4SPN, $v:expr) => {
         bit_range_map!($v as u64, (5, 5), (3, 3)) |
@@ -165,7 +156,7 @@ macro_rules! immgen {
     // C2 Compressed Instructions
     //
 
-    (C2_SLLI, $v:expr) => {
+    (C_SHAMT, $v:expr) => {
         bit_range_map!($v as u64, (2, 6), (0, 4)) |
         bit_range_map!($v as u64, (12, 12), (5, 5))
     };
@@ -189,19 +180,37 @@ macro_rules! immgen {
     };
 }
 
+macro_rules! illegal {
+    ($rinst:expr, $reason:expr) => {
+        return Err(IllegalInst { raw : $rinst.raw, reason : $reason })
+    };
+}
+
+/// Converts an encoding field to its enum, rejecting values with no
+/// meaning.
 #[inline(always)]
-fn pre_decode(rinst : &RawInst) -> InstSpec {
+fn field<T : num::FromPrimitive>(
+    rinst : &RawInst, val : u32, name : &'static str) -> Result<T, IllegalInst>
+{
+    match T::from_u32(val) {
+        Some(v) => Ok(v),
+        None => illegal!(rinst, IllegalReason::InvalidField(name))
+    }
+}
+
+#[inline(always)]
+fn pre_decode(rinst : &RawInst) -> Result<InstSpec, IllegalInst> {
     use InstOpcode::*;
 
-    match rinst.raw & 0b11 {
+    Ok(match rinst.raw & 0b11 {
         0 => InstSpec(C0, bit_range_get!(rinst.raw, (13, 15)) as usize),
         1 => InstSpec(C1, bit_range_get!(rinst.raw, (13, 15)) as usize),
         2 => InstSpec(C2, bit_range_get!(rinst.raw, (13, 15)) as usize),
-        _ => InstSpec(
-            num::FromPrimitive::from_u32(rinst.raw & 0b1111111)
-                .expect("Unknown opcode!"),
-            bit_range_get!(rinst.raw, (12, 14)) as usize),
-    }
+        _ => match num::FromPrimitive::from_u32(rinst.raw & 0b1111111) {
+            Some(opcode) => InstSpec(opcode, bit_range_get!(rinst.raw, (12, 14)) as usize),
+            None => illegal!(rinst, IllegalReason::UnknownOpcode)
+        }
+    })
 }
 
 #[inline(always)]
@@ -214,6 +223,11 @@ pub fn rs2(rinst : &RawInst) -> usize {
     bit_range_get!(rinst.raw, (20, 24)) as usize
 }
 
+#[inline(always)]
+pub fn rs3(rinst : &RawInst) -> usize {
+    bit_range_get!(rinst.raw, (27, 31)) as usize
+}
+
 #[inline(always)]
 pub fn rd(rinst : &RawInst) -> usize {
     bit_range_get!(rinst.raw, (7, 11)) as usize
@@ -224,6 +238,11 @@ fn funct7_32(rinst : &RawInst) -> u64 {
     bit_range_get!(rinst.raw, (25, 31)) as u64
 }
 
+#[inline(always)]
+fn fp_fmt(rinst : &RawInst) -> Result<FpFmt, IllegalInst> {
+    field(rinst, bit_range_get!(rinst.raw, (25, 26)), "fmt")
+}
+
 #[inline(always)]
 pub fn rs1_c(rinst : &RawInst) -> usize {
     bit_range_get!(rinst.raw, (7, 9)) as usize
@@ -234,11 +253,14 @@ pub fn rs2_c(rinst : &RawInst) -> usize {
     bit_range_get!(rinst.raw, (2, 4)) as usize
 }
 
+/// Decodes a 16- or 32-bit instruction. Unknown opcodes, invalid function
+/// fields and reserved encodings are reported with the raw bits so that
+/// they can be raised as illegal-instruction exceptions.
 #[inline(always)]
-pub fn decode(rinst : &RawInst) -> DecodedInst {
-    let spec = pre_decode(rinst);
+pub fn decode(rinst : &RawInst) -> Result<DecodedInst, IllegalInst> {
+    let spec = pre_decode(rinst)?;
 
-    match spec {
+    let inst = match spec {
         //
         // Base Integer Instructions
         //
@@ -256,29 +278,117 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
                     rs2 : rs2(rinst),
                     rd : rd(rinst)
                 },
-                _ => panic!("Invalid funct7!")
+                0b0000001 => DecodedInst::Mul {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
             }
-
         },
-        InstSpec(InstOpcode::OP, 1) => DecodedInst::Sll {
-            rs1 : rs1(rinst),
-            rs2 : rs2(rinst),
-            rd : rd(rinst)
+        InstSpec(InstOpcode::OP, 1) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match funct7 {
+                0b0000000 => DecodedInst::Sll {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000001 => DecodedInst::Mulh {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0110000 => DecodedInst::Rol {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0100100 => DecodedInst::Bclr {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0110100 => DecodedInst::Binv {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0010100 => DecodedInst::Bset {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
         },
-        InstSpec(InstOpcode::OP, 2) => DecodedInst::Slt {
-            rs1 : rs1(rinst),
-            rs2 : rs2(rinst),
-            rd : rd(rinst)
+        InstSpec(InstOpcode::OP, 2) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match funct7 {
+                0b0000000 => DecodedInst::Slt {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000001 => DecodedInst::Mulhsu {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0010000 => DecodedInst::Sh1add {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
         },
-        InstSpec(InstOpcode::OP, 3) => DecodedInst::Sltu {
-            rs1 : rs1(rinst),
-            rs2 : rs2(rinst),
-            rd : rd(rinst)
+        InstSpec(InstOpcode::OP, 3) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match funct7 {
+                0b0000000 => DecodedInst::Sltu {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000001 => DecodedInst::Mulhu {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
         },
-        InstSpec(InstOpcode::OP, 4) => DecodedInst::Xor {
-            rs1 : rs1(rinst),
-            rs2 : rs2(rinst),
-            rd : rd(rinst)
+        InstSpec(InstOpcode::OP, 4) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match funct7 {
+                0b0000000 => DecodedInst::Xor {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000001 => DecodedInst::Div {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0010000 => DecodedInst::Sh2add {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0100000 => DecodedInst::Xnor {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000101 => DecodedInst::Min {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
         },
         InstSpec(InstOpcode::OP, 5) => {
             let funct7 = bit_range_get!(rinst.raw, (25, 31));
@@ -298,7 +408,22 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
                     rs2 : rs2(rinst),
                     rd : rd(rinst)
                 },
-                _ => panic!("Invalid funct7!")
+                0b0110000 => DecodedInst::Ror {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0100100 => DecodedInst::Bext {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000101 => DecodedInst::Minu {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
             }
         },
         InstSpec(InstOpcode::OP, 6) => {
@@ -314,7 +439,22 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
                     rs2 : rs2(rinst),
                     rd : rd(rinst)
                 },
-                _ => panic!("Invalid funct7!")
+                0b0010000 => DecodedInst::Sh3add {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0100000 => DecodedInst::Orn {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000101 => DecodedInst::Max {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
             }
         },
         InstSpec(InstOpcode::OP, 7) => {
@@ -330,7 +470,17 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
                     rs2 : rs2(rinst),
                     rd : rd(rinst)
                 },
-                _ => panic!("Invalid funct7!")
+                0b0100000 => DecodedInst::Andn {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000101 => DecodedInst::Maxu {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
             }
         },
 
@@ -351,14 +501,65 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
                     rs2 : rs2(rinst),
                     rd : rd(rinst)
                 },
-                _ => panic!("Invalid funct7!")
+                0b0000001 => DecodedInst::Mulw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000100 => DecodedInst::AddUw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
             }
-
         },
-        InstSpec(InstOpcode::OP32, 1) => DecodedInst::Sllw {
-            rs1 : rs1(rinst),
-            rs2 : rs2(rinst),
-            rd : rd(rinst)
+        InstSpec(InstOpcode::OP32, 1) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match funct7 {
+                0b0000000 => DecodedInst::Sllw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0110000 => DecodedInst::Rolw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
+        },
+        InstSpec(InstOpcode::OP32, 2) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match funct7 {
+                0b0010000 => DecodedInst::Sh1addUw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
+        },
+        InstSpec(InstOpcode::OP32, 4) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match funct7 {
+                0b0000001 => DecodedInst::Divw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0010000 => DecodedInst::Sh2addUw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0000100 if rs2(rinst) == 0 => DecodedInst::ZextH {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
         },
         InstSpec(InstOpcode::OP32, 5) => {
             let funct7 = bit_range_get!(rinst.raw, (25, 31));
@@ -373,18 +574,45 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
                     rs2 : rs2(rinst),
                     rd : rd(rinst)
                 },
-                _ => panic!("Invalid funct7: {}", funct7)
+                0b0000001 => DecodedInst::Divuw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0110000 => DecodedInst::Rorw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
             }
         },
-        InstSpec(InstOpcode::OP32, 6) => DecodedInst::Remw {
-            rs1 : rs1(rinst),
-            rs2 : rs2(rinst),
-            rd : rd(rinst)
+        InstSpec(InstOpcode::OP32, 6) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match funct7 {
+                0b0000001 => DecodedInst::Remw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                0b0010000 => DecodedInst::Sh3addUw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
         },
-        InstSpec(InstOpcode::OP32, 7) => DecodedInst::Remuw {
-            rs1 : rs1(rinst),
-            rs2 : rs2(rinst),
-            rd : rd(rinst)
+        InstSpec(InstOpcode::OP32, 7) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match funct7 {
+                0b0000001 => DecodedInst::Remuw {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
         },
 
 
@@ -422,15 +650,51 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
             rd : rd(rinst),
             imm : immgen!(I, rinst.raw)
         },
-        InstSpec(InstOpcode::OPIMM, 1) => DecodedInst::Slli {
-            rs1 : rs1(rinst),
-            rd : rd(rinst),
-            shamt : immgen!(I, rinst.raw) & 0b111111
-        },
-        InstSpec(InstOpcode::OPIMM, 1) => DecodedInst::Slli {
-            rs1 : rs1(rinst),
-            rd : rd(rinst),
-            shamt : immgen!(I, rinst.raw) & 0b111111
+        InstSpec(InstOpcode::OPIMM, 1) => {
+            let funct6 = bit_range_get!(rinst.raw, (26, 31));
+            match (funct6, rs2(rinst)) {
+                (0b000000, _) => DecodedInst::Slli {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst),
+                    shamt : immgen!(I, rinst.raw) & 0b111111
+                },
+                (0b010010, _) => DecodedInst::Bclri {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst),
+                    shamt : immgen!(I, rinst.raw) & 0b111111
+                },
+                (0b011010, _) => DecodedInst::Binvi {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst),
+                    shamt : immgen!(I, rinst.raw) & 0b111111
+                },
+                (0b001010, _) => DecodedInst::Bseti {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst),
+                    shamt : immgen!(I, rinst.raw) & 0b111111
+                },
+                (0b011000, 0b00000) => DecodedInst::Clz {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                (0b011000, 0b00001) => DecodedInst::Ctz {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                (0b011000, 0b00010) => DecodedInst::Cpop {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                (0b011000, 0b00100) => DecodedInst::SextB {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                (0b011000, 0b00101) => DecodedInst::SextH {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct6"))
+            }
         },
         InstSpec(InstOpcode::OPIMM, 5) => {
             let funct6 = bit_range_get!(rinst.raw, (26, 31));
@@ -445,7 +709,27 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
                     rd : rd(rinst),
                     shamt : immgen!(I, rinst.raw) & 0b111111
                 },
-                _ => panic!("Invalid funct6: {}", funct6)
+                0b010010 => DecodedInst::Bexti {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst),
+                    shamt : immgen!(I, rinst.raw) & 0b111111
+                },
+                0b011000 => DecodedInst::Rori {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst),
+                    shamt : immgen!(I, rinst.raw) & 0b111111
+                },
+                _ => match bit_range_get!(rinst.raw, (20, 31)) {
+                    0x287 => DecodedInst::OrcB {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                    0x6b8 => DecodedInst::Rev8 {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                    _ => illegal!(rinst, IllegalReason::InvalidField("funct6"))
+                }
             }
         },
 
@@ -458,10 +742,33 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
             rd : rd(rinst),
             imm : immgen!(I, rinst.raw)
         },
-        InstSpec(InstOpcode::OPIMM32, 1) => DecodedInst::Slliw {
-            rs1 : rs1(rinst),
-            rd : rd(rinst),
-            shamt : immgen!(I, rinst.raw)
+        InstSpec(InstOpcode::OPIMM32, 1) => {
+            let funct7 = bit_range_get!(rinst.raw, (25, 31));
+            match (funct7, rs2(rinst)) {
+                (0b0000000, _) => DecodedInst::Slliw {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst),
+                    shamt : immgen!(I, rinst.raw) & 0b11111
+                },
+                (0b0000100, _) | (0b0000101, _) => DecodedInst::SlliUw {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst),
+                    shamt : immgen!(I, rinst.raw) & 0b111111
+                },
+                (0b0110000, 0b00000) => DecodedInst::Clzw {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                (0b0110000, 0b00001) => DecodedInst::Ctzw {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                (0b0110000, 0b00010) => DecodedInst::Cpopw {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+            }
         },
         InstSpec(InstOpcode::OPIMM32, 5) => {
             let funct7 = bit_range_get!(rinst.raw, (25, 31));
@@ -476,7 +783,12 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
                     rd : rd(rinst),
                     shamt : immgen!(I, rinst.raw) & 0b11111
                 },
-                _ => panic!("Invalid funct7!")
+                0b0110000 => DecodedInst::Roriw {
+                    rs1 : rs1(rinst),
+                    rd : rd(rinst),
+                    shamt : immgen!(I, rinst.raw) & 0b11111
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
             }
         },
 
@@ -496,48 +808,154 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
             rd : rd(rinst),
             imm : immgen!(J, rinst.raw)
         },
-        InstSpec(InstOpcode::JALR, _) => DecodedInst::Jalr {
+        InstSpec(InstOpcode::JALR, 0) => DecodedInst::Jalr {
             rs1 : rs1(rinst),
             rd : rd(rinst),
             imm : immgen!(I, rinst.raw)
         },
         InstSpec(InstOpcode::BRANCH, funct3) => DecodedInst::Branch {
-            func : num::FromPrimitive::from_usize(funct3).expect("Unknown funct!"),
+            func : field(rinst, funct3 as u32, "funct3")?,
             rs1 : rs1(rinst),
             rs2 : rs2(rinst),
             imm : immgen!(B, rinst.raw)
         },
+        InstSpec(InstOpcode::LOAD, 7) => illegal!(rinst, IllegalReason::WrongXlen),
         InstSpec(InstOpcode::LOAD, funct3) => DecodedInst::Load {
-            width : num::FromPrimitive::from_usize(funct3).expect("Unknown funct!"),
+            width : field(rinst, funct3 as u32, "funct3")?,
+            rs1 : rs1(rinst),
+            rd : rd(rinst),
+            imm : immgen!(I, rinst.raw)
+        },
+        InstSpec(InstOpcode::STORE, funct3) if funct3 <= 3 => DecodedInst::Store {
+            width : field(rinst, funct3 as u32, "funct3")?,
+            rs1 : rs1(rinst),
+            rs2 : rs2(rinst),
+            imm : immgen!(S, rinst.raw)
+        },
+
+        //
+        // Atomic Instructions
+        //
+
+        InstSpec(InstOpcode::AMO, 2) | InstSpec(InstOpcode::AMO, 3) =>
+            decode_amo(rinst)?,
+
+        //
+        // Floating-Point Instructions
+        //
+
+        InstSpec(InstOpcode::LOADFP, 2) => DecodedInst::FLoad {
+            fmt : FpFmt::S,
             rs1 : rs1(rinst),
             rd : rd(rinst),
             imm : immgen!(I, rinst.raw)
         },
-        InstSpec(InstOpcode::STORE, funct3) => DecodedInst::Store {
-            width : num::FromPrimitive::from_usize(funct3).expect("Unknown funct!"),
+        InstSpec(InstOpcode::LOADFP, 3) => DecodedInst::FLoad {
+            fmt : FpFmt::D,
+            rs1 : rs1(rinst),
+            rd : rd(rinst),
+            imm : immgen!(I, rinst.raw)
+        },
+        InstSpec(InstOpcode::STOREFP, 2) => DecodedInst::FStore {
+            fmt : FpFmt::S,
             rs1 : rs1(rinst),
             rs2 : rs2(rinst),
             imm : immgen!(S, rinst.raw)
         },
+        InstSpec(InstOpcode::STOREFP, 3) => DecodedInst::FStore {
+            fmt : FpFmt::D,
+            rs1 : rs1(rinst),
+            rs2 : rs2(rinst),
+            imm : immgen!(S, rinst.raw)
+        },
+        InstSpec(InstOpcode::MADD, rm) => DecodedInst::FMadd {
+            fmt : fp_fmt(rinst)?,
+            rs1 : rs1(rinst),
+            rs2 : rs2(rinst),
+            rs3 : rs3(rinst),
+            rd : rd(rinst),
+            rm
+        },
+        InstSpec(InstOpcode::MSUB, rm) => DecodedInst::FMsub {
+            fmt : fp_fmt(rinst)?,
+            rs1 : rs1(rinst),
+            rs2 : rs2(rinst),
+            rs3 : rs3(rinst),
+            rd : rd(rinst),
+            rm
+        },
+        InstSpec(InstOpcode::NMSUB, rm) => DecodedInst::FNmsub {
+            fmt : fp_fmt(rinst)?,
+            rs1 : rs1(rinst),
+            rs2 : rs2(rinst),
+            rs3 : rs3(rinst),
+            rd : rd(rinst),
+            rm
+        },
+        InstSpec(InstOpcode::NMADD, rm) => DecodedInst::FNmadd {
+            fmt : fp_fmt(rinst)?,
+            rs1 : rs1(rinst),
+            rs2 : rs2(rinst),
+            rs3 : rs3(rinst),
+            rd : rd(rinst),
+            rm
+        },
+        InstSpec(InstOpcode::OPFP, funct3) => decode_opfp(rinst, funct3)?,
+
+        //
+        // Memory ordering
+        //
+
+        InstSpec(InstOpcode::MISCMEM, 0) => {
+            let fm = bit_range_get!(rinst.raw, (28, 31));
+            let pred = bit_range_get!(rinst.raw, (24, 27)) as u8;
+            let succ = bit_range_get!(rinst.raw, (20, 23)) as u8;
+
+            // Reserved fm values and the rs1/rd fields are ignored, which
+            // leaves an ordinary fence
+            match (fm, pred, succ, rs1(rinst), rd(rinst)) {
+                (0b1000, 0b0011, 0b0011, _, _) => DecodedInst::FenceTso,
+                (0, 0b0001, 0, 0, 0) => DecodedInst::Pause,
+                _ => DecodedInst::Fence { pred, succ }
+            }
+        },
+        InstSpec(InstOpcode::MISCMEM, 1) => DecodedInst::FenceI,
+
         InstSpec(InstOpcode::SYSTEM, 0) => {
-            let rs1 = rs1(rinst);
-            let rs2 = rs2(rinst);
-            let imm = immgen!(U, rinst.raw);
+            let funct12 = bit_range_get!(rinst.raw, (20, 31));
 
-            match (rs1, rs2, imm) {
+            match (funct12, rs1(rinst), rd(rinst)) {
                 (0, 0, 0) => DecodedInst::ECall,
-                (0, 0, 1) => DecodedInst::EBreak,
-                _ => DecodedInst::EBreak //panic!("Invalid decode for InstOpcode::SYSTEM!")
+                (1, 0, 0) => DecodedInst::EBreak,
+                (0x302, 0, 0) => DecodedInst::Mret,
+                (0x102, 0, 0) => DecodedInst::Sret,
+                (0x105, 0, 0) => DecodedInst::Wfi,
+                (f12, _, 0) if f12 >> 5 == 0b0001001 => DecodedInst::SfenceVma {
+                    rs1 : rs1(rinst),
+                    rs2 : rs2(rinst)
+                },
+                _ => illegal!(rinst, IllegalReason::InvalidField("funct12"))
             }
-        }
+        },
+        InstSpec(InstOpcode::SYSTEM, 4) =>
+            illegal!(rinst, IllegalReason::InvalidField("funct3")),
+        InstSpec(InstOpcode::SYSTEM, funct3) => DecodedInst::Csr {
+            func : field(rinst, funct3 as u32, "funct3")?,
+            csr : bit_range_get!(rinst.raw, (20, 31)) as usize,
+            rs1 : rs1(rinst),
+            rd : rd(rinst)
+        },
 
         //
         // Compressed Quandrant 0 Instructions
         //
 
-        InstSpec(InstOpcode::C0, 0) => DecodedInst::CAddi4spn {
-            rd : rs2_c(rinst) + 8,
-            imm : immgen!(C0_ADDI4SPN, rinst.raw)
+        InstSpec(InstOpcode::C0, 0) => match immgen!(C0_ADDI4SPN, rinst.raw) {
+            0 => illegal!(rinst, IllegalReason::Reserved("C.ADDI4SPN")),
+            imm => DecodedInst::CAddi4spn {
+                rd : rs2_c(rinst) + 8,
+                imm
+            }
         },
         InstSpec(InstOpcode::C0, 1) => DecodedInst::CLoad {
             width : CLoadStoreWidth::Cfd,
@@ -579,14 +997,20 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
         //
         // Compressed Quandrant 1 Instructions
         //
+        // HINT encodings (rd = x0, or a zero immediate/shamt) decode as the
+        // ordinary instruction, which then has no architectural effect.
+        //
 
         InstSpec(InstOpcode::C1, 0) => DecodedInst::CAddi {
             rsrd : rd(rinst),
             imm : immgen!(C1_OPIMM, rinst.raw)
         },
-        InstSpec(InstOpcode::C1, 1) => DecodedInst::CAddiw {
-            rsrd : rd(rinst),
-            imm : immgen!(C1_OPIMM, rinst.raw)
+        InstSpec(InstOpcode::C1, 1) => match rd(rinst) {
+            0 => illegal!(rinst, IllegalReason::Reserved("C.ADDIW")),
+            rsrd => DecodedInst::CAddiw {
+                rsrd,
+                imm : immgen!(C1_OPIMM, rinst.raw)
+            }
         },
         InstSpec(InstOpcode::C1, 2) => DecodedInst::CLi {
             rd : rd(rinst),
@@ -594,13 +1018,15 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
         },
         InstSpec(InstOpcode::C1, 3) => {
             let rd = rd(rinst);
-            match rd {
-                2 => DecodedInst::CAddi16sp {
-                    imm : immgen!(C1_ADDI16SP, rinst.raw)
+            match (rd, immgen!(C1_ADDI16SP, rinst.raw), immgen!(C1_LUI, rinst.raw)) {
+                (2, 0, _) => illegal!(rinst, IllegalReason::Reserved("C.ADDI16SP")),
+                (2, imm, _) => DecodedInst::CAddi16sp {
+                    imm
                 },
-                _ => DecodedInst::CLui {
-                    rd : rd,
-                    imm : immgen!(C1_LUI, rinst.raw)
+                (_, _, 0) => illegal!(rinst, IllegalReason::Reserved("C.LUI")),
+                (rd, _, imm) => DecodedInst::CLui {
+                    rd,
+                    imm
                 }
             }
         },
@@ -613,42 +1039,42 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
 
             match (bit12, bit10_11, bit5_6) {
                 (_, 0, _) => DecodedInst::CSrli {
-                    rsrd : rsrd,
-                    shamt : immgen!(C1_OPIMM, rinst.raw)
+                    rsrd,
+                    shamt : immgen!(C_SHAMT, rinst.raw)
                 },
                 (_, 1, _) => DecodedInst::CSrai {
-                    rsrd : rsrd,
-                    shamt : immgen!(C1_OPIMM, rinst.raw)
+                    rsrd,
+                    shamt : immgen!(C_SHAMT, rinst.raw)
                 },
                 (_, 2, _) => DecodedInst::CAndi {
-                    rsrd : rsrd,
+                    rsrd,
                     imm : immgen!(C1_OPIMM, rinst.raw)
                 },
                 (0, 3, 0) => DecodedInst::CSub {
-                    rsrd : rsrd,
-                    rs2 : rs2
+                    rsrd,
+                    rs2
                 },
                 (0, 3, 1) => DecodedInst::CXor {
-                    rsrd : rsrd,
-                    rs2 : rs2
+                    rsrd,
+                    rs2
                 },
                 (0, 3, 2) => DecodedInst::COr {
-                    rsrd : rsrd,
-                    rs2 : rs2
+                    rsrd,
+                    rs2
                 },
                 (0, 3, 3) => DecodedInst::CAnd {
-                    rsrd : rsrd,
-                    rs2 : rs2
+                    rsrd,
+                    rs2
                 },
                 (1, 3, 0) => DecodedInst::CSubw {
-                    rsrd : rsrd,
-                    rs2 : rs2
+                    rsrd,
+                    rs2
                 },
                 (1, 3, 1) => DecodedInst::CAddw {
-                    rsrd : rsrd,
-                    rs2 : rs2
+                    rsrd,
+                    rs2
                 },
-                _ => panic!("Invalid decode for C1!")
+                _ => illegal!(rinst, IllegalReason::Reserved("C.SUBW/C.ADDW"))
             }
 
         },
@@ -673,22 +1099,28 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
 
         InstSpec(InstOpcode::C2, 0) => DecodedInst::CSlli {
             rsrd : rd(rinst),
-            shamt : immgen!(C2_SLLI, rinst.raw)
+            shamt : immgen!(C_SHAMT, rinst.raw)
         },
         InstSpec(InstOpcode::C2, 1) => DecodedInst::CLoadStack {
             width : CLoadStoreWidth::Cfd,
             rd : rd(rinst),
             imm : immgen!(C2_LD, rinst.raw)
         },
-        InstSpec(InstOpcode::C2, 2) => DecodedInst::CLoadStack {
-            width : CLoadStoreWidth::Cw,
-            rd : rd(rinst),
-            imm : immgen!(C2_LD, rinst.raw)
+        InstSpec(InstOpcode::C2, 2) => match rd(rinst) {
+            0 => illegal!(rinst, IllegalReason::Reserved("C.LWSP")),
+            rd => DecodedInst::CLoadStack {
+                width : CLoadStoreWidth::Cw,
+                rd,
+                imm : immgen!(C2_LW, rinst.raw)
+            }
         },
-        InstSpec(InstOpcode::C2, 3) => DecodedInst::CLoadStack {
-            width : CLoadStoreWidth::Cd,
-            rd : rd(rinst),
-            imm : immgen!(C2_LD, rinst.raw)
+        InstSpec(InstOpcode::C2, 3) => match rd(rinst) {
+            0 => illegal!(rinst, IllegalReason::Reserved("C.LDSP")),
+            rd => DecodedInst::CLoadStack {
+                width : CLoadStoreWidth::Cd,
+                rd,
+                imm : immgen!(C2_LD, rinst.raw)
+            }
         },
         InstSpec(InstOpcode::C2, 4) => {
             let bit12 = bit_range_get!(rinst.raw, (12, 12));
@@ -696,22 +1128,23 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
             let rs2 = bit_range_get!(rinst.raw, (2, 6)) as usize;
 
             match (bit12, rs1, rs2) {
+                (0, 0, 0) => illegal!(rinst, IllegalReason::Reserved("C.JR")),
                 (0, rs1, 0) => DecodedInst::CJr {
-                    rs1 : rs1
+                    rs1
                 },
                 (0, rs1, rs2) => DecodedInst::CMv {
                     rsrd : rs1,
-                    rs2 : rs2
+                    rs2
                 },
                 (1, 0, 0) => DecodedInst::CEBreak,
                 (1, rs1, 0) => DecodedInst::CJalr {
-                    rs1 : rs1
+                    rs1
                 },
                 (1, rs1, rs2) => DecodedInst::CAdd {
                     rsrd : rs1,
-                    rs2 : rs2
+                    rs2
                 },
-                _ => panic!("Invalid decode for C2 Opcode!")
+                _ => unreachable!()
             }
         },
         InstSpec(InstOpcode::C2, 5) => DecodedInst::CStoreStack {
@@ -731,6 +1164,330 @@ pub fn decode(rinst : &RawInst) -> DecodedInst {
         },
 
 
-        x => panic!("Unknown instruction: {:?}", x)
+        _ => illegal!(rinst, IllegalReason::UnknownOpcode)
+    };
+
+    Ok(inst)
+}
+
+/// Decodes for a given base ISA. RV32 reuses the RV64 decoder for the
+/// encodings the two share.
+#[inline(always)]
+pub fn decode_for(rinst : &RawInst, isa : &Isa) -> Result<DecodedInst, IllegalInst> {
+    let inst = match isa.xlen() {
+        ArchWidth::RV32 => decode_rv32(rinst)?,
+        ArchWidth::RV64 => decode(rinst)?,
+        ArchWidth::RV128 => decode_rv128(rinst)?
+    };
+
+    if isa.embedded() {
+        check_embedded(rinst, isa.xlen())?;
+    }
+
+    Ok(inst)
+}
+
+/// RV32 gives some compressed encodings different meanings (C.JAL for
+/// C.ADDIW, C.FLW/C.FSW for C.LD/C.SD), has its own ZEXT.H and REV8
+/// encodings, and drops the *W opcodes, doubleword accesses and shift
+/// amounts of 32 or more.
+fn decode_rv32(rinst : &RawInst) -> Result<DecodedInst, IllegalInst> {
+    let imm12 = bit_range_get!(rinst.raw, (20, 31));
+
+    let inst = match pre_decode(rinst)? {
+        InstSpec(InstOpcode::C0, 3) => DecodedInst::CLoad {
+            width : CLoadStoreWidth::Cfw,
+            rs1 : rs1_c(rinst) + 8,
+            rd : rs2_c(rinst) + 8,
+            imm : immgen!(C0_LSW, rinst.raw)
+        },
+        InstSpec(InstOpcode::C0, 7) => DecodedInst::CStore {
+            width : CLoadStoreWidth::Cfw,
+            rs1 : rs1_c(rinst) + 8,
+            rs2 : rs2_c(rinst) + 8,
+            imm : immgen!(C0_LSW, rinst.raw)
+        },
+        InstSpec(InstOpcode::C1, 1) => DecodedInst::CJal {
+            imm : immgen!(C1_J_JAL, rinst.raw)
+        },
+        InstSpec(InstOpcode::C2, 3) => DecodedInst::CLoadStack {
+            width : CLoadStoreWidth::Cfw,
+            rd : rd(rinst),
+            imm : immgen!(C2_LW, rinst.raw)
+        },
+        InstSpec(InstOpcode::C2, 7) => DecodedInst::CStoreStack {
+            width : CLoadStoreWidth::Cfw,
+            rs2 : bit_range_get!(rinst.raw, (2, 6)) as usize,
+            imm : immgen!(C2_SW, rinst.raw)
+        },
+        InstSpec(InstOpcode::OP, 4) if funct7_32(rinst) == 0b0000100 && rs2(rinst) == 0 =>
+            DecodedInst::ZextH {
+                rs1 : rs1(rinst),
+                rd : rd(rinst)
+            },
+        InstSpec(InstOpcode::OPIMM, 5) if imm12 == 0x698 => DecodedInst::Rev8 {
+            rs1 : rs1(rinst),
+            rd : rd(rinst)
+        },
+        InstSpec(InstOpcode::OPIMM, 5) if imm12 == 0x6b8 =>
+            illegal!(rinst, IllegalReason::WrongXlen),
+        InstSpec(InstOpcode::OP32, _) | InstSpec(InstOpcode::OPIMM32, _) =>
+            illegal!(rinst, IllegalReason::WrongXlen),
+        _ => decode(rinst)?
+    };
+
+    use DecodedInst::*;
+
+    let rv64_only = match inst {
+        Load {width : LoadStoreWidth::Double, ..} | Load {width : LoadStoreWidth::WordU, ..} |
+        Store {width : LoadStoreWidth::Double, ..} |
+        Lr {width : AmoWidth::D, ..} | Sc {width : AmoWidth::D, ..} |
+        Amo {width : AmoWidth::D, ..} |
+        FCvtToInt {ity : FpIntType::L, ..} | FCvtToInt {ity : FpIntType::Lu, ..} |
+        FCvtFromInt {ity : FpIntType::L, ..} | FCvtFromInt {ity : FpIntType::Lu, ..} |
+        FMvXF {fmt : FpFmt::D, ..} | FMvFX {fmt : FpFmt::D, ..} |
+        CAddiw {..} | CSubw {..} | CAddw {..} => true,
+
+        Slli {shamt, ..} | Srli {shamt, ..} | Srai {shamt, ..} | Rori {shamt, ..} |
+        Bclri {shamt, ..} | Bexti {shamt, ..} | Binvi {shamt, ..} | Bseti {shamt, ..} |
+        CSlli {shamt, ..} | CSrli {shamt, ..} | CSrai {shamt, ..} => shamt >= 32,
+
+        _ => false
+    };
+
+    if rv64_only {
+        illegal!(rinst, IllegalReason::WrongXlen);
     }
+
+    Ok(inst)
+}
+
+/// RV128 turns C.FLD/C.FSD and their stack forms into the quadword
+/// accesses, widens shift amounts to 7 bits and adds the OP-64/OP-IMM-64
+/// doubleword operations, LQ/SQ and LDU.
+fn decode_rv128(rinst : &RawInst) -> Result<DecodedInst, IllegalInst> {
+    let (rs1, rs2, rd) = (rs1(rinst), rs2(rinst), rd(rinst));
+    let shamt7 = bit_range_get!(rinst.raw, (20, 26)) as u64;
+    let funct5 = bit_range_get!(rinst.raw, (27, 31));
+    // The *D shifts work on 64 bits, like the RV64 ones
+    let shamt6 = bit_range_get!(rinst.raw, (20, 25)) as u64;
+    let funct6 = bit_range_get!(rinst.raw, (26, 31));
+    let funct7 = funct7_32(rinst);
+
+    // C.SLLI/C.SRLI/C.SRAI: zero means 64 and the amount is sign-extended
+    let c_shamt = match immgen!(C_SHAMT, rinst.raw) {
+        0 => 64,
+        shamt if shamt & 0x20 != 0 => shamt | 0x40,
+        shamt => shamt
+    };
+
+    let inst = match pre_decode(rinst)? {
+        InstSpec(InstOpcode::C0, 1) => DecodedInst::CLoad {
+            width : CLoadStoreWidth::Cq,
+            rs1 : rs1_c(rinst) + 8,
+            rd : rs2_c(rinst) + 8,
+            imm : immgen!(C0_LSQ, rinst.raw)
+        },
+        InstSpec(InstOpcode::C0, 5) => DecodedInst::CStore {
+            width : CLoadStoreWidth::Cq,
+            rs1 : rs1_c(rinst) + 8,
+            rs2 : rs2_c(rinst) + 8,
+            imm : immgen!(C0_LSQ, rinst.raw)
+        },
+        InstSpec(InstOpcode::C1, 4) if bit_range_get!(rinst.raw, (11, 11)) == 0 => {
+            let rsrd = rs1_c(rinst) + 8;
+
+            if bit_range_get!(rinst.raw, (10, 10)) == 0 {
+                DecodedInst::CSrli { rsrd, shamt : c_shamt }
+            }
+            else {
+                DecodedInst::CSrai { rsrd, shamt : c_shamt }
+            }
+        },
+        InstSpec(InstOpcode::C2, 0) => DecodedInst::CSlli {
+            rsrd : rd,
+            shamt : c_shamt
+        },
+        InstSpec(InstOpcode::C2, 1) => match rd {
+            0 => illegal!(rinst, IllegalReason::Reserved("C.LQSP")),
+            rd => DecodedInst::CLoadStack {
+                width : CLoadStoreWidth::Cq,
+                rd,
+                imm : immgen!(C2_LQSP, rinst.raw)
+            }
+        },
+        InstSpec(InstOpcode::C2, 5) => DecodedInst::CStoreStack {
+            width : CLoadStoreWidth::Cq,
+            rs2 : bit_range_get!(rinst.raw, (2, 6)) as usize,
+            imm : immgen!(C2_SQSP, rinst.raw)
+        },
+
+        InstSpec(InstOpcode::OPIMM, 1) if funct5 == 0 =>
+            DecodedInst::Slli { rs1, rd, shamt : shamt7 },
+        InstSpec(InstOpcode::OPIMM, 5) if funct5 == 0 =>
+            DecodedInst::Srli { rs1, rd, shamt : shamt7 },
+        InstSpec(InstOpcode::OPIMM, 5) if funct5 == 0b01000 =>
+            DecodedInst::Srai { rs1, rd, shamt : shamt7 },
+
+        InstSpec(InstOpcode::LOAD, 7) => DecodedInst::Load {
+            width : LoadStoreWidth::DoubleU,
+            rs1,
+            rd,
+            imm : immgen!(I, rinst.raw)
+        },
+        InstSpec(InstOpcode::MISCMEM, 2) => DecodedInst::Load {
+            width : LoadStoreWidth::Quad,
+            rs1,
+            rd,
+            imm : immgen!(I, rinst.raw)
+        },
+        InstSpec(InstOpcode::STORE, 4) => DecodedInst::Store {
+            width : LoadStoreWidth::Quad,
+            rs1,
+            rs2,
+            imm : immgen!(S, rinst.raw)
+        },
+
+        InstSpec(InstOpcode::CUSTOM2, 0) => DecodedInst::Addid {
+            rs1,
+            rd,
+            imm : immgen!(I, rinst.raw)
+        },
+        InstSpec(InstOpcode::CUSTOM2, 1) if funct6 == 0 =>
+            DecodedInst::Sllid { rs1, rd, shamt : shamt6 },
+        InstSpec(InstOpcode::CUSTOM2, 5) if funct6 == 0 =>
+            DecodedInst::Srlid { rs1, rd, shamt : shamt6 },
+        InstSpec(InstOpcode::CUSTOM2, 5) if funct6 == 0b010000 =>
+            DecodedInst::Sraid { rs1, rd, shamt : shamt6 },
+        InstSpec(InstOpcode::CUSTOM3, funct3) => match (funct7, funct3) {
+            (0b0000000, 0) => DecodedInst::Addd { rs1, rs2, rd },
+            (0b0100000, 0) => DecodedInst::Subd { rs1, rs2, rd },
+            (0b0000000, 1) => DecodedInst::Slld { rs1, rs2, rd },
+            (0b0000000, 5) => DecodedInst::Srld { rs1, rs2, rd },
+            (0b0100000, 5) => DecodedInst::Srad { rs1, rs2, rd },
+            (0b0000001, 0) => DecodedInst::Muld { rs1, rs2, rd },
+            (0b0000001, 4) => DecodedInst::Divd { rs1, rs2, rd },
+            (0b0000001, 5) => DecodedInst::Divud { rs1, rs2, rd },
+            (0b0000001, 6) => DecodedInst::Remd { rs1, rs2, rd },
+            (0b0000001, 7) => DecodedInst::Remud { rs1, rs2, rd },
+            _ => illegal!(rinst, IllegalReason::InvalidField("funct7"))
+        },
+        _ => decode(rinst)?
+    };
+
+    Ok(inst)
+}
+
+/// The E bases only have x0-x15. Checks every integer register field the
+/// encoding uses; floating-point registers are unaffected.
+fn check_embedded(rinst : &RawInst, xlen : ArchWidth) -> Result<(), IllegalInst> {
+    use InstOpcode::*;
+
+    let rv32 = xlen == ArchWidth::RV32;
+    let (rd, rs1, rs2) = (rd(rinst), rs1(rinst), rs2(rinst));
+    let c_rs2 = bit_range_get!(rinst.raw, (2, 6)) as usize;
+    let funct5 = bit_range_get!(rinst.raw, (27, 31));
+
+    let regs = match pre_decode(rinst)? {
+        InstSpec(C1, 0) | InstSpec(C1, 2) | InstSpec(C1, 3) | InstSpec(C2, 0) |
+        InstSpec(C2, 2) => vec![rd],
+        InstSpec(C1, 1) | InstSpec(C2, 3) if !rv32 => vec![rd],
+        InstSpec(C2, 4) => vec![rd, c_rs2],
+        InstSpec(C2, 6) => vec![c_rs2],
+        InstSpec(C2, 7) if !rv32 => vec![c_rs2],
+
+        InstSpec(LUI, _) | InstSpec(AUIPC, _) | InstSpec(JAL, _) => vec![rd],
+        InstSpec(JALR, _) | InstSpec(LOAD, _) | InstSpec(OPIMM, _) |
+        InstSpec(OPIMM32, _) => vec![rd, rs1],
+        InstSpec(STORE, _) | InstSpec(BRANCH, _) => vec![rs1, rs2],
+        InstSpec(OP, _) | InstSpec(OP32, _) | InstSpec(AMO, _) => vec![rd, rs1, rs2],
+        InstSpec(LOADFP, _) | InstSpec(STOREFP, _) => vec![rs1],
+        InstSpec(OPFP, _) => match funct5 {
+            0b10100 | 0b11000 | 0b11100 => vec![rd],
+            0b11010 | 0b11110 => vec![rs1],
+            _ => vec![]
+        },
+        InstSpec(SYSTEM, 0) => vec![rs1, rs2],
+        InstSpec(SYSTEM, 1..=3) => vec![rd, rs1],
+        InstSpec(SYSTEM, _) => vec![rd],
+
+        _ => vec![]
+    };
+
+    match regs.into_iter().find(|r| *r >= 16) {
+        Some(reg) => illegal!(rinst, IllegalReason::InvalidRegister(reg)),
+        None => Ok(())
+    }
+}
+
+#[inline(always)]
+fn decode_opfp(rinst : &RawInst, funct3 : usize) -> Result<DecodedInst, IllegalInst> {
+    let funct5 = bit_range_get!(rinst.raw, (27, 31));
+    let fmt = fp_fmt(rinst)?;
+    let rs1 = rs1(rinst);
+    let rs2 = rs2(rinst);
+    let rd = rd(rinst);
+    let rm = funct3;
+
+    Ok(match (funct5, funct3, rs2) {
+        (0b00000, _, _) => DecodedInst::FAdd { fmt, rs1, rs2, rd, rm },
+        (0b00001, _, _) => DecodedInst::FSub { fmt, rs1, rs2, rd, rm },
+        (0b00010, _, _) => DecodedInst::FMul { fmt, rs1, rs2, rd, rm },
+        (0b00011, _, _) => DecodedInst::FDiv { fmt, rs1, rs2, rd, rm },
+        (0b01011, _, 0) => DecodedInst::FSqrt { fmt, rs1, rd, rm },
+        (0b00100, 0, _) => DecodedInst::FSgnj { fmt, rs1, rs2, rd },
+        (0b00100, 1, _) => DecodedInst::FSgnjn { fmt, rs1, rs2, rd },
+        (0b00100, 2, _) => DecodedInst::FSgnjx { fmt, rs1, rs2, rd },
+        (0b00101, 0, _) => DecodedInst::FMin { fmt, rs1, rs2, rd },
+        (0b00101, 1, _) => DecodedInst::FMax { fmt, rs1, rs2, rd },
+        (0b01000, _, 1) if fmt == FpFmt::S => DecodedInst::FCvtSD { rs1, rd, rm },
+        (0b01000, _, 0) if fmt == FpFmt::D => DecodedInst::FCvtDS { rs1, rd, rm },
+        (0b10100, 0, _) => DecodedInst::FLe { fmt, rs1, rs2, rd },
+        (0b10100, 1, _) => DecodedInst::FLt { fmt, rs1, rs2, rd },
+        (0b10100, 2, _) => DecodedInst::FEq { fmt, rs1, rs2, rd },
+        (0b11000, _, 0..=3) => DecodedInst::FCvtToInt {
+            fmt,
+            ity : field(rinst, rs2 as u32, "rs2")?,
+            rs1,
+            rd,
+            rm
+        },
+        (0b11010, _, 0..=3) => DecodedInst::FCvtFromInt {
+            fmt,
+            ity : field(rinst, rs2 as u32, "rs2")?,
+            rs1,
+            rd,
+            rm
+        },
+        (0b11100, 0, 0) => DecodedInst::FMvXF { fmt, rs1, rd },
+        (0b11100, 1, 0) => DecodedInst::FClass { fmt, rs1, rd },
+        (0b11110, 0, 0) => DecodedInst::FMvFX { fmt, rs1, rd },
+        _ => illegal!(rinst, IllegalReason::InvalidField("funct5"))
+    })
+}
+
+#[inline(always)]
+fn decode_amo(rinst : &RawInst) -> Result<DecodedInst, IllegalInst> {
+    let funct5 = bit_range_get!(rinst.raw, (27, 31));
+    let width = field(rinst, bit_range_get!(rinst.raw, (12, 14)), "funct3")?;
+    let ord = AmoOrdering {
+        aq : bit_range_get!(rinst.raw, (26, 26)) == 1,
+        rl : bit_range_get!(rinst.raw, (25, 25)) == 1
+    };
+    let rs1 = rs1(rinst);
+    let rs2 = rs2(rinst);
+    let rd = rd(rinst);
+
+    Ok(match funct5 {
+        0b00010 if rs2 == 0 => DecodedInst::Lr { width, ord, rs1, rd },
+        0b00011 => DecodedInst::Sc { width, ord, rs1, rs2, rd },
+        _ => DecodedInst::Amo {
+            op : field(rinst, funct5, "funct5")?,
+            width,
+            ord,
+            rs1,
+            rs2,
+            rd
+        }
+    })
 }
@@ -0,0 +1,462 @@
+use crate::memif::*;
+use crate::rv64emu::*;
+use crate::rv64priv::*;
+
+//
+// Sv39/Sv48/Sv57 address translation. Every guest access goes through
+// ArchState::translate, which consults the TLB before walking the page
+// table rooted at satp.
+//
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store
+}
+
+impl Access {
+    pub fn page_fault(&self, addr : u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionPageFault(addr),
+            Access::Load => Exception::LoadPageFault(addr),
+            Access::Store => Exception::StorePageFault(addr)
+        }
+    }
+
+    /// The region permission the access needs.
+    pub fn perm(&self) -> u8 {
+        match self {
+            Access::Fetch => PERM_X,
+            Access::Load => PERM_R,
+            Access::Store => PERM_W
+        }
+    }
+
+    pub fn access_fault(&self, addr : u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault(addr),
+            Access::Load => Exception::LoadAccessFault(addr),
+            Access::Store => Exception::StoreAccessFault(addr)
+        }
+    }
+}
+
+//
+// satp
+//
+
+pub const SATP_MODE_BARE : u64 = 0;
+pub const SATP_MODE_SV39 : u64 = 8;
+pub const SATP_MODE_SV48 : u64 = 9;
+pub const SATP_MODE_SV57 : u64 = 10;
+
+const SATP_PPN_MASK : u64 = (1 << 44) - 1;
+const SATP_ASID_MASK : u64 = 0xFFFF << 44;
+
+/// Writes selecting an unsupported mode have no effect at all.
+pub fn legalize_satp(old : u64, new : u64) -> u64 {
+    match new >> 60 {
+        SATP_MODE_BARE | SATP_MODE_SV39 | SATP_MODE_SV48 | SATP_MODE_SV57 =>
+            new & (0xF << 60 | SATP_ASID_MASK | SATP_PPN_MASK),
+        _ => old
+    }
+}
+
+//
+// Page table entries
+//
+
+pub const PTE_V : u64 = 1 << 0;
+pub const PTE_R : u64 = 1 << 1;
+pub const PTE_W : u64 = 1 << 2;
+pub const PTE_X : u64 = 1 << 3;
+pub const PTE_U : u64 = 1 << 4;
+pub const PTE_G : u64 = 1 << 5;
+pub const PTE_A : u64 = 1 << 6;
+pub const PTE_D : u64 = 1 << 7;
+
+/// Reserved bits plus Svpbmt and Svnapot, none of which are implemented.
+const PTE_RESERVED : u64 = 0x3FF << 54;
+const PTE_PPN_MASK : u64 = (1 << 44) - 1;
+
+const PAGE_SHIFT : u64 = 12;
+const PAGE_SIZE : u64 = 1 << PAGE_SHIFT;
+
+/// Whether an access of size bytes spans two pages.
+#[inline(always)]
+pub fn crosses_page(addr : u64, size : u64) -> bool {
+    (addr & (PAGE_SIZE - 1)) + size > PAGE_SIZE
+}
+
+//
+// TLB
+//
+
+const TLB_ENTRIES : usize = 256;
+
+/// A translation for one 4 KiB page. Entries created from superpages keep
+/// their level so that SFENCE.VMA on any address within the superpage
+/// removes them.
+#[derive(Debug, Default, Clone, Copy)]
+struct TlbEntry {
+    valid : bool,
+    vpn : u64,
+    ppn : u64,
+    asid : u64,
+    global : bool,
+    level : u64,
+    pte : u64
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TlbStats {
+    pub hits : u64,
+    pub misses : u64,
+    pub walks : u64
+}
+
+/// Direct-mapped, ASID-tagged TLB.
+#[derive(Debug, Clone)]
+pub struct Tlb {
+    entries : Vec<TlbEntry>,
+    pub stats : TlbStats
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Tlb {
+            entries : vec![TlbEntry::default(); TLB_ENTRIES],
+            stats : TlbStats::default()
+        }
+    }
+}
+
+impl Tlb {
+    #[inline(always)]
+    fn lookup(&self, vpn : u64, asid : u64) -> Option<TlbEntry> {
+        let entry = self.entries[vpn as usize % TLB_ENTRIES];
+
+        if entry.valid && entry.vpn == vpn && (entry.global || entry.asid == asid) {
+            Some(entry)
+        }
+        else {
+            None
+        }
+    }
+
+    fn insert(&mut self, entry : TlbEntry) {
+        self.entries[entry.vpn as usize % TLB_ENTRIES] = entry;
+    }
+
+    /// SFENCE.VMA: None stands for all addresses or all ASIDs. Global
+    /// mappings survive an ASID-specific flush.
+    pub fn flush(&mut self, vaddr : Option<u64>, asid : Option<u64>) {
+        for entry in self.entries.iter_mut().filter(|e| e.valid) {
+            let shift = 9 * entry.level;
+            let addr_match = vaddr
+                .map_or(true, |va| (va >> PAGE_SHIFT) >> shift == entry.vpn >> shift);
+            let asid_match = asid.map_or(true, |asid| !entry.global && entry.asid == asid);
+
+            if addr_match && asid_match {
+                entry.valid = false;
+            }
+        }
+    }
+}
+
+impl ArchState {
+    /// Loads and stores use MPP as their privilege when mstatus.MPRV is set.
+    #[inline(always)]
+    pub fn effective_mode(&self, access : Access) -> PrivMode {
+        if access != Access::Fetch && self.csrs.mstatus & MSTATUS_MPRV != 0 {
+            num::FromPrimitive::from_u64(self.csrs.mstatus >> 11 & 0b11).unwrap_or(PrivMode::User)
+        }
+        else {
+            self.mode
+        }
+    }
+
+    /// Permission check on a leaf PTE, including the SUM and MXR rules.
+    fn leaf_permits(&self, pte : u64, mode : PrivMode, access : Access) -> bool {
+        let status = self.csrs.mstatus;
+
+        if mode == PrivMode::User && pte & PTE_U == 0 {
+            return false;
+        }
+
+        if mode == PrivMode::Supervisor && pte & PTE_U != 0 &&
+           (access == Access::Fetch || status & MSTATUS_SUM == 0) {
+            return false;
+        }
+
+        match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => pte & PTE_R != 0 || (status & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+            Access::Store => pte & PTE_W != 0
+        }
+    }
+
+    /// Translates a virtual address to a physical one.
+    #[inline(always)]
+    pub fn translate(
+        &mut self, mem : &mut dyn MemIf, vaddr : u64, access : Access) -> Result<u64, Exception> {
+
+        let satp = self.csrs.satp;
+
+        if satp >> 60 == SATP_MODE_BARE {
+            return Ok(vaddr);
+        }
+
+        let mode = self.effective_mode(access);
+
+        if mode == PrivMode::Machine {
+            return Ok(vaddr);
+        }
+
+        let levels = match satp >> 60 {
+            SATP_MODE_SV39 => 3,
+            SATP_MODE_SV48 => 4,
+            _ => 5
+        };
+
+        // Addresses must be sign-extended from the top translated bit
+        let va_bits = PAGE_SHIFT + 9 * levels;
+        if ((vaddr << (64 - va_bits)) as i64 >> (64 - va_bits)) as u64 != vaddr {
+            return Err(access.page_fault(vaddr));
+        }
+
+        let vpn = vaddr >> PAGE_SHIFT;
+        let asid = (satp & SATP_ASID_MASK) >> 44;
+
+        let entry = match self.tlb.lookup(vpn, asid) {
+            // A store to a clean page walks again to set D
+            Some(entry) if access != Access::Store || entry.pte & PTE_D != 0 => {
+                self.tlb.stats.hits += 1;
+
+                if !self.leaf_permits(entry.pte, mode, access) {
+                    return Err(access.page_fault(vaddr));
+                }
+
+                entry
+            },
+            hit => {
+                if hit.is_none() {
+                    self.tlb.stats.misses += 1;
+                }
+
+                let entry = self.walk(mem, vaddr, access, mode, levels, asid)?;
+                self.tlb.insert(entry);
+                entry
+            }
+        };
+
+        Ok(entry.ppn << PAGE_SHIFT | (vaddr & (PAGE_SIZE - 1)))
+    }
+
+    /// Walks the page table, setting A (and D for stores) in the leaf.
+    fn walk(
+        &mut self, mem : &mut dyn MemIf, vaddr : u64, access : Access, mode : PrivMode,
+        levels : u64, asid : u64) -> Result<TlbEntry, Exception> {
+
+        self.tlb.stats.walks += 1;
+
+        let vpn = vaddr >> PAGE_SHIFT;
+        let mut table = (self.csrs.satp & SATP_PPN_MASK) << PAGE_SHIFT;
+        let mut global = false;
+
+        for level in (0..levels).rev() {
+            let pte_addr = table + (vpn >> (9 * level) & 0x1FF) * 8;
+
+            // Walks are checked by PMP as S-mode accesses
+            if !self.pmp_permits(pte_addr, 8, Access::Load, PrivMode::Supervisor) {
+                return Err(access.access_fault(vaddr));
+            }
+
+            let mut pte = read64(mem, pte_addr).map_err(|_| access.access_fault(vaddr))?;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) ||
+               pte & PTE_RESERVED != 0 {
+                return Err(access.page_fault(vaddr));
+            }
+
+            let ppn = pte >> 10 & PTE_PPN_MASK;
+            global |= pte & PTE_G != 0;
+
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+
+            // Superpages must be aligned to their size
+            let level_mask = (1 << (9 * level)) - 1;
+
+            if ppn & level_mask != 0 || !self.leaf_permits(pte, mode, access) {
+                return Err(access.page_fault(vaddr));
+            }
+
+            let ad = PTE_A | if access == Access::Store { PTE_D } else { 0 };
+
+            if pte & ad != ad {
+                if !self.pmp_permits(pte_addr, 8, Access::Store, PrivMode::Supervisor) {
+                    return Err(access.access_fault(vaddr));
+                }
+
+                pte |= ad;
+                write64(mem, pte_addr, pte).map_err(|_| access.access_fault(vaddr))?;
+            }
+
+            return Ok(TlbEntry {
+                valid : true,
+                vpn,
+                ppn : (ppn & !level_mask) | (vpn & level_mask),
+                asid,
+                global,
+                level,
+                pte
+            });
+        }
+
+        // No leaf by level 0
+        Err(access.page_fault(vaddr))
+    }
+}
+
+#[cfg(test)]
+use crate::rv64csr::*;
+#[cfg(test)]
+use crate::rv64pmp::*;
+#[cfg(test)]
+use crate::rv64defs::IllegalReason;
+
+/// Sv39 tables in a 64 KiB TestMem: root at 0x1000, level 1 at 0x2000
+/// and level 0 at 0x3000, all mapping the low 2 MiB of VA.
+#[cfg(test)]
+fn sv39_arch() -> (ArchState, TestMem) {
+    let mut arch = ArchState::new_system();
+    let mut mem = TestMem::new(0x10000);
+
+    // PMP lets S-mode at all of memory
+    arch.pmp.write_addr(0, u64::MAX);
+    arch.pmp.write_cfg(0, PMP_A_NAPOT | PMP_R | PMP_W | PMP_X);
+
+    write64(&mut mem, 0x1000, (0x2 << 10) | PTE_V).unwrap();
+    write64(&mut mem, 0x2000, (0x3 << 10) | PTE_V).unwrap();
+
+    arch.csr_write(CSR_SATP, SATP_MODE_SV39 << 60 | 1 << 44 | 0x1).unwrap();
+    arch.csrs.mtvec = 0x100;
+    arch.mode = PrivMode::Supervisor;
+    (arch, mem)
+}
+
+#[cfg(test)]
+fn map(mem : &mut TestMem, vpn : u64, ppn : u64, flags : u64) {
+    write64(mem, 0x3000 + vpn * 8, (ppn << 10) | flags | PTE_V).unwrap();
+}
+
+#[test]
+fn test_translate_sv39() {
+    let (mut arch, mut mem) = sv39_arch();
+
+    map(&mut mem, 0x10, 0x8, PTE_R | PTE_W);
+    map(&mut mem, 0x11, 0x9, PTE_R | PTE_X | PTE_U);
+
+    // First access walks and sets A; the second hits in the TLB
+    assert_eq!(arch.translate(&mut mem, 0x10123, Access::Load), Ok(0x8123));
+    assert_eq!(read64(&mem, 0x3080).unwrap() & (PTE_A | PTE_D), PTE_A);
+    assert_eq!(arch.translate(&mut mem, 0x10456, Access::Load), Ok(0x8456));
+    assert_eq!(arch.tlb.stats, TlbStats { hits : 1, misses : 1, walks : 1 });
+
+    // A store to the clean page walks again to set D
+    assert_eq!(arch.translate(&mut mem, 0x10008, Access::Store), Ok(0x8008));
+    assert_eq!(read64(&mem, 0x3080).unwrap() & PTE_D, PTE_D);
+    assert_eq!(arch.tlb.stats.walks, 2);
+
+    // Unmapped, non-writable and non-canonical addresses fault
+    assert_eq!(arch.translate(&mut mem, 0x12000, Access::Load),
+               Err(Exception::LoadPageFault(0x12000)));
+    assert_eq!(arch.translate(&mut mem, 0x11000, Access::Store),
+               Err(Exception::StorePageFault(0x11000)));
+    assert_eq!(arch.translate(&mut mem, 1 << 40, Access::Fetch),
+               Err(Exception::InstructionPageFault(1 << 40)));
+
+    // S-mode can read U pages only with SUM and never execute them
+    assert_eq!(arch.translate(&mut mem, 0x11000, Access::Load),
+               Err(Exception::LoadPageFault(0x11000)));
+    arch.set_mstatus(MSTATUS_SUM);
+    assert_eq!(arch.translate(&mut mem, 0x11000, Access::Load), Ok(0x9000));
+    assert!(arch.translate(&mut mem, 0x11000, Access::Fetch).is_err());
+
+    // U-mode cannot touch S pages
+    arch.mode = PrivMode::User;
+    assert_eq!(arch.translate(&mut mem, 0x11000, Access::Fetch), Ok(0x9000));
+    assert!(arch.translate(&mut mem, 0x10000, Access::Load).is_err());
+
+    // MXR makes execute-only pages readable
+    map(&mut mem, 0x12, 0xA, PTE_X | PTE_U);
+    assert!(arch.translate(&mut mem, 0x12000, Access::Load).is_err());
+    arch.set_mstatus(MSTATUS_MXR);
+    assert_eq!(arch.translate(&mut mem, 0x12000, Access::Load), Ok(0xA000));
+
+    // M-mode is untranslated unless MPRV selects a lower mode
+    arch.mode = PrivMode::Machine;
+    assert_eq!(arch.translate(&mut mem, 0x12000, Access::Load), Ok(0x12000));
+    arch.set_mstatus(MSTATUS_MPRV | MSTATUS_MXR);
+    assert_eq!(arch.translate(&mut mem, 0x12000, Access::Load), Ok(0xA000));
+}
+
+#[test]
+fn test_superpages_and_sfence() {
+    let (mut arch, mut mem) = sv39_arch();
+
+    // 2 MiB page at VA 0x4000_0000 (via a second level-1 table entry) and
+    // a misaligned one at VA 0x4020_0000
+    write64(&mut mem, 0x1008, (0x4 << 10) | PTE_V).unwrap();
+    write64(&mut mem, 0x4000, (0x200 << 10) | PTE_V | PTE_R | PTE_G).unwrap();
+    write64(&mut mem, 0x4008, (0x201 << 10) | PTE_V | PTE_R).unwrap();
+
+    assert_eq!(arch.translate(&mut mem, 0x4012_3456, Access::Load), Ok(0x0032_3456));
+    assert_eq!(arch.translate(&mut mem, 0x4020_0000, Access::Load),
+               Err(Exception::LoadPageFault(0x4020_0000)));
+
+    // Global mappings survive an ASID flush, then an address flush
+    // anywhere in the superpage removes them
+    write64(&mut mem, 0x4000, (0x400 << 10) | PTE_V | PTE_R | PTE_G).unwrap();
+    arch.tlb.flush(None, Some(1));
+    assert_eq!(arch.translate(&mut mem, 0x4012_3456, Access::Load), Ok(0x0032_3456));
+
+    arch.regw(10, 0x401F_F000);
+    exec_raw(&mut arch, &mut mem, 0x12050073);
+    assert_eq!(arch.translate(&mut mem, 0x4012_3456, Access::Load), Ok(0x0052_3456));
+
+    // sfence.vma is illegal in S-mode with TVM, as is satp
+    arch.set_mstatus(MSTATUS_TVM);
+    assert_eq!(arch.csr_read(CSR_SATP), Err(IllegalReason::InvalidCsr(CSR_SATP)));
+    exec_raw(&mut arch, &mut mem, 0x12050073);
+    assert_eq!((arch.csrs.mcause, arch.mode), (2, PrivMode::Machine));
+}
+
+#[test]
+fn test_paged_exec() {
+    let (mut arch, mut mem) = sv39_arch();
+
+    // Code page at VA 0x10000 holding "ld x5, 0(x10)", data page at 0x20000
+    map(&mut mem, 0x10, 0x8, PTE_X);
+    map(&mut mem, 0x20, 0x9, PTE_R);
+    mem.store::<u32>(0x8000, 0x00053283).unwrap();
+    write64(&mut mem, 0x9010, 0x1234).unwrap();
+
+    arch.pc = 0x10000;
+    arch.regw(10, 0x20010);
+    let raw = arch.fetch_inst(&mut mem).unwrap();
+    assert_eq!(raw.raw, 0x00053283);
+    exec_raw(&mut arch, &mut mem, raw.raw);
+    assert_eq!(arch.regr(5), 0x1234);
+
+    // A load spanning into an unmapped page faults with nothing written
+    arch.pc = 0x10000;
+    arch.regw(10, 0x20FFC);
+    exec_raw(&mut arch, &mut mem, 0x00053283);
+    assert_eq!((arch.csrs.mcause, arch.csrs.mtval), (13, 0x21000));
+    assert_eq!(arch.regr(5), 0x1234);
+}
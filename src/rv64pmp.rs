@@ -0,0 +1,229 @@
+use crate::rv64emu::*;
+use crate::rv64priv::*;
+use crate::rv64mmu::Access;
+
+//
+// Physical memory protection: 64 entries with 4-byte granularity. Checks
+// apply to physical addresses after translation, including page-table
+// walks, and only in system mode.
+//
+
+pub const PMP_ENTRIES : usize = 64;
+
+pub const PMP_R : u8 = 1 << 0;
+pub const PMP_W : u8 = 1 << 1;
+pub const PMP_X : u8 = 1 << 2;
+pub const PMP_A : u8 = 0b11 << 3;
+pub const PMP_L : u8 = 1 << 7;
+
+pub const PMP_A_OFF : u8   = 0 << 3;
+pub const PMP_A_TOR : u8   = 1 << 3;
+pub const PMP_A_NA4 : u8   = 2 << 3;
+pub const PMP_A_NAPOT : u8 = 3 << 3;
+
+/// pmpaddr holds bits 55:2 of an address.
+const PMP_ADDR_MASK : u64 = (1 << 54) - 1;
+
+#[derive(Debug, Clone)]
+pub struct Pmp {
+    pub cfg : [u8; PMP_ENTRIES],
+    pub addr : [u64; PMP_ENTRIES]
+}
+
+impl Default for Pmp {
+    fn default() -> Self {
+        Pmp {
+            cfg : [0; PMP_ENTRIES],
+            addr : [0; PMP_ENTRIES]
+        }
+    }
+}
+
+impl Pmp {
+    fn locked(&self, i : usize) -> bool {
+        self.cfg[i] & PMP_L != 0
+    }
+
+    /// Locked entries ignore writes. R = 0, W = 1 is reserved and reads
+    /// back with W clear.
+    pub fn write_cfg(&mut self, i : usize, val : u8) {
+        if self.locked(i) {
+            return;
+        }
+
+        let val = val & (PMP_R | PMP_W | PMP_X | PMP_A | PMP_L);
+
+        self.cfg[i] = if val & PMP_R == 0 { val & !PMP_W } else { val };
+    }
+
+    /// A locked TOR entry also locks the address below it.
+    pub fn write_addr(&mut self, i : usize, val : u64) {
+        if self.locked(i) ||
+           (i + 1 < PMP_ENTRIES && self.locked(i + 1) && self.cfg[i + 1] & PMP_A == PMP_A_TOR) {
+            return;
+        }
+
+        self.addr[i] = val & PMP_ADDR_MASK;
+    }
+
+    /// Byte range [start, end) covered by an entry, or None when off.
+    fn range(&self, i : usize) -> Option<(u64, u64)> {
+        let addr = self.addr[i];
+
+        match self.cfg[i] & PMP_A {
+            PMP_A_OFF => None,
+            PMP_A_TOR => {
+                let start = if i == 0 { 0 } else { self.addr[i - 1] << 2 };
+                Some((start, addr << 2))
+            },
+            PMP_A_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_A_NAPOT => {
+                let ones = addr.trailing_ones() as u64;
+                let start = (addr & !((1 << ones) - 1)) << 2;
+                Some((start, start + (8 << ones)))
+            },
+            _ => unreachable!("PMP_A is two bits wide")
+        }
+    }
+
+    /// The lowest-numbered entry overlapping the access decides. It must
+    /// cover the whole access, and outside M-mode an access that matches
+    /// nothing fails. M-mode is only bound by locked entries.
+    pub fn permits(&self, paddr : u64, size : u64, access : Access, mode : PrivMode) -> bool {
+        let end = paddr.saturating_add(size);
+
+        for i in 0..PMP_ENTRIES {
+            let (start, stop) = match self.range(i) {
+                Some(range) => range,
+                None => continue
+            };
+
+            if end <= start || paddr >= stop {
+                continue;
+            }
+
+            if paddr < start || end > stop {
+                return false;
+            }
+
+            if mode == PrivMode::Machine && !self.locked(i) {
+                return true;
+            }
+
+            let perm = match access {
+                Access::Fetch => PMP_X,
+                Access::Load => PMP_R,
+                Access::Store => PMP_W
+            };
+
+            return self.cfg[i] & perm != 0;
+        }
+
+        mode == PrivMode::Machine
+    }
+}
+
+impl ArchState {
+    #[inline(always)]
+    pub fn pmp_permits(&self, paddr : u64, size : u64, access : Access, mode : PrivMode) -> bool {
+        !self.system || self.pmp.permits(paddr, size, access, mode)
+    }
+}
+
+#[cfg(test)]
+use crate::memif::*;
+#[cfg(test)]
+use crate::rv64csr::*;
+
+#[test]
+fn test_pmp_matching() {
+    let mut pmp = Pmp::default();
+    let s = PrivMode::Supervisor;
+
+    // Nothing configured: S-mode fails, M-mode succeeds
+    assert!(!pmp.permits(0x1000, 4, Access::Load, s));
+    assert!(pmp.permits(0x1000, 4, Access::Load, PrivMode::Machine));
+
+    // 0: TOR [0, 0x1000) read-only
+    // 1: NA4 at 0x1000 read-write
+    // 2: NAPOT [0x2000, 0x3000) execute-only
+    pmp.write_addr(0, 0x1000 >> 2);
+    pmp.write_cfg(0, PMP_A_TOR | PMP_R);
+    pmp.write_addr(1, 0x1000 >> 2);
+    pmp.write_cfg(1, PMP_A_NA4 | PMP_R | PMP_W);
+    pmp.write_addr(2, (0x2000 >> 2) | 0x1FF);
+    pmp.write_cfg(2, PMP_A_NAPOT | PMP_X);
+
+    assert!(pmp.permits(0xFF8, 8, Access::Load, s));
+    assert!(!pmp.permits(0xFF8, 8, Access::Store, s));
+    assert!(pmp.permits(0x1000, 4, Access::Store, s));
+    assert!(pmp.permits(0x2FFC, 4, Access::Fetch, s));
+    assert!(!pmp.permits(0x2FFC, 4, Access::Load, s));
+    assert!(!pmp.permits(0x3000, 4, Access::Fetch, s));
+
+    // Accesses straddling an entry boundary fail even if both sides allow them
+    assert!(!pmp.permits(0xFFC, 8, Access::Load, s));
+
+    // The lowest-numbered match wins
+    pmp.write_addr(3, 0x800 >> 2);
+    pmp.write_cfg(3, PMP_A_NA4 | PMP_R | PMP_W);
+    assert!(!pmp.permits(0x800, 4, Access::Store, s));
+
+    // W without R is reserved
+    pmp.write_cfg(4, PMP_A_NA4 | PMP_W);
+    assert_eq!(pmp.cfg[4], PMP_A_NA4);
+
+    // M-mode ignores unlocked entries and honours locked ones, which can
+    // no longer be changed, nor can the address below a locked TOR
+    assert!(pmp.permits(0xFF8, 8, Access::Store, PrivMode::Machine));
+    pmp.write_cfg(0, PMP_A_TOR | PMP_R | PMP_L);
+    assert!(!pmp.permits(0xFF8, 8, Access::Store, PrivMode::Machine));
+    pmp.write_cfg(0, PMP_A_TOR | PMP_R | PMP_W);
+    pmp.write_addr(0, 0);
+    assert_eq!((pmp.cfg[0], pmp.addr[0]), (PMP_A_TOR | PMP_R | PMP_L, 0x1000 >> 2));
+
+    pmp.write_cfg(2, PMP_A_TOR | PMP_X | PMP_L);
+    pmp.write_addr(1, 0);
+    assert_eq!(pmp.addr[1], 0x1000 >> 2);
+}
+
+#[test]
+fn test_pmp_access_faults() {
+    let mut arch = ArchState::new_system();
+    let mut mem = TestMem::new(0x1000);
+
+    // pmpcfg0 packs entries 0-7: entry 0 is a read-only NAPOT [0, 0x800),
+    // entry 1 a read-write TOR [0x800, 0x1000)
+    arch.csr_write(CSR_PMPADDR0, 0xFF).unwrap();
+    arch.csr_write(CSR_PMPADDR0 + 1, 0x1000 >> 2).unwrap();
+    arch.csr_write(CSR_PMPCFG0, ((PMP_A_TOR | PMP_R | PMP_W) as u64) << 8 |
+                                (PMP_A_NAPOT | PMP_R) as u64).unwrap();
+    assert_eq!(arch.csr_read(CSR_PMPCFG0), Ok(0x0B19));
+    assert!(arch.csr_read(CSR_PMPCFG0 + 1).is_err());
+
+    arch.csrs.mtvec = 0x100;
+    arch.mode = PrivMode::Supervisor;
+
+    // sd x5, 0(x10) to the read-only region
+    arch.regw(10, 0x400);
+    exec_raw(&mut arch, &mut mem, 0x00553023);
+    assert_eq!((arch.mode, arch.csrs.mcause, arch.csrs.mtval), (PrivMode::Machine, 7, 0x400));
+
+    // ...and a byte store, through the same check
+    arch.mode = PrivMode::Supervisor;
+    exec_raw(&mut arch, &mut mem, 0x00550023);
+    assert_eq!(arch.csrs.mcause, 7);
+
+    // Fetches need X
+    arch.mode = PrivMode::Supervisor;
+    arch.pc = 0x900;
+    assert_eq!(arch.fetch_inst(&mut mem).unwrap_err(), Exception::InstructionAccessFault(0x900));
+
+    // The region is writable from M-mode, and MPRV applies S-mode rules
+    arch.mode = PrivMode::Machine;
+    arch.pc = 0;
+    assert_eq!(exec_raw(&mut arch, &mut mem, 0x00553023), ExecResult::Continue);
+    arch.set_mstatus(MSTATUS_MPRV | 1 << 11);
+    exec_raw(&mut arch, &mut mem, 0x00553023);
+    assert_eq!(arch.csrs.mcause, 7);
+}
@@ -0,0 +1,432 @@
+use crate::rv64emu::*;
+use crate::isa::*;
+
+//
+// Privileged architecture: M/S/U modes, trap entry and return and
+// interrupt selection. The CSRs are accessed through rv64csr.
+//
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, FromPrimitive)]
+pub enum PrivMode {
+    User       = 0,
+    Supervisor = 1,
+    Machine    = 3
+}
+
+//
+// mstatus fields
+//
+
+pub const MSTATUS_SIE : u64  = 1 << 1;
+pub const MSTATUS_MIE : u64  = 1 << 3;
+pub const MSTATUS_SPIE : u64 = 1 << 5;
+pub const MSTATUS_MPIE : u64 = 1 << 7;
+pub const MSTATUS_SPP : u64  = 1 << 8;
+pub const MSTATUS_MPP : u64  = 0b11 << 11;
+pub const MSTATUS_FS : u64   = 0b11 << 13;
+pub const MSTATUS_MPRV : u64 = 1 << 17;
+pub const MSTATUS_SUM : u64  = 1 << 18;
+pub const MSTATUS_MXR : u64  = 1 << 19;
+pub const MSTATUS_TVM : u64  = 1 << 20;
+pub const MSTATUS_TW : u64   = 1 << 21;
+pub const MSTATUS_TSR : u64  = 1 << 22;
+pub const MSTATUS_UXL : u64  = 0b11 << 32;
+pub const MSTATUS_SD : u64   = 1 << 63;
+/// Where SD lives in RV32, which has no UXL/SXL.
+pub const MSTATUS_SD32 : u64 = 1 << 31;
+
+/// UXL and SXL are fixed at 64 bits.
+const MSTATUS_XLEN : u64 = 2 << 32 | 2 << 34;
+
+const MSTATUS_WRITABLE : u64 =
+    MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE | MSTATUS_SPP |
+    MSTATUS_MPP | MSTATUS_FS | MSTATUS_MPRV | MSTATUS_SUM | MSTATUS_MXR |
+    MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR;
+
+/// The subset of mstatus visible through sstatus.
+pub const SSTATUS_MASK : u64 =
+    MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM |
+    MSTATUS_MXR | MSTATUS_UXL | MSTATUS_SD | MSTATUS_SD32;
+
+//
+// Interrupts, numbered by their bit in mip/mie and their cause code
+//
+
+pub const IRQ_S_SOFT : u64  = 1;
+pub const IRQ_M_SOFT : u64  = 3;
+pub const IRQ_S_TIMER : u64 = 5;
+pub const IRQ_M_TIMER : u64 = 7;
+pub const IRQ_S_EXT : u64   = 9;
+pub const IRQ_M_EXT : u64   = 11;
+
+/// Order in which simultaneously pending interrupts are taken.
+const IRQ_PRIORITY : [u64; 6] =
+    [IRQ_M_EXT, IRQ_M_SOFT, IRQ_M_TIMER, IRQ_S_EXT, IRQ_S_SOFT, IRQ_S_TIMER];
+
+pub const MIE_MASK : u64 = 0xAAA;
+pub const S_IRQ_MASK : u64 = 0x222;
+
+/// Every exception except an ECALL from M-mode can be delegated.
+pub const MEDELEG_MASK : u64 = 0xB3FF;
+
+/// Trap-related CSRs, stored as written (after WARL legalisation).
+#[derive(Debug, Default, Clone)]
+pub struct PrivCsrs {
+    pub mstatus : u64,
+    pub medeleg : u64,
+    pub mideleg : u64,
+    pub mie : u64,
+    pub mip : u64,
+    pub mtvec : u64,
+    pub mscratch : u64,
+    pub mepc : u64,
+    pub mcause : u64,
+    pub mtval : u64,
+    pub mcounteren : u64,
+    pub stvec : u64,
+    pub sscratch : u64,
+    pub sepc : u64,
+    pub scause : u64,
+    pub stval : u64,
+    pub scounteren : u64,
+    pub satp : u64
+}
+
+/// Handler address for a trap: vectored mode only applies to interrupts.
+#[inline(always)]
+fn trap_vector(tvec : u64, cause : u64, interrupt : bool) -> u64 {
+    let base = tvec & !0b11;
+
+    if interrupt && tvec & 0b11 == 1 {
+        base + 4 * cause
+    }
+    else {
+        base
+    }
+}
+
+/// Only direct (0) and vectored (1) modes exist.
+#[inline(always)]
+pub fn legalize_tvec(val : u64) -> u64 {
+    if val & 0b11 > 1 { val & !0b11 } else { val }
+}
+
+impl ArchState {
+    pub fn mstatus(&self) -> u64 {
+        let (val, sd) = if self.rv32() {
+            (self.csrs.mstatus, MSTATUS_SD32)
+        }
+        else {
+            (self.csrs.mstatus | MSTATUS_XLEN, MSTATUS_SD)
+        };
+
+        if val & MSTATUS_FS == MSTATUS_FS {
+            val | sd
+        }
+        else {
+            val
+        }
+    }
+
+    pub fn set_mstatus(&mut self, val : u64) {
+        let mut val = val & MSTATUS_WRITABLE;
+
+        // MPP is WARL and 2 is reserved
+        if val & MSTATUS_MPP == 2 << 11 {
+            val &= !MSTATUS_MPP;
+        }
+
+        self.csrs.mstatus = val;
+    }
+
+    /// With mstatus.FS Off, FP instructions and CSRs are illegal. Only
+    /// system mode models FS; user programs always have the FPU.
+    #[inline(always)]
+    pub fn fp_enabled(&self) -> bool {
+        !self.system || self.csrs.mstatus & MSTATUS_FS != 0
+    }
+
+    /// FS is not tracked precisely: any FP state change makes it Dirty.
+    #[inline(always)]
+    pub fn set_fp_dirty(&mut self) {
+        self.csrs.mstatus |= MSTATUS_FS;
+    }
+
+    /// Reads an exception pc, hiding bit 1 when C is disabled.
+    #[inline(always)]
+    pub fn epc(&self, epc : u64) -> u64 {
+        if self.isa.has(Extension::C) { epc } else { epc & !0b11 }
+    }
+
+    /// Enters the trap handler for a cause, in S-mode if delegated there
+    /// and otherwise in M-mode. Traps never go to a lower privilege.
+    pub fn take_trap(&mut self, cause : u64, tval : u64, interrupt : bool) {
+        let deleg = if interrupt { self.csrs.mideleg } else { self.csrs.medeleg };
+        let interrupt_bit = if self.rv32() { 1 << 31 } else { 1 << 63 };
+        let mcause = if interrupt { cause | interrupt_bit } else { cause };
+        let status = self.csrs.mstatus;
+
+        if self.debug {
+            println!("        trap: cause {:x} tval {:x} from {:?}", mcause, tval, self.mode);
+        }
+
+        if self.mode <= PrivMode::Supervisor && deleg >> cause & 1 == 1 {
+            self.csrs.sepc = self.pc;
+            self.csrs.scause = mcause;
+            self.csrs.stval = tval;
+
+            let spie = if status & MSTATUS_SIE != 0 { MSTATUS_SPIE } else { 0 };
+            let spp = if self.mode == PrivMode::Supervisor { MSTATUS_SPP } else { 0 };
+            self.csrs.mstatus = (status & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP)) | spie | spp;
+
+            self.mode = PrivMode::Supervisor;
+            self.pc = trap_vector(self.csrs.stvec, cause, interrupt);
+        }
+        else {
+            self.csrs.mepc = self.pc;
+            self.csrs.mcause = mcause;
+            self.csrs.mtval = tval;
+
+            let mpie = if status & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+            let mpp = (self.mode as u64) << 11;
+            self.csrs.mstatus = (status & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp;
+
+            self.mode = PrivMode::Machine;
+            self.pc = trap_vector(self.csrs.mtvec, cause, interrupt);
+        }
+    }
+
+    /// MRET: back to the mode in MPP with MIE restored from MPIE.
+    pub fn mret(&mut self) {
+        let status = self.csrs.mstatus;
+        let mode = num::FromPrimitive::from_u64(status >> 11 & 0b11).unwrap_or(PrivMode::User);
+        let mie = if status & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+        let mprv = if mode != PrivMode::Machine { MSTATUS_MPRV } else { 0 };
+
+        self.csrs.mstatus = (status & !(MSTATUS_MIE | MSTATUS_MPP | mprv)) | mie | MSTATUS_MPIE;
+        self.mode = mode;
+        self.pc = self.epc(self.csrs.mepc);
+    }
+
+    /// SRET: back to the mode in SPP with SIE restored from SPIE.
+    pub fn sret(&mut self) {
+        let status = self.csrs.mstatus;
+        let mode = if status & MSTATUS_SPP != 0 { PrivMode::Supervisor } else { PrivMode::User };
+        let sie = if status & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+
+        self.csrs.mstatus = (status & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | sie | MSTATUS_SPIE;
+        self.mode = mode;
+        self.pc = self.epc(self.csrs.sepc);
+    }
+
+    /// Highest-priority interrupt that is pending, enabled and not masked
+    /// by the privilege mode it would be taken in.
+    pub fn pending_interrupt(&self) -> Option<u64> {
+        let pending = self.csrs.mip & self.csrs.mie;
+
+        if pending == 0 {
+            return None;
+        }
+
+        let status = self.csrs.mstatus;
+        let m_enabled = self.mode < PrivMode::Machine || status & MSTATUS_MIE != 0;
+        let s_enabled = self.mode < PrivMode::Supervisor ||
+            (self.mode == PrivMode::Supervisor && status & MSTATUS_SIE != 0);
+
+        let m_pending = pending & !self.csrs.mideleg;
+        let s_pending = pending & self.csrs.mideleg;
+
+        IRQ_PRIORITY.iter().copied().find(|irq| {
+            (m_enabled && m_pending >> irq & 1 == 1) ||
+            (s_enabled && s_pending >> irq & 1 == 1)
+        })
+    }
+
+    /// Takes a pending interrupt, if any, before the next instruction.
+    pub fn take_interrupt(&mut self) -> bool {
+        match self.pending_interrupt() {
+            Some(irq) => {
+                self.take_trap(irq, 0, true);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::memif::*;
+#[cfg(test)]
+use crate::rv64csr::*;
+#[cfg(test)]
+use crate::rv64fpu;
+
+/// System-mode hart with direct-mode vectors at 0x100 (M) and 0x200 (S)
+/// and 0x400 bytes of memory.
+#[cfg(test)]
+fn system_arch() -> (ArchState, TestMem) {
+    let mut arch = ArchState::new_system();
+    arch.csrs.mtvec = 0x100;
+    arch.csrs.stvec = 0x200;
+    arch.pc = 0x40;
+    (arch, TestMem::new(0x400))
+}
+
+#[test]
+fn test_ecall_routing() {
+    let (mut arch, mut mem) = system_arch();
+
+    // ecall from M goes to mtvec with mepc at the ecall
+    assert_eq!(exec_raw(&mut arch, &mut mem, 0x00000073), ExecResult::Continue);
+    assert_eq!((arch.pc, arch.mode), (0x100, PrivMode::Machine));
+    assert_eq!((arch.csrs.mepc, arch.csrs.mcause), (0x40, 11));
+    assert_eq!(arch.mstatus() & MSTATUS_MPP, 3 << 11);
+
+    // mret to U-mode, then ecall from U is delegated to S
+    arch.csrs.medeleg = 1 << 8;
+    arch.csrs.mstatus &= !MSTATUS_MPP;
+    arch.csrs.mepc = 0x80;
+    assert_eq!(exec_raw(&mut arch, &mut mem, 0x30200073), ExecResult::Continue);
+    assert_eq!((arch.pc, arch.mode), (0x80, PrivMode::User));
+
+    exec_raw(&mut arch, &mut mem, 0x00000073);
+    assert_eq!((arch.pc, arch.mode), (0x200, PrivMode::Supervisor));
+    assert_eq!((arch.csrs.sepc, arch.csrs.scause), (0x80, 8));
+    assert_eq!(arch.mstatus() & MSTATUS_SPP, 0);
+
+    // ecall from S is not delegated and goes to M
+    exec_raw(&mut arch, &mut mem, 0x00000073);
+    assert_eq!((arch.pc, arch.mode), (0x100, PrivMode::Machine));
+    assert_eq!((arch.csrs.mepc, arch.csrs.mcause), (0x200, 9));
+    assert_eq!(arch.mstatus() & MSTATUS_MPP, 1 << 11);
+
+    // sret from S back to U
+    arch.mode = PrivMode::Supervisor;
+    arch.csrs.sepc = 0x84;
+    exec_raw(&mut arch, &mut mem, 0x10200073);
+    assert_eq!((arch.pc, arch.mode), (0x84, PrivMode::User));
+
+    // mret and sret are illegal from U-mode
+    exec_raw(&mut arch, &mut mem, 0x30200073);
+    assert_eq!((arch.pc, arch.mode), (0x100, PrivMode::Machine));
+    assert_eq!((arch.csrs.mcause, arch.csrs.mtval), (2, 0x30200073));
+}
+
+#[test]
+fn test_precise_memory_traps() {
+    let (mut arch, mut mem) = system_arch();
+
+    // ld x5, 0(x10) from an unmapped address leaves x5 untouched
+    arch.regw(5, 0x55);
+    arch.regw(10, 0x1000);
+    exec_raw(&mut arch, &mut mem, 0x00053283);
+    assert_eq!(arch.regr(5), 0x55);
+    assert_eq!((arch.pc, arch.csrs.mepc), (0x100, 0x40));
+    assert_eq!((arch.csrs.mcause, arch.csrs.mtval), (5, 0x1000));
+
+    // sd x5, 0x3fc(x0) straddles the end of memory and writes nothing
+    arch.pc = 0x40;
+    exec_raw(&mut arch, &mut mem, 0x3e503e23);
+    assert_eq!((arch.csrs.mcause, arch.csrs.mtval), (7, 0x3fc));
+    assert_eq!(read32(&mem, 0x3fc).unwrap(), 0);
+
+    // amoadd.w x5, x6, (x10) on a misaligned address
+    arch.pc = 0x40;
+    arch.regw(10, 0x82);
+    exec_raw(&mut arch, &mut mem, 0x006522af);
+    assert_eq!((arch.csrs.mcause, arch.csrs.mtval), (6, 0x82));
+
+    // Fetching from an unmapped pc
+    arch.pc = 0x2000;
+    let e = arch.fetch_inst(&mut mem).unwrap_err();
+    assert_eq!(e, Exception::InstructionAccessFault(0x2000));
+    arch.raise(e);
+    assert_eq!((arch.pc, arch.csrs.mepc, arch.csrs.mcause), (0x100, 0x2000, 1));
+
+    // Without C, jal to a 2-byte aligned target traps at the jal
+    arch.isa = Isa::parse("rv64g").unwrap();
+    arch.pc = 0x40;
+    exec_raw(&mut arch, &mut mem, 0x0060006f);
+    assert_eq!((arch.csrs.mepc, arch.csrs.mcause, arch.csrs.mtval), (0x40, 0, 0x46));
+}
+
+#[test]
+fn test_interrupts() {
+    let (mut arch, _) = system_arch();
+
+    // Pending but disabled in mie
+    arch.csrs.mip = 1 << IRQ_S_SOFT;
+    assert_eq!(arch.pending_interrupt(), None);
+
+    // M-mode interrupts are masked in M-mode until MIE is set
+    arch.csrs.mie = MIE_MASK;
+    assert_eq!(arch.pending_interrupt(), None);
+    arch.set_mstatus(MSTATUS_MIE);
+    assert_eq!(arch.pending_interrupt(), Some(IRQ_S_SOFT));
+
+    // Vectored mode offsets interrupts by 4 * cause
+    arch.csrs.mtvec = 0x101;
+    assert!(arch.take_interrupt());
+    assert_eq!(arch.pc, 0x100 + 4 * IRQ_S_SOFT);
+    assert_eq!(arch.csrs.mcause, 1 << 63 | IRQ_S_SOFT);
+    assert_eq!(arch.mstatus() & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+
+    // Delegated interrupts are always taken from U-mode, in S-mode
+    arch.csrs.mideleg = S_IRQ_MASK;
+    arch.mode = PrivMode::User;
+    arch.csrs.mip = 1 << IRQ_S_SOFT | 1 << IRQ_S_TIMER;
+    assert!(arch.take_interrupt());
+    assert_eq!((arch.pc, arch.mode), (0x200, PrivMode::Supervisor));
+    assert_eq!(arch.csrs.scause, 1 << 63 | IRQ_S_SOFT);
+
+    // ...but not from S-mode with SIE clear
+    assert_eq!(arch.pending_interrupt(), None);
+
+    // M-mode interrupts preempt S-mode regardless of SIE
+    arch.csrs.mip |= 1 << IRQ_M_TIMER;
+    assert_eq!(arch.pending_interrupt(), Some(IRQ_M_TIMER));
+
+    // Through sip only SSIP is writable
+    arch.mode = PrivMode::Machine;
+    arch.csr_write(CSR_SIP, 0).unwrap();
+    assert_eq!(arch.csrs.mip, 1 << IRQ_S_TIMER | 1 << IRQ_M_TIMER);
+}
+
+#[test]
+fn test_fp_status() {
+    let (mut arch, mut mem) = system_arch();
+    let fs = |arch : &ArchState| arch.mstatus() & MSTATUS_FS;
+    arch.fregs[1] = 1.5_f64.to_bits();
+    arch.fregs[2] = 2.0_f64.to_bits();
+
+    // FS starts Off, so fadd.d f3, f1, f2 and csrr x5, fcsr trap
+    assert_eq!(fs(&arch), 0);
+    exec_raw(&mut arch, &mut mem, 0x0220f1d3);
+    assert_eq!((arch.pc, arch.csrs.mcause, arch.csrs.mtval), (0x100, 2, 0x0220f1d3));
+    arch.pc = 0x40;
+    exec_raw(&mut arch, &mut mem, 0x003022f3);
+    assert_eq!((arch.pc, arch.csrs.mcause), (0x100, 2));
+
+    // Writing an FP register makes FS Dirty, which sets SD
+    arch.pc = 0x40;
+    arch.csrs.mstatus = 1 << 13;
+    exec_raw(&mut arch, &mut mem, 0x0220f1d3);
+    assert_eq!(arch.pc, 0x44);
+    assert_eq!(arch.fregs[3], 3.5_f64.to_bits());
+    assert_eq!(arch.mstatus() & (MSTATUS_FS | MSTATUS_SD), MSTATUS_FS | MSTATUS_SD);
+
+    // So do csrwi fflags, 0 and the flags fcvt.w.d x5, f1 accrues...
+    arch.csrs.mstatus = 2 << 13;
+    exec_raw(&mut arch, &mut mem, 0x00105073);
+    assert_eq!(fs(&arch), MSTATUS_FS);
+    arch.csrs.mstatus = 2 << 13;
+    exec_raw(&mut arch, &mut mem, 0xc200f2d3);
+    assert_eq!(arch.fflags(), rv64fpu::FFLAG_NX);
+    assert_eq!(fs(&arch), MSTATUS_FS);
+
+    // ...but an exact fcvt.w.d x5, f2 leaves it Clean
+    arch.csrs.mstatus = 2 << 13;
+    exec_raw(&mut arch, &mut mem, 0xc20172d3);
+    assert_eq!(arch.regr(5), 2);
+    assert_eq!(fs(&arch), 2 << 13);
+}
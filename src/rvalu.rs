@@ -0,0 +1,262 @@
+use std::ops::{BitAnd, BitOr, BitXor, Not, Shl, Shr};
+
+//
+// Integer ALU generic over the register width. rv64alu is tuned for the
+// 64-bit registers everything else uses; this module gives the base and M
+// operations for any XLEN, so RV128 can run them on u128 and on u64 for
+// its *D forms.
+//
+
+pub trait Xlen : Copy + Eq + Ord +
+    Not<Output = Self> + BitAnd<Output = Self> + BitOr<Output = Self> +
+    BitXor<Output = Self> + Shl<u32, Output = Self> + Shr<u32, Output = Self>
+{
+    const BITS : u32;
+    const ZERO : Self;
+    const ONE : Self;
+    const MAX : Self;
+
+    fn wrapping_add(self, other : Self) -> Self;
+    fn wrapping_sub(self, other : Self) -> Self;
+    fn wrapping_mul(self, other : Self) -> Self;
+    fn checked_div(self, other : Self) -> Option<Self>;
+    fn checked_rem(self, other : Self) -> Option<Self>;
+
+    /// Signed operations on the same bits.
+    fn signed_lt(self, other : Self) -> bool;
+    fn signed_div(self, other : Self) -> Self;
+    fn signed_rem(self, other : Self) -> Self;
+    fn arith_shr(self, shamt : u32) -> Self;
+
+    /// Upper half of the unsigned double-width product.
+    fn mulhu(self, other : Self) -> Self;
+
+    fn low_u32(self) -> u32;
+
+    #[inline(always)]
+    fn is_negative(self) -> bool {
+        self >> (Self::BITS - 1) == Self::ONE
+    }
+}
+
+macro_rules! impl_xlen {
+    ($t:ty, $s:ty, |$a:ident, $b:ident| $mulhu:expr) => {
+        impl Xlen for $t {
+            const BITS : u32 = <$t>::BITS;
+            const ZERO : Self = 0;
+            const ONE : Self = 1;
+            const MAX : Self = <$t>::MAX;
+
+            #[inline(always)]
+            fn wrapping_add(self, other : Self) -> Self { <$t>::wrapping_add(self, other) }
+
+            #[inline(always)]
+            fn wrapping_sub(self, other : Self) -> Self { <$t>::wrapping_sub(self, other) }
+
+            #[inline(always)]
+            fn wrapping_mul(self, other : Self) -> Self { <$t>::wrapping_mul(self, other) }
+
+            #[inline(always)]
+            fn checked_div(self, other : Self) -> Option<Self> { <$t>::checked_div(self, other) }
+
+            #[inline(always)]
+            fn checked_rem(self, other : Self) -> Option<Self> { <$t>::checked_rem(self, other) }
+
+            #[inline(always)]
+            fn signed_lt(self, other : Self) -> bool { (self as $s) < (other as $s) }
+
+            #[inline(always)]
+            fn signed_div(self, other : Self) -> Self { (self as $s).wrapping_div(other as $s) as $t }
+
+            #[inline(always)]
+            fn signed_rem(self, other : Self) -> Self { (self as $s).wrapping_rem(other as $s) as $t }
+
+            #[inline(always)]
+            fn arith_shr(self, shamt : u32) -> Self { ((self as $s) >> shamt) as $t }
+
+            #[inline(always)]
+            fn mulhu(self, other : Self) -> Self {
+                let ($a, $b) = (self, other);
+                $mulhu
+            }
+
+            #[inline(always)]
+            fn low_u32(self) -> u32 { self as u32 }
+        }
+    }
+}
+
+impl_xlen!(u32, i32, |a, b| ((a as u64 * b as u64) >> 32) as u32);
+impl_xlen!(u64, i64, |a, b| ((a as u128 * b as u128) >> 64) as u64);
+impl_xlen!(u128, i128, |a, b| mulhu128(a, b));
+
+/// Schoolbook multiply on 64-bit limbs, as there is no wider type.
+#[inline(always)]
+fn mulhu128(a : u128, b : u128) -> u128 {
+    let (a0, a1) = (a as u64 as u128, a >> 64);
+    let (b0, b1) = (b as u64 as u128, b >> 64);
+
+    let p00 = a0 * b0;
+    let p01 = a0 * b1;
+    let p10 = a1 * b0;
+    let p11 = a1 * b1;
+
+    let mid = (p00 >> 64) + (p01 as u64 as u128) + (p10 as u64 as u128);
+    p11 + (p01 >> 64) + (p10 >> 64) + (mid >> 64)
+}
+
+/// Only the low log2(XLEN) bits of a shift amount count.
+#[inline(always)]
+fn shamt<T : Xlen>(amt : T) -> u32 {
+    amt.low_u32() & (T::BITS - 1)
+}
+
+#[inline(always)]
+pub fn add<T : Xlen>(op1 : T, op2 : T) -> T {
+    op1.wrapping_add(op2)
+}
+
+#[inline(always)]
+pub fn sub<T : Xlen>(op1 : T, op2 : T) -> T {
+    op1.wrapping_sub(op2)
+}
+
+#[inline(always)]
+pub fn and<T : Xlen>(op1 : T, op2 : T) -> T {
+    op1 & op2
+}
+
+#[inline(always)]
+pub fn or<T : Xlen>(op1 : T, op2 : T) -> T {
+    op1 | op2
+}
+
+#[inline(always)]
+pub fn xor<T : Xlen>(op1 : T, op2 : T) -> T {
+    op1 ^ op2
+}
+
+#[inline(always)]
+pub fn slt<T : Xlen>(op1 : T, op2 : T) -> T {
+    if op1.signed_lt(op2) { T::ONE } else { T::ZERO }
+}
+
+#[inline(always)]
+pub fn sltu<T : Xlen>(op1 : T, op2 : T) -> T {
+    if op1 < op2 { T::ONE } else { T::ZERO }
+}
+
+#[inline(always)]
+pub fn sll<T : Xlen>(v : T, amt : T) -> T {
+    v << shamt(amt)
+}
+
+#[inline(always)]
+pub fn srl<T : Xlen>(v : T, amt : T) -> T {
+    v >> shamt(amt)
+}
+
+#[inline(always)]
+pub fn sra<T : Xlen>(v : T, amt : T) -> T {
+    v.arith_shr(shamt(amt))
+}
+
+//
+// M Extension, with the same division-by-zero and overflow results as
+// rv64alu. The signed high products are derived from the unsigned one.
+//
+
+#[inline(always)]
+pub fn mul<T : Xlen>(op1 : T, op2 : T) -> T {
+    op1.wrapping_mul(op2)
+}
+
+#[inline(always)]
+pub fn mulhu<T : Xlen>(op1 : T, op2 : T) -> T {
+    op1.mulhu(op2)
+}
+
+#[inline(always)]
+pub fn mulhsu<T : Xlen>(op1 : T, op2 : T) -> T {
+    let res = op1.mulhu(op2);
+    if op1.is_negative() { res.wrapping_sub(op2) } else { res }
+}
+
+#[inline(always)]
+pub fn mulh<T : Xlen>(op1 : T, op2 : T) -> T {
+    let res = mulhsu(op1, op2);
+    if op2.is_negative() { res.wrapping_sub(op1) } else { res }
+}
+
+#[inline(always)]
+pub fn div<T : Xlen>(n : T, d : T) -> T {
+    if d == T::ZERO { T::MAX } else { n.signed_div(d) }
+}
+
+#[inline(always)]
+pub fn divu<T : Xlen>(n : T, d : T) -> T {
+    n.checked_div(d).unwrap_or(T::MAX)
+}
+
+#[inline(always)]
+pub fn rem<T : Xlen>(n : T, d : T) -> T {
+    if d == T::ZERO { n } else { n.signed_rem(d) }
+}
+
+#[inline(always)]
+pub fn remu<T : Xlen>(n : T, d : T) -> T {
+    n.checked_rem(d).unwrap_or(n)
+}
+
+#[cfg(test)]
+const TEST_VALUES : [u64; 8] = [
+    0, 1, 3, 0x7FFF_FFFF, 0x8000_0000_0000_0000, 0xFFFF_FFFF_FFFF_FFFF,
+    0x1234_5678_9ABC_DEF0, 0xFFFF_FFFF_8000_0001
+];
+
+#[test]
+fn test_matches_rv64alu() {
+    use crate::rv64alu;
+
+    for a in TEST_VALUES.iter().copied() {
+        for b in TEST_VALUES.iter().copied() {
+            assert_eq!(add(a, b), rv64alu::add(a, b));
+            assert_eq!(slt(a, b), rv64alu::slt(a, b));
+            assert_eq!(sll(a, b), rv64alu::sll(a, b));
+            assert_eq!(srl(a, b), rv64alu::srl(a, b));
+            assert_eq!(sra(a, b), rv64alu::sra(a, b));
+            assert_eq!(mulh(a, b), rv64alu::mulh(a, b), "{:x} {:x}", a, b);
+            assert_eq!(mulhsu(a, b), rv64alu::mulhsu(a, b), "{:x} {:x}", a, b);
+            assert_eq!(mulhu(a, b), rv64alu::mulhu(a, b));
+            assert_eq!(div(a, b), rv64alu::div(a, b));
+            assert_eq!(rem(a, b), rv64alu::rem(a, b));
+            assert_eq!(divu(a, b), rv64alu::divu(a, b));
+
+            let (a32, b32) = (a as u32, b as u32);
+            assert_eq!(mulh(a32, b32) as i32 as u64, rv64alu::mulh32(a, b));
+        }
+    }
+}
+
+#[test]
+fn test_rv128() {
+    let min = 1u128 << 127;
+
+    assert_eq!(add(u128::MAX, 1), 0);
+    assert_eq!(sll(1u128, 127 + 128), min);
+    assert_eq!(sra(min, 127), u128::MAX);
+    assert_eq!(srl(min, 127), 1);
+    assert_eq!(slt(min, 0), 1);
+    assert_eq!(sltu(min, 0), 0);
+
+    assert_eq!(mulhu(u128::MAX, u128::MAX), u128::MAX - 1);
+    assert_eq!(mulhu(1u128 << 64, 1u128 << 64), 1);
+    assert_eq!(mulh(u128::MAX, u128::MAX), 0);
+    assert_eq!(mulh(min, min), 1 << 126);
+    assert_eq!(mulhsu(u128::MAX, 2), u128::MAX);
+
+    assert_eq!(div(min, u128::MAX), min);
+    assert_eq!(rem(min, u128::MAX), 0);
+    assert_eq!(divu(5u128, 0), u128::MAX);
+    assert_eq!(remu(5u128, 0), 5);
+}
@@ -0,0 +1,166 @@
+use crate::elf::*;
+
+#[derive(Debug)]
+pub struct Symbol {
+    pub addr : u64,
+    pub size : u64,
+    pub name : String
+}
+
+/// Address-sorted symbol table built from the guest ELF.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    syms : Vec<Symbol>
+}
+
+/// Demangles Rust (legacy and v0) and Itanium C++ names. Anything else is
+/// returned unchanged.
+pub fn demangle(name : &str) -> String {
+    if let Ok(sym) = rustc_demangle::try_demangle(name) {
+        return format!("{:#}", sym);
+    }
+
+    if name.starts_with("_Z") {
+        if let Ok(sym) = cpp_demangle::Symbol::new(name) {
+            if let Ok(s) = sym.demangle(&cpp_demangle::DemangleOptions::default()) {
+                return s;
+            }
+        }
+    }
+
+    name.to_string()
+}
+
+#[inline(always)]
+fn sym_rank(sym : &ElfSymbol) -> u32 {
+    let type_rank = match sym.sym_type {
+        STT_FUNC => 0,
+        STT_OBJECT => 1,
+        _ => 2
+    };
+
+    let bind_rank = match sym.bind {
+        STB_GLOBAL => 0,
+        STB_WEAK => 1,
+        _ => 2
+    };
+
+    type_rank * 3 + bind_rank
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self { syms : Vec::new() }
+    }
+
+    pub fn from_elf(elf : &ElfFile) -> Result<Self, ElfError> {
+        let mut elf_syms = elf.symbols()?
+            .into_iter()
+            .filter(|s| {
+                matches!(s.sym_type, STT_NOTYPE | STT_OBJECT | STT_FUNC) &&
+                !s.name.is_empty() &&
+                !s.name.starts_with('$') &&
+                !s.name.starts_with(".L")
+            })
+            .collect::<Vec<_>>();
+
+        // Aliases share an address, so keep the most descriptive one: a
+        // global function beats a local data label.
+        elf_syms.sort_by_key(|s| (s.value, sym_rank(s)));
+        elf_syms.dedup_by_key(|s| s.value);
+
+        Ok(Self {
+            syms : elf_syms.into_iter()
+                .map(|s| Symbol {
+                    addr : s.value,
+                    size : s.size,
+                    name : demangle(&s.name)
+                })
+                .collect()
+        })
+    }
+
+    /// First symbol with the given (demangled) name.
+    pub fn find(&self, name : &str) -> Option<&Symbol> {
+        self.syms.iter().find(|s| s.name == name)
+    }
+
+    /// Symbol containing addr and the offset of addr into it. Sized symbols
+    /// only cover [addr, addr + size); unsized ones (assembly labels) extend
+    /// up to the next symbol.
+    pub fn lookup(&self, addr : u64) -> Option<(&Symbol, u64)> {
+        let idx = self.syms.partition_point(|s| s.addr <= addr);
+
+        if idx == 0 {
+            return None;
+        }
+
+        let sym = &self.syms[idx - 1];
+        let offset = addr - sym.addr;
+
+        if sym.size != 0 && offset >= sym.size {
+            None
+        }
+        else {
+            Some((sym, offset))
+        }
+    }
+
+    /// Formats addr as "name", "name+0x10" or a bare hex address.
+    pub fn describe(&self, addr : u64) -> String {
+        match self.lookup(addr) {
+            Some((sym, 0)) => sym.name.clone(),
+            Some((sym, offset)) => format!("{}+0x{:x}", sym.name, offset),
+            None => format!("0x{:x}", addr)
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_table() -> SymbolTable {
+    let elf = ElfFile::parse(build_test_elf(
+        0x10000, &[(0x10000, &[0; 0x100], 0x100)],
+        &[
+            ("_start", 0x10000, 0, STT_NOTYPE),
+            ("main", 0x10010, 0x20, STT_FUNC),
+            ("main_alias", 0x10010, 0, STT_NOTYPE),
+            ("_ZN3foo3bar17h0123456789abcdefE", 0x10040, 0x10, STT_FUNC),
+            ("_ZN9Namespace5ClassC2Ev", 0x10050, 0x10, STT_FUNC),
+            ("$x", 0x10060, 0, STT_NOTYPE)
+        ])).unwrap();
+
+    SymbolTable::from_elf(&elf).unwrap()
+}
+
+#[test]
+fn test_symbol_lookup() {
+    let syms = test_table();
+
+    assert_eq!(syms.syms.len(), 4);
+    assert_eq!(syms.describe(0x10010), "main");
+    assert_eq!(syms.describe(0x10014), "main+0x4");
+
+    let (sym, offset) = syms.lookup(0x10018).unwrap();
+    assert_eq!((sym.name.as_str(), offset), ("main", 8));
+
+    // Unsized label covers everything up to the next symbol
+    assert_eq!(syms.describe(0x1000c), "_start+0xc");
+
+    // Past the end of a sized symbol
+    assert!(syms.lookup(0x10030).is_none());
+    assert!(syms.lookup(0xFFFF).is_none());
+    assert_eq!(syms.describe(0x10030), "0x10030");
+
+    assert_eq!(syms.find("main").unwrap().addr, 0x10010);
+    assert!(syms.find("main_alias").is_none());
+}
+
+#[test]
+fn test_symbol_demangle() {
+    let syms = test_table();
+
+    assert_eq!(syms.describe(0x10044), "foo::bar+0x4");
+    assert_eq!(syms.describe(0x10050), "Namespace::Class::Class()");
+    assert_eq!(demangle("printf"), "printf");
+    assert_eq!(demangle("_RNvCs1234_7mycrate4main"), "mycrate::main");
+}
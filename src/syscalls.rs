@@ -1,11 +1,21 @@
 
 use crate::memif::*;
+use crate::marshal::*;
+use crate::initstack::{self, MainVars};
+use crate::fdtable::FdTable;
+use crate::pagestore::PAGE_SIZE;
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
 
 #[derive(Debug, PartialEq, FromPrimitive)]
 pub enum SyscallNum {
     Getcwd = 17,
     Dup = 23,
     Fcntl = 25,
+    Mkdirat = 34,
+    Unlinkat = 35,
+    Linkat = 37,
     Faccessat = 48,
     Chdir = 49,
     Openat = 56,
@@ -35,6 +45,7 @@ pub enum SyscallNum {
     Munmap = 215,
     Mremap = 216,
     Mmap = 222,
+    Mprotect = 226,
     Open = 1024,
     Link = 1025,
     Unlink = 1026,
@@ -46,54 +57,965 @@ pub enum SyscallNum {
     Getmainvars = 2011
 }
 
+/// Per-guest state that syscalls read or update.
+#[derive(Debug, Default)]
+pub struct ProcessState {
+    pub vars : MainVars,
+    /// Pointer size in bytes, 4 on RV32.
+    pub word : u64,
+    pub files : FdTable,
+    /// Absolute paths that exist under here are opened from here instead.
+    pub sysroot : Option<PathBuf>
+}
+
+impl ProcessState {
+    /// A signed argument such as a file offset, which comes zero-extended
+    /// on RV32.
+    fn signed(&self, val : u64) -> i64 {
+        if self.word == 4 { val as i32 as i64 } else { val as i64 }
+    }
+
+    /// A pathname argument, as a host path.
+    fn path(&self, mem : &dyn MemIf, addr : u64) -> Result<CString, Errno> {
+        let path = copy_in_str(mem, addr)?;
+
+        if let (Some(root), Some(b'/')) = (&self.sysroot, path.first()) {
+            let inside = [root.as_os_str().as_bytes(), &path].concat();
+
+            if std::fs::symlink_metadata(OsStr::from_bytes(&inside)).is_ok() {
+                return Ok(CString::new(inside).unwrap());
+            }
+        }
+
+        Ok(CString::new(path).unwrap())
+    }
+}
+
+const MAP_FIXED : u64 = 0x10;
+const MAP_ANONYMOUS : u64 = 0x20;
+
 #[derive(Debug)]
 pub struct Syscall {
-    pub num : SyscallNum,
-    pub args : [u64; 7]
+    /// From a7.
+    pub num : u64,
+    /// From a0-a5.
+    pub args : [u64; 6]
 }
 
+impl Syscall {
+    pub fn kind(&self) -> Option<SyscallNum> {
+        num::FromPrimitive::from_u64(self.num)
+    }
+
+    /// Whether it can add, remove or change the permissions of mappings,
+    /// which leaves cached decodes of their code stale.
+    pub fn remaps(&self) -> bool {
+        matches!(self.kind(), Some(SyscallNum::Mmap | SyscallNum::Munmap | SyscallNum::Mprotect))
+    }
+}
 
-pub fn exec_syscall(syscall : &Syscall, mem : &mut dyn MemIf, debug : bool) -> u64 {
+/// What a syscall does to the guest.
+#[derive(Debug, PartialEq)]
+pub enum SyscallResult {
+    /// The value for a0: the result, or -errno on failure.
+    Return(u64),
+    Exit(i32)
+}
+
+/// Runs a syscall made with the Linux calling convention: the number in
+/// a7, the arguments in a0-a5 and the result or -errno back in a0.
+/// Unknown and unimplemented syscalls fail with ENOSYS.
+pub fn exec_syscall(
+    syscall : &Syscall, mem : &mut dyn MemIf, process : &mut ProcessState, debug : bool) -> SyscallResult {
     if debug {
-        println!("Syscall: {:?}", syscall);
+        println!("Syscall: {:?} {:?}", syscall.kind(), syscall);
+    }
+
+    let res = match syscall.kind() {
+        Some(SyscallNum::Exit) | Some(SyscallNum::ExitGroup) => {
+            return SyscallResult::Exit(syscall.args[0] as i32);
+        },
+        Some(num) => dispatch(&num, &syscall.args, mem, process, debug),
+        None => Err(libc::ENOSYS)
+    };
+
+    if res == Err(libc::ENOSYS) {
+        eprintln!("warning: unimplemented syscall {} ({:?})", syscall.num, syscall.kind());
     }
 
-    match &syscall.num {
-        SyscallNum::Fstat => {
-            unsafe {
-                let stat = mem.mut_ptr(syscall.args[1]) as *mut libc::stat;
-                libc::fstat( syscall.args[0] as i32, stat) as u64
+    SyscallResult::Return(result(res))
+}
+
+fn dispatch(
+    num : &SyscallNum, args : &[u64; 6], mem : &mut dyn MemIf, process : &mut ProcessState,
+    debug : bool) -> Result<u64, Errno> {
+
+    let files = &process.files;
+
+    match num {
+        SyscallNum::Write => {
+            let fd = files.get(args[0])?;
+            let data = copy_in(mem, args[1], args[2].min(MAX_IO))?;
+
+            if debug {
+                println!("Write: {:?}", String::from_utf8_lossy(&data));
             }
+
+            host_write(fd, &data)
+        },
+        SyscallNum::Writev => sys_writev(mem, files.get(args[0])?, args[1], args[2], process.word),
+        SyscallNum::Read => sys_read(mem, files.get(args[0])?, args[1], args[2], None),
+        SyscallNum::Pread => sys_read(mem, files.get(args[0])?, args[1], args[2], Some(args[3])),
+        SyscallNum::Pwrite => {
+            let fd = files.get(args[0])?;
+            let data = copy_in(mem, args[1], args[2].min(MAX_IO))?;
+            retry(|| unsafe {
+                libc::pwrite(fd, data.as_ptr() as *const libc::c_void, data.len(), args[3] as libc::off_t)
+            } as i64)
+        },
+        SyscallNum::Lseek => {
+            let offset = process.signed(args[1]);
+            host(unsafe { libc::lseek(files.get(args[0])?, offset as libc::off_t, args[2] as i32) } as i64)
         },
-        SyscallNum::Brk => {
-            match mem.brk(syscall.args[0]) {
-                Ok(new_heap_end) => new_heap_end,
-                _ => u64::MAX
+        SyscallNum::Openat => sys_openat(mem, process, args[0], args[1], args[2], args[3]),
+        SyscallNum::Open => sys_openat(mem, process, AT_FDCWD, args[0], args[1], args[2]),
+        SyscallNum::Close => process.files.close(args[0]).map(|()| 0),
+        SyscallNum::Dup => sys_dup(process, args[0], 0),
+        SyscallNum::Fcntl => sys_fcntl(process, args[0], args[1], args[2]),
+        SyscallNum::Getdents => sys_getdents64(mem, files.get(args[0])?, args[1], args[2]),
+        SyscallNum::Fstat => sys_fstat(mem, files.get(args[0])?, args[1]),
+        SyscallNum::Fstatat => sys_fstatat(mem, process, args[0], args[1], args[2], args[3]),
+        SyscallNum::Stat => sys_fstatat(mem, process, AT_FDCWD, args[0], args[1], 0),
+        SyscallNum::Lstat => sys_fstatat(mem, process, AT_FDCWD, args[0], args[1], AT_SYMLINK_NOFOLLOW),
+        SyscallNum::Faccessat | SyscallNum::Access => {
+            let (dirfd, args) = at_args(num, args);
+            let path = process.path(mem, args[0])?;
+            host(unsafe { libc::faccessat(files.dir(dirfd)?, path.as_ptr(), args[1] as i32, 0) } as i64)
+        },
+        SyscallNum::Getcwd => sys_getcwd(mem, args[0], args[1]),
+        SyscallNum::Chdir => {
+            let path = process.path(mem, args[0])?;
+            host(unsafe { libc::chdir(path.as_ptr()) } as i64)
+        },
+        SyscallNum::Mkdirat | SyscallNum::Mkdir => {
+            let (dirfd, args) = at_args(num, args);
+            let path = process.path(mem, args[0])?;
+            host(unsafe { libc::mkdirat(files.dir(dirfd)?, path.as_ptr(), args[1] as libc::mode_t) } as i64)
+        },
+        SyscallNum::Unlinkat | SyscallNum::Unlink => {
+            let (dirfd, args) = at_args(num, args);
+            let path = process.path(mem, args[0])?;
+            host(unsafe { libc::unlinkat(files.dir(dirfd)?, path.as_ptr(), args[1] as i32) } as i64)
+        },
+        SyscallNum::Linkat => {
+            let (old, new) = (process.path(mem, args[1])?, process.path(mem, args[3])?);
+            let (old_dir, new_dir) = (files.dir(args[0])?, files.dir(args[2])?);
+            host(unsafe {
+                libc::linkat(old_dir, old.as_ptr(), new_dir, new.as_ptr(), args[4] as i32)
+            } as i64)
+        },
+        SyscallNum::Link => {
+            let (old, new) = (process.path(mem, args[0])?, process.path(mem, args[1])?);
+            host(unsafe { libc::link(old.as_ptr(), new.as_ptr()) } as i64)
+        },
+        SyscallNum::Gettimeofday => sys_gettimeofday(mem, args[0], process.word),
+        SyscallNum::Time => {
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+
+            if args[0] != 0 {
+                put_long(mem, args[0], process.word, now.as_secs())?;
             }
+
+            Ok(now.as_secs())
         },
-        SyscallNum::Write => {
-            unsafe {
-                if debug {
-                    println!("Write count: {}", syscall.args[0]);
-                    let cstr : *mut u8 = mem.mut_ptr(syscall.args[1]);
-
-                    for i in 0..syscall.args[0] {
-                        println!("/{}/", *cstr.add(i as usize));
-                    }
-                }
-                // println!("{}", *cstr.add(0));
-                // println!("{}", *cstr.add(1));
-                // println!("{}", *cstr.add(2));
-                // println!("{}", *cstr.add(3));
-                // println!("{}", *cstr.add(4));
-                // println!("{}", *cstr.add(5));
-
-                libc::write(
-                    syscall.args[0] as i32,
-                    mem.mut_ptr(syscall.args[1]) as *mut libc::c_void,
-                    syscall.args[0] as usize) as u64
+        SyscallNum::Times => sys_times(mem, args[0], process.word),
+        SyscallNum::Uname => sys_uname(mem, args[0], process.word),
+        SyscallNum::Getpid => Ok(unsafe { libc::getpid() } as u64),
+        SyscallNum::Getuid => Ok(unsafe { libc::getuid() } as u64),
+        SyscallNum::Geteuid => Ok(unsafe { libc::geteuid() } as u64),
+        SyscallNum::Getgid => Ok(unsafe { libc::getgid() } as u64),
+        SyscallNum::Getegid => Ok(unsafe { libc::getegid() } as u64),
+        SyscallNum::RtSigaction => {
+            // Signals are never delivered, so handlers are accepted and
+            // never run. The old action reads as SIG_DFL.
+            if args[2] != 0 {
+                zero_out(mem, args[2], 2 * process.word + 8)?;
             }
+
+            Ok(0)
+        },
+        // The break stays put on failure, as on Linux
+        SyscallNum::Brk => mem.brk(args[0]).or_else(|()| mem.brk(0)).map_err(|()| libc::ENOMEM),
+        SyscallNum::Mmap => {
+            let [addr, len, prot, flags, fd, offset] = *args;
+            let fd = if flags & MAP_ANONYMOUS != 0 { -1 } else { files.get(fd)? };
+            sys_mmap(mem, addr, len, prot_perms(prot), flags, fd, offset)
+        },
+        SyscallNum::Munmap => mem.munmap(args[0], args[1]).map(|()| 0).map_err(|()| libc::EINVAL),
+        SyscallNum::Mprotect => {
+            mem.mprotect(args[0], args[1], prot_perms(args[2])).map(|()| 0).map_err(|()| libc::ENOMEM)
         },
-        x => panic!("Unimplemented syscall: {:?}", x)
+        // Mappings cannot grow in place; allocators fall back to a new
+        // mapping and a copy
+        SyscallNum::Mremap => Err(libc::ENOMEM),
+        SyscallNum::Getmainvars => {
+            Ok(initstack::getmainvars(mem, args[0], args[1], process.word, &process.vars))
+        },
+        _ => Err(libc::ENOSYS)
+    }
+}
+
+//
+// Files. Guest descriptors go through the fd table and paths through the
+// sysroot; flags are translated from the asm-generic values RISC-V uses
+// to the host's.
+//
+
+const AT_FDCWD : u64 = -100i64 as u64;
+const AT_SYMLINK_NOFOLLOW : u64 = 0x100;
+
+const F_DUPFD : u64 = 0;
+const F_GETFD : u64 = 1;
+const F_SETFD : u64 = 2;
+const F_GETFL : u64 = 3;
+const F_SETFL : u64 = 4;
+const F_DUPFD_CLOEXEC : u64 = 1030;
+
+/// Largest read or write done in one go; larger ones come up short, which
+/// the guest has to allow for anyway.
+const MAX_IO : u64 = 1 << 24;
+
+const OPEN_FLAGS : [(u64, i32); 11] = [
+    (0o100, libc::O_CREAT),
+    (0o200, libc::O_EXCL),
+    (0o400, libc::O_NOCTTY),
+    (0o1000, libc::O_TRUNC),
+    (0o2000, libc::O_APPEND),
+    (0o4000, libc::O_NONBLOCK),
+    (0o10000, libc::O_DSYNC),
+    (0o200000, libc::O_DIRECTORY),
+    (0o400000, libc::O_NOFOLLOW),
+    (0o2000000, libc::O_CLOEXEC),
+    (0o4000000, libc::O_SYNC)
+];
+
+fn host_open_flags(flags : u64) -> i32 {
+    OPEN_FLAGS.iter().filter(|(guest, _)| flags & guest != 0)
+        .fold(flags as i32 & libc::O_ACCMODE, |acc, (_, host)| acc | host)
+}
+
+fn guest_open_flags(flags : i32) -> u64 {
+    OPEN_FLAGS.iter().filter(|(_, host)| flags & host == *host)
+        .fold((flags & libc::O_ACCMODE) as u64, |acc, (guest, _)| acc | guest)
+}
+
+/// The dirfd and remaining arguments of an *at() call, or of the older
+/// call without the dirfd, whose trailing flags are then zero.
+fn at_args(num : &SyscallNum, args : &[u64; 6]) -> (u64, [u64; 5]) {
+    match num {
+        SyscallNum::Faccessat | SyscallNum::Mkdirat | SyscallNum::Unlinkat => {
+            (args[0], [args[1], args[2], args[3], args[4], args[5]])
+        },
+        _ => (AT_FDCWD, [args[0], args[1], 0, 0, 0])
+    }
+}
+
+fn sys_openat(
+    mem : &mut dyn MemIf, process : &mut ProcessState, dirfd : u64, path : u64, flags : u64,
+    mode : u64) -> Result<u64, Errno> {
+
+    let path = process.path(mem, path)?;
+    let dirfd = process.files.dir(dirfd)?;
+    let fd = host(unsafe {
+        libc::openat(dirfd, path.as_ptr(), host_open_flags(flags), mode as libc::c_uint)
+    } as i64)?;
+
+    Ok(process.files.insert(fd as i32, 0))
+}
+
+fn sys_dup(process : &mut ProcessState, fd : u64, min : u64) -> Result<u64, Errno> {
+    let fd = host(unsafe { libc::dup(process.files.get(fd)?) } as i64)?;
+    Ok(process.files.insert(fd as i32, min))
+}
+
+/// Descriptors are never inherited by another program, so close-on-exec
+/// is accepted and ignored.
+fn sys_fcntl(process : &mut ProcessState, fd : u64, cmd : u64, arg : u64) -> Result<u64, Errno> {
+    let host_fd = process.files.get(fd)?;
+
+    match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC => sys_dup(process, fd, arg),
+        F_GETFD | F_SETFD => Ok(0),
+        F_GETFL => {
+            let flags = host(unsafe { libc::fcntl(host_fd, libc::F_GETFL) } as i64)?;
+            Ok(guest_open_flags(flags as i32))
+        },
+        F_SETFL => host(unsafe { libc::fcntl(host_fd, libc::F_SETFL, host_open_flags(arg)) } as i64),
+        _ => Err(libc::EINVAL)
+    }
+}
+
+fn sys_read(
+    mem : &mut dyn MemIf, fd : i32, buf : u64, count : u64, offset : Option<u64>) -> Result<u64, Errno> {
+
+    let count = count.min(MAX_IO);
+
+    // Before allocating for it
+    if count != 0 && mem.check(buf, count, PERM_W).is_err() {
+        return Err(libc::EFAULT);
+    }
+
+    let mut data = vec![0u8; count as usize];
+    let ptr = data.as_mut_ptr() as *mut libc::c_void;
+
+    let n = retry(|| unsafe {
+        match offset {
+            Some(offset) => libc::pread(fd, ptr, count as usize, offset as libc::off_t),
+            None => libc::read(fd, ptr, count as usize)
+        }
+    } as i64)?;
+
+    copy_out(mem, buf, &data[..n as usize])?;
+    Ok(n)
+}
+
+/// struct linux_dirent64 is the same on every host, so the entries are
+/// copied as they are.
+fn sys_getdents64(mem : &mut dyn MemIf, fd : i32, buf : u64, count : u64) -> Result<u64, Errno> {
+    let mut data = vec![0u8; count.min(MAX_IO) as usize];
+
+    let n = host(unsafe {
+        libc::syscall(libc::SYS_getdents64, fd, data.as_mut_ptr(), data.len())
+    } as i64)?;
+
+    copy_out(mem, buf, &data[..n as usize])?;
+    Ok(n)
+}
+
+fn sys_fstatat(
+    mem : &mut dyn MemIf, process : &ProcessState, dirfd : u64, path : u64, buf : u64,
+    flags : u64) -> Result<u64, Errno> {
+
+    let path = process.path(mem, path)?;
+    let mut st : libc::stat = unsafe { std::mem::zeroed() };
+
+    host(unsafe {
+        libc::fstatat(process.files.dir(dirfd)?, path.as_ptr(), &mut st, flags as i32)
+    } as i64)?;
+
+    write_struct(mem, buf, 8, &GuestStat::from(&st))?;
+    Ok(0)
+}
+
+/// Returns the length of the path including the NUL, as the Linux call
+/// does.
+fn sys_getcwd(mem : &mut dyn MemIf, buf : u64, size : u64) -> Result<u64, Errno> {
+    let cwd = std::env::current_dir().map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+    let mut path = cwd.as_os_str().as_bytes().to_vec();
+    path.push(0);
+
+    if path.len() as u64 > size {
+        return Err(libc::ERANGE);
+    }
+
+    copy_out(mem, buf, &path)?;
+    Ok(path.len() as u64)
+}
+
+/// Syscalls return errors as negated errno values.
+fn errno(e : i32) -> u64 {
+    (-(e as i64)) as u64
+}
+
+fn result(res : Result<u64, Errno>) -> u64 {
+    res.unwrap_or_else(errno)
+}
+
+fn last_errno() -> Errno {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// A host call's result, which is -1 on failure with errno set.
+fn host(res : i64) -> Result<u64, Errno> {
+    if res < 0 { Err(last_errno()) } else { Ok(res as u64) }
+}
+
+/// Restarts a host call that a signal interrupted before it did anything:
+/// the signal was for the emulator, and the guest never sees it.
+fn retry(mut call : impl FnMut() -> i64) -> Result<u64, Errno> {
+    loop {
+        match host(call()) {
+            Err(libc::EINTR) => continue,
+            res => return res
+        }
+    }
+}
+
+/// Like Linux, this may write less than all of data, and the guest has
+/// to write the rest.
+fn host_write(fd : i32, data : &[u8]) -> Result<u64, Errno> {
+    retry(|| unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) } as i64)
+}
+
+fn sys_fstat(mem : &mut dyn MemIf, fd : i32, addr : u64) -> Result<u64, Errno> {
+    let mut st : libc::stat = unsafe { std::mem::zeroed() };
+    host(unsafe { libc::fstat(fd, &mut st) } as i64)?;
+
+    // The layout does not depend on the word size
+    write_struct(mem, addr, 8, &GuestStat::from(&st))?;
+    Ok(0)
+}
+
+/// The buffers are gathered and written with one host write, which may
+/// come up short. As on Linux, a bad buffer fails the call only if it is
+/// the first with anything in it; otherwise the write stops there.
+fn sys_writev(
+    mem : &mut dyn MemIf, fd : i32, iov : u64, count : u64, word : u64) -> Result<u64, Errno> {
+
+    const IOV_MAX : u64 = 1024;
+
+    let ssize_max = (1 << (8 * word - 1)) - 1;
+
+    if count > IOV_MAX {
+        return Err(libc::EINVAL);
+    }
+
+    let iovecs = read_array::<GuestIovec>(mem, iov, count, word)?;
+
+    let total = iovecs.iter().map(|v| v.len).try_fold(0u64, u64::checked_add)
+        .ok_or(libc::EINVAL)?;
+
+    if total > ssize_max {
+        return Err(libc::EINVAL);
+    }
+
+    let mut data = Vec::new();
+
+    for v in iovecs.iter().filter(|v| v.len != 0) {
+        match copy_in(mem, v.base, v.len.min(MAX_IO - data.len() as u64)) {
+            Ok(bytes) => data.extend(bytes),
+            Err(e) if data.is_empty() => return Err(e),
+            Err(_) => break
+        }
+
+        if data.len() as u64 == MAX_IO {
+            break;
+        }
+    }
+
+    host_write(fd, &data)
+}
+
+fn sys_gettimeofday(mem : &mut dyn MemIf, addr : u64, word : u64) -> Result<u64, Errno> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let tv = GuestTimeval { sec : now.as_secs() as i64, usec : now.subsec_micros() as i64 };
+
+    if addr != 0 {
+        write_struct(mem, addr, word, &tv)?;
+    }
+
+    Ok(0)
+}
+
+/// The host's times for the whole emulator stand in for the guest's.
+fn sys_times(mem : &mut dyn MemIf, addr : u64, word : u64) -> Result<u64, Errno> {
+    let mut host : libc::tms = unsafe { std::mem::zeroed() };
+    let ticks = unsafe { libc::times(&mut host) };
+
+    let tms = GuestTms {
+        utime : host.tms_utime as i64,
+        stime : host.tms_stime as i64,
+        cutime : host.tms_cutime as i64,
+        cstime : host.tms_cstime as i64
+    };
+
+    if addr != 0 {
+        write_struct(mem, addr, word, &tms)?;
+    }
+
+    Ok(ticks as u64)
+}
+
+/// A Linux kernel on a RISC-V machine, named after the host.
+fn sys_uname(mem : &mut dyn MemIf, addr : u64, word : u64) -> Result<u64, Errno> {
+    let mut host : libc::utsname = unsafe { std::mem::zeroed() };
+    unsafe { libc::uname(&mut host) };
+
+    let nodename = unsafe { std::ffi::CStr::from_ptr(host.nodename.as_ptr()) };
+
+    let uts = GuestUtsname {
+        sysname : "Linux".to_string(),
+        nodename : nodename.to_string_lossy().into_owned(),
+        release : "6.1.0".to_string(),
+        version : "#1 SMP".to_string(),
+        machine : if word == 4 { "riscv32" } else { "riscv64" }.to_string(),
+        domainname : "(none)".to_string()
+    };
+
+    write_struct(mem, addr, word, &uts)?;
+    Ok(0)
+}
+
+/// Region permissions for PROT_* bits. As on Linux, writable pages are
+/// also readable.
+fn prot_perms(prot : u64) -> u8 {
+    let perms = prot as u8 & PERM_RWX;
+    if perms & PERM_W != 0 { perms | PERM_R } else { perms }
+}
+
+/// File-backed mappings are private copies of the file contents taken at
+/// mmap() time; bytes past the end of the file read as zero. Nothing is
+/// allocated for anonymous mappings, and only the part of the file that
+/// lands in the mapping is read.
+fn sys_mmap(
+    mem : &mut dyn MemIf, addr : u64, len : u64, perms : u8, flags : u64, fd : i32,
+    offset : u64) -> Result<u64, Errno> {
+
+    if len == 0 {
+        return Err(libc::EINVAL);
+    }
+
+    let len = len.checked_add(PAGE_SIZE - 1).ok_or(libc::ENOMEM)? & !(PAGE_SIZE - 1);
+    let fixed = if flags & MAP_FIXED != 0 { Some(addr) } else { None };
+
+    if fixed.is_some_and(|addr| addr.checked_add(len).is_none()) {
+        return Err(libc::ENOMEM);
+    }
+
+    let data = if flags & MAP_ANONYMOUS == 0 { Some(read_file_at(fd, offset, len)?) } else { None };
+
+    match mem.mmap(fixed, len, data.as_deref(), perms) {
+        Ok(start) => Ok(start),
+        Err(()) if fixed.is_some() => Err(libc::EINVAL),
+        Err(()) => Err(libc::ENOMEM)
+    }
+}
+
+/// Up to len bytes of the file from offset, or fewer at its end.
+fn read_file_at(fd : i32, offset : u64, len : u64) -> Result<Vec<u8>, Errno> {
+    let mut st : libc::stat = unsafe { std::mem::zeroed() };
+    host(unsafe { libc::fstat(fd, &mut st) } as i64)?;
+
+    let size = (st.st_size as u64).saturating_sub(offset).min(len);
+    let mut data = vec![0u8; size as usize];
+    let mut done = 0;
+
+    while done < data.len() {
+        let n = retry(|| unsafe {
+            libc::pread(fd, data[done..].as_mut_ptr() as *mut libc::c_void,
+                data.len() - done, (offset + done as u64) as libc::off_t)
+        } as i64)?;
+
+        if n == 0 {
+            break;
+        }
+
+        done += n as usize;
+    }
+
+    data.truncate(done);
+    Ok(data)
+}
+
+#[cfg(test)]
+fn test_process() -> ProcessState {
+    ProcessState { word : 8, ..Default::default() }
+}
+
+/// Runs a syscall and returns a0.
+#[cfg(test)]
+fn test_syscall(mem : &mut dyn MemIf, process : &mut ProcessState, num : SyscallNum, args : &[u64]) -> u64 {
+    let mut syscall = Syscall { num : num as u64, args : [0; 6] };
+    syscall.args[..args.len()].copy_from_slice(args);
+
+    match exec_syscall(&syscall, mem, process, false) {
+        SyscallResult::Return(val) => val,
+        res => panic!("{:?}", res)
+    }
+}
+
+/// A non-blocking pipe, the guest's fds for its ends and the host's.
+#[cfg(test)]
+fn test_pipe(process : &mut ProcessState) -> (u64, u64, [i32; 2]) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK) }, 0);
+    (process.files.insert(fds[0], 0), process.files.insert(fds[1], 0), fds)
+}
+
+/// Everything in the pipe, without blocking.
+#[cfg(test)]
+fn drain(fd : i32) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        match unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+            n if n > 0 => data.extend_from_slice(&buf[..n as usize]),
+            _ => return data
+        }
     }
 }
+
+/// A fresh directory under the host's temporary directory.
+#[cfg(test)]
+fn test_dir(name : &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustv-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_marshalled_syscalls() {
+    let mut mem = TestMem::new(0x2000);
+    let mut process = test_process();
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+    let rd = process.files.insert(fds[0], 0);
+    let wr = process.files.insert(fds[1], 0);
+    let mut sys = |mem : &mut TestMem, num, args : &[u64]| test_syscall(mem, &mut process, num, args);
+
+    // A pipe is a FIFO with nothing in it
+    assert_eq!(sys(&mut mem, SyscallNum::Fstat, &[rd, 0x100]), 0);
+    assert_eq!(read32(&mem, 0x110).unwrap() as u32 & libc::S_IFMT, libc::S_IFIFO);
+    assert_eq!(sys(&mut mem, SyscallNum::Fstat, &[rd, 0x1FF0]), errno(libc::EFAULT));
+    assert_eq!(sys(&mut mem, SyscallNum::Fstat, &[99, 0x100]), errno(libc::EBADF));
+
+    mem.write_bytes(0x400, b"hello, world").unwrap();
+    for (i, (base, len)) in [(0x400, 5), (0x405, 0), (0x405, 7)].iter().enumerate() {
+        write64(&mut mem, 0x200 + 16 * i as u64, *base).unwrap();
+        write64(&mut mem, 0x208 + 16 * i as u64, *len).unwrap();
+    }
+
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x200, 3]), 12);
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x1FF8, 1]), errno(libc::EFAULT));
+
+    let mut buf = [0; 12];
+    assert_eq!(unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut libc::c_void, 12) }, 12);
+    assert_eq!(&buf, b"hello, world");
+
+    assert_eq!(sys(&mut mem, SyscallNum::Uname, &[0x800]), 0);
+    assert_eq!(mem.read_cstr(0x800, 64), Some(b"Linux".to_vec()));
+    assert_eq!(mem.read_cstr(0x800 + 4 * 65, 64), Some(b"riscv64".to_vec()));
+
+    assert_eq!(sys(&mut mem, SyscallNum::Gettimeofday, &[0x900, 0]), 0);
+    assert!(read64(&mem, 0x900).unwrap() > 1_500_000_000 && read64(&mem, 0x908).unwrap() < 1_000_000);
+}
+
+#[test]
+fn test_file_syscalls() {
+    const O_WRONLY_CREAT : u64 = 0o101;
+    const O_DIRECTORY : u64 = 0o200000;
+
+    let dir = test_dir("files");
+    let mut mem = TestMem::new(0x4000);
+    let mut process = test_process();
+    let mut sys = |mem : &mut TestMem, num, args : &[u64]| test_syscall(mem, &mut process, num, args);
+
+    let path = |mem : &mut TestMem, addr : u64, name : &str| {
+        mem.write_bytes(addr, dir.join(name).to_str().unwrap().as_bytes()).unwrap();
+        mem.write(addr + dir.join(name).as_os_str().len() as u64, 0).unwrap();
+        addr
+    };
+
+    let out = path(&mut mem, 0x1000, "out.txt");
+    mem.write_bytes(0x2000, b"line 1\nline 2\n").unwrap();
+
+    // Descriptors start after stdin, stdout and stderr
+    let fd = sys(&mut mem, SyscallNum::Openat, &[AT_FDCWD, out, O_WRONLY_CREAT, 0o644]);
+    assert_eq!(fd, 3);
+    assert_eq!(sys(&mut mem, SyscallNum::Pwrite, &[fd, 0x2000, 14, 0]), 14);
+    assert_eq!(sys(&mut mem, SyscallNum::Fcntl, &[fd, F_GETFL]), 1);
+    assert_eq!(sys(&mut mem, SyscallNum::Close, &[fd]), 0);
+    assert_eq!(sys(&mut mem, SyscallNum::Close, &[fd]), errno(libc::EBADF));
+    assert_eq!(std::fs::read(dir.join("out.txt")).unwrap(), b"line 1\nline 2\n");
+
+    let fd = sys(&mut mem, SyscallNum::Open, &[out, 0, 0]);
+    assert_eq!(sys(&mut mem, SyscallNum::Lseek, &[fd, 5, libc::SEEK_SET as u64]), 5);
+    assert_eq!(sys(&mut mem, SyscallNum::Read, &[fd, 0x3000, 4]), 4);
+    assert_eq!(mem.read_cstr(0x3000, 4), Some(b"1\nli".to_vec()));
+    assert_eq!(sys(&mut mem, SyscallNum::Pread, &[fd, 0x3000, 100, 12]), 2);
+    assert_eq!(sys(&mut mem, SyscallNum::Read, &[fd, 0x3FFF, 2]), errno(libc::EFAULT));
+
+    let dup = sys(&mut mem, SyscallNum::Fcntl, &[fd, F_DUPFD, 10]);
+    assert_eq!(dup, 10);
+    assert_eq!(sys(&mut mem, SyscallNum::Read, &[dup, 0x3000, 100]), 5);
+    assert_eq!(sys(&mut mem, SyscallNum::Dup, &[fd]), 4);
+
+    assert_eq!(sys(&mut mem, SyscallNum::Fstat, &[dup, 0x100]), 0);
+    assert_eq!(read64(&mem, 0x100 + 48).unwrap(), 14);
+    assert_eq!(sys(&mut mem, SyscallNum::Fstatat, &[AT_FDCWD, out, 0x200, 0]), 0);
+    assert_eq!(read64(&mem, 0x200 + 8).unwrap(), read64(&mem, 0x100 + 8).unwrap());
+    assert_eq!(sys(&mut mem, SyscallNum::Faccessat, &[AT_FDCWD, out, libc::R_OK as u64]), 0);
+
+    // Links, directories and the *at() calls relative to one
+    let sub = path(&mut mem, 0x1100, "sub");
+    let link = path(&mut mem, 0x1200, "sub/link.txt");
+    let missing = path(&mut mem, 0x1300, "missing");
+    assert_eq!(sys(&mut mem, SyscallNum::Mkdirat, &[AT_FDCWD, sub, 0o755]), 0);
+    assert_eq!(sys(&mut mem, SyscallNum::Mkdir, &[sub, 0o755]), errno(libc::EEXIST));
+    assert_eq!(sys(&mut mem, SyscallNum::Link, &[out, link]), 0);
+    assert_eq!(sys(&mut mem, SyscallNum::Stat, &[link, 0x300]), 0);
+    assert_eq!(read32(&mem, 0x300 + 20).unwrap(), 2);
+    assert_eq!(sys(&mut mem, SyscallNum::Access, &[missing, 0]), errno(libc::ENOENT));
+    assert_eq!(sys(&mut mem, SyscallNum::Openat, &[AT_FDCWD, missing, 0, 0]), errno(libc::ENOENT));
+
+    let dirfd = sys(&mut mem, SyscallNum::Openat, &[AT_FDCWD, sub, O_DIRECTORY, 0]);
+    let n = sys(&mut mem, SyscallNum::Getdents, &[dirfd, 0x2000, 0x1000]);
+    let mut names = Vec::new();
+    let mut entry = 0x2000;
+
+    while entry < 0x2000 + n {
+        names.push(String::from_utf8(mem.read_cstr(entry + 19, 256).unwrap()).unwrap());
+        entry += mem.load::<u16>(entry + 16).unwrap() as u64;
+    }
+
+    names.sort();
+    assert_eq!(names, vec![".", "..", "link.txt"]);
+
+    mem.write_bytes(0x1400, b"link.txt\0").unwrap();
+    assert_eq!(sys(&mut mem, SyscallNum::Unlinkat, &[dirfd, 0x1400, 0]), 0);
+    assert_eq!(sys(&mut mem, SyscallNum::Unlinkat, &[dirfd, 0x1400, 0]), errno(libc::ENOENT));
+    assert_eq!(sys(&mut mem, SyscallNum::Unlink, &[sub]), errno(libc::EISDIR));
+    assert_eq!(sys(&mut mem, SyscallNum::Unlinkat, &[AT_FDCWD, sub, libc::AT_REMOVEDIR as u64]), 0);
+
+    assert_eq!(sys(&mut mem, SyscallNum::Getcwd, &[0x3000, 1]), errno(libc::ERANGE));
+    let len = sys(&mut mem, SyscallNum::Getcwd, &[0x3000, 4096]);
+    assert_eq!(mem.read_cstr(0x3000, 4096).unwrap().len() as u64 + 1, len);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sysroot_paths() {
+    let root = test_dir("sysroot");
+    std::fs::create_dir_all(root.join("lib")).unwrap();
+    std::fs::write(root.join("lib/libfoo.so"), b"guest").unwrap();
+
+    let mut mem = TestMem::new(0x1000);
+    let mut process = ProcessState { sysroot : Some(root.clone()), ..test_process() };
+
+    // Absolute paths under the sysroot win; others are the host's
+    mem.write_bytes(0x100, b"/lib/libfoo.so\0").unwrap();
+    mem.write_bytes(0x200, b"/dev/null\0").unwrap();
+    mem.write_bytes(0x300, b"lib/libfoo.so\0").unwrap();
+
+    let inside = root.join("lib/libfoo.so");
+    assert_eq!(process.path(&mem, 0x100).unwrap().as_bytes(), inside.as_os_str().as_bytes());
+    assert_eq!(process.path(&mem, 0x200).unwrap().as_bytes(), b"/dev/null");
+    assert_eq!(process.path(&mem, 0x300).unwrap().as_bytes(), b"lib/libfoo.so");
+
+    let fd = test_syscall(&mut mem, &mut process, SyscallNum::Openat, &[AT_FDCWD, 0x100, 0, 0]);
+    assert_eq!(test_syscall(&mut mem, &mut process, SyscallNum::Read, &[fd, 0x400, 16]), 5);
+    assert_eq!(mem.read_cstr(0x400, 16), Some(b"guest".to_vec()));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_syscall_abi() {
+    use crate::rv64emu::ArchState;
+    use crate::isa::Isa;
+
+    let mut mem = TestMem::new(0x1000);
+    let mut process = test_process();
+    let (_, wr, fds) = test_pipe(&mut process);
+    let mut arch = ArchState::new();
+
+    // write(wr, 0x100, 3): number in a7, arguments in a0-a2
+    mem.write_bytes(0x100, b"abcdef").unwrap();
+    for (reg, val) in [(17, 64), (10, wr), (11, 0x100), (12, 3), (13, 99)].iter() {
+        arch.regw(*reg, *val);
+    }
+
+    let syscall = arch.rv64_parse_syscall();
+    assert_eq!((syscall.kind(), syscall.args), (Some(SyscallNum::Write), [wr, 0x100, 3, 99, 0, 0]));
+    assert!(!syscall.remaps());
+    assert!(Syscall { num : SyscallNum::Mprotect as u64, args : [0; 6] }.remaps());
+    assert_eq!(exec_syscall(&syscall, &mut mem, &mut process, false), SyscallResult::Return(3));
+    assert_eq!(drain(fds[0]), b"abc");
+
+    // Errors come back as -errno, which is how a0 reads them
+    arch.regw(10, 77);
+    let SyscallResult::Return(res) = exec_syscall(&arch.rv64_parse_syscall(), &mut mem, &mut process, false)
+        else { panic!() };
+    arch.regw(10, res);
+    assert_eq!(arch.regr(10) as i64, -libc::EBADF as i64);
+
+    arch.regw(17, 9999);
+    assert_eq!(exec_syscall(&arch.rv64_parse_syscall(), &mut mem, &mut process, false),
+        SyscallResult::Return(errno(libc::ENOSYS)));
+    assert_eq!(test_syscall(&mut mem, &mut process, SyscallNum::Kill, &[1, 9]), errno(libc::ENOSYS));
+
+    arch.regw(17, SyscallNum::ExitGroup as u64);
+    arch.regw(10, 3);
+    assert_eq!(exec_syscall(&arch.rv64_parse_syscall(), &mut mem, &mut process, false),
+        SyscallResult::Exit(3));
+
+    // On RV32 arguments are zero-extended, and signed ones are narrowed
+    // back
+    arch.isa = Isa::parse("rv32imac").unwrap();
+    arch.regw(10, 0x8000_0000);
+    arch.regw(11, -100i64 as u64);
+    let syscall = arch.rv64_parse_syscall();
+    assert_eq!(&syscall.args[..2], &[0x8000_0000, 0xFFFF_FF9C]);
+    let rv32 = ProcessState { word : 4, ..Default::default() };
+    assert_eq!(rv32.signed(syscall.args[1]), -100);
+    assert_eq!(rv32.files.dir(syscall.args[1]), Ok(libc::AT_FDCWD));
+}
+
+#[test]
+fn test_write_syscalls() {
+    let mut mem = TestMem::new(0x40000);
+    let mut process = test_process();
+    let (_, wr, fds) = test_pipe(&mut process);
+    let mut sys = |mem : &mut TestMem, num, args : &[u64]| test_syscall(mem, &mut process, num, args);
+
+    mem.write_bytes(0x100, b"hello, world\n").unwrap();
+
+    // The count is a2, not the fd
+    assert_eq!(sys(&mut mem, SyscallNum::Write, &[wr, 0x100, 5]), 5);
+    assert_eq!(sys(&mut mem, SyscallNum::Write, &[wr, 0x100, 0]), 0);
+    assert_eq!(sys(&mut mem, SyscallNum::Write, &[wr, 0x3FFFF, 2]), errno(libc::EFAULT));
+    assert_eq!(sys(&mut mem, SyscallNum::Write, &[42, 0x100, 5]), errno(libc::EBADF));
+    assert_eq!(sys(&mut mem, SyscallNum::Write, &[42, 0x3FFFF, 2]), errno(libc::EBADF));
+    assert_eq!(drain(fds[0]), b"hello");
+
+    // iovecs of { base, len }, some of them empty
+    let iov = |mem : &mut TestMem, addr : u64, vecs : &[(u64, u64)]| {
+        for (i, (base, len)) in vecs.iter().enumerate() {
+            mem.store(addr + 16 * i as u64, *base).unwrap();
+            mem.store(addr + 16 * i as u64 + 8, *len).unwrap();
+        }
+    };
+
+    iov(&mut mem, 0x200, &[(0x107, 5), (0, 0), (0x105, 2), (0x10C, 1)]);
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x200, 4]), 8);
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x200, 0]), 0);
+    assert_eq!(drain(fds[0]), b"world, \n");
+
+    // A bad buffer fails the call only if it comes first
+    iov(&mut mem, 0x300, &[(0x100, 5), (0x3FFFF, 2), (0x100, 5)]);
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x300, 3]), 5);
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x310, 2]), errno(libc::EFAULT));
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x3FFF8, 1]), errno(libc::EFAULT));
+    assert_eq!(drain(fds[0]), b"hello");
+
+    iov(&mut mem, 0x400, &[(0x100, u64::MAX), (0x100, 2)]);
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x400, 2]), errno(libc::EINVAL));
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x400, 1025]), errno(libc::EINVAL));
+
+    // A pipe takes as much of a write as it has room for, then none
+    let capacity = unsafe { libc::fcntl(fds[1], libc::F_GETPIPE_SZ) } as u64;
+    assert_eq!(sys(&mut mem, SyscallNum::Write, &[wr, 0x1000, capacity + 10000]), capacity);
+    assert_eq!(sys(&mut mem, SyscallNum::Write, &[wr, 0x1000, 10000]), errno(libc::EAGAIN));
+    assert_eq!(drain(fds[0]).len() as u64, capacity);
+
+    iov(&mut mem, 0x500, &[(0x1000, capacity - 1), (0x100, 5)]);
+    assert_eq!(sys(&mut mem, SyscallNum::Writev, &[wr, 0x500, 2]), capacity);
+    assert_eq!(drain(fds[0]).last(), Some(&b'h'));
+}
+
+#[test]
+fn test_interrupted_calls_restart() {
+    let mut calls = 0;
+
+    let res = retry(|| {
+        calls += 1;
+
+        if calls < 3 {
+            unsafe { *libc::__errno_location() = libc::EINTR };
+            -1
+        }
+        else {
+            7
+        }
+    });
+
+    assert_eq!((res, calls), (Ok(7), 3));
+
+    let res = retry(|| {
+        unsafe { *libc::__errno_location() = libc::EAGAIN };
+        -1
+    });
+
+    assert_eq!(res, Err(libc::EAGAIN));
+}
+
+#[test]
+fn test_process_syscalls() {
+    use crate::elf::{build_test_elf, ElfFile};
+    use crate::progmem::ProgramMemory;
+
+    let elf = ElfFile::parse(build_test_elf(0x10000, &[(0x10000, &[0x13, 0, 0, 0], 0x1000)], &[])).unwrap();
+    let mut mem = ProgramMemory::from_elf(&elf);
+    let mut process = test_process();
+    process.vars.args = vec!["prog".to_string()];
+
+    let dir = test_dir("mmap");
+    std::fs::write(dir.join("file"), b"hello").unwrap();
+    let file = std::fs::File::open(dir.join("file")).unwrap();
+    let fd = process.files.insert(std::os::unix::io::IntoRawFd::into_raw_fd(file), 0);
+    let mut sys = |mem : &mut ProgramMemory, num, args : &[u64]| test_syscall(mem, &mut process, num, args);
+
+    // A failed brk leaves the break where it was
+    let heap = mem.heap_start();
+    assert_eq!(sys(&mut mem, SyscallNum::Brk, &[0]), heap);
+    assert_eq!(sys(&mut mem, SyscallNum::Brk, &[heap + 0x2000]), heap + 0x2000);
+    assert_eq!(sys(&mut mem, SyscallNum::Brk, &[heap + (1 << 40)]), heap + 0x2000);
+    assert_eq!(sys(&mut mem, SyscallNum::Brk, &[1]), heap + 0x2000);
+    assert_eq!(sys(&mut mem, SyscallNum::Brk, &[heap - 1]), heap + 0x2000);
+
+    const PROT_RW : u64 = 3;
+    const MAP_PRIVATE_ANON : u64 = 0x22;
+
+    let addr = sys(&mut mem, SyscallNum::Mmap, &[0, 0x2000, PROT_RW, MAP_PRIVATE_ANON, u64::MAX, 0]);
+    assert_eq!(addr & 0xFFF, 0);
+    write64(&mut mem, addr + 0x1000, 5).unwrap();
+    assert_eq!(sys(&mut mem, SyscallNum::Mmap, &[0, 0x1000, PROT_RW, 0x2, 77, 0]), errno(libc::EBADF));
+    assert_eq!(sys(&mut mem, SyscallNum::Mmap, &[0, 0, PROT_RW, MAP_PRIVATE_ANON, 0, 0]),
+        errno(libc::EINVAL));
+    assert_eq!(sys(&mut mem, SyscallNum::Mprotect, &[addr, 0x1000, 1]), 0);
+    assert!(mem.check(addr, 8, PERM_W).is_err());
+    assert_eq!(sys(&mut mem, SyscallNum::Mremap, &[addr, 0x2000, 0x4000, 1]), errno(libc::ENOMEM));
+    assert_eq!(sys(&mut mem, SyscallNum::Munmap, &[addr, 0x2000]), 0);
+    assert!(!mem.mapped(addr, 1));
+    assert_eq!(sys(&mut mem, SyscallNum::Munmap, &[addr + 1, 0x1000]), errno(libc::EINVAL));
+
+    // Lengths that cannot fit fail before anything is allocated for them
+    let fixed_anon = MAP_PRIVATE_ANON | MAP_FIXED;
+    assert_eq!(sys(&mut mem, SyscallNum::Mmap, &[0, 1 << 46, 1, MAP_PRIVATE_ANON, 0, 0]), errno(libc::ENOMEM));
+    assert_eq!(sys(&mut mem, SyscallNum::Mmap, &[0, u64::MAX, 1, MAP_PRIVATE_ANON, 0, 0]), errno(libc::ENOMEM));
+    assert_eq!(sys(&mut mem, SyscallNum::Mmap, &[!0xFFF, 0x2000, 1, fixed_anon, 0, 0]), errno(libc::ENOMEM));
+
+    // Only the file's bytes are copied; the rest of a large mapping is zero
+    let addr = sys(&mut mem, SyscallNum::Mmap, &[0, 1 << 40, 1, 0x2, fd, 1]);
+    assert_eq!(read32(&mem, addr).unwrap(), u32::from_le_bytes(*b"ello") as u64);
+    assert_eq!(read64(&mem, addr + 4).unwrap(), 0);
+    assert_eq!(read64(&mem, addr + (1 << 39)).unwrap(), 0);
+    assert_eq!(sys(&mut mem, SyscallNum::Munmap, &[addr, 1 << 40]), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let stack = mem.stack_top() - 0x1000;
+    assert_eq!(sys(&mut mem, SyscallNum::Getpid, &[]), std::process::id() as u64);
+    assert_eq!(sys(&mut mem, SyscallNum::Getuid, &[]), unsafe { libc::getuid() } as u64);
+    assert_eq!(sys(&mut mem, SyscallNum::Getegid, &[]), unsafe { libc::getegid() } as u64);
+
+    write64(&mut mem, stack + 8, u64::MAX).unwrap();
+    assert_eq!(sys(&mut mem, SyscallNum::RtSigaction, &[2, 0, stack]), 0);
+    assert_eq!(read64(&mem, stack + 8).unwrap(), 0);
+
+    let now = sys(&mut mem, SyscallNum::Time, &[stack]);
+    assert_eq!(read64(&mem, stack).unwrap(), now);
+    assert!(sys(&mut mem, SyscallNum::Time, &[0]) >= now);
+
+    assert!(sys(&mut mem, SyscallNum::Times, &[stack]) as i64 > 0);
+    assert_eq!(sys(&mut mem, SyscallNum::Times, &[0x10]), errno(libc::EFAULT));
+    assert_eq!(sys(&mut mem, SyscallNum::Uname, &[0x10]), errno(libc::EFAULT));
+    assert_eq!(sys(&mut mem, SyscallNum::Gettimeofday, &[0x10, 0]), errno(libc::EFAULT));
+
+    assert_eq!(sys(&mut mem, SyscallNum::Getmainvars, &[stack, 0x1000]), 0);
+    assert_eq!(read64(&mem, stack).unwrap(), 1);
+    assert_eq!(sys(&mut mem, SyscallNum::Getmainvars, &[stack, 8]), errno(libc::ENOMEM));
+}